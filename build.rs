@@ -0,0 +1,27 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // Only the grpc feature has generated code, a vendored protoc keeps protobuf-compiler off
+    // the list of build requirements
+    #[cfg(feature = "grpc")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("No vendored protoc");
+        std::env::set_var("PROTOC", protoc);
+
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/podpingd.proto"], &["proto"])
+            .expect("Error compiling proto/podpingd.proto");
+    }
+}
@@ -0,0 +1,33 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use podpingd::parse::{parse_podping, parse_podping_or_quarantine, MAX_CUSTOM_JSON_LENGTH};
+
+// Any custom_json from the chain either parses or is quarantined, never panics, and a podping
+// that parses still parses once serialized again, as long as it still fits in a custom_json
+fuzz_target!(|json: &str| {
+    let mut malformed = vec![];
+
+    match parse_podping_or_quarantine("pp_podcast_update", json, "fuzz", &mut malformed) {
+        Some(podping) => {
+            assert!(malformed.is_empty());
+
+            let serialized = serde_json::to_string(&podping).unwrap();
+            if serialized.len() <= MAX_CUSTOM_JSON_LENGTH {
+                assert!(parse_podping(&serialized).is_ok(), "{}", serialized);
+            }
+        }
+        None => assert_eq!(malformed.len(), 1),
+    }
+});
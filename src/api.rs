@@ -0,0 +1,270 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::podping::{PodpingFilter, PodpingRecord};
+use crate::replay::{archive_days, ReplaySource};
+use color_eyre::eyre::Error;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use serde_json::json;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{debug, error, info};
+
+const DEFAULT_API_LISTEN: &str = "127.0.0.1:8080";
+/// About a day of blocks
+const DEFAULT_MAX_RANGE_BLOCKS: u64 = 28800;
+
+struct ApiState {
+    archive: Arc<ReplaySource>,
+    max_range_blocks: u64,
+}
+
+pub(crate) fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Full<Bytes>> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+pub(crate) fn error_response(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    json_response(status, &json!({ "error": message }))
+}
+
+/// Query parameters of `/podpings`, `iri`, `reason` and `medium` can be repeated
+struct PodpingsQuery {
+    from: u64,
+    to: u64,
+    filter: PodpingFilter,
+}
+
+impl PodpingsQuery {
+    fn parse(query: &str, max_range_blocks: u64) -> Result<PodpingsQuery, String> {
+        let mut from = None;
+        let mut to = None;
+        let mut filter = PodpingFilter::default();
+
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "from" => from = Some(parse_block(&key, &value)?),
+                "to" => to = Some(parse_block(&key, &value)?),
+                "iri" => {
+                    filter.iris.insert(value.to_string());
+                }
+                "reason" => {
+                    filter.reasons.insert(value.to_string());
+                }
+                "medium" => {
+                    filter.mediums.insert(value.to_string());
+                }
+                _ => return Err(format!("Unknown parameter {}", key)),
+            }
+        }
+
+        let from = from.ok_or("from is required")?;
+        let to = to.unwrap_or(from.saturating_add(max_range_blocks - 1));
+
+        if from > to {
+            return Err("from is after to".to_string());
+        }
+
+        if to - from >= max_range_blocks {
+            return Err(format!(
+                "At most {} blocks can be queried at once",
+                max_range_blocks
+            ));
+        }
+
+        Ok(PodpingsQuery { from, to, filter })
+    }
+}
+
+fn parse_block(key: &str, value: &str) -> Result<u64, String> {
+    value
+        .parse::<u64>()
+        .map_err(|_| format!("{} must be a block number", key))
+}
+
+/// Podpings in the range that match the query, in block order
+async fn podpings(state: &ApiState, query: PodpingsQuery) -> Result<Vec<Vec<u8>>, Error> {
+    let mut days = archive_days(state.archive.clone(), Some(query.from), Some(query.to));
+    let mut records = vec![];
+
+    while let Some(day) = days.recv().await {
+        let (_, blocks) = day?;
+
+        for block in &blocks {
+            for tx in &block.transactions {
+                for (i, podping) in tx.indexed_podpings() {
+                    if query.filter.matches(podping) {
+                        records.push(serde_json::to_vec(&PodpingRecord::new(
+                            block, tx, i, podping,
+                        ))?);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+async fn handle(
+    state: Arc<ApiState>,
+    request: Request<hyper::body::Incoming>,
+) -> Response<Full<Bytes>> {
+    if request.method() != Method::GET {
+        return error_response(StatusCode::METHOD_NOT_ALLOWED, "Only GET is supported");
+    }
+
+    let path = request.uri().path().trim_end_matches('/');
+    let query = request.uri().query().unwrap_or("");
+
+    debug!("api: GET {}?{}", path, query);
+
+    if path == "/podpings" {
+        let query = match PodpingsQuery::parse(query, state.max_range_blocks) {
+            Ok(query) => query,
+            Err(message) => return error_response(StatusCode::BAD_REQUEST, &message),
+        };
+
+        return match podpings(&state, query).await {
+            // Records are serialized as they're read, so only the array is put together here
+            Ok(records) => {
+                let mut body = b"[".to_vec();
+                body.extend(records.join(&b","[..]));
+                body.push(b']');
+
+                Response::builder()
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Full::new(Bytes::from(body)))
+                    .unwrap()
+            }
+            Err(e) => {
+                error!("api: error reading the archive: {:#}", e);
+                error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Error reading the archive",
+                )
+            }
+        };
+    }
+
+    if let Some(block_num) = path.strip_prefix("/blocks/") {
+        let block_num = match block_num.parse::<u64>() {
+            Ok(block_num) => block_num,
+            Err(_) => return error_response(StatusCode::BAD_REQUEST, "Not a block number"),
+        };
+
+        let mut days = archive_days(state.archive.clone(), Some(block_num), Some(block_num));
+
+        while let Some(day) = days.recv().await {
+            match day {
+                Ok((_, blocks)) => {
+                    if let Some(block) = blocks.first() {
+                        return json_response(StatusCode::OK, block);
+                    }
+                }
+                Err(e) => {
+                    error!("api: error reading the archive: {:#}", e);
+                    return error_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Error reading the archive",
+                    );
+                }
+            }
+        }
+
+        // Blocks without podpings aren't archived
+        return error_response(StatusCode::NOT_FOUND, "No podpings archived for this block");
+    }
+
+    error_response(StatusCode::NOT_FOUND, "Not found")
+}
+
+/// Serves the read-only query API over the writer's archive in the background, when enabled
+///
+/// Panics if the writer doesn't keep an archive or the listen address can't be bound.
+pub(crate) async fn start_server(settings: &Settings) {
+    if !settings.api.enabled {
+        return;
+    }
+
+    let archive = match ReplaySource::from_writer(settings) {
+        Some(archive) => Arc::new(archive),
+        None => panic!("The query API needs the disk or object storage writer enabled"),
+    };
+
+    let listen = settings
+        .api
+        .listen
+        .clone()
+        .unwrap_or(DEFAULT_API_LISTEN.to_string());
+    let addr = match listen.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(e) => panic!("Invalid api listen address {}: {}", listen, e),
+    };
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => panic!("Unable to listen for the query API on {}: {}", addr, e),
+    };
+
+    let state = Arc::new(ApiState {
+        archive,
+        max_range_blocks: settings
+            .api
+            .max_range_blocks
+            .unwrap_or(DEFAULT_MAX_RANGE_BLOCKS)
+            .max(1),
+    });
+
+    info!("Query API listening on {}", addr);
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("api: error accepting a connection: {}", e);
+                    continue;
+                }
+            };
+            let state = state.clone();
+
+            tokio::spawn(async move {
+                let service = service_fn(move |request| {
+                    let state = state.clone();
+
+                    async move { Ok::<_, Infallible>(handle(state, request).await) }
+                });
+
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    debug!("api: connection closed: {}", e);
+                }
+            });
+        }
+    });
+}
@@ -0,0 +1,138 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use clap::{Args, Parser, Subcommand};
+#[cfg(all(unix, feature = "service"))]
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(version, about)]
+pub(crate) struct Cli {
+    /// Log to stderr so stdout only has podpings, for piping into jq and the like
+    ///
+    /// The console writer prints NDJSON unless writer.console_format picks another format
+    #[arg(long, short, global = true)]
+    pub(crate) quiet: bool,
+    /// What to do, `run` when left out
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+impl Cli {
+    /// The command to run, following the chain when none was given
+    pub(crate) fn command(&self) -> Command {
+        self.command
+            .clone()
+            .unwrap_or(Command::Run(RunArgs::default()))
+    }
+}
+
+#[derive(Subcommand, Clone)]
+pub(crate) enum Command {
+    /// Follow the chain and write podpings with the configured writer, the default
+    Run(RunArgs),
+    /// Scan a block range into the writer then exit, leaving last_updated_block alone
+    ///
+    /// Every block in the range is written again, e.g. to fill a gap or a new destination
+    Backfill {
+        /// First block to write
+        #[arg(long)]
+        from_block: u64,
+        /// Last block to write
+        #[arg(long)]
+        to_block: u64,
+    },
+    /// Send an existing archive through the configured writer and forwarder, then exit
+    Replay {
+        /// A data directory, or s3://<bucket> on the writer's object storage endpoint
+        source: String,
+        /// First block to replay, defaults to the start of the archive
+        #[arg(long)]
+        from_block: Option<u64>,
+        /// Last block to replay, defaults to the end of the archive
+        #[arg(long)]
+        to_block: Option<u64>,
+    },
+    /// Check written podpings against the chain, re-write any that are missing, then exit
+    Verify {
+        /// First block to check, defaults to verify.lookback_blocks before last_updated_block
+        #[arg(long)]
+        from_block: Option<u64>,
+        /// Last block to check, defaults to last_updated_block
+        #[arg(long)]
+        to_block: Option<u64>,
+    },
+    /// Save the nodes' raw responses for a block range as fixtures, then exit
+    ///
+    /// source.type = "fixtures" reads them back through the parser and writers
+    Capture {
+        /// Directory to save a <block_num>.json for each block in, created if needed
+        directory: String,
+        /// First block to save
+        #[arg(long)]
+        from_block: u64,
+        /// Last block to save
+        #[arg(long)]
+        to_block: u64,
+    },
+    /// Print the sync status of the podpingd running with this config, from status.listen
+    Status {
+        /// Print the raw JSON instead of a summary
+        #[arg(long)]
+        json: bool,
+    },
+    /// Parse podping custom_json payloads and print the schema version each one parsed as
+    ///
+    /// Exits non-zero if any of them aren't a podping. Needs no configuration
+    Parse {
+        /// Files with one custom_json's json each, stdin if none are given
+        files: Vec<String>,
+    },
+    /// Work with the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Work with the writer's failure_dead_letter_directory
+    Dlq {
+        #[command(subcommand)]
+        action: DlqAction,
+    },
+    /// Run under the Windows Service Control Manager, see the README to register the service
+    #[cfg(all(windows, feature = "service"))]
+    Service,
+}
+
+#[derive(Args, Clone, Default)]
+pub(crate) struct RunArgs {
+    /// Detach from the terminal and keep running in the background
+    ///
+    /// Set log.file, there's no terminal left to log to
+    #[cfg(all(unix, feature = "service"))]
+    #[arg(long)]
+    pub(crate) daemon: bool,
+    /// Write the daemon's process id to this file, which also stops a second copy starting
+    #[cfg(all(unix, feature = "service"))]
+    #[arg(long, value_name = "FILE", requires = "daemon")]
+    pub(crate) pid_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Clone, Copy)]
+pub(crate) enum ConfigAction {
+    /// Load the config files and environment, report any problems, then exit
+    Check,
+}
+
+#[derive(Subcommand, Clone, Copy)]
+pub(crate) enum DlqAction {
+    /// Write every dead-lettered block again, removing the files that succeed, then exit
+    Retry,
+}
@@ -9,33 +9,350 @@
  *
  *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
  */
+use crate::cli::Command;
+use crate::config_check;
+use crate::secrets::apply_secret_files;
 use chrono::{DateTime, Utc};
-use config::{Config, File};
+use color_eyre::eyre::{eyre, Report};
+use config::{Config, ConfigError, File};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::path::Path;
 use std::time::Duration;
 
 pub(crate) const CARGO_PKG_VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
 
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum ScannerStart {
+    Resume,
+    Head,
+    Beginning,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum ScannerFollow {
+    Head,
+    Irreversible,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum ScannerCatchupSource {
+    Rpc,
+    Haf,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum ScannerNetwork {
+    Mainnet,
+    Testnet,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Scanner {
     pub(crate) rpc_nodes: Vec<String>,
+    pub(crate) network: Option<ScannerNetwork>,
+    // Overrides the network's chain id, for other Hive chains like a local testnet
+    pub(crate) chain_id: Option<String>,
+    pub(crate) start: Option<ScannerStart>,
+    pub(crate) follow: Option<ScannerFollow>,
+    pub(crate) catchup_concurrency: Option<usize>,
+    pub(crate) catchup_max_blocks_per_second: Option<u64>,
+    pub(crate) catchup_batch_size: Option<u64>,
+    pub(crate) catchup_max_batch_size: Option<u64>,
+    pub(crate) catchup_adaptive_batch_size: Option<bool>,
+    pub(crate) catchup_memory_limit_mb: Option<u64>,
+    pub(crate) rpc_requests_per_second: Option<f64>,
+    pub(crate) rpc_burst: Option<u32>,
+    pub(crate) rpc_node_benchmark: Option<bool>,
+    #[serde(default, with = "humantime_serde")]
+    pub(crate) rpc_node_benchmark_interval: Option<Duration>,
+    pub(crate) catchup_source: Option<ScannerCatchupSource>,
+    // custom_json ids read as podpings, as globs or /regexes/
+    pub(crate) podping_ids: Option<Vec<String>>,
+    pub(crate) podping_ids_exclude: Option<Vec<String>>,
+    #[cfg_attr(not(feature = "haf"), allow(unused))]
+    pub(crate) haf_database_url: Option<String>,
+    #[cfg_attr(not(feature = "haf"), allow(unused))]
+    pub(crate) haf_batch_size: Option<u64>,
     pub(crate) start_block: Option<u64>,
     pub(crate) start_datetime: Option<DateTime<Utc>>,
+    pub(crate) end_block: Option<u64>,
+    pub(crate) end_datetime: Option<DateTime<Utc>>,
+
+    pub(crate) tls_client_cert: Option<String>,
+    pub(crate) tls_client_key: Option<String>,
+    pub(crate) tls_ca_cert: Option<String>,
+
+    #[serde(default, with = "humantime_serde")]
+    pub(crate) dns_cache_max_ttl: Option<Duration>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Log {
+    pub(crate) format: Option<LogFormat>,
+    pub(crate) file: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(not(feature = "otlp"), allow(unused))]
+pub struct Telemetry {
+    pub(crate) otlp_enabled: bool,
+    pub(crate) otlp_endpoint: Option<String>,
+    pub(crate) service_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum InvalidIriPolicy {
+    Flag,
+    Drop,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Iri {
+    pub(crate) validate: bool,
+    pub(crate) normalize: bool,
+    pub(crate) invalid_policy: Option<InvalidIriPolicy>,
+    pub(crate) allowed_schemes: Option<Vec<String>>,
+    pub(crate) max_length: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct Filter {
+    // Globs, or /regex/, matched against each podping IRI
+    pub(crate) iri_allow: Option<Vec<String>>,
+    pub(crate) iri_deny: Option<Vec<String>>,
+    // A file or http(s) URL listing IRIs to keep on top of iri_allow, re-read when it changes
+    pub(crate) watchlist_path: Option<String>,
+    #[serde(default, with = "humantime_serde")]
+    pub(crate) watchlist_reload_interval: Option<Duration>,
+    // Hive accounts podpings are accepted from, matched against each custom_json's signers
+    pub(crate) posting_accounts: Option<Vec<String>>,
+    // Also accept the accounts this one has given its posting authority to, read from the chain
+    pub(crate) posting_authority_account: Option<String>,
+    #[serde(default, with = "humantime_serde")]
+    pub(crate) posting_authority_refresh_interval: Option<Duration>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum HttpVersion {
+    // HTTP/2 when the server offers it over TLS, HTTP/1.1 otherwise
+    Auto,
+    Http1,
+    // HTTP/2 without negotiating it first, also for plain http:// servers
+    Http2,
+}
+
+/// HTTP client settings for the RPC nodes, object storage and the forwarder
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct Http {
+    #[serde(default, with = "humantime_serde")]
+    pub(crate) connect_timeout: Option<Duration>,
+    // The whole request, including reading the response
+    #[serde(default, with = "humantime_serde")]
+    pub(crate) timeout: Option<Duration>,
+    #[cfg_attr(
+        not(any(feature = "object-storage", feature = "forwarder")),
+        allow(unused)
+    )]
+    pub(crate) pool_max_idle_per_host: Option<usize>,
+    #[cfg_attr(
+        not(any(feature = "object-storage", feature = "forwarder")),
+        allow(unused)
+    )]
+    #[serde(default, with = "humantime_serde")]
+    pub(crate) pool_idle_timeout: Option<Duration>,
+    #[cfg_attr(
+        not(any(feature = "object-storage", feature = "forwarder")),
+        allow(unused)
+    )]
+    pub(crate) version: Option<HttpVersion>,
+    // Overrides HTTP_PROXY, HTTPS_PROXY and NO_PROXY, which are used otherwise
+    pub(crate) proxy: Option<String>,
+    pub(crate) no_proxy: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum SourceType {
+    // Scan the chain through the scanner's rpc_nodes
+    Hive,
+    // Like hive, but catching up from the scanner's HAF database
+    Haf,
+    // Podping records relayed by another podpingd over a websocket
+    #[serde(rename = "podping_websocket")]
+    PodpingWebsocket,
+    // An existing podpingd archive, a directory or s3://<bucket> like podpingd replay takes
+    Replay,
+    // Made up podpings, for load testing without a Hive connection
+    Simulator,
+    // Raw blocks saved by podpingd capture, for regression tests against real payloads
+    Fixtures,
+}
+
+/// Where live podpings come from
+#[derive(Debug, Deserialize, Default)]
+pub struct Source {
+    #[serde(rename = "type")]
+    pub(crate) type_: Option<SourceType>,
+    pub(crate) websocket_url: Option<String>,
+    // Reconnect when nothing, not even a ping, arrives for this long
+    #[cfg_attr(not(feature = "websocket"), allow(unused))]
+    #[serde(default, with = "humantime_serde")]
+    pub(crate) websocket_idle_timeout: Option<Duration>,
+    pub(crate) replay_path: Option<String>,
+    pub(crate) fixtures_path: Option<String>,
+    pub(crate) simulator_podpings_per_second: Option<f64>,
+    #[serde(default, with = "humantime_serde")]
+    pub(crate) simulator_block_interval: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub(crate) simulator_burst_interval: Option<Duration>,
+    pub(crate) simulator_burst_podpings: Option<u64>,
+    pub(crate) simulator_feeds: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
 pub enum WriterType {
     Disk,
     ObjectStorage,
+    Socket,
+    Elasticsearch,
+    Parquet,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
 pub enum WriterUrlStyle {
     Path,
     VirtualHost,
 }
 
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum ObjectStorageProvider {
+    S3,
+    Gcs,
+    Azure,
+    Local,
+    // S3 compatible stores, S3 with their own defaults
+    Minio,
+    Ceph,
+    B2,
+    R2,
+    Wasabi,
+}
+
+/// Server side encryption requested on S3 uploads
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum ObjectStorageSse {
+    // SSE-S3, keys managed by the store
+    Aes256,
+    // SSE-KMS, with object_storage_sse_kms_key_id or the bucket's default key
+    Kms,
+    // SSE-C, with the key in object_storage_sse_customer_key
+    Customer,
+}
+
+/// Where a Cloudflare R2 bucket's data is kept, which decides its endpoint
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[cfg_attr(not(feature = "object-storage"), allow(unused))]
+pub enum R2Jurisdiction {
+    Default,
+    Eu,
+    Fedramp,
+}
+
+/// Defaults for an S3 compatible store, picked with `object_storage_provider`
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(feature = "object-storage"), allow(unused))]
+pub struct S3Preset {
+    pub(crate) url_style: WriterUrlStyle,
+    // Signed with when object_storage_region is empty, None when the store needs the real one
+    pub(crate) region: Option<&'static str>,
+    // Whether a single part upload's ETag is the MD5 of its body
+    pub(crate) md5_etags: bool,
+    // Whether an upload with If-None-Match: * is refused when the object exists
+    pub(crate) conditional_writes: bool,
+}
+
+impl ObjectStorageProvider {
+    /// Whether the store is reached through the S3 API
+    pub fn is_s3(self) -> bool {
+        !matches!(
+            self,
+            ObjectStorageProvider::Gcs
+                | ObjectStorageProvider::Azure
+                | ObjectStorageProvider::Local
+        )
+    }
+
+    pub fn s3_preset(self) -> S3Preset {
+        match self {
+            // Encrypted or compressed objects don't get an MD5 ETag
+            ObjectStorageProvider::Minio => S3Preset {
+                url_style: WriterUrlStyle::Path,
+                region: Some("us-east-1"),
+                md5_etags: false,
+                conditional_writes: true,
+            },
+            ObjectStorageProvider::Ceph => S3Preset {
+                url_style: WriterUrlStyle::Path,
+                region: None,
+                md5_etags: true,
+                conditional_writes: false,
+            },
+            ObjectStorageProvider::B2 | ObjectStorageProvider::Wasabi => S3Preset {
+                url_style: WriterUrlStyle::VirtualHost,
+                region: None,
+                md5_etags: true,
+                conditional_writes: false,
+            },
+            ObjectStorageProvider::R2 => S3Preset {
+                url_style: WriterUrlStyle::Path,
+                region: Some("auto"),
+                md5_etags: true,
+                conditional_writes: true,
+            },
+            ObjectStorageProvider::S3
+            | ObjectStorageProvider::Gcs
+            | ObjectStorageProvider::Azure
+            | ObjectStorageProvider::Local => S3Preset {
+                url_style: WriterUrlStyle::VirtualHost,
+                region: None,
+                md5_etags: true,
+                conditional_writes: true,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum ExistingObjects {
+    Overwrite,
+    Conditional,
+    Head,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum WriterFailurePolicy {
+    Abort,
+    Skip,
+    Retry,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum ConsoleFormat {
+    Log,
+    Ndjson,
+    Pretty,
+    Csv,
+    Tsv,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Writer {
     pub(crate) enabled: bool,
@@ -44,35 +361,336 @@ pub struct Writer {
     #[serde(rename = "type")]
     pub(crate) type_: Option<WriterType>,
 
+    pub(crate) block_metadata: Option<bool>,
+    pub(crate) block_metadata_empty_blocks: Option<bool>,
+    #[cfg_attr(not(any(feature = "disk", feature = "object-storage")), allow(unused))]
+    pub(crate) empty_block_markers: Option<bool>,
+    pub(crate) quarantine_malformed: Option<bool>,
+    pub(crate) reorder_window: Option<usize>,
+    pub(crate) checkpoint_name: Option<String>,
+    pub(crate) checkpoint_recovery: Option<bool>,
+
+    #[cfg_attr(not(any(feature = "disk", feature = "object-storage")), allow(unused))]
+    pub(crate) dataset_publish: Option<bool>,
+    #[cfg_attr(not(any(feature = "disk", feature = "object-storage")), allow(unused))]
+    pub(crate) dataset_prefix: Option<String>,
+    pub(crate) day_manifest: Option<bool>,
+
+    pub(crate) console_format: Option<ConsoleFormat>,
+    pub(crate) console_payload_template: Option<String>,
+    pub(crate) console_payload_template_file: Option<String>,
+
+    pub(crate) failure_policy: Option<WriterFailurePolicy>,
+    pub(crate) failure_dead_letter_directory: Option<String>,
+    #[cfg_attr(
+        not(any(
+            feature = "disk",
+            feature = "object-storage",
+            feature = "elasticsearch",
+            feature = "parquet"
+        )),
+        allow(unused)
+    )]
+    #[serde(with = "humantime_serde")]
+    pub(crate) failure_retry_interval: Option<Duration>,
+
+    pub(crate) socket_path: Option<String>,
+
     pub(crate) disk_directory: Option<String>,
+    #[cfg_attr(not(feature = "disk"), allow(unused))]
+    pub(crate) disk_fsync: Option<bool>,
     pub(crate) disk_trim_old: Option<bool>,
     #[serde(with = "humantime_serde")]
     pub(crate) disk_trim_keep_duration: Option<Duration>,
 
+    pub(crate) object_storage_provider: Option<ObjectStorageProvider>,
     pub(crate) object_storage_base_url: Option<String>,
     pub(crate) object_storage_bucket_name: Option<String>,
     pub(crate) object_storage_region: Option<String>,
+    #[cfg_attr(not(feature = "object-storage"), allow(unused))]
     pub(crate) object_storage_url_style: Option<WriterUrlStyle>,
+    pub(crate) object_storage_r2_account_id: Option<String>,
+    #[cfg_attr(not(feature = "object-storage"), allow(unused))]
+    pub(crate) object_storage_r2_jurisdiction: Option<R2Jurisdiction>,
+    pub(crate) object_storage_create_bucket: Option<bool>,
+    pub(crate) object_storage_sse: Option<ObjectStorageSse>,
+    pub(crate) object_storage_sse_kms_key_id: Option<String>,
+    pub(crate) object_storage_sse_customer_key: Option<String>,
+    #[cfg_attr(not(feature = "object-storage"), allow(unused))]
+    pub(crate) object_storage_tls_ca_cert: Option<String>,
+    pub(crate) object_storage_existing_objects: Option<ExistingObjects>,
+    #[cfg_attr(not(feature = "object-storage"), allow(unused))]
+    pub(crate) object_storage_tags: Option<Vec<ObjectStorageTag>>,
+    #[cfg_attr(not(feature = "object-storage"), allow(unused))]
+    pub(crate) object_storage_cache_control: Option<String>,
+    #[cfg_attr(not(feature = "object-storage"), allow(unused))]
+    pub(crate) object_storage_content_disposition: Option<String>,
+    pub(crate) object_storage_metadata: Option<HashMap<String, String>>,
+    pub(crate) object_storage_replicas: Option<Vec<ObjectStorageReplica>>,
+    #[cfg_attr(not(feature = "object-storage"), allow(unused))]
+    pub(crate) object_storage_replica_max_backlog: Option<usize>,
+    pub(crate) object_storage_upload_concurrency: Option<usize>,
+    // Overrides http.pool_max_idle_per_host for object storage
+    #[cfg_attr(not(feature = "object-storage"), allow(unused))]
+    pub(crate) object_storage_max_connections_per_host: Option<usize>,
+    #[cfg_attr(not(feature = "object-storage"), allow(unused))]
+    pub(crate) object_storage_multipart_threshold_mb: Option<usize>,
+    pub(crate) object_storage_multipart_part_size_mb: Option<usize>,
+
+    // For object_storage_provider "azure"
+    #[cfg_attr(not(feature = "object-storage"), allow(unused))]
+    pub(crate) azure_account_name: Option<String>,
+    #[cfg_attr(not(feature = "object-storage"), allow(unused))]
+    pub(crate) azure_sas_token: Option<String>,
+    #[cfg_attr(not(feature = "object-storage"), allow(unused))]
+    pub(crate) azure_managed_identity_client_id: Option<String>,
+
+    pub(crate) elasticsearch_url: Option<String>,
+    pub(crate) elasticsearch_index_pattern: Option<String>,
+    #[cfg_attr(not(feature = "elasticsearch"), allow(unused))]
+    pub(crate) elasticsearch_max_retries: Option<u32>,
+    #[cfg_attr(not(feature = "elasticsearch"), allow(unused))]
+    pub(crate) elasticsearch_install_template: Option<bool>,
+    #[cfg_attr(not(feature = "elasticsearch"), allow(unused))]
+    pub(crate) elasticsearch_checkpoint_index: Option<String>,
+    pub(crate) elasticsearch_api_key: Option<String>,
+    #[cfg_attr(not(feature = "elasticsearch"), allow(unused))]
+    pub(crate) elasticsearch_username: Option<String>,
+    pub(crate) elasticsearch_password: Option<String>,
+
+    #[cfg_attr(not(feature = "parquet"), allow(unused))]
+    pub(crate) parquet_directory: Option<String>,
+    #[cfg_attr(not(feature = "parquet"), allow(unused))]
+    pub(crate) parquet_object_storage: Option<bool>,
+    #[cfg_attr(not(feature = "parquet"), allow(unused))]
+    pub(crate) parquet_prefix: Option<String>,
+    #[cfg_attr(not(feature = "parquet"), allow(unused))]
+    pub(crate) parquet_max_rows: Option<usize>,
+    #[cfg_attr(not(feature = "parquet"), allow(unused))]
+    #[serde(with = "humantime_serde")]
+    pub(crate) parquet_flush_interval: Option<Duration>,
+}
+
+/// An extra bucket the object storage writer copies every block to
+#[derive(Debug, Deserialize)]
+#[cfg_attr(not(feature = "object-storage"), allow(unused))]
+pub struct ObjectStorageReplica {
+    pub(crate) name: Option<String>,
+    pub(crate) base_url: String,
+    pub(crate) bucket_name: String,
+    pub(crate) region: String,
+    pub(crate) url_style: Option<WriterUrlStyle>,
+    // Names of the environment variables holding the replica's keys, AWS_* if unset
+    pub(crate) access_key_id_env: Option<String>,
+    pub(crate) secret_access_key_env: Option<String>,
+    pub(crate) session_token_env: Option<String>,
+}
+
+/// A tag on every block object the object storage writer uploads
+#[derive(Debug, Deserialize)]
+#[cfg_attr(not(feature = "object-storage"), allow(unused))]
+pub struct ObjectStorageTag {
+    pub(crate) key: String,
+    // A template, see `ObjectTags`
+    pub(crate) value: String,
+}
+
+/// Sends the podpings for some feed hosts only to the writer and endpoints it names
+#[derive(Debug, Deserialize, Clone)]
+pub struct Route {
+    pub(crate) hosts: Vec<String>,
+    // "writer", "forwarder" for forwarder.endpoint, or the name of one of forwarder.endpoints
+    pub(crate) to: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum ForwarderDelivery {
+    Ordered,
+    Concurrent,
+}
+
+/// OAuth2 client credentials grant for a forwarder endpoint's bearer token
+#[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(not(feature = "forwarder"), allow(unused))]
+pub struct OAuth2 {
+    pub(crate) token_url: String,
+    pub(crate) client_id: String,
+    pub(crate) client_secret: Option<String>,
+    pub(crate) client_secret_file: Option<String>,
+    pub(crate) scope: Option<String>,
+    pub(crate) audience: Option<String>,
+}
+
+/// One of several endpoints the forwarder POSTs every podping to
+#[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(not(feature = "forwarder"), allow(unused))]
+pub struct ForwarderEndpoint {
+    pub(crate) name: String,
+    pub(crate) url: String,
+    pub(crate) enabled: Option<bool>,
+    pub(crate) headers: Option<HashMap<String, String>>,
+    // Sent as "Authorization: Bearer <token>"
+    pub(crate) auth_token: Option<String>,
+    pub(crate) auth_token_file: Option<String>,
+    // Override forwarder.delivery and forwarder.max_in_flight
+    pub(crate) delivery: Option<ForwarderDelivery>,
+    pub(crate) max_in_flight: Option<usize>,
+    pub(crate) oauth2: Option<OAuth2>,
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(not(feature = "forwarder"), allow(unused))]
+pub struct Forwarder {
+    pub(crate) enabled: bool,
+    pub(crate) endpoint: Option<String>,
+    pub(crate) endpoints: Option<Vec<ForwarderEndpoint>>,
+    pub(crate) delivery: Option<ForwarderDelivery>,
+    pub(crate) max_in_flight: Option<usize>,
+    // For the single endpoint, each of endpoints sets its own
+    pub(crate) oauth2: Option<OAuth2>,
+    pub(crate) health_endpoint: Option<String>,
+    pub(crate) secret: Option<String>,
+
+    #[serde(default, with = "humantime_serde")]
+    pub(crate) dedupe_window: Option<Duration>,
+    pub(crate) dedupe_max_entries: Option<usize>,
+
+    #[serde(default, with = "humantime_serde")]
+    pub(crate) aggregate_window: Option<Duration>,
+
+    pub(crate) tls_client_cert: Option<String>,
+    pub(crate) tls_client_key: Option<String>,
+    pub(crate) tls_ca_cert: Option<String>,
+    #[serde(with = "humantime_serde")]
+    pub(crate) tls_reload_interval: Option<Duration>,
+
+    pub(crate) payload_template: Option<String>,
+    pub(crate) payload_template_file: Option<String>,
+
+    pub(crate) batch_enabled: Option<bool>,
+    pub(crate) batch_max_events: Option<usize>,
+    #[serde(with = "humantime_serde")]
+    pub(crate) batch_max_wait: Option<Duration>,
+
+    pub(crate) queue_enabled: Option<bool>,
+    pub(crate) queue_directory: Option<String>,
+    #[serde(with = "humantime_serde")]
+    pub(crate) queue_retry_min_interval: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub(crate) queue_retry_max_interval: Option<Duration>,
+
+    // Consecutive failures that open an endpoint's circuit, 0 never opens it
+    pub(crate) circuit_failure_threshold: Option<u32>,
+    #[serde(with = "humantime_serde")]
+    pub(crate) circuit_probe_interval: Option<Duration>,
+
+    pub(crate) failure_policy: Option<WriterFailurePolicy>,
+    pub(crate) failure_dead_letter_directory: Option<String>,
+    #[serde(with = "humantime_serde")]
+    pub(crate) failure_retry_interval: Option<Duration>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Status {
+    pub(crate) file: Option<String>,
+    #[serde(with = "humantime_serde")]
+    pub(crate) interval: Option<Duration>,
+    pub(crate) listen: Option<String>,
+    pub(crate) shutdown_report_webhook: Option<String>,
+    #[serde(default, with = "humantime_serde")]
+    pub(crate) write_latency_budget: Option<Duration>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Verify {
+    pub(crate) enabled: bool,
+    #[serde(with = "humantime_serde")]
+    pub(crate) interval: Option<Duration>,
+    pub(crate) lookback_blocks: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Api {
+    pub(crate) enabled: bool,
+    pub(crate) listen: Option<String>,
+    pub(crate) max_range_blocks: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(not(feature = "grpc"), allow(unused))]
+pub struct Grpc {
+    pub(crate) enabled: bool,
+    pub(crate) listen: Option<String>,
+    pub(crate) subscriber_buffer: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum SecretsProvider {
+    Vault,
+    Sops,
+}
+
+/// A setting filled in from the secrets provider
+#[derive(Debug, Deserialize)]
+pub struct SecretValue {
+    pub(crate) setting: String,
+    // The Vault path to read, unused for SOPS
+    pub(crate) path: Option<String>,
+    pub(crate) key: String,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct Secrets {
+    pub(crate) provider: Option<SecretsProvider>,
+    pub(crate) vault_address: Option<String>,
+    pub(crate) vault_token: Option<String>,
+    pub(crate) vault_namespace: Option<String>,
+    pub(crate) sops_file: Option<String>,
+    #[serde(default, with = "humantime_serde")]
+    pub(crate) refresh_interval: Option<Duration>,
+    pub(crate) values: Option<Vec<SecretValue>>,
 }
 
 #[derive(Debug, Deserialize)]
 #[allow(unused)]
 pub struct Settings {
     pub(crate) debug: bool,
+    pub(crate) log: Log,
+    pub(crate) telemetry: Telemetry,
     pub(crate) scanner: Scanner,
+    // Every setting is commented out in the default config, which leaves no table
+    #[serde(default)]
+    pub(crate) source: Source,
+    pub(crate) iri: Iri,
+    // Every setting is commented out in the default config, which leaves no table
+    #[serde(default)]
+    pub(crate) filter: Filter,
     pub(crate) writer: Writer,
+    pub(crate) forwarder: Forwarder,
+    pub(crate) verify: Verify,
+    pub(crate) status: Status,
+    pub(crate) api: Api,
+    pub(crate) grpc: Grpc,
+    pub(crate) routes: Option<Vec<Route>>,
+    // Every setting is commented out in the default config, which leaves no table
+    #[serde(default)]
+    pub(crate) secrets: Secrets,
+    // Every setting is commented out in the default config, which leaves no table
+    #[serde(default)]
+    pub(crate) http: Http,
 }
 
-pub(crate) fn load_config() -> Settings {
+/// Reads the config files and environment without checking what's configured
+pub(crate) fn read_config() -> Result<Settings, ConfigError> {
     let user_config_file_option = option_env!("PODPINGD_CONFIG_FILE");
 
     let user_config_file = match user_config_file_option {
         Some(file) => {
             if !Path::new(file).exists() {
-                panic!(
+                return Err(ConfigError::Message(format!(
                     "File {} defined by PODPINGD_CONFIG_FILE does not exist",
                     file
-                );
+                )));
             }
             file
         }
@@ -83,8 +701,14 @@ pub(crate) fn load_config() -> Settings {
         .add_source(File::with_name("conf/00-default.toml"))
         .add_source(File::with_name(user_config_file).required(false))
         .add_source(config::Environment::with_prefix("PODPINGD").separator("__"))
-        .build()
-        .unwrap();
+        .build()?;
+
+    apply_secret_files(config)?.try_deserialize()
+}
+
+/// Reads the settings and checks them for running `command`
+pub(crate) fn load_config(command: &Command) -> Result<Settings, Report> {
+    let settings = read_config().map_err(|e| eyre!("Unable to load the configuration: {}", e))?;
 
-    config.try_deserialize().unwrap()
+    config_check::check(settings, command)
 }
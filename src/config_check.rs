@@ -0,0 +1,1546 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::cli::Command;
+use crate::config::{
+    read_config, ExistingObjects, ForwarderEndpoint, OAuth2, ObjectStorageProvider,
+    ObjectStorageSse, Route, ScannerCatchupSource, SecretsProvider, Settings, SourceType,
+    WriterFailurePolicy, WriterType,
+};
+use crate::filter::pattern_regex;
+use crate::routing::{HostPattern, FORWARDER_TARGET, WRITER_TARGET};
+use crate::secret_provider::PROVIDED_SETTINGS;
+use crate::secrets::secret_env_var_is_set;
+use chrono::Utc;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use regex::Regex;
+use reqwest::header::{HeaderName, HeaderValue};
+use std::collections::HashSet;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+
+// S3's limit, Azure allows the same
+const MAX_OBJECT_TAGS: usize = 10;
+// The smallest part S3 takes, other than the last
+const MIN_MULTIPART_PART_SIZE_MB: usize = 5;
+
+/// A setting podpingd would stop on, and what to do about it
+pub(crate) struct ConfigProblem {
+    pub(crate) setting: String,
+    pub(crate) problem: String,
+    pub(crate) fix: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}\n    fix: {}",
+            self.setting, self.problem, self.fix
+        )
+    }
+}
+
+#[derive(Default)]
+struct Problems(Vec<ConfigProblem>);
+
+impl Problems {
+    fn add(&mut self, setting: &str, problem: impl ToString, fix: impl ToString) {
+        self.0.push(ConfigProblem {
+            setting: setting.to_string(),
+            problem: problem.to_string(),
+            fix: fix.to_string(),
+        });
+    }
+
+    /// Adds a problem if the setting is unset or empty, returns the value otherwise
+    fn require<'a>(
+        &mut self,
+        setting: &str,
+        value: &'a Option<String>,
+        fix: impl ToString,
+    ) -> Option<&'a str> {
+        match value.as_deref() {
+            Some(value) if !value.is_empty() => Some(value),
+            _ => {
+                self.add(setting, "is not set", fix);
+                None
+            }
+        }
+    }
+
+    fn url(&mut self, setting: &str, value: &str) {
+        if let Err(e) = value.parse::<url::Url>() {
+            self.add(
+                setting,
+                format!("{:?} isn't a valid URL: {}", value, e),
+                "use a full URL like https://example.com",
+            );
+        }
+    }
+
+    fn listen(&mut self, setting: &str, value: &Option<String>) {
+        if let Some(listen) = value.as_deref().filter(|listen| !listen.is_empty()) {
+            if let Err(e) = listen.parse::<SocketAddr>() {
+                self.add(
+                    setting,
+                    format!("{:?} isn't a valid listen address: {}", listen, e),
+                    "use an IP address and port like 127.0.0.1:8080",
+                );
+            }
+        }
+    }
+
+    fn tls(
+        &mut self,
+        section: &str,
+        cert_file: &Option<String>,
+        key_file: &Option<String>,
+        ca_file: &Option<String>,
+    ) {
+        let non_empty = |file: &Option<String>| file.clone().filter(|f| !f.is_empty());
+
+        if non_empty(cert_file).is_some() != non_empty(key_file).is_some() {
+            self.add(
+                &format!("{}.tls_client_cert", section),
+                "tls_client_cert and tls_client_key must be set together",
+                "set both for mutual TLS, or neither",
+            );
+        }
+
+        for (setting, file) in [
+            ("tls_client_cert", cert_file),
+            ("tls_client_key", key_file),
+            ("tls_ca_cert", ca_file),
+        ] {
+            self.pem_file(&format!("{}.{}", section, setting), file);
+        }
+    }
+
+    fn pem_file(&mut self, setting: &str, value: &Option<String>) {
+        if let Some(file) = value
+            .as_deref()
+            .filter(|file| !file.is_empty() && !Path::new(file).exists())
+        {
+            self.add(
+                setting,
+                format!("{} doesn't exist", file),
+                "point it at a PEM file podpingd can read",
+            );
+        }
+    }
+
+    fn oauth2(&mut self, section: &str, oauth2: &Option<OAuth2>) {
+        let oauth2 = match oauth2 {
+            Some(oauth2) => oauth2,
+            None => return,
+        };
+
+        match oauth2.token_url.is_empty() {
+            true => self.add(
+                &format!("{}.oauth2.token_url", section),
+                "is not set",
+                "set it to the identity provider's token endpoint",
+            ),
+            false => self.url(&format!("{}.oauth2.token_url", section), &oauth2.token_url),
+        }
+
+        if oauth2.client_id.is_empty() {
+            self.add(
+                &format!("{}.oauth2.client_id", section),
+                "is not set",
+                "set it to the client registered with the identity provider",
+            );
+        }
+
+        let non_empty = |value: &Option<String>| value.as_deref().is_some_and(|v| !v.is_empty());
+        match (
+            non_empty(&oauth2.client_secret),
+            non_empty(&oauth2.client_secret_file),
+        ) {
+            (true, true) => self.add(
+                &format!("{}.oauth2.client_secret", section),
+                "client_secret and client_secret_file are both set",
+                "only set one",
+            ),
+            (false, false) => self.add(
+                &format!("{}.oauth2.client_secret", section),
+                "is not set",
+                "set client_secret, or client_secret_file for a mounted secret",
+            ),
+            _ => {}
+        }
+    }
+
+    fn max_in_flight(&mut self, setting: &str, value: Option<usize>) {
+        if value == Some(0) {
+            self.add(
+                setting,
+                "is 0, nothing would ever be sent",
+                "set it to 1 or more, or use delivery = \"ordered\"",
+            );
+        }
+    }
+
+    fn failure_policy(
+        &mut self,
+        section: &str,
+        policy: Option<WriterFailurePolicy>,
+        dead_letter_directory: &Option<String>,
+    ) {
+        if policy == Some(WriterFailurePolicy::Skip) {
+            self.require(
+                &format!("{}.failure_dead_letter_directory", section),
+                dead_letter_directory,
+                format!(
+                    "set a directory for skipped blocks, or change {}.failure_policy",
+                    section
+                ),
+            );
+        }
+    }
+}
+
+/// Checks every setting podpingd needs for what's enabled, without connecting to anything
+///
+/// Everything wrong is returned at once, rather than panicking on the first one in a writer's
+/// `new()`.
+pub(crate) fn validate(settings: &Settings) -> Vec<ConfigProblem> {
+    let mut problems = Problems::default();
+
+    check_features(settings, &mut problems);
+    check_scanner(settings, &mut problems);
+    check_source(settings, &mut problems);
+    check_http(settings, &mut problems);
+
+    if settings.writer.enabled {
+        check_writer(settings, &mut problems);
+    }
+
+    if settings.forwarder.enabled {
+        check_forwarder(settings, &mut problems);
+    }
+
+    for (setting, patterns) in [
+        ("filter.iri_allow", &settings.filter.iri_allow),
+        ("filter.iri_deny", &settings.filter.iri_deny),
+    ] {
+        for pattern in patterns.iter().flatten() {
+            if let Err(e) = Regex::new(&pattern_regex(pattern)) {
+                problems.add(
+                    setting,
+                    format!("{:?} isn't a valid pattern: {}", pattern, e),
+                    "use a glob with * and ?, or a regex between slashes",
+                );
+            }
+        }
+    }
+
+    if let Some(path) = settings
+        .filter
+        .watchlist_path
+        .as_deref()
+        .filter(|path| !path.is_empty())
+    {
+        match path.starts_with("http://") || path.starts_with("https://") {
+            true => problems.url("filter.watchlist_path", path),
+            false if !Path::new(path).is_file() => problems.add(
+                "filter.watchlist_path",
+                format!("{} doesn't exist", path),
+                "point it at a file with one feed IRI per line, or an http(s) URL serving one",
+            ),
+            false => {}
+        }
+    }
+
+    check_podping_senders(settings, &mut problems);
+
+    if let Some(routes) = &settings.routes {
+        check_routes(settings, routes, &mut problems);
+    }
+
+    if settings.api.enabled {
+        match (settings.writer.enabled, &settings.writer.type_) {
+            (true, Some(WriterType::Disk) | Some(WriterType::ObjectStorage)) => {}
+            _ => problems.add(
+                "api.enabled",
+                "the query API reads blocks back from the writer's archive",
+                "enable the disk or object storage writer, or set api.enabled = false",
+            ),
+        }
+        problems.listen("api.listen", &settings.api.listen);
+    }
+
+    if settings.grpc.enabled {
+        problems.listen("grpc.listen", &settings.grpc.listen);
+    }
+    problems.listen("status.listen", &settings.status.listen);
+
+    if let Some(provider) = settings.secrets.provider {
+        check_secrets(settings, provider, &mut problems);
+    }
+
+    problems.0
+}
+
+/// Backends left out of the build can't be configured
+fn check_features(settings: &Settings, problems: &mut Problems) {
+    let missing_feature = |problems: &mut Problems, setting: &str, what: &str, feature: &str| {
+        problems.add(
+            setting,
+            format!(
+                "{} is configured but podpingd was built without the {} feature",
+                what, feature
+            ),
+            format!("rebuild with --features {}, or change {}", feature, setting),
+        )
+    };
+
+    if settings.writer.enabled {
+        match settings.writer.type_ {
+            #[cfg(not(feature = "disk"))]
+            Some(WriterType::Disk) => {
+                missing_feature(problems, "writer.type", "the disk writer", "disk")
+            }
+            #[cfg(not(feature = "object-storage"))]
+            Some(WriterType::ObjectStorage) => missing_feature(
+                problems,
+                "writer.type",
+                "the object storage writer",
+                "object-storage",
+            ),
+            #[cfg(not(feature = "elasticsearch"))]
+            Some(WriterType::Elasticsearch) => missing_feature(
+                problems,
+                "writer.type",
+                "the Elasticsearch writer",
+                "elasticsearch",
+            ),
+            #[cfg(not(feature = "parquet"))]
+            Some(WriterType::Parquet) => {
+                missing_feature(problems, "writer.type", "the Parquet writer", "parquet")
+            }
+            #[cfg(not(unix))]
+            Some(WriterType::Socket) => problems.add(
+                "writer.type",
+                "the socket writer needs Unix sockets or FIFOs, which this platform doesn't have",
+                "use another writer type",
+            ),
+            _ => {}
+        }
+    }
+
+    if cfg!(not(feature = "forwarder")) && settings.forwarder.enabled {
+        missing_feature(problems, "forwarder.enabled", "the forwarder", "forwarder")
+    }
+
+    if cfg!(not(feature = "otlp")) && settings.telemetry.otlp_enabled {
+        missing_feature(problems, "telemetry.otlp_enabled", "OTLP export", "otlp")
+    }
+
+    if cfg!(not(feature = "haf"))
+        && settings.scanner.catchup_source == Some(ScannerCatchupSource::Haf)
+    {
+        missing_feature(
+            problems,
+            "scanner.catchup_source",
+            "catching up from HAF",
+            "haf",
+        )
+    }
+
+    if cfg!(not(feature = "haf")) && settings.source.type_ == Some(SourceType::Haf) {
+        missing_feature(problems, "source.type", "catching up from HAF", "haf")
+    }
+
+    if cfg!(not(feature = "websocket"))
+        && settings.source.type_ == Some(SourceType::PodpingWebsocket)
+    {
+        missing_feature(
+            problems,
+            "source.type",
+            "following a podping websocket",
+            "websocket",
+        )
+    }
+
+    if cfg!(not(feature = "grpc")) && settings.grpc.enabled {
+        missing_feature(problems, "grpc.enabled", "the gRPC server", "grpc")
+    }
+}
+
+fn check_scanner(settings: &Settings, problems: &mut Problems) {
+    let scanner = &settings.scanner;
+
+    if scanner.rpc_nodes.is_empty() {
+        problems.add(
+            "scanner.rpc_nodes",
+            "is empty",
+            "list at least one Hive API node, e.g. [\"https://api.hive.blog\"]",
+        );
+    }
+    for node in &scanner.rpc_nodes {
+        problems.url("scanner.rpc_nodes", node);
+    }
+
+    if let Some(chain_id) = &scanner.chain_id {
+        if chain_id.len() != 64 || !chain_id.chars().all(|c| c.is_ascii_hexdigit()) {
+            problems.add(
+                "scanner.chain_id",
+                format!("{:?} isn't a chain id", chain_id),
+                "use the 64 hex digit chain_id the node's condenser_api.get_version answers with",
+            );
+        }
+    }
+
+    if scanner
+        .podping_ids
+        .as_ref()
+        .is_some_and(|podping_ids| podping_ids.is_empty())
+    {
+        problems.add(
+            "scanner.podping_ids",
+            "is empty, no custom_json would be read as a podping",
+            "list the ids to read, e.g. [\"podping\", \"pp_*_*\"], or leave it unset",
+        );
+    }
+    for (setting, patterns) in [
+        ("scanner.podping_ids", &scanner.podping_ids),
+        ("scanner.podping_ids_exclude", &scanner.podping_ids_exclude),
+    ] {
+        for pattern in patterns.iter().flatten() {
+            if let Err(e) = Regex::new(&pattern_regex(pattern)) {
+                problems.add(
+                    setting,
+                    format!("{:?} isn't a valid pattern: {}", pattern, e),
+                    "use a glob with * and ?, or a regex between slashes",
+                );
+            }
+        }
+    }
+
+    if let (Some(start_block), Some(end_block)) = (scanner.start_block, scanner.end_block) {
+        if start_block > end_block {
+            problems.add(
+                "scanner.end_block",
+                format!("{} is before start_block {}", end_block, start_block),
+                "set end_block to a block after start_block",
+            );
+        }
+    }
+
+    if let Some(start_datetime) = scanner.start_datetime {
+        if start_datetime > Utc::now() {
+            problems.add(
+                "scanner.start_datetime",
+                format!("{} is in the future", start_datetime),
+                "set start_datetime to a time that has passed, or leave it unset to start at the head",
+            );
+        }
+    }
+
+    if let (Some(start_datetime), Some(end_datetime)) =
+        (scanner.start_datetime, scanner.end_datetime)
+    {
+        if start_datetime > end_datetime {
+            problems.add(
+                "scanner.end_datetime",
+                format!(
+                    "{} is before start_datetime {}",
+                    end_datetime, start_datetime
+                ),
+                "set end_datetime to a time after start_datetime",
+            );
+        }
+    }
+
+    if scanner.catchup_source == Some(ScannerCatchupSource::Haf)
+        || settings.source.type_ == Some(SourceType::Haf)
+    {
+        problems.require(
+            "scanner.haf_database_url",
+            &scanner.haf_database_url,
+            "set it to the HAF database, e.g. postgresql://haf_app@haf.example.com/haf_block_log",
+        );
+
+        if scanner.haf_batch_size == Some(0) {
+            problems.add(
+                "scanner.haf_batch_size",
+                "is zero",
+                "set it to the blocks per query, or leave it unset for 1000",
+            );
+        }
+    }
+
+    if scanner.catchup_memory_limit_mb == Some(0) {
+        problems.add(
+            "scanner.catchup_memory_limit_mb",
+            "is zero",
+            "set it to the MB of fetched blocks catch-up may hold, or leave it unset for 256",
+        );
+    }
+
+    if scanner
+        .rpc_node_benchmark_interval
+        .is_some_and(|interval| interval.is_zero())
+    {
+        problems.add(
+            "scanner.rpc_node_benchmark_interval",
+            "is zero",
+            "set an interval like \"15m\", or rpc_node_benchmark = false to turn benchmarks off",
+        );
+    }
+
+    problems.tls(
+        "scanner",
+        &scanner.tls_client_cert,
+        &scanner.tls_client_key,
+        &scanner.tls_ca_cert,
+    );
+}
+
+/// Only blocks read from Hive say who signed each podping
+fn check_podping_senders(settings: &Settings, problems: &mut Problems) {
+    let filter = &settings.filter;
+
+    if filter.posting_accounts.is_none() && filter.posting_authority_account.is_none() {
+        return;
+    }
+
+    if let Some(
+        source_type @ (SourceType::PodpingWebsocket | SourceType::Replay | SourceType::Simulator),
+    ) = settings.source.type_
+    {
+        problems.add(
+            "filter.posting_accounts",
+            format!(
+                "podping senders can't be checked with a {:?} source, its podpings don't say who signed them",
+                source_type
+            ),
+            "check senders on the podpingd the blocks come from, or leave posting_accounts and posting_authority_account unset",
+        );
+    }
+    if settings.source.type_ == Some(SourceType::Fixtures) {
+        problems.add(
+            "filter.posting_accounts",
+            "podping senders aren't checked when reading fixtures, every podping in them is kept",
+            "leave posting_accounts and posting_authority_account unset",
+        );
+    }
+
+    if filter
+        .posting_accounts
+        .iter()
+        .flatten()
+        .any(|account| account.is_empty())
+    {
+        problems.add(
+            "filter.posting_accounts",
+            "has an empty account name",
+            "list Hive account names, e.g. [\"podping.aaa\"]",
+        );
+    }
+
+    if filter
+        .posting_authority_account
+        .as_ref()
+        .is_some_and(|account| account.is_empty())
+    {
+        problems.add(
+            "filter.posting_authority_account",
+            "is empty",
+            "set it to the account that gives out posting authority, e.g. \"podping\", or leave it unset",
+        );
+    }
+
+    if filter
+        .posting_authority_refresh_interval
+        .is_some_and(|interval| interval.is_zero())
+    {
+        problems.add(
+            "filter.posting_authority_refresh_interval",
+            "is zero",
+            "set an interval like \"1h\", or leave it unset",
+        );
+    }
+}
+
+fn check_source(settings: &Settings, problems: &mut Problems) {
+    let source = &settings.source;
+
+    if source.type_ == Some(SourceType::Replay) {
+        if let Some(path) = problems.require(
+            "source.replay_path",
+            &source.replay_path,
+            "set it to a podpingd archive directory, or s3://<bucket> on the writer's endpoint",
+        ) {
+            match path.starts_with("s3://") {
+                true if cfg!(not(feature = "object-storage")) => problems.add(
+                    "source.replay_path",
+                    format!(
+                        "{} is in object storage but podpingd was built without the object-storage feature",
+                        path
+                    ),
+                    "rebuild with --features object-storage, or replay a directory",
+                ),
+                false if !Path::new(path).is_dir() => problems.add(
+                    "source.replay_path",
+                    format!("{} isn't a directory", path),
+                    "point it at the directory a disk writer wrote",
+                ),
+                _ => {}
+            }
+        }
+    }
+
+    if source.type_ == Some(SourceType::Fixtures) {
+        if let Some(path) = problems.require(
+            "source.fixtures_path",
+            &source.fixtures_path,
+            "set it to a directory podpingd capture saved blocks to",
+        ) {
+            if !Path::new(path).is_dir() {
+                problems.add(
+                    "source.fixtures_path",
+                    format!("{} isn't a directory", path),
+                    "save some blocks there with podpingd capture <DIRECTORY> --from-block <N> --to-block <N>",
+                );
+            }
+        }
+    }
+
+    if source.type_ == Some(SourceType::Simulator) {
+        if source
+            .simulator_podpings_per_second
+            .is_some_and(|podpings_per_second| {
+                !(podpings_per_second >= 0.0 && podpings_per_second.is_finite())
+            })
+        {
+            problems.add(
+                "source.simulator_podpings_per_second",
+                "isn't a number of podpings",
+                "set it to 0 or more, e.g. 5 for about what mainnet sees",
+            );
+        }
+        if source
+            .simulator_burst_interval
+            .is_some_and(|burst_interval| burst_interval.is_zero())
+        {
+            problems.add(
+                "source.simulator_burst_interval",
+                "is zero",
+                "set an interval like \"10m\", or leave it unset for no bursts",
+            );
+        }
+        if source.simulator_feeds == Some(0) {
+            problems.add(
+                "source.simulator_feeds",
+                "is zero, podpings need a feed",
+                "set it to 1 or more, or leave it unset",
+            );
+        }
+    }
+
+    if let Some(source_type @ (SourceType::PodpingWebsocket | SourceType::Simulator)) =
+        source.type_.filter(|_| settings.verify.enabled)
+    {
+        problems.add(
+            "verify.enabled",
+            format!(
+                "a {:?} source only has new blocks, there's no history to verify against",
+                source_type
+            ),
+            "verify from a hive, haf, replay or fixtures source, or set verify.enabled = false",
+        );
+    }
+
+    if source.type_ != Some(SourceType::PodpingWebsocket) {
+        return;
+    }
+
+    if let Some(url) = problems.require(
+        "source.websocket_url",
+        &source.websocket_url,
+        "set it to the relay's websocket, e.g. wss://relay.example.com/podpings",
+    ) {
+        match url.parse::<url::Url>() {
+            Ok(parsed) if !matches!(parsed.scheme(), "ws" | "wss") => problems.add(
+                "source.websocket_url",
+                format!("{:?} isn't a websocket URL", url),
+                "use a ws:// or wss:// URL",
+            ),
+            Ok(_) => {}
+            Err(_) => problems.url("source.websocket_url", url),
+        }
+    }
+
+    if source
+        .websocket_idle_timeout
+        .is_some_and(|idle_timeout| idle_timeout.is_zero())
+    {
+        problems.add(
+            "source.websocket_idle_timeout",
+            "is zero",
+            "set a timeout like \"5m\", or leave it unset",
+        );
+    }
+}
+
+fn check_http(settings: &Settings, problems: &mut Problems) {
+    let http = &settings.http;
+
+    for (setting, timeout) in [
+        ("http.connect_timeout", http.connect_timeout),
+        ("http.timeout", http.timeout),
+    ] {
+        if timeout.is_some_and(|timeout| timeout.is_zero()) {
+            problems.add(
+                setting,
+                "is zero",
+                "set a timeout like \"30s\", or leave it unset",
+            );
+        }
+    }
+
+    match http.proxy() {
+        Some(proxy) => problems.url("http.proxy", proxy),
+        None if http
+            .no_proxy
+            .as_deref()
+            .is_some_and(|no_proxy| !no_proxy.is_empty()) =>
+        {
+            problems.add(
+                "http.no_proxy",
+                "is set without http.proxy",
+                "set http.proxy, or use the NO_PROXY environment variable with HTTP(S)_PROXY",
+            )
+        }
+        None => {}
+    }
+}
+
+fn check_writer(settings: &Settings, problems: &mut Problems) {
+    let writer = &settings.writer;
+
+    if let Some(name) = &writer.checkpoint_name {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        {
+            problems.add(
+                "writer.checkpoint_name",
+                format!("{:?} isn't safe as a file name and object key", name),
+                "only use letters, digits, '-', '_' and '.'",
+            );
+        }
+    }
+
+    problems.failure_policy(
+        "writer",
+        writer.failure_policy,
+        &writer.failure_dead_letter_directory,
+    );
+
+    if writer.block_metadata_empty_blocks.unwrap_or(false)
+        && !writer.block_metadata.unwrap_or(false)
+    {
+        problems.add(
+            "writer.block_metadata_empty_blocks",
+            "is set but block_metadata isn't",
+            "set block_metadata = true as well",
+        );
+    }
+
+    if writer.day_manifest.unwrap_or(false)
+        && !matches!(writer.type_, Some(WriterType::ObjectStorage))
+    {
+        problems.add(
+            "writer.day_manifest",
+            "only the object storage writer writes day manifests",
+            "set type = \"objectstorage\", or set day_manifest = false",
+        );
+    }
+
+    if writer.quarantine_malformed.unwrap_or(false)
+        && !matches!(
+            writer.type_,
+            Some(WriterType::Disk | WriterType::ObjectStorage)
+        )
+    {
+        problems.add(
+            "writer.quarantine_malformed",
+            "only the disk and object storage writers quarantine malformed podpings",
+            "set type = \"disk\" or \"objectstorage\", or set quarantine_malformed = false",
+        );
+    }
+
+    if writer.checkpoint_recovery.unwrap_or(false)
+        && !matches!(writer.type_, Some(WriterType::ObjectStorage))
+    {
+        problems.add(
+            "writer.checkpoint_recovery",
+            "only the object storage writer can recover its checkpoint",
+            "set type = \"objectstorage\", or set checkpoint_recovery = false",
+        );
+    }
+
+    match writer.type_ {
+        None => problems.add(
+            "writer.type",
+            "is not set",
+            "set it to \"disk\", \"objectstorage\", \"elasticsearch\", \"parquet\" or \"socket\", or set writer.enabled = false",
+        ),
+        Some(WriterType::Disk) => {
+            if let Some(directory) = problems.require(
+                "writer.disk_directory",
+                &writer.disk_directory,
+                "set it to the directory podpings are written to",
+            ) {
+                if !Path::new(directory).is_dir() {
+                    problems.add(
+                        "writer.disk_directory",
+                        format!("{} doesn't exist or isn't a directory", directory),
+                        format!("create it with mkdir -p {}", directory),
+                    );
+                }
+            }
+
+            if writer.disk_trim_old.unwrap_or(false) && writer.disk_trim_keep_duration.is_none() {
+                problems.add(
+                    "writer.disk_trim_keep_duration",
+                    "is not set but disk_trim_old is enabled",
+                    "set how long to keep podpings, e.g. \"30days\", or set disk_trim_old = false",
+                );
+            }
+        }
+        Some(WriterType::ObjectStorage) => {
+            problems.require(
+                "writer.object_storage_bucket_name",
+                &writer.object_storage_bucket_name,
+                "set it to the bucket podpings are written to",
+            );
+            check_object_storage_provider(settings, problems);
+
+            if writer.object_storage_upload_concurrency == Some(0) {
+                problems.add(
+                    "writer.object_storage_upload_concurrency",
+                    "is zero",
+                    "set it to the uploads allowed at once, or leave it unset for 64",
+                );
+            }
+
+            for (i, replica) in writer
+                .object_storage_replicas
+                .iter()
+                .flatten()
+                .enumerate()
+            {
+                let setting = format!("writer.object_storage_replicas[{}]", i);
+
+                problems.url(&format!("{}.base_url", setting), &replica.base_url);
+                if replica.access_key_id_env.is_some() != replica.secret_access_key_env.is_some()
+                {
+                    problems.add(
+                        &setting,
+                        "only one of access_key_id_env and secret_access_key_env is set",
+                        "set both, or neither to use the AWS_* variables",
+                    );
+                }
+            }
+        }
+        Some(WriterType::Elasticsearch) => {
+            if let Some(url) = problems.require(
+                "writer.elasticsearch_url",
+                &writer.elasticsearch_url,
+                "set it to the cluster's URL, e.g. http://localhost:9200",
+            ) {
+                problems.url("writer.elasticsearch_url", url);
+            }
+
+            if let Some(index_pattern) = &writer.elasticsearch_index_pattern {
+                if index_pattern.split('%').next().unwrap_or_default().is_empty() {
+                    problems.add(
+                        "writer.elasticsearch_index_pattern",
+                        format!("{:?} doesn't start with a fixed prefix", index_pattern),
+                        "start it with the index name, e.g. podpings-%Y.%m",
+                    );
+                }
+            }
+        }
+        Some(WriterType::Parquet) => match writer.parquet_object_storage.unwrap_or(false) {
+            true => {
+                problems.require(
+                    "writer.object_storage_bucket_name",
+                    &writer.object_storage_bucket_name,
+                    "set it to the bucket Parquet files are written to",
+                );
+                check_object_storage_provider(settings, problems);
+            }
+            false => {
+                problems.require(
+                    "writer.parquet_directory",
+                    &writer.parquet_directory,
+                    "set it to the directory Parquet files are written to, or set parquet_object_storage = true",
+                );
+            }
+        },
+        Some(WriterType::Socket) => {
+            problems.require(
+                "writer.socket_path",
+                &writer.socket_path,
+                "set it to the Unix socket or FIFO to write to",
+            );
+        }
+    }
+}
+
+fn check_object_storage_provider(settings: &Settings, problems: &mut Problems) {
+    let writer = &settings.writer;
+
+    let provider = writer
+        .object_storage_provider
+        .unwrap_or(ObjectStorageProvider::S3);
+    let r2_account_id = writer
+        .object_storage_r2_account_id
+        .as_deref()
+        .filter(|account_id| !account_id.is_empty());
+
+    if r2_account_id.is_some() && provider != ObjectStorageProvider::R2 {
+        problems.add(
+            "writer.object_storage_r2_account_id",
+            format!("only applies to \"r2\", not {:?}", provider),
+            "remove it, or set object_storage_provider = \"r2\"",
+        );
+    }
+
+    match provider {
+        ObjectStorageProvider::S3
+        | ObjectStorageProvider::Minio
+        | ObjectStorageProvider::Ceph
+        | ObjectStorageProvider::B2
+        | ObjectStorageProvider::R2
+        | ObjectStorageProvider::Wasabi => {
+            let preset = provider.s3_preset();
+
+            match provider == ObjectStorageProvider::R2 && r2_account_id.is_some() {
+                true => {
+                    if writer
+                        .object_storage_base_url
+                        .as_deref()
+                        .is_some_and(|base_url| !base_url.is_empty())
+                    {
+                        problems.add(
+                            "writer.object_storage_base_url",
+                            "overrides object_storage_r2_account_id",
+                            "leave object_storage_base_url empty, or remove object_storage_r2_account_id",
+                        );
+                    }
+                }
+                false => {
+                    if let Some(base_url) = problems.require(
+                        "writer.object_storage_base_url",
+                        &writer.object_storage_base_url,
+                        "set it to the S3 endpoint, e.g. https://s3.us-east-1.amazonaws.com",
+                    ) {
+                        problems.url("writer.object_storage_base_url", base_url);
+                    }
+                }
+            }
+            if provider == ObjectStorageProvider::R2 {
+                if let Some(region) = writer.object_storage_region.as_deref().filter(|region| {
+                    !region.is_empty() && *region != "auto" && *region != "us-east-1"
+                }) {
+                    problems.add(
+                        "writer.object_storage_region",
+                        format!("R2 only has the \"auto\" region, not {:?}", region),
+                        "set it to \"auto\" or leave it empty",
+                    );
+                }
+            }
+            if preset.region.is_none() {
+                problems.require(
+                    "writer.object_storage_region",
+                    &writer.object_storage_region,
+                    "set it to the bucket's region, e.g. us-east-1",
+                );
+            }
+            if !preset.conditional_writes
+                && writer.object_storage_existing_objects == Some(ExistingObjects::Conditional)
+            {
+                problems.add(
+                    "writer.object_storage_existing_objects",
+                    format!("{:?} doesn't refuse conditional writes", provider),
+                    "set it to \"head\" to check for objects before writing them",
+                );
+            }
+        }
+        ObjectStorageProvider::Local => {
+            problems.require(
+                "writer.object_storage_base_url",
+                &writer.object_storage_base_url,
+                "set it to the directory buckets are kept in",
+            );
+        }
+        ObjectStorageProvider::Gcs | ObjectStorageProvider::Azure => {
+            if writer.object_storage_create_bucket.unwrap_or(false) {
+                problems.add(
+                    "writer.object_storage_create_bucket",
+                    "podpingd can only create S3 buckets",
+                    "create the bucket or container beforehand, or set object_storage_create_bucket = false",
+                );
+            }
+        }
+    }
+
+    check_object_storage_sse(settings, problems);
+    check_object_storage_tags(settings, problems);
+    check_object_storage_headers(settings, problems);
+
+    if writer
+        .object_storage_multipart_part_size_mb
+        .is_some_and(|part_size| part_size < MIN_MULTIPART_PART_SIZE_MB)
+    {
+        problems.add(
+            "writer.object_storage_multipart_part_size_mb",
+            format!("is under {} MB", MIN_MULTIPART_PART_SIZE_MB),
+            "set it to at least 5, the smallest part S3 compatible stores take",
+        );
+    }
+
+    problems.pem_file(
+        "writer.object_storage_tls_ca_cert",
+        &writer.object_storage_tls_ca_cert,
+    );
+}
+
+fn check_object_storage_sse(settings: &Settings, problems: &mut Problems) {
+    let writer = &settings.writer;
+    let provider = writer
+        .object_storage_provider
+        .unwrap_or(ObjectStorageProvider::S3);
+    let is_set = |value: &Option<String>| value.as_deref().is_some_and(|value| !value.is_empty());
+
+    if writer.object_storage_sse.is_some() && !provider.is_s3() {
+        problems.add(
+            "writer.object_storage_sse",
+            format!("only applies to S3 compatible stores, not {:?}", provider),
+            "remove it, the provider encrypts objects with its own settings",
+        );
+    }
+    if is_set(&writer.object_storage_sse_kms_key_id)
+        && writer.object_storage_sse != Some(ObjectStorageSse::Kms)
+    {
+        problems.add(
+            "writer.object_storage_sse_kms_key_id",
+            "is only used with object_storage_sse = \"kms\"",
+            "set object_storage_sse = \"kms\", or remove the key ID",
+        );
+    }
+
+    match writer.object_storage_sse {
+        Some(ObjectStorageSse::Customer) => {
+            problems.require(
+                "writer.object_storage_sse_customer_key",
+                &writer.object_storage_sse_customer_key,
+                "set it to a base64 encoded 256-bit key, e.g. from openssl rand -base64 32",
+            );
+            if writer
+                .object_storage_base_url
+                .as_deref()
+                .is_some_and(|base_url| base_url.starts_with("http://"))
+            {
+                problems.add(
+                    "writer.object_storage_base_url",
+                    "is plain HTTP, which S3 refuses SSE-C keys over",
+                    "use an https:// endpoint",
+                );
+            }
+        }
+        _ => {
+            if is_set(&writer.object_storage_sse_customer_key) {
+                problems.add(
+                    "writer.object_storage_sse_customer_key",
+                    "is only used with object_storage_sse = \"customer\"",
+                    "set object_storage_sse = \"customer\", or remove the key",
+                );
+            }
+        }
+    }
+}
+
+fn check_object_storage_tags(settings: &Settings, problems: &mut Problems) {
+    let tags = settings
+        .writer
+        .object_storage_tags
+        .as_deref()
+        .unwrap_or_default();
+
+    if tags.len() > MAX_OBJECT_TAGS {
+        problems.add(
+            "writer.object_storage_tags",
+            format!(
+                "has {} tags, S3 allows {} per object",
+                tags.len(),
+                MAX_OBJECT_TAGS
+            ),
+            "remove some tags",
+        );
+    }
+
+    let mut keys = HashSet::new();
+    for tag in tags {
+        if tag.key.is_empty() {
+            problems.add(
+                "writer.object_storage_tags",
+                "has a tag with an empty key",
+                "give every tag a key",
+            );
+        } else if !keys.insert(tag.key.as_str()) {
+            problems.add(
+                "writer.object_storage_tags",
+                format!("has more than one {:?} tag", tag.key),
+                "keep one of them",
+            );
+        }
+    }
+}
+
+fn check_object_storage_headers(settings: &Settings, problems: &mut Problems) {
+    let writer = &settings.writer;
+
+    for (setting, value) in [
+        (
+            "writer.object_storage_cache_control",
+            &writer.object_storage_cache_control,
+        ),
+        (
+            "writer.object_storage_content_disposition",
+            &writer.object_storage_content_disposition,
+        ),
+    ] {
+        if let Some(value) = value.as_deref() {
+            if HeaderValue::from_str(value).is_err() {
+                problems.add(
+                    setting,
+                    format!("{:?} isn't a valid HTTP header value", value),
+                    "use a value without newlines",
+                );
+            }
+        }
+    }
+
+    for (key, value) in writer.object_storage_metadata.iter().flatten() {
+        if HeaderName::from_bytes(format!("x-amz-meta-{}", key).as_bytes()).is_err()
+            || HeaderValue::from_str(value).is_err()
+        {
+            problems.add(
+                "writer.object_storage_metadata",
+                format!("{:?} isn't valid object metadata", key),
+                "use a key without spaces and a value without newlines",
+            );
+        }
+    }
+}
+
+fn check_forwarder(settings: &Settings, problems: &mut Problems) {
+    let forwarder = &settings.forwarder;
+
+    let endpoint = forwarder
+        .endpoint
+        .as_deref()
+        .filter(|endpoint| !endpoint.is_empty());
+    let endpoints = forwarder.endpoints.as_deref().unwrap_or_default();
+
+    match (endpoint, endpoints.is_empty()) {
+        (Some(_), false) => problems.add(
+            "forwarder.endpoints",
+            "forwarder.endpoint is set as well",
+            "move endpoint into endpoints, or remove endpoints",
+        ),
+        (Some(endpoint), true) => problems.url("forwarder.endpoint", endpoint),
+        (None, true) => problems.add(
+            "forwarder.endpoint",
+            "is not set",
+            "set it to the URL podpings are POSTed to, or list several in forwarder.endpoints",
+        ),
+        (None, false) => check_forwarder_endpoints(endpoints, problems),
+    }
+
+    if let Some(health_endpoint) = forwarder.health_endpoint.as_deref() {
+        problems.url("forwarder.health_endpoint", health_endpoint);
+    }
+
+    problems.max_in_flight("forwarder.max_in_flight", forwarder.max_in_flight);
+    problems.oauth2("forwarder", &forwarder.oauth2);
+
+    problems.tls(
+        "forwarder",
+        &forwarder.tls_client_cert,
+        &forwarder.tls_client_key,
+        &forwarder.tls_ca_cert,
+    );
+
+    if forwarder.queue_enabled.unwrap_or(false) {
+        problems.require(
+            "forwarder.queue_directory",
+            &forwarder.queue_directory,
+            "set it to a directory for the retry queue, or set queue_enabled = false",
+        );
+    }
+
+    problems.failure_policy(
+        "forwarder",
+        forwarder.failure_policy,
+        &forwarder.failure_dead_letter_directory,
+    );
+
+    if forwarder.aggregate_window.is_some()
+        && (forwarder.payload_template.is_some() || forwarder.payload_template_file.is_some())
+    {
+        problems.add(
+            "forwarder.payload_template",
+            "doesn't apply to feed updates, which aggregate_window sends instead of podpings",
+            "remove the template, or unset aggregate_window to forward podpings",
+        );
+    }
+}
+
+fn check_forwarder_endpoints(endpoints: &[ForwarderEndpoint], problems: &mut Problems) {
+    let mut names = HashSet::new();
+
+    for endpoint in endpoints {
+        let setting = format!("forwarder.endpoints.{}", endpoint.name);
+
+        if endpoint.name.is_empty()
+            || !endpoint
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            problems.add(
+                &format!("{}.name", setting),
+                format!("{:?} isn't a valid endpoint name", endpoint.name),
+                "use letters, digits, - and _, it's also the endpoint's queue subdirectory",
+            );
+        } else if !names.insert(endpoint.name.as_str()) {
+            problems.add(
+                &format!("{}.name", setting),
+                "is used by more than one endpoint",
+                "give every endpoint its own name",
+            );
+        }
+
+        match endpoint.url.is_empty() {
+            true => problems.add(
+                &format!("{}.url", setting),
+                "is not set",
+                "set it to the URL podpings are POSTed to",
+            ),
+            false => problems.url(&format!("{}.url", setting), &endpoint.url),
+        }
+
+        for (name, value) in endpoint.headers.iter().flatten() {
+            if HeaderName::from_bytes(name.as_bytes()).is_err()
+                || HeaderValue::from_str(value).is_err()
+            {
+                problems.add(
+                    &format!("{}.headers", setting),
+                    format!("{:?} isn't a valid HTTP header", name),
+                    "use a header name without spaces and a value without newlines",
+                );
+            }
+        }
+
+        problems.max_in_flight(
+            &format!("{}.max_in_flight", setting),
+            endpoint.max_in_flight,
+        );
+        problems.oauth2(&setting, &endpoint.oauth2);
+
+        let authorization_header = endpoint
+            .headers
+            .iter()
+            .flatten()
+            .any(|(name, _)| name.eq_ignore_ascii_case("authorization"));
+
+        let non_empty = |value: &Option<String>| value.as_deref().is_some_and(|v| !v.is_empty());
+        if non_empty(&endpoint.auth_token) && non_empty(&endpoint.auth_token_file) {
+            problems.add(
+                &format!("{}.auth_token", setting),
+                "auth_token and auth_token_file are both set",
+                "only set one",
+            );
+        }
+        if endpoint.oauth2.is_some()
+            && (authorization_header
+                || non_empty(&endpoint.auth_token)
+                || non_empty(&endpoint.auth_token_file))
+        {
+            problems.add(
+                &format!("{}.oauth2", setting),
+                "is set along with an auth_token or Authorization header",
+                "use either OAuth2 or a fixed token",
+            );
+        }
+    }
+}
+
+/// The writer and endpoints routes can send podpings to
+fn route_targets(settings: &Settings) -> Vec<&str> {
+    let mut targets = vec![];
+
+    if settings.writer.enabled {
+        targets.push(WRITER_TARGET);
+    }
+
+    if settings.forwarder.enabled {
+        if settings
+            .forwarder
+            .endpoint
+            .as_deref()
+            .is_some_and(|endpoint| !endpoint.is_empty())
+        {
+            targets.push(FORWARDER_TARGET);
+        }
+
+        for endpoint in settings.forwarder.endpoints.iter().flatten() {
+            targets.push(endpoint.name.as_str());
+        }
+    }
+
+    targets
+}
+
+fn check_routes(settings: &Settings, routes: &[Route], problems: &mut Problems) {
+    let targets = route_targets(settings);
+
+    if settings.forwarder.enabled
+        && settings
+            .forwarder
+            .endpoints
+            .iter()
+            .flatten()
+            .any(|endpoint| endpoint.name == WRITER_TARGET)
+    {
+        problems.add(
+            "forwarder.endpoints.writer.name",
+            "is the archive writer's name in routes",
+            "rename the endpoint",
+        );
+    }
+
+    for (i, route) in routes.iter().enumerate() {
+        if route.hosts.is_empty() {
+            problems.add(
+                &format!("routes.{}.hosts", i),
+                "is empty",
+                "list the feed hosts the route is for, e.g. \"*.example.com\"",
+            );
+        }
+
+        for host in &route.hosts {
+            if let Err(e) = HostPattern::parse(host) {
+                problems.add(
+                    &format!("routes.{}.hosts", i),
+                    e,
+                    "use a host name, or *.domain for every host under it",
+                );
+            }
+        }
+
+        if route.to.is_empty() {
+            problems.add(
+                &format!("routes.{}.to", i),
+                "is empty",
+                "list the writer and endpoints the route sends podpings to",
+            );
+        }
+
+        for target in &route.to {
+            if !targets.contains(&target.as_str()) {
+                problems.add(
+                    &format!("routes.{}.to", i),
+                    format!("{:?} isn't an enabled writer or forwarder endpoint", target),
+                    "use \"writer\", \"forwarder\" for forwarder.endpoint, or the name of one of forwarder.endpoints",
+                );
+            }
+        }
+    }
+}
+
+fn check_secrets(settings: &Settings, provider: SecretsProvider, problems: &mut Problems) {
+    let secrets = &settings.secrets;
+
+    match provider {
+        SecretsProvider::Vault => {
+            match secrets
+                .vault_address
+                .as_deref()
+                .filter(|address| !address.is_empty())
+            {
+                Some(address) => problems.url("secrets.vault_address", address),
+                None if std::env::var("VAULT_ADDR").is_err() => problems.add(
+                    "secrets.vault_address",
+                    "is not set, and neither is VAULT_ADDR",
+                    "set it to the Vault server, e.g. https://vault.example.com:8200",
+                ),
+                None => {}
+            }
+
+            if secrets
+                .vault_token
+                .as_deref()
+                .unwrap_or_default()
+                .is_empty()
+                && !secret_env_var_is_set("VAULT_TOKEN")
+            {
+                problems.add(
+                    "secrets.vault_token",
+                    "is not set, and neither is VAULT_TOKEN or VAULT_TOKEN_FILE",
+                    "set VAULT_TOKEN, or vault_token_file to a mounted token",
+                );
+            }
+        }
+        SecretsProvider::Sops => {
+            if let Some(file) = problems.require(
+                "secrets.sops_file",
+                &secrets.sops_file,
+                "set it to the SOPS encrypted file to read",
+            ) {
+                if !Path::new(file).is_file() {
+                    problems.add(
+                        "secrets.sops_file",
+                        format!("{} doesn't exist", file),
+                        "check the path, it's relative to the working directory",
+                    );
+                }
+            }
+        }
+    }
+
+    for (i, value) in secrets.values.iter().flatten().enumerate() {
+        let setting = format!("secrets.values[{}]", i);
+
+        if !PROVIDED_SETTINGS.contains(&value.setting.as_str()) {
+            problems.add(
+                &format!("{}.setting", setting),
+                format!(
+                    "{:?} can't be fetched from a secrets provider",
+                    value.setting
+                ),
+                "use one of the settings listed in conf/00-default.toml under [secrets]",
+            );
+        }
+
+        if provider == SecretsProvider::Vault
+            && value.path.as_deref().unwrap_or_default().is_empty()
+        {
+            problems.add(
+                &format!("{}.path", setting),
+                "is not set",
+                "set it to the Vault path holding the secret, e.g. secret/data/podpingd",
+            );
+        }
+    }
+}
+
+/// Everything wrong with the settings for running `command`
+///
+/// backfill, verify and dlq retry go back over blocks already written, so they need a writer that
+/// keeps them block by block.
+pub(crate) fn validate_command(settings: &Settings, command: &Command) -> Vec<ConfigProblem> {
+    let mut problems = Problems(validate(settings));
+
+    let subcommand = match command {
+        Command::Backfill { .. } => "backfill",
+        Command::Verify { .. } => "verify",
+        Command::Dlq { .. } => "dlq retry",
+        _ => return problems.0,
+    };
+
+    match (settings.writer.enabled, &settings.writer.type_) {
+        (false, _) => problems.add(
+            "writer.enabled",
+            format!("{} needs the persistent writer enabled", subcommand),
+            "set writer.enabled = true with the writer the podpings were written with",
+        ),
+        (true, Some(WriterType::Socket)) => problems.add(
+            "writer.type",
+            format!("{} can't go back over the socket writer, it keeps nothing", subcommand),
+            "use the disk, object storage or elasticsearch writer",
+        ),
+        (true, Some(WriterType::Parquet)) => problems.add(
+            "writer.type",
+            format!("{} can't go back over Parquet files block by block", subcommand),
+            "use the disk, object storage or elasticsearch writer, or re-run the range with podpingd replay",
+        ),
+        _ => {}
+    }
+
+    if let (
+        Command::Backfill { .. } | Command::Verify { .. },
+        Some(source_type @ (SourceType::PodpingWebsocket | SourceType::Simulator)),
+    ) = (command, settings.source.type_)
+    {
+        problems.add(
+            "source.type",
+            format!(
+                "{} needs a source with history, a {:?} source only has new blocks",
+                subcommand, source_type
+            ),
+            "use a hive, haf, replay or fixtures source",
+        );
+    }
+
+    problems.0
+}
+
+/// Hands back settings that are fine for running `command`, otherwise an error listing every problem
+pub(crate) fn check(settings: Settings, command: &Command) -> Result<Settings> {
+    let problems = validate_command(&settings, command);
+
+    if !problems.is_empty() {
+        return Err(eyre!(
+            "{} configuration problem(s):\n{}",
+            problems.len(),
+            problems
+                .iter()
+                .map(|problem| problem.to_string())
+                .collect::<Vec<_>>()
+                .join("\n")
+        ));
+    }
+
+    Ok(settings)
+}
+
+/// `podpingd config check`, prints every problem with the settings
+pub(crate) fn print_config_check() -> Result<()> {
+    let settings = match read_config() {
+        Ok(settings) => settings,
+        Err(e) => return Err(eyre!("Unable to load the configuration: {}", e)),
+    };
+
+    let problems = validate(&settings);
+    if problems.is_empty() {
+        println!("Configuration OK");
+        return Ok(());
+    }
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+
+    Err(eyre!("{} configuration problem(s) found", problems.len()))
+}
@@ -0,0 +1,144 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::hive::scanner::HiveBlockWithNum;
+use crate::writer::writer::{new_writer, Writer};
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use serde::Deserialize;
+use std::path::PathBuf;
+use tracing::{error, info, warn};
+
+/// Single blocks from the file writers, batches from Elasticsearch
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DeadLetterPayload {
+    Block(HiveBlockWithNum),
+    Blocks(Vec<HiveBlockWithNum>),
+}
+
+#[derive(Deserialize)]
+struct DeadLetterFile {
+    payload: DeadLetterPayload,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RetryReport {
+    pub(crate) retried: usize,
+    pub(crate) failed: usize,
+    pub(crate) unreadable: usize,
+}
+
+/// Writes every block `writer_name` dead-lettered again, deleting each file once it's written
+///
+/// Blocks go through `repair_block`, so the checkpoint stays where it is. A file that still
+/// fails is left for the next retry.
+pub(crate) async fn retry<W: Writer + Send + Sync>(
+    settings: &Settings,
+    writer_name: &str,
+) -> Result<RetryReport, Error> {
+    let dir = match settings
+        .writer
+        .failure_dead_letter_directory
+        .as_deref()
+        .filter(|dir| !dir.is_empty())
+    {
+        Some(dir) => PathBuf::from(dir),
+        None => return Err(eyre!("failure_dead_letter_directory is not set")),
+    };
+
+    let prefix = format!("{}_", writer_name);
+    let mut files = vec![];
+    let mut entries = match tokio::fs::read_dir(&dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!(
+                "No dead letter directory at {}, nothing to retry",
+                dir.display()
+            );
+            return Ok(RetryReport::default());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+
+        if file_name.starts_with(&prefix) && file_name.ends_with(".json") {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+
+    info!(
+        "Retrying {} {} dead letters from {}",
+        files.len(),
+        writer_name,
+        dir.display()
+    );
+
+    let writer = new_writer::<W>(settings).await?;
+    let mut report = RetryReport::default();
+
+    for file in files {
+        let dead_letter =
+            match serde_json::from_slice::<DeadLetterFile>(&tokio::fs::read(&file).await?) {
+                Ok(dead_letter) => dead_letter,
+                Err(e) => {
+                    warn!(
+                        "Skipping {}, it isn't a block dead letter: {}",
+                        file.display(),
+                        e
+                    );
+                    report.unreadable += 1;
+                    continue;
+                }
+            };
+
+        let blocks = match dead_letter.payload {
+            DeadLetterPayload::Block(block) => vec![block],
+            DeadLetterPayload::Blocks(blocks) => blocks,
+        };
+
+        let mut written = true;
+        for block in blocks {
+            let block_num = block.block_num;
+
+            if let Err(e) = writer.repair_block(block).await {
+                error!(
+                    "Block {} from {} still failing: {:#}",
+                    block_num,
+                    file.display(),
+                    e
+                );
+                written = false;
+                break;
+            }
+        }
+
+        match written {
+            true => {
+                tokio::fs::remove_file(&file).await?;
+                info!("Retried {}", file.display());
+                report.retried += 1;
+            }
+            false => report.failed += 1,
+        }
+    }
+
+    info!(
+        "Dead letter retry complete: {} written, {} still failing, {} unreadable",
+        report.retried, report.failed, report.unreadable
+    );
+
+    Ok(report)
+}
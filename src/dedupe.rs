@@ -0,0 +1,228 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::hive::scanner::HiveBlockWithNum;
+use crate::podping::{podping_iris, podping_reason};
+use crate::stage::BlockStage;
+use crate::status::SYNC_STATUS;
+use chrono::{DateTime, TimeDelta, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tracing::debug;
+
+pub(crate) const DEFAULT_DEDUPE_MAX_ENTRIES: usize = 100_000;
+
+type DedupeKey = (String, String);
+
+/// Feed IRI and reason pairs seen in the last `window` of block time
+pub(crate) struct DedupeWindow {
+    window: TimeDelta,
+    max_entries: usize,
+    seen: HashMap<DedupeKey, DateTime<Utc>>,
+    // Insertion order for expiry, can hold stale entries for keys that were seen again later
+    order: VecDeque<(DedupeKey, DateTime<Utc>)>,
+}
+
+impl DedupeWindow {
+    pub(crate) fn new(window: Duration, max_entries: Option<usize>) -> DedupeWindow {
+        DedupeWindow {
+            window: TimeDelta::from_std(window).expect("dedupe_window is too long"),
+            max_entries: max_entries.unwrap_or(DEFAULT_DEDUPE_MAX_ENTRIES).max(1),
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn forget_oldest(&mut self) {
+        if let Some((key, seen_at)) = self.order.pop_front() {
+            if self.seen.get(&key) == Some(&seen_at) {
+                self.seen.remove(&key);
+            }
+        }
+    }
+
+    /// Records the pair, returning whether it wasn't already seen within the window
+    fn check(&mut self, key: DedupeKey, at: DateTime<Utc>) -> bool {
+        while self
+            .order
+            .front()
+            .is_some_and(|(_, seen_at)| *seen_at <= at - self.window)
+        {
+            self.forget_oldest();
+        }
+
+        if let Some(seen_at) = self.seen.get(&key) {
+            if at - *seen_at < self.window {
+                return false;
+            }
+        }
+
+        self.seen.insert(key.clone(), at);
+        self.order.push_back((key, at));
+
+        while self.seen.len() > self.max_entries {
+            self.forget_oldest();
+        }
+
+        true
+    }
+}
+
+impl BlockStage for DedupeWindow {
+    /// Removes podpings whose every IRI and reason pair was already seen in the window
+    ///
+    /// Corrections are passed through untouched, the receiver needs the replacement block whole.
+    fn process_block(&mut self, mut block: HiveBlockWithNum) -> HiveBlockWithNum {
+        if block.replaces.is_some() {
+            return block;
+        }
+
+        let mut suppressed: u64 = 0;
+
+        for tx in &mut block.transactions {
+            tx.retain_podpings(|podping| {
+                let reason = podping_reason(podping).unwrap_or_default();
+                let mut new = false;
+
+                for iri in podping_iris(podping) {
+                    // Check every IRI so each one's window starts now, not just the first new one
+                    new |= self.check((iri, reason.clone()), block.timestamp);
+                }
+
+                if !new {
+                    suppressed += 1;
+                }
+
+                new
+            });
+        }
+
+        block.transactions.retain(|tx| !tx.podpings.is_empty());
+
+        if suppressed > 0 {
+            debug!(
+                "dedupe: suppressed {} duplicate podpings in block {}",
+                suppressed, block.block_num
+            );
+            SYNC_STATUS.increment_counter("duplicates_suppressed", suppressed);
+        }
+
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hive::scanner::HiveTransactionWithTxId;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + seconds, 0).unwrap()
+    }
+
+    fn key(iri: &str, reason: &str) -> DedupeKey {
+        (iri.to_string(), reason.to_string())
+    }
+
+    fn block(seconds: i64, podpings: &[(&[&str], &str)]) -> HiveBlockWithNum {
+        HiveBlockWithNum {
+            block_num: seconds as u64,
+            block_id: format!("{:08x}", seconds),
+            previous: format!("{:08x}", seconds - 1),
+            witness: "w".to_string(),
+            timestamp: at(seconds),
+            transactions: podpings
+                .iter()
+                .enumerate()
+                .map(|(i, (iris, reason))| {
+                    HiveTransactionWithTxId::new(
+                        format!("tx{}", i),
+                        0,
+                        serde_json::from_value(serde_json::json!({
+                            "version": "1.0",
+                            "medium": "podcast",
+                            "reason": reason,
+                            "iris": iris,
+                        }))
+                        .unwrap(),
+                    )
+                })
+                .collect(),
+            malformed: vec![],
+            replaces: None,
+        }
+    }
+
+    fn tx_ids(block: &HiveBlockWithNum) -> Vec<&str> {
+        block
+            .transactions
+            .iter()
+            .map(|tx| tx.tx_id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn repeats_within_the_window_are_suppressed() {
+        let mut window = DedupeWindow::new(WINDOW, None);
+
+        assert!(window.check(key("a", "update"), at(0)));
+        assert!(!window.check(key("a", "update"), at(59)));
+        assert!(window.check(key("a", "live"), at(59)));
+        assert!(window.check(key("a", "update"), at(60)));
+    }
+
+    #[test]
+    fn the_oldest_entries_go_past_max_entries() {
+        let mut window = DedupeWindow::new(WINDOW, Some(2));
+
+        assert!(window.check(key("a", "update"), at(0)));
+        assert!(window.check(key("b", "update"), at(1)));
+        assert!(window.check(key("c", "update"), at(2)));
+
+        assert_eq!(window.seen.len(), 2);
+        assert!(window.check(key("a", "update"), at(3)));
+        assert!(!window.check(key("c", "update"), at(3)));
+    }
+
+    #[test]
+    fn podpings_with_any_new_iri_are_kept() {
+        let mut window = DedupeWindow::new(WINDOW, None);
+
+        let first = window.process_block(block(0, &[(&["a", "b"], "update")]));
+        assert_eq!(tx_ids(&first), ["tx0"]);
+
+        let second = window.process_block(block(
+            10,
+            &[
+                (&["a"], "update"),
+                (&["b", "c"], "update"),
+                (&["a"], "live"),
+            ],
+        ));
+        assert_eq!(tx_ids(&second), ["tx1", "tx2"]);
+
+        let third = window.process_block(block(20, &[(&["a", "b", "c"], "update")]));
+        assert!(third.transactions.is_empty());
+    }
+
+    #[test]
+    fn corrections_pass_through_whole() {
+        let mut window = DedupeWindow::new(WINDOW, None);
+        window.process_block(block(0, &[(&["a"], "update")]));
+
+        let mut correction = block(1, &[(&["a"], "update")]);
+        correction.replaces = Some(Box::new(block(1, &[])));
+
+        assert_eq!(tx_ids(&window.process_block(correction)), ["tx0"]);
+    }
+}
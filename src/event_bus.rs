@@ -0,0 +1,137 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::{debug, warn};
+
+/// An event published on the bus, numbered in publish order starting at 1
+#[derive(Debug, Clone)]
+pub(crate) struct Event<T> {
+    pub(crate) seq: u64,
+    pub(crate) payload: T,
+}
+
+struct Subscriber<T> {
+    name: String,
+    tx: mpsc::Sender<Event<T>>,
+    acked: Arc<AtomicU64>,
+}
+
+/// Publish/subscribe bus between the scanner and the writers
+///
+/// Each subscriber gets its own bounded queue, so a slow writer applies backpressure
+/// to the publisher instead of silently lagging like a broadcast channel.
+/// Subscribers only see events published after they subscribed, so every subscriber is added
+/// before the source starts.
+/// The bus closes, and subscribers see the end of their stream, when it's dropped.
+pub(crate) struct EventBus<T: Clone> {
+    queue_capacity: usize,
+    next_seq: AtomicU64,
+    subscribers: Mutex<Vec<Subscriber<T>>>,
+}
+
+impl<T: Clone> EventBus<T> {
+    pub(crate) fn new(queue_capacity: usize) -> EventBus<T> {
+        EventBus {
+            queue_capacity,
+            next_seq: AtomicU64::new(1),
+            subscribers: Mutex::new(vec![]),
+        }
+    }
+
+    pub(crate) fn subscribe(&self, name: &str) -> Subscription<T> {
+        let (tx, rx) = mpsc::channel(self.queue_capacity);
+        let acked = Arc::new(AtomicU64::new(self.next_seq.load(Ordering::SeqCst) - 1));
+
+        self.subscribers.lock().unwrap().push(Subscriber {
+            name: name.to_string(),
+            tx,
+            acked: acked.clone(),
+        });
+
+        Subscription { rx, acked }
+    }
+
+    /// Queues the event for every subscriber, waiting on any that are full
+    ///
+    /// Errors once every subscriber has gone away, since nothing would ever see the event.
+    pub(crate) async fn publish(&self, payload: T) -> Result<u64, Error> {
+        let event = Event {
+            seq: self.next_seq.fetch_add(1, Ordering::SeqCst),
+            payload,
+        };
+
+        let senders = {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            subscribers.retain(|subscriber| !subscriber.tx.is_closed());
+
+            if subscribers.is_empty() {
+                return Err(eyre!("Event bus has no subscribers left"));
+            }
+
+            subscribers
+                .iter()
+                .map(|subscriber| {
+                    (
+                        subscriber.name.clone(),
+                        subscriber.tx.clone(),
+                        subscriber.acked.clone(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for (name, tx, acked) in senders {
+            let sent = match tx.try_send(event.clone()) {
+                Ok(_) => Ok(()),
+                Err(TrySendError::Full(event)) => {
+                    // Its acks tell a subscriber busy with one event from one falling behind
+                    debug!(
+                        "Waiting on event bus subscriber {}, {} events behind",
+                        name,
+                        (event.seq - 1).saturating_sub(acked.load(Ordering::SeqCst))
+                    );
+
+                    tx.send(event).await.map_err(|_| ())
+                }
+                Err(TrySendError::Closed(_)) => Err(()),
+            };
+
+            if sent.is_err() {
+                warn!("Event bus subscriber {} went away", name);
+            }
+        }
+
+        Ok(event.seq)
+    }
+}
+
+pub(crate) struct Subscription<T> {
+    rx: mpsc::Receiver<Event<T>>,
+    acked: Arc<AtomicU64>,
+}
+
+impl<T> Subscription<T> {
+    /// Next event, or `None` once the bus is dropped and the queue drained
+    pub(crate) async fn recv(&mut self) -> Option<Event<T>> {
+        self.rx.recv().await
+    }
+
+    /// Marks every event up to and including `seq` as handled
+    pub(crate) fn ack(&self, seq: u64) {
+        self.acked.fetch_max(seq, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,267 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Filter;
+use crate::hive::scanner::HiveBlockWithNum;
+use crate::iri::canonical_iri;
+use crate::podping::{map_podping_iris, podping_iris};
+use crate::stage::BlockStage;
+use crate::status::SYNC_STATUS;
+use crate::watchlist::WATCHLIST;
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use regex::RegexSet;
+use std::collections::HashSet;
+use tracing::debug;
+
+/// Turns a filter pattern into a regex
+///
+/// `/.../` is a regex as written, anything else a case insensitive glob over the whole IRI
+/// where `*` is any run of characters and `?` any one.
+pub(crate) fn pattern_regex(pattern: &str) -> String {
+    if let Some(regex) = pattern
+        .strip_prefix('/')
+        .and_then(|pattern| pattern.strip_suffix('/'))
+    {
+        return regex.to_string();
+    }
+
+    let glob = regex::escape(pattern)
+        .replace(r"\*", ".*")
+        .replace(r"\?", ".");
+
+    format!("(?i)^{}$", glob)
+}
+
+fn pattern_set(setting: &str, patterns: &Option<Vec<String>>) -> Result<Option<RegexSet>> {
+    let patterns = match patterns {
+        Some(patterns) if !patterns.is_empty() => patterns,
+        _ => return Ok(None),
+    };
+
+    match RegexSet::new(patterns.iter().map(|pattern| pattern_regex(pattern))) {
+        Ok(set) => Ok(Some(set)),
+        Err(e) => Err(eyre!("Invalid pattern in filter.{}: {}", setting, e)),
+    }
+}
+
+/// Keeps only the IRIs `filter.iri_allow` or the watchlist let through and `filter.iri_deny`
+/// doesn't stop, dropping podpings left without any
+pub(crate) struct IriFilter {
+    allow: Option<RegexSet>,
+    deny: Option<RegexSet>,
+    watchlist: bool,
+}
+
+impl IriFilter {
+    /// None when there's nothing to filter on
+    pub(crate) fn from_settings(filter: &Filter) -> Result<Option<IriFilter>, Error> {
+        let allow = pattern_set("iri_allow", &filter.iri_allow)?;
+        let deny = pattern_set("iri_deny", &filter.iri_deny)?;
+        let watchlist = filter
+            .watchlist_path
+            .as_deref()
+            .is_some_and(|path| !path.is_empty());
+
+        Ok(match (&allow, &deny, watchlist) {
+            (None, None, false) => None,
+            _ => Some(IriFilter {
+                allow,
+                deny,
+                watchlist,
+            }),
+        })
+    }
+
+    fn passes(&self, iri: &str, watchlist: Option<&HashSet<String>>) -> bool {
+        let allowed = match (&self.allow, watchlist) {
+            (None, None) => true,
+            (allow, watchlist) => {
+                allow.as_ref().is_some_and(|allow| allow.is_match(iri))
+                    || watchlist.is_some_and(|watchlist| watchlist.contains(&canonical_iri(iri)))
+            }
+        };
+
+        allowed && !self.deny.as_ref().is_some_and(|deny| deny.is_match(iri))
+    }
+}
+
+impl BlockStage for IriFilter {
+    fn process_block(&mut self, mut block: HiveBlockWithNum) -> HiveBlockWithNum {
+        let mut filtered: u64 = 0;
+        let mut dropped: u64 = 0;
+        // The same list for the whole block, even if it's reloaded partway through
+        let watchlist = self.watchlist.then(|| WATCHLIST.iris());
+        let passes = |iri: &str| self.passes(iri, watchlist.as_deref());
+
+        for tx in &mut block.transactions {
+            for (op_index, podping) in tx.take_podpings() {
+                let iris = podping_iris(&podping);
+                let passing = iris.iter().filter(|iri| passes(iri)).count();
+
+                if passing == iris.len() {
+                    tx.push(op_index, podping);
+                    continue;
+                }
+
+                filtered += (iris.len() - passing) as u64;
+
+                if passing == 0 {
+                    dropped += 1;
+                    continue;
+                }
+
+                let rewritten =
+                    map_podping_iris(&podping, |iri| passes(iri).then(|| iri.to_string()));
+
+                // A podping that can't be rewritten is kept whole for its IRIs that pass
+                tx.push(op_index, rewritten.unwrap_or(podping));
+            }
+        }
+
+        block.transactions.retain(|tx| !tx.podpings.is_empty());
+
+        if filtered > 0 {
+            debug!(
+                "filter: removed {} IRIs and {} podpings in block {}",
+                filtered, dropped, block.block_num
+            );
+            SYNC_STATUS.increment_counter("iris_filtered", filtered);
+        }
+        if dropped > 0 {
+            SYNC_STATUS.increment_counter("podpings_filtered", dropped);
+        }
+
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hive::scanner::HiveTransactionWithTxId;
+    use chrono::Utc;
+
+    fn block(podpings: &[&[&str]]) -> HiveBlockWithNum {
+        HiveBlockWithNum {
+            block_num: 1,
+            block_id: "00000001".to_string(),
+            previous: "00000000".to_string(),
+            witness: "w".to_string(),
+            timestamp: Utc::now(),
+            transactions: podpings
+                .iter()
+                .enumerate()
+                .map(|(i, iris)| {
+                    HiveTransactionWithTxId::new(
+                        format!("tx{}", i),
+                        0,
+                        serde_json::from_value(serde_json::json!({
+                            "version": "1.0",
+                            "medium": "podcast",
+                            "reason": "update",
+                            "iris": iris,
+                        }))
+                        .unwrap(),
+                    )
+                })
+                .collect(),
+            malformed: vec![],
+            replaces: None,
+        }
+    }
+
+    fn filter(allow: &[&str], deny: &[&str]) -> IriFilter {
+        let patterns =
+            |patterns: &[&str]| Some(patterns.iter().map(|pattern| pattern.to_string()).collect());
+
+        IriFilter::from_settings(&Filter {
+            iri_allow: patterns(allow),
+            iri_deny: patterns(deny),
+            ..Default::default()
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    fn block_iris(block: &HiveBlockWithNum) -> Vec<Vec<String>> {
+        block
+            .transactions
+            .iter()
+            .flat_map(|tx| tx.podpings.iter().map(podping_iris))
+            .collect()
+    }
+
+    #[test]
+    fn globs_match_the_whole_iri_case_insensitively() {
+        let regex = regex::Regex::new(&pattern_regex("https://*.example.com/feed?.xml")).unwrap();
+
+        assert!(regex.is_match("HTTPS://cdn.example.com/feed1.xml"));
+        assert!(!regex.is_match("https://cdn.example.com/feed12.xml"));
+        assert!(!regex.is_match("xhttps://cdn.example.com/feed1.xml"));
+        assert!(!regex.is_match("https://cdn.exampleXcom/feed1.xml"));
+    }
+
+    #[test]
+    fn slashes_make_a_regex() {
+        assert_eq!(pattern_regex("/^https://.+/$/"), "^https://.+/$");
+    }
+
+    #[test]
+    fn nothing_to_filter_on_is_no_filter() {
+        assert!(IriFilter::from_settings(&Filter::default())
+            .unwrap()
+            .is_none());
+        assert!(IriFilter::from_settings(&Filter {
+            iri_allow: Some(vec![]),
+            ..Default::default()
+        })
+        .unwrap()
+        .is_none());
+    }
+
+    #[test]
+    fn invalid_patterns_are_an_error() {
+        assert!(IriFilter::from_settings(&Filter {
+            iri_deny: Some(vec!["/(/".to_string()]),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let filter = filter(&["https://*"], &["*.spam.example/*"]);
+
+        assert!(filter.passes("https://good.example/feed", None));
+        assert!(!filter.passes("https://a.spam.example/feed", None));
+        assert!(!filter.passes("http://good.example/feed", None));
+    }
+
+    #[test]
+    fn podpings_keep_only_the_iris_that_pass() {
+        let mut filter = filter(&[], &["https://spam.example/*"]);
+
+        let filtered = filter.process_block(block(&[
+            &["https://good.example/feed", "https://spam.example/feed"],
+            &["https://spam.example/other"],
+            &["https://good.example/other"],
+        ]));
+
+        assert_eq!(
+            block_iris(&filtered),
+            [
+                vec!["https://good.example/feed"],
+                vec!["https://good.example/other"]
+            ]
+        );
+    }
+}
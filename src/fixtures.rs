@@ -0,0 +1,138 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::hive::jsonrpc::block_api;
+use crate::hive::jsonrpc::client::{JsonRpcClient, RpcClientOptions};
+use crate::hive::jsonrpc::request_params::GetBlockParams;
+use crate::hive::jsonrpc::responses::GetBlockResponse;
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::{Report, Result};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+// Tries per block, spread over the nodes
+const ATTEMPTS_PER_NODE: usize = 3;
+const PROGRESS_EVERY_BLOCKS: u64 = 1000;
+
+/// Raw block_api.get_block responses saved as `<block_num>.json`, to run the parser and writers
+/// against real blocks without a node
+pub(crate) fn fixture_path(directory: &Path, block_num: u64) -> PathBuf {
+    directory.join(format!("{}.json", block_num))
+}
+
+/// The block numbers with a fixture in `directory`, in order
+pub(crate) async fn fixture_blocks(directory: &Path) -> Result<Vec<u64>, Error> {
+    let mut entries = tokio::fs::read_dir(directory).await.map_err(|e| {
+        eyre!(
+            "Unable to read fixtures from {}: {}",
+            directory.display(),
+            e
+        )
+    })?;
+    let mut blocks = vec![];
+
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+
+        if let Some(block_num) = file_name
+            .to_str()
+            .and_then(|file_name| file_name.strip_suffix(".json"))
+            .and_then(|block_num| block_num.parse::<u64>().ok())
+        {
+            blocks.push(block_num);
+        }
+    }
+
+    blocks.sort_unstable();
+
+    Ok(blocks)
+}
+
+pub(crate) async fn read_fixture(
+    directory: &Path,
+    block_num: u64,
+) -> Result<GetBlockResponse, Error> {
+    let path = fixture_path(directory, block_num);
+    let contents = tokio::fs::read_to_string(&path).await?;
+
+    serde_json::from_str(&contents).map_err(|e| eyre!("Invalid fixture {}: {}", path.display(), e))
+}
+
+/// Saves the node's response for every block in the range to `directory`, then exits
+///
+/// Responses are written as they came, so a fixture is exactly what a node sent. Existing
+/// fixtures for the range are overwritten.
+pub(crate) async fn capture<J: JsonRpcClient>(
+    settings: &Settings,
+    directory: &str,
+    from_block: u64,
+    to_block: u64,
+) -> Result<(), Report> {
+    let directory = Path::new(directory);
+    let mut json_rpc_client = J::new(
+        settings.scanner.rpc_nodes.clone(),
+        0,
+        RpcClientOptions::from_settings(settings)?,
+    )?;
+    let attempts = settings.scanner.rpc_nodes.len().max(1) * ATTEMPTS_PER_NODE;
+
+    tokio::fs::create_dir_all(directory).await?;
+    info!(
+        "capture: saving blocks {} to {} to {}",
+        from_block,
+        to_block,
+        directory.display()
+    );
+
+    for block_num in from_block..=to_block {
+        let mut attempt = 1;
+
+        let response = loop {
+            let params = GetBlockParams {
+                block_num: &block_num,
+            };
+
+            match block_api::get_block_raw(json_rpc_client.get_client(), params).await {
+                Ok(response) => break response,
+                Err(e) if attempt < attempts => {
+                    warn!("capture: error fetching block {}: {:#}", block_num, e);
+                    json_rpc_client.rotate_node()?;
+                    attempt += 1;
+                }
+                Err(e) => return Err(eyre!("Unable to fetch block {}: {:#}", block_num, e)),
+            }
+        };
+
+        // A block past the head comes back empty
+        serde_json::from_str::<GetBlockResponse>(response.get()).map_err(|e| {
+            eyre!(
+                "Block {} isn't a block the scanner can read: {}",
+                block_num,
+                e
+            )
+        })?;
+
+        tokio::fs::write(fixture_path(directory, block_num), response.get()).await?;
+
+        if (block_num - from_block + 1).is_multiple_of(PROGRESS_EVERY_BLOCKS) {
+            info!("capture: saved up to block {}", block_num);
+        }
+    }
+
+    info!(
+        "capture: saved {} blocks to {}",
+        to_block - from_block + 1,
+        directory.display()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,125 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::forwarder::queue::QueuedDelivery;
+use crate::hive::scanner::HiveBlockWithNum;
+use crate::podping::{podping_iris, podping_medium, podping_reason};
+use chrono::{DateTime, TimeDelta, Utc};
+use color_eyre::eyre::Error;
+use color_eyre::Result;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::time::Duration;
+
+/// Every podping for a feed within the aggregation window, sent as one event
+#[derive(Debug, Serialize)]
+pub(crate) struct FeedUpdate {
+    pub(crate) iri: String,
+    pub(crate) first_block: u64,
+    pub(crate) last_block: u64,
+    pub(crate) first_timestamp: DateTime<Utc>,
+    pub(crate) last_timestamp: DateTime<Utc>,
+    pub(crate) reasons: BTreeSet<String>,
+    pub(crate) mediums: BTreeSet<String>,
+    /// Podpings coalesced into the update
+    pub(crate) podpings: u64,
+}
+
+impl FeedUpdate {
+    pub(crate) fn delivery(&self) -> Result<QueuedDelivery, Error> {
+        // IRIs aren't always valid in a header, the window's first block and a hash of the
+        // feed are
+        let iri_hash = hex::encode(Sha256::digest(self.iri.as_bytes()));
+
+        Ok(QueuedDelivery {
+            idempotency_key: format!("{}/{}", self.first_block, &iri_hash[..32]),
+            iris: vec![self.iri.clone()],
+            body: serde_json::to_string(self)?,
+        })
+    }
+}
+
+/// Coalesces podpings per feed IRI, each feed's window opening with its first podping
+///
+/// Windows are measured in block time, so catch-up aggregates the same way as following the
+/// chain live.
+pub(crate) struct FeedUpdateWindow {
+    window: TimeDelta,
+    open: HashMap<String, FeedUpdate>,
+    // Feeds in the order their windows close
+    closing: VecDeque<(DateTime<Utc>, String)>,
+}
+
+impl FeedUpdateWindow {
+    pub(crate) fn new(window: Duration) -> FeedUpdateWindow {
+        FeedUpdateWindow {
+            window: TimeDelta::from_std(window).expect("aggregate_window is too long"),
+            open: HashMap::new(),
+            closing: VecDeque::new(),
+        }
+    }
+
+    /// Adds the block's podpings, returning the updates whose window closed before it
+    pub(crate) fn push(&mut self, block: &HiveBlockWithNum) -> Vec<FeedUpdate> {
+        let mut closed = vec![];
+
+        while let Some((closes_at, _)) = self.closing.front() {
+            if *closes_at > block.timestamp {
+                break;
+            }
+
+            let (_, iri) = self.closing.pop_front().unwrap();
+            closed.extend(self.open.remove(&iri));
+        }
+
+        for tx in &block.transactions {
+            for podping in &tx.podpings {
+                let reason = podping_reason(podping);
+                let medium = podping_medium(podping);
+
+                for iri in podping_iris(podping) {
+                    let update = self.open.entry(iri.clone()).or_insert_with(|| {
+                        self.closing
+                            .push_back((block.timestamp + self.window, iri.clone()));
+
+                        FeedUpdate {
+                            iri,
+                            first_block: block.block_num,
+                            last_block: block.block_num,
+                            first_timestamp: block.timestamp,
+                            last_timestamp: block.timestamp,
+                            reasons: BTreeSet::new(),
+                            mediums: BTreeSet::new(),
+                            podpings: 0,
+                        }
+                    });
+
+                    update.last_block = block.block_num;
+                    update.last_timestamp = block.timestamp;
+                    update.reasons.extend(reason.clone());
+                    update.mediums.extend(medium.clone());
+                    update.podpings += 1;
+                }
+            }
+        }
+
+        closed
+    }
+
+    /// Closes every open window, when the podpings stop coming
+    pub(crate) fn drain(&mut self) -> Vec<FeedUpdate> {
+        self.closing
+            .drain(..)
+            .filter_map(|(_, iri)| self.open.remove(&iri))
+            .collect()
+    }
+}
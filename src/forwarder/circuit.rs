@@ -0,0 +1,130 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::status::{CircuitState, SYNC_STATUS};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+pub(crate) const DEFAULT_CIRCUIT_FAILURE_THRESHOLD: u32 = 5;
+pub(crate) const DEFAULT_CIRCUIT_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+enum State {
+    Closed { consecutive_failures: u32 },
+    Open { probe_at: Instant },
+    // One delivery is out to see if the endpoint is back, another goes at probe_at if it
+    // never comes back
+    HalfOpen { probe_at: Instant },
+}
+
+/// Stops sending to an endpoint that keeps failing, then lets a single delivery through every
+/// probe interval until one succeeds
+pub(crate) struct CircuitBreaker {
+    label: String,
+    // None if the circuit never opens
+    failure_threshold: Option<u32>,
+    probe_interval: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    /// A `failure_threshold` of 0 never opens the circuit
+    pub(crate) fn new(
+        label: String,
+        failure_threshold: u32,
+        probe_interval: Duration,
+    ) -> CircuitBreaker {
+        let failure_threshold = Some(failure_threshold).filter(|threshold| *threshold > 0);
+
+        if failure_threshold.is_some() {
+            SYNC_STATUS.record_circuit_state(&label, CircuitState::Closed);
+        }
+
+        CircuitBreaker {
+            label,
+            failure_threshold,
+            probe_interval,
+            state: Mutex::new(State::Closed {
+                consecutive_failures: 0,
+            }),
+        }
+    }
+
+    /// Lets a delivery through, or says how long until the next probe can go
+    pub(crate) fn try_acquire(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+
+        let probe_at = match *state {
+            State::Closed { .. } => return None,
+            State::Open { probe_at } | State::HalfOpen { probe_at } => probe_at,
+        };
+        let now = Instant::now();
+
+        if probe_at > now {
+            return Some(probe_at - now);
+        }
+
+        info!("Probing {} with a single delivery", self.label);
+        *state = State::HalfOpen {
+            probe_at: now + self.probe_interval,
+        };
+        SYNC_STATUS.record_circuit_state(&self.label, CircuitState::HalfOpen);
+
+        None
+    }
+
+    pub(crate) fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        if !matches!(*state, State::Closed { .. }) {
+            info!("{} is healthy again, closing its circuit", self.label);
+            SYNC_STATUS.record_circuit_state(&self.label, CircuitState::Closed);
+        }
+
+        *state = State::Closed {
+            consecutive_failures: 0,
+        };
+    }
+
+    pub(crate) fn record_failure(&self) {
+        let failure_threshold = match self.failure_threshold {
+            Some(failure_threshold) => failure_threshold,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap();
+
+        match *state {
+            State::Closed {
+                consecutive_failures,
+            } if consecutive_failures + 1 < failure_threshold => {
+                *state = State::Closed {
+                    consecutive_failures: consecutive_failures + 1,
+                };
+                return;
+            }
+            State::Closed { .. } => warn!(
+                "{} failed {} times in a row, opening its circuit and probing every {:?}",
+                self.label, failure_threshold, self.probe_interval
+            ),
+            State::HalfOpen { .. } => {
+                warn!("Probe of {} failed, its circuit stays open", self.label)
+            }
+            // Deliveries that were already in flight when it opened
+            State::Open { .. } => return,
+        }
+
+        *state = State::Open {
+            probe_at: Instant::now() + self.probe_interval,
+        };
+        SYNC_STATUS.record_circuit_state(&self.label, CircuitState::Open);
+    }
+}
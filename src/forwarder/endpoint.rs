@@ -0,0 +1,238 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::{Forwarder as ForwarderSettings, ForwarderDelivery, ForwarderEndpoint, OAuth2};
+use crate::secrets::read_secret_file;
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use std::collections::HashSet;
+
+const DEFAULT_MAX_IN_FLIGHT: usize = 8;
+
+/// How many of an endpoint's podpings can be in flight at once
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Delivery {
+    /// One at a time, in chain order
+    Ordered,
+    /// Up to this many at once, in no particular order
+    Concurrent(usize),
+}
+
+impl Delivery {
+    fn from_settings(
+        delivery: Option<ForwarderDelivery>,
+        max_in_flight: Option<usize>,
+    ) -> Delivery {
+        match delivery.unwrap_or(ForwarderDelivery::Ordered) {
+            ForwarderDelivery::Ordered => Delivery::Ordered,
+            ForwarderDelivery::Concurrent => {
+                Delivery::Concurrent(max_in_flight.unwrap_or(DEFAULT_MAX_IN_FLIGHT).max(1))
+            }
+        }
+    }
+}
+
+/// Where an endpoint's podpings are POSTed and the extra headers sent with them
+#[derive(Clone, PartialEq)]
+pub(crate) struct EndpointConfig {
+    pub(crate) url: String,
+    pub(crate) headers: HeaderMap,
+    pub(crate) enabled: bool,
+    pub(crate) delivery: Delivery,
+}
+
+impl EndpointConfig {
+    /// The single `forwarder.endpoint`, from before `forwarder.endpoints`
+    pub(crate) fn from_url(
+        forwarder: &ForwarderSettings,
+        url: &str,
+    ) -> Result<EndpointConfig, Error> {
+        if url.is_empty() {
+            return Err(eyre!("forwarder endpoint is empty"));
+        }
+
+        Ok(EndpointConfig {
+            url: url.to_string(),
+            headers: HeaderMap::new(),
+            enabled: true,
+            delivery: Delivery::from_settings(forwarder.delivery, forwarder.max_in_flight),
+        })
+    }
+
+    pub(crate) fn from_settings(
+        forwarder: &ForwarderSettings,
+        endpoint: &ForwarderEndpoint,
+    ) -> Result<EndpointConfig, Error> {
+        if endpoint.url.is_empty() {
+            return Err(eyre!("forwarder endpoint {} has no url", endpoint.name));
+        }
+
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in endpoint.headers.iter().flatten() {
+            let header_name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| {
+                eyre!(
+                    "forwarder endpoint {} header {:?} is invalid: {}",
+                    endpoint.name,
+                    name,
+                    e
+                )
+            })?;
+            // Custom headers often carry API keys, keep them out of debug output
+            let mut header_value = HeaderValue::from_str(value).map_err(|e| {
+                eyre!(
+                    "forwarder endpoint {} header {} has an invalid value: {}",
+                    endpoint.name,
+                    name,
+                    e
+                )
+            })?;
+            header_value.set_sensitive(true);
+
+            headers.insert(header_name, header_value);
+        }
+
+        if let Some(auth_token) = auth_token(endpoint)? {
+            let mut header_value = HeaderValue::from_str(&format!("Bearer {}", auth_token))
+                .map_err(|e| {
+                    eyre!(
+                        "forwarder endpoint {} auth_token is invalid: {}",
+                        endpoint.name,
+                        e
+                    )
+                })?;
+            header_value.set_sensitive(true);
+
+            headers.insert(AUTHORIZATION, header_value);
+        }
+
+        if endpoint.oauth2.is_some() && headers.contains_key(AUTHORIZATION) {
+            return Err(eyre!(
+                "forwarder endpoint {} has oauth2 and an auth_token or Authorization header, only set one",
+                endpoint.name
+            ));
+        }
+
+        Ok(EndpointConfig {
+            url: endpoint.url.clone(),
+            headers,
+            enabled: endpoint.enabled.unwrap_or(true),
+            delivery: Delivery::from_settings(
+                endpoint.delivery.or(forwarder.delivery),
+                endpoint.max_in_flight.or(forwarder.max_in_flight),
+            ),
+        })
+    }
+}
+
+fn auth_token(endpoint: &ForwarderEndpoint) -> Result<Option<String>, Error> {
+    let non_empty = |value: &Option<String>| value.clone().filter(|value| !value.is_empty());
+
+    match (
+        non_empty(&endpoint.auth_token),
+        non_empty(&endpoint.auth_token_file),
+    ) {
+        (Some(_), Some(_)) => Err(eyre!(
+            "forwarder endpoint {} auth_token and auth_token_file are both set, only set one",
+            endpoint.name
+        )),
+        (Some(auth_token), None) => Ok(Some(auth_token)),
+        (None, Some(path)) => match read_secret_file(&path) {
+            Ok(auth_token) => Ok(Some(auth_token)),
+            Err(e) => Err(eyre!(
+                "Unable to read forwarder endpoint {} auth_token_file {}: {}",
+                endpoint.name,
+                path,
+                e
+            )),
+        },
+        (None, None) => Ok(None),
+    }
+}
+
+/// Every endpoint podpings are forwarded to, named, or `None` for `forwarder.endpoint`
+pub(crate) fn configured_endpoints(
+    forwarder: &ForwarderSettings,
+) -> Result<Vec<(Option<String>, EndpointConfig)>, Error> {
+    let endpoint = forwarder
+        .endpoint
+        .as_deref()
+        .filter(|endpoint| !endpoint.is_empty());
+    let endpoints = forwarder.endpoints.as_deref().unwrap_or_default();
+
+    match (endpoint, endpoints.is_empty()) {
+        (Some(_), false) => Err(eyre!(
+            "forwarder endpoint and endpoints are both set, only set one"
+        )),
+        (Some(endpoint), true) => Ok(vec![(None, EndpointConfig::from_url(forwarder, endpoint)?)]),
+        (None, true) => Err(eyre!("forwarder endpoint is not set!")),
+        (None, false) => {
+            let mut names = HashSet::new();
+
+            endpoints
+                .iter()
+                .map(|endpoint| {
+                    if !is_valid_endpoint_name(&endpoint.name) {
+                        return Err(eyre!(
+                            "forwarder endpoint name {:?} must be letters, digits, - and _",
+                            endpoint.name
+                        ));
+                    }
+                    if !names.insert(endpoint.name.as_str()) {
+                        return Err(eyre!(
+                            "forwarder endpoint name {} is used more than once",
+                            endpoint.name
+                        ));
+                    }
+
+                    Ok((
+                        Some(endpoint.name.clone()),
+                        EndpointConfig::from_settings(forwarder, endpoint)?,
+                    ))
+                })
+                .collect()
+        }
+    }
+}
+
+/// How an endpoint is named in logs, `None` for `forwarder.endpoint`
+pub(crate) fn endpoint_label(name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("forwarder endpoint {}", name),
+        None => "forwarder endpoint".to_string(),
+    }
+}
+
+/// The endpoint's OAuth2 client credentials, read once at startup
+pub(crate) fn endpoint_oauth2<'a>(
+    forwarder: &'a ForwarderSettings,
+    name: Option<&str>,
+) -> Option<&'a OAuth2> {
+    match name {
+        Some(name) => forwarder
+            .endpoints
+            .iter()
+            .flatten()
+            .find(|endpoint| endpoint.name == name)?
+            .oauth2
+            .as_ref(),
+        None => forwarder.oauth2.as_ref(),
+    }
+}
+
+/// Names double as the endpoint's queue subdirectory
+pub(crate) fn is_valid_endpoint_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
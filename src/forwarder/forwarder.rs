@@ -0,0 +1,1082 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::event_bus::{Event, Subscription};
+use crate::forwarder::aggregate::{FeedUpdate, FeedUpdateWindow};
+use crate::forwarder::circuit::{
+    CircuitBreaker, DEFAULT_CIRCUIT_FAILURE_THRESHOLD, DEFAULT_CIRCUIT_PROBE_INTERVAL,
+};
+use crate::forwarder::endpoint::{
+    configured_endpoints, endpoint_label, endpoint_oauth2, Delivery, EndpointConfig,
+};
+use crate::forwarder::oauth2::OAuth2Token;
+use crate::forwarder::queue::{DeliveryQueue, QueuedDelivery};
+use crate::forwarder::signature::{
+    batch_idempotency_key, idempotency_key, signature_headers, IDEMPOTENCY_KEY_HEADER,
+};
+use crate::forwarder::tls::{ClientTls, ReloadingClient};
+use crate::hive::scanner::{BlockBatch, HiveBlockWithNum};
+use crate::podping::{podping_iris, PodpingRecord};
+use crate::reload::LIVE_SETTINGS;
+use crate::routing::{HostRoutes, FORWARDER_TARGET};
+use crate::template::PayloadTemplate;
+use crate::writer::failure_policy::FailureHandler;
+use crate::writer::reorder::{ReorderWindow, DEFAULT_REORDER_WINDOW};
+use crate::writer::writer::{Writer, WriterInitError};
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use reqwest::StatusCode;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, sleep_until, Instant};
+use tracing::{debug, error, info, warn};
+
+const CONTENT_TYPE_APPLICATION_JSON: &str = "application/json";
+const DEFAULT_QUEUE_RETRY_MIN_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_QUEUE_RETRY_MAX_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_BATCH_MAX_EVENTS: usize = 100;
+const DEFAULT_BATCH_MAX_WAIT: Duration = Duration::from_secs(3);
+const DEFAULT_TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+pub(crate) struct ForwarderTarget {
+    // None for the single forwarder.endpoint
+    name: Option<String>,
+    http_client: Arc<ReloadingClient>,
+    endpoint: RwLock<EndpointConfig>,
+    secret: Option<String>,
+    oauth2: Option<OAuth2Token>,
+    circuit: CircuitBreaker,
+    settings_generation: Mutex<u64>,
+}
+
+impl ForwarderTarget {
+    fn label(&self) -> String {
+        endpoint_label(self.name.as_deref())
+    }
+
+    fn reloaded_endpoint(&self, settings: &Settings) -> Result<EndpointConfig, Error> {
+        let name = match &self.name {
+            Some(name) => name,
+            None => {
+                return EndpointConfig::from_url(
+                    &settings.forwarder,
+                    settings.forwarder.endpoint.as_deref().unwrap_or_default(),
+                )
+            }
+        };
+
+        match settings
+            .forwarder
+            .endpoints
+            .iter()
+            .flatten()
+            .find(|endpoint| endpoint.name == *name)
+        {
+            Some(endpoint) => EndpointConfig::from_settings(&settings.forwarder, endpoint),
+            None => Err(eyre!("{} is no longer in forwarder.endpoints", name)),
+        }
+    }
+
+    /// Current endpoint, switching over when a new one is reloaded on SIGHUP
+    fn endpoint(&self) -> EndpointConfig {
+        let reloaded = LIVE_SETTINGS.changed_since(&mut self.settings_generation.lock().unwrap());
+
+        if let Some(settings) = reloaded {
+            match self.reloaded_endpoint(&settings) {
+                Ok(endpoint) => {
+                    let mut current = self.endpoint.write().unwrap();
+
+                    if *current != endpoint {
+                        match endpoint.enabled {
+                            true => info!(
+                                "{} reloaded, forwarding podpings to {}",
+                                self.label(),
+                                endpoint.url
+                            ),
+                            false => info!("{} reloaded, it's disabled", self.label()),
+                        }
+                        *current = endpoint;
+                    }
+                }
+                Err(e) => warn!(
+                    "Reloaded {} is invalid, keeping the current one: {}",
+                    self.label(),
+                    e
+                ),
+            }
+        }
+
+        self.endpoint.read().unwrap().clone()
+    }
+
+    fn enabled(&self) -> bool {
+        self.endpoint().enabled
+    }
+
+    /// Posts a payload, tracking the result in the endpoint's circuit
+    ///
+    /// Callers check `circuit.try_acquire()` first.
+    async fn post(&self, idempotency_key: &str, body: &str) -> Result<(), Error> {
+        let result = self.send(idempotency_key, body).await;
+
+        match result {
+            Ok(_) => self.circuit.record_success(),
+            Err(_) => self.circuit.record_failure(),
+        }
+
+        result
+    }
+
+    async fn send(&self, idempotency_key: &str, body: &str) -> Result<(), Error> {
+        let endpoint = self.endpoint();
+        let mut request = self
+            .http_client
+            .client()
+            .post(endpoint.url.as_str())
+            .headers(endpoint.headers)
+            .header("Content-Type", CONTENT_TYPE_APPLICATION_JSON)
+            .header(IDEMPOTENCY_KEY_HEADER, idempotency_key);
+
+        if let Some(secret) = &self.secret {
+            for (header, value) in signature_headers(secret, body.as_bytes()) {
+                request = request.header(header, value);
+            }
+        }
+
+        if let Some(oauth2) = &self.oauth2 {
+            request = request.bearer_auth(oauth2.access_token().await?);
+        }
+
+        let response = request.body(body.to_string()).send().await?;
+        let status = response.status();
+
+        if let (Some(oauth2), StatusCode::UNAUTHORIZED) = (&self.oauth2, status) {
+            oauth2.invalidate().await;
+        }
+
+        debug!(
+            "idempotency key: {}, {} status: {:?}",
+            idempotency_key,
+            self.label(),
+            status
+        );
+
+        match status.is_success() {
+            true => Ok(()),
+            false => Err(eyre!(
+                "Endpoint {} returned {} for {}",
+                endpoint.url,
+                status,
+                idempotency_key
+            )),
+        }
+    }
+
+    /// Posts deliveries as a single JSON object, their keys in `idempotency_keys` in the same
+    /// order as `podpings`, under one key for the whole batch
+    async fn post_batch(&self, deliveries: &[QueuedDelivery]) -> Result<(), Error> {
+        let idempotency_keys = deliveries
+            .iter()
+            .map(|d| d.idempotency_key.as_str())
+            .collect::<Vec<_>>();
+        let body = format!(
+            "{{\"idempotency_keys\":{},\"podpings\":[{}]}}",
+            serde_json::to_string(&idempotency_keys)?,
+            deliveries
+                .iter()
+                .map(|d| d.body.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+
+        self.post(&batch_idempotency_key(idempotency_keys), &body)
+            .await
+    }
+}
+
+/// Posts an empty JSON object to the health endpoint, signed like any other payload
+async fn check_health(
+    http_client: &ReloadingClient,
+    secret: Option<&str>,
+    health_endpoint: &str,
+) -> Result<(), Error> {
+    let body = "{}";
+    let mut request = http_client
+        .client()
+        .post(health_endpoint)
+        .header("Content-Type", CONTENT_TYPE_APPLICATION_JSON);
+
+    if let Some(secret) = secret {
+        for (header, value) in signature_headers(secret, body.as_bytes()) {
+            request = request.header(header, value);
+        }
+    }
+
+    let response = request.body(body).send().await?;
+    let status = response.status();
+
+    match status.is_success() {
+        true => Ok(()),
+        false => Err(eyre!(
+            "Health endpoint {} returned {}",
+            health_endpoint,
+            status
+        )),
+    }
+}
+
+/// An endpoint and its own retry queue, so one that's down doesn't hold up the others
+struct Endpoint {
+    target: Arc<ForwarderTarget>,
+    queue: Option<Arc<DeliveryQueue>>,
+    // None unless a route names the endpoint, then it only gets podpings for those hosts
+    routes: Option<HostRoutes>,
+}
+
+impl Endpoint {
+    fn routed(&self, deliveries: &[QueuedDelivery]) -> Vec<QueuedDelivery> {
+        deliveries
+            .iter()
+            .filter(|delivery| {
+                self.routes
+                    .as_ref()
+                    .is_none_or(|routes| routes.matches(&delivery.iris))
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Every endpoint is tried, an error for any that failed without a queue to fall back on
+///
+/// A retry by the failure policy sends to all of them again, receivers that already have the
+/// podpings drop them by their idempotency keys.
+fn endpoint_errors(errors: Vec<Error>) -> Result<(), Error> {
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.into_iter().next().unwrap()),
+        _ => Err(eyre!(
+            "{}",
+            errors
+                .iter()
+                .map(|e| format!("{:#}", e))
+                .collect::<Vec<_>>()
+                .join("; ")
+        )),
+    }
+}
+
+fn block_deliveries(
+    block: &HiveBlockWithNum,
+    payload_template: Option<&PayloadTemplate>,
+) -> Result<Vec<QueuedDelivery>, Error> {
+    let mut deliveries = vec![];
+
+    for tx in &block.transactions {
+        for (i, podping) in tx.indexed_podpings() {
+            let body = match payload_template {
+                Some(template) => template.render_json(block, tx, i, podping)?,
+                None => serde_json::to_string(&PodpingRecord::new(block, tx, i, podping))?,
+            };
+
+            deliveries.push(QueuedDelivery {
+                idempotency_key: idempotency_key(block.block_num, &tx.tx_id, i),
+                iris: podping_iris(podping),
+                body,
+            });
+        }
+    }
+
+    Ok(deliveries)
+}
+
+fn circuit_open(target: &ForwarderTarget) -> Error {
+    eyre!(
+        "{}'s circuit is open after repeated failures, not sending",
+        target.label()
+    )
+}
+
+/// Queues a delivery the endpoint didn't take, or returns the error if there's no queue
+async fn queue_if_failed(
+    endpoint: &Endpoint,
+    delivery: QueuedDelivery,
+    result: Result<(), Error>,
+) -> Result<(), Error> {
+    let e = match result {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+
+    match &endpoint.queue {
+        Some(queue) => {
+            warn!(
+                "Queueing {} to retry on {}: {}",
+                delivery.idempotency_key,
+                endpoint.target.label(),
+                e
+            );
+            queue.push(delivery).await
+        }
+        None => Err(e),
+    }
+}
+
+async fn forward_deliveries(
+    endpoint: &Endpoint,
+    deliveries: Vec<QueuedDelivery>,
+) -> Result<(), Error> {
+    let target = &endpoint.target;
+    let max_in_flight = match target.endpoint().delivery {
+        Delivery::Ordered => 1,
+        Delivery::Concurrent(max_in_flight) => max_in_flight,
+    };
+    let mut in_flight = JoinSet::new();
+
+    for delivery in deliveries {
+        // A failure is queued before the next delivery for its feed is checked below
+        if in_flight.len() >= max_in_flight {
+            if let Some(joined) = in_flight.join_next().await {
+                let (delivery, result) = joined?;
+                queue_if_failed(endpoint, delivery, result).await?;
+            }
+        }
+
+        // Anything for the same feed still waiting in the queue has to go first
+        if let Some(queue) = &endpoint.queue {
+            if queue.is_blocked(&delivery.iris) {
+                debug!(
+                    "Queueing {} for {} behind pending deliveries",
+                    delivery.idempotency_key,
+                    target.label()
+                );
+                queue.push(delivery).await?;
+                continue;
+            }
+        }
+
+        if target.circuit.try_acquire().is_some() {
+            match &endpoint.queue {
+                Some(queue) => {
+                    debug!(
+                        "Queueing {} while {}'s circuit is open",
+                        delivery.idempotency_key,
+                        target.label()
+                    );
+                    queue.push(delivery).await?;
+                    continue;
+                }
+                None => return Err(circuit_open(target)),
+            }
+        }
+
+        let target = target.clone();
+        in_flight.spawn(async move {
+            let result = target.post(&delivery.idempotency_key, &delivery.body).await;
+
+            (delivery, result)
+        });
+    }
+
+    while let Some(joined) = in_flight.join_next().await {
+        let (delivery, result) = joined?;
+        queue_if_failed(endpoint, delivery, result).await?;
+    }
+
+    Ok(())
+}
+
+async fn forward_to_endpoints(
+    endpoints: &[Endpoint],
+    deliveries: Vec<QueuedDelivery>,
+) -> Result<(), Error> {
+    let mut errors = vec![];
+
+    for endpoint in endpoints {
+        if !endpoint.target.enabled() {
+            continue;
+        }
+
+        if let Err(e) = forward_deliveries(endpoint, endpoint.routed(&deliveries)).await {
+            errors.push(e);
+        }
+    }
+
+    endpoint_errors(errors)
+}
+
+async fn forward_block_transactions(
+    endpoints: &[Endpoint],
+    payload_template: Option<&PayloadTemplate>,
+    block: HiveBlockWithNum,
+) -> Result<(), Error> {
+    forward_to_endpoints(endpoints, block_deliveries(&block, payload_template)?).await
+}
+
+async fn forward_batch(endpoint: &Endpoint, deliveries: Vec<QueuedDelivery>) -> Result<(), Error> {
+    let deliveries = match &endpoint.queue {
+        Some(queue) => {
+            let mut ready = vec![];
+
+            // Anything for the same feed still waiting in the queue has to go first
+            for delivery in deliveries {
+                match queue.is_blocked(&delivery.iris) {
+                    true => queue.push(delivery).await?,
+                    false => ready.push(delivery),
+                }
+            }
+
+            ready
+        }
+        None => deliveries,
+    };
+
+    if deliveries.is_empty() {
+        return Ok(());
+    }
+
+    if endpoint.target.circuit.try_acquire().is_some() {
+        return match &endpoint.queue {
+            Some(queue) => {
+                debug!(
+                    "Queueing batch of {} podpings while {}'s circuit is open",
+                    deliveries.len(),
+                    endpoint.target.label()
+                );
+                for delivery in deliveries {
+                    queue.push(delivery).await?;
+                }
+                Ok(())
+            }
+            None => Err(circuit_open(&endpoint.target)),
+        };
+    }
+
+    match endpoint.target.post_batch(&deliveries).await {
+        Ok(_) => {
+            debug!(
+                "Forwarded batch of {} podpings to {}",
+                deliveries.len(),
+                endpoint.target.label()
+            );
+            Ok(())
+        }
+        Err(e) => match &endpoint.queue {
+            Some(queue) => {
+                // The queue retries deliveries one at a time
+                warn!(
+                    "Queueing batch of {} podpings to retry on {}: {}",
+                    deliveries.len(),
+                    endpoint.target.label(),
+                    e
+                );
+                for delivery in deliveries {
+                    queue.push(delivery).await?;
+                }
+                Ok(())
+            }
+            None => Err(e),
+        },
+    }
+}
+
+async fn forward_batch_to_endpoints(
+    endpoints: &[Endpoint],
+    deliveries: Vec<QueuedDelivery>,
+) -> Result<(), Error> {
+    let mut errors = vec![];
+
+    for endpoint in endpoints {
+        if !endpoint.target.enabled() {
+            continue;
+        }
+
+        if let Err(e) = forward_batch(endpoint, endpoint.routed(&deliveries)).await {
+            errors.push(e);
+        }
+    }
+
+    endpoint_errors(errors)
+}
+
+async fn drain_queue(
+    target: Arc<ForwarderTarget>,
+    queue: Arc<DeliveryQueue>,
+    min_interval: Duration,
+    max_interval: Duration,
+) {
+    let mut interval = min_interval;
+
+    loop {
+        if queue.is_empty() {
+            queue.notified().await;
+        }
+
+        sleep(interval).await;
+
+        // Disabled endpoints keep what's queued until they're enabled again
+        if !target.enabled() {
+            continue;
+        }
+
+        // Once a delivery for a feed fails, later ones for that feed wait for the next pass
+        let mut blocked_iris: HashSet<String> = HashSet::new();
+        let mut failed = false;
+        // With the circuit open the oldest delivery is the probe, the rest wait for it
+        let mut circuit_wait = None;
+
+        for (id, delivery) in queue.pending() {
+            if delivery.iris.iter().any(|iri| blocked_iris.contains(iri)) {
+                continue;
+            }
+
+            if let Some(wait) = target.circuit.try_acquire() {
+                circuit_wait = Some(wait);
+                break;
+            }
+
+            match target.post(&delivery.idempotency_key, &delivery.body).await {
+                Ok(_) => {
+                    info!(
+                        "Delivered queued podping {} to {}",
+                        delivery.idempotency_key,
+                        target.label()
+                    );
+
+                    if let Err(e) = queue.remove(id, &delivery).await {
+                        error!("Error removing delivered podping from queue: {}", e);
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "Queued delivery {} to {} failed: {}",
+                        delivery.idempotency_key,
+                        target.label(),
+                        e
+                    );
+                    failed = true;
+                    blocked_iris.extend(delivery.iris);
+                }
+            }
+        }
+
+        interval = match (circuit_wait, failed) {
+            (Some(wait), _) => wait.max(min_interval),
+            (None, true) => (interval * 2).min(max_interval),
+            (None, false) => min_interval,
+        };
+    }
+}
+
+struct BatchWindow {
+    max_events: usize,
+    max_wait: Duration,
+}
+
+#[derive(Default)]
+struct BatchBuffer {
+    deliveries: Vec<QueuedDelivery>,
+    deadline: Option<Instant>,
+}
+
+pub(crate) struct Forwarder {
+    endpoints: Vec<Endpoint>,
+    http_client: Arc<ReloadingClient>,
+    health_endpoint: Option<String>,
+    secret: Option<String>,
+    batch_window: Option<BatchWindow>,
+    batch_buffer: Mutex<BatchBuffer>,
+    payload_template: Option<PayloadTemplate>,
+    // Set to send one event per feed per window instead of every podping
+    feed_updates: Option<Mutex<FeedUpdateWindow>>,
+    reorder_window: usize,
+    failure_handler: FailureHandler,
+}
+
+/// Podpings already forwarded can't be recalled, the replacement's are sent as usual and any
+/// the two blocks share are deduplicated by their idempotency keys
+fn warn_if_replaced(block: &HiveBlockWithNum) {
+    if let Some(replaced) = &block.replaces {
+        warn!(
+            "Block {} {} was replaced in a fork by {}, forwarding its podpings",
+            block.block_num, replaced.block_id, block.block_id
+        );
+    }
+}
+
+impl Forwarder {
+    async fn forward_block(&self, block: HiveBlockWithNum) -> Result<(), Error> {
+        warn_if_replaced(&block);
+
+        // Blocks without podpings still close windows
+        if let Some(feed_updates) = &self.feed_updates {
+            let closed = feed_updates.lock().unwrap().push(&block);
+
+            return self
+                .forward_feed_updates(
+                    &format!("feed updates up to block {}", block.block_num),
+                    closed,
+                )
+                .await;
+        }
+
+        if block.transactions.is_empty() {
+            return Ok(());
+        }
+
+        match &self.batch_window {
+            Some(batch_window) => {
+                self.add_to_batch(
+                    batch_window,
+                    block_deliveries(&block, self.payload_template.as_ref())?,
+                )
+                .await
+            }
+            None => {
+                self.failure_handler
+                    .write_block(block, |block| {
+                        forward_block_transactions(
+                            &self.endpoints,
+                            self.payload_template.as_ref(),
+                            block,
+                        )
+                    })
+                    .await
+            }
+        }
+    }
+
+    /// Adds deliveries to the batch, flushing it once it's full
+    async fn add_to_batch(
+        &self,
+        batch_window: &BatchWindow,
+        deliveries: Vec<QueuedDelivery>,
+    ) -> Result<(), Error> {
+        let full = {
+            let mut batch_buffer = self.batch_buffer.lock().unwrap();
+
+            batch_buffer.deliveries.extend(deliveries);
+            batch_buffer
+                .deadline
+                .get_or_insert_with(|| Instant::now() + batch_window.max_wait);
+
+            batch_buffer.deliveries.len() >= batch_window.max_events
+        };
+
+        match full {
+            true => self.flush_batch().await,
+            false => Ok(()),
+        }
+    }
+
+    async fn forward_feed_updates(
+        &self,
+        description: &str,
+        feed_updates: Vec<FeedUpdate>,
+    ) -> Result<(), Error> {
+        if feed_updates.is_empty() {
+            return Ok(());
+        }
+
+        let deliveries = feed_updates
+            .iter()
+            .map(FeedUpdate::delivery)
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        match &self.batch_window {
+            Some(batch_window) => self.add_to_batch(batch_window, deliveries).await,
+            None => {
+                self.failure_handler
+                    .write(description, deliveries, |deliveries| {
+                        forward_to_endpoints(&self.endpoints, deliveries)
+                    })
+                    .await
+            }
+        }
+    }
+
+    /// Sends every feed update still in its window, once the podpings stop
+    async fn flush_feed_updates(&self) -> Result<(), Error> {
+        let feed_updates = match &self.feed_updates {
+            Some(feed_updates) => feed_updates.lock().unwrap().drain(),
+            None => return Ok(()),
+        };
+
+        self.forward_feed_updates("remaining feed updates", feed_updates)
+            .await
+    }
+
+    /// Sends everything still held back, for runs that end after catch up blocks, like replay
+    pub(crate) async fn finish(&self) -> Result<(), Error> {
+        self.flush_feed_updates().await?;
+        self.flush_batch().await
+    }
+
+    /// Forwards catch up blocks together, so concurrent endpoints get more than one block's
+    /// podpings in flight at a time
+    async fn forward_blocks(&self, blocks: Vec<HiveBlockWithNum>) -> Result<(), Error> {
+        if let Some(feed_updates) = &self.feed_updates {
+            let closed = {
+                let mut feed_updates = feed_updates.lock().unwrap();
+
+                blocks
+                    .iter()
+                    .inspect(|block| warn_if_replaced(block))
+                    .flat_map(|block| feed_updates.push(block))
+                    .collect::<Vec<_>>()
+            };
+            let description = match blocks.last() {
+                Some(block) => format!("feed updates up to block {}", block.block_num),
+                None => return Ok(()),
+            };
+
+            return self.forward_feed_updates(&description, closed).await;
+        }
+
+        if self.batch_window.is_some() || blocks.len() < 2 {
+            for block in blocks {
+                self.forward_block(block).await?;
+            }
+
+            return Ok(());
+        }
+
+        let mut deliveries = vec![];
+
+        for block in &blocks {
+            warn_if_replaced(block);
+            deliveries.extend(block_deliveries(block, self.payload_template.as_ref())?);
+        }
+
+        if deliveries.is_empty() {
+            return Ok(());
+        }
+
+        let description = format!(
+            "blocks {} to {}",
+            blocks[0].block_num,
+            blocks[blocks.len() - 1].block_num
+        );
+
+        self.failure_handler
+            .write(&description, deliveries, |deliveries| {
+                forward_to_endpoints(&self.endpoints, deliveries)
+            })
+            .await
+    }
+
+    async fn flush_batch(&self) -> Result<(), Error> {
+        let deliveries = std::mem::take(&mut *self.batch_buffer.lock().unwrap()).deliveries;
+
+        if deliveries.is_empty() {
+            return Ok(());
+        }
+
+        let description = format!("batch {}", deliveries[0].idempotency_key);
+
+        self.failure_handler
+            .write(&description, deliveries, |deliveries| {
+                forward_batch_to_endpoints(&self.endpoints, deliveries)
+            })
+            .await
+    }
+
+    /// Waits for the next message, flushing the batch if its window closes first
+    async fn recv<T>(&self, rx: &mut Subscription<T>) -> Result<Option<Event<T>>, Error> {
+        loop {
+            let deadline = self.batch_buffer.lock().unwrap().deadline;
+
+            match deadline {
+                Some(deadline) => tokio::select! {
+                    result = rx.recv() => return Ok(result),
+                    _ = sleep_until(deadline) => self.flush_batch().await?,
+                },
+                None => return Ok(rx.recv().await),
+            }
+        }
+    }
+}
+
+impl Writer for Forwarder {
+    async fn new(settings: &Settings) -> Result<Self, WriterInitError>
+    where
+        Self: Sized,
+    {
+        let configured_endpoints = configured_endpoints(&settings.forwarder)
+            .map_err(|e| WriterInitError::Config(e.to_string()))?;
+
+        let secret = match settings.forwarder.secret.clone() {
+            Some(secret) if !secret.is_empty() => Some(secret),
+            _ => {
+                warn!("No forwarder secret is set, payloads will not be signed.");
+                None
+            }
+        };
+
+        let payload_template = PayloadTemplate::from_settings(
+            "forwarder payload",
+            &settings.forwarder.payload_template,
+            &settings.forwarder.payload_template_file,
+        )
+        .map_err(|e| WriterInitError::Config(e.to_string()))?;
+
+        let failure_handler = FailureHandler::new(
+            "forwarder",
+            settings.forwarder.failure_policy,
+            settings.forwarder.failure_dead_letter_directory.clone(),
+            settings.forwarder.failure_retry_interval,
+        )?;
+
+        let tls = ClientTls::from_settings(
+            "forwarder",
+            &settings.forwarder.tls_client_cert,
+            &settings.forwarder.tls_client_key,
+            &settings.forwarder.tls_ca_cert,
+        )
+        .map_err(|e| WriterInitError::Config(e.to_string()))?;
+        let http_client = match ReloadingClient::new("forwarder", tls, &settings.http) {
+            Ok(http_client) => Arc::new(http_client),
+            Err(e) => {
+                return Err(WriterInitError::Config(format!(
+                    "Error building forwarder HTTP client: {:#}",
+                    e
+                )))
+            }
+        };
+
+        let queue_directory = match settings.forwarder.queue_enabled.unwrap_or(false) {
+            true => match settings.forwarder.queue_directory.clone() {
+                Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+                _ => {
+                    return Err(WriterInitError::Config(
+                        "forwarder queue is enabled but queue_directory is not set!".to_string(),
+                    ))
+                }
+            },
+            false => None,
+        };
+
+        let mut endpoints = vec![];
+
+        for (name, endpoint) in configured_endpoints {
+            let label = endpoint_label(name.as_deref());
+            let oauth2 = match endpoint_oauth2(&settings.forwarder, name.as_deref()) {
+                Some(oauth2) => Some(
+                    OAuth2Token::from_settings(&label, oauth2, http_client.clone())
+                        .map_err(|e| WriterInitError::Config(e.to_string()))?,
+                ),
+                None => None,
+            };
+            let target = Arc::new(ForwarderTarget {
+                name,
+                http_client: http_client.clone(),
+                endpoint: RwLock::new(endpoint.clone()),
+                secret: secret.clone(),
+                oauth2,
+                circuit: CircuitBreaker::new(
+                    label,
+                    settings
+                        .forwarder
+                        .circuit_failure_threshold
+                        .unwrap_or(DEFAULT_CIRCUIT_FAILURE_THRESHOLD),
+                    settings
+                        .forwarder
+                        .circuit_probe_interval
+                        .unwrap_or(DEFAULT_CIRCUIT_PROBE_INTERVAL),
+                ),
+                settings_generation: Mutex::new(0),
+            });
+
+            match (endpoint.enabled, endpoint.delivery) {
+                (false, _) => info!("{} is disabled", target.label()),
+                (true, Delivery::Ordered) => info!("Forwarding podpings to {}", endpoint.url),
+                (true, Delivery::Concurrent(max_in_flight)) => info!(
+                    "Forwarding podpings to {}, up to {} at a time",
+                    endpoint.url, max_in_flight
+                ),
+            }
+
+            // Named endpoints each get their own queue in a subdirectory
+            let queue = match &queue_directory {
+                Some(queue_directory) => {
+                    let queue_directory = match &target.name {
+                        Some(name) => queue_directory.join(name),
+                        None => queue_directory.clone(),
+                    };
+
+                    match DeliveryQueue::open(&queue_directory) {
+                        Ok(queue) => Some(Arc::new(queue)),
+                        Err(e) => {
+                            return Err(WriterInitError::Unavailable(format!(
+                                "Error opening forwarder queue {}: {}",
+                                queue_directory.display(),
+                                e
+                            )))
+                        }
+                    }
+                }
+                None => None,
+            };
+
+            let routes = HostRoutes::for_target(
+                settings,
+                target.name.as_deref().unwrap_or(FORWARDER_TARGET),
+            )
+            .map_err(|e| WriterInitError::Config(e.to_string()))?;
+
+            endpoints.push(Endpoint {
+                target,
+                queue,
+                routes,
+            });
+        }
+
+        // Started once nothing else can fail, so a retried new() doesn't leave tasks behind
+        for endpoint in &endpoints {
+            if let Some(queue) = &endpoint.queue {
+                tokio::spawn(drain_queue(
+                    endpoint.target.clone(),
+                    queue.clone(),
+                    settings
+                        .forwarder
+                        .queue_retry_min_interval
+                        .unwrap_or(DEFAULT_QUEUE_RETRY_MIN_INTERVAL),
+                    settings
+                        .forwarder
+                        .queue_retry_max_interval
+                        .unwrap_or(DEFAULT_QUEUE_RETRY_MAX_INTERVAL),
+                ));
+            }
+        }
+
+        tokio::spawn(
+            http_client.clone().watch(
+                settings
+                    .forwarder
+                    .tls_reload_interval
+                    .unwrap_or(DEFAULT_TLS_RELOAD_INTERVAL),
+            ),
+        );
+
+        let batch_window = match settings.forwarder.batch_enabled.unwrap_or(false) {
+            true => {
+                let batch_window = BatchWindow {
+                    max_events: settings
+                        .forwarder
+                        .batch_max_events
+                        .unwrap_or(DEFAULT_BATCH_MAX_EVENTS),
+                    max_wait: settings
+                        .forwarder
+                        .batch_max_wait
+                        .unwrap_or(DEFAULT_BATCH_MAX_WAIT),
+                };
+
+                info!(
+                    "Forwarding podpings in batches of up to {} events or {:?}",
+                    batch_window.max_events, batch_window.max_wait
+                );
+
+                Some(batch_window)
+            }
+            false => None,
+        };
+
+        Ok(Forwarder {
+            endpoints,
+            http_client,
+            health_endpoint: settings
+                .forwarder
+                .health_endpoint
+                .clone()
+                .filter(|health_endpoint| !health_endpoint.is_empty()),
+            secret,
+            batch_window,
+            batch_buffer: Mutex::new(BatchBuffer::default()),
+            payload_template,
+            feed_updates: settings.forwarder.aggregate_window.map(|window| {
+                info!(
+                    "Forwarding one update per feed for every {:?} of podpings",
+                    window
+                );
+                Mutex::new(FeedUpdateWindow::new(window))
+            }),
+            reorder_window: settings
+                .writer
+                .reorder_window
+                .unwrap_or(DEFAULT_REORDER_WINDOW),
+            failure_handler,
+        })
+    }
+
+    async fn get_last_block(&self) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    async fn preflight(&self) -> Result<(), Error> {
+        let health_endpoint = match &self.health_endpoint {
+            Some(health_endpoint) => health_endpoint,
+            None => {
+                debug!("No forwarder health_endpoint set, skipping preflight");
+                return Ok(());
+            }
+        };
+
+        check_health(&self.http_client, self.secret.as_deref(), health_endpoint).await?;
+        info!("forwarder preflight: {} is reachable", health_endpoint);
+
+        Ok(())
+    }
+
+    async fn start(&self, mut rx: Subscription<HiveBlockWithNum>) -> Result<(), Error> {
+        let mut reorder_window = ReorderWindow::new(self.reorder_window);
+
+        loop {
+            let event = match self.recv(&mut rx).await? {
+                Some(event) => event,
+                // The scanner stops after scanner.end_block
+                None => return self.finish().await,
+            };
+
+            for block in reorder_window.push(event.payload)? {
+                let (block_num, timestamp) = (block.block_num, block.timestamp);
+
+                self.forward_block(block).await?;
+                self.failure_handler
+                    .record_write_latency(block_num, timestamp);
+            }
+            rx.ack(event.seq);
+        }
+    }
+
+    async fn start_batch(&self, mut rx: Subscription<BlockBatch>) -> Result<(), Error> {
+        while let Some(event) = self.recv(&mut rx).await? {
+            // Ordered endpoints get podpings in the order they happened
+            self.forward_blocks(event.payload.blocks).await?;
+            rx.ack(event.seq);
+        }
+
+        // Don't hold podpings from the end of catch up until the live scan's first block, feed
+        // updates carry over into it
+        self.flush_batch().await
+    }
+    // The endpoint keeps no output that can be listed, so forwarded podpings aren't verified
+    async fn missing_files(&self, _block: &HiveBlockWithNum) -> Result<Vec<PathBuf>, Error> {
+        Ok(vec![])
+    }
+
+    async fn repair_block(&self, _block: HiveBlockWithNum) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::OAuth2;
+use crate::forwarder::tls::ReloadingClient;
+use crate::secrets::read_secret_file;
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+/// Tokens are fetched again this long before they expire, or halfway for shorter lived ones
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+struct CachedToken {
+    access_token: String,
+    // None when the provider didn't say, it's used until the endpoint answers 401
+    refresh_at: Option<Instant>,
+}
+
+/// Bearer token from an OAuth2 client credentials grant, fetched when first needed and again
+/// shortly before it expires
+pub(crate) struct OAuth2Token {
+    name: String,
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    scope: Option<String>,
+    audience: Option<String>,
+    http_client: Arc<ReloadingClient>,
+    token: Mutex<Option<CachedToken>>,
+}
+
+impl OAuth2Token {
+    pub(crate) fn from_settings(
+        name: &str,
+        oauth2: &OAuth2,
+        http_client: Arc<ReloadingClient>,
+    ) -> Result<OAuth2Token, Error> {
+        let non_empty = |value: &Option<String>| value.clone().filter(|value| !value.is_empty());
+
+        if oauth2.token_url.is_empty() || oauth2.client_id.is_empty() {
+            return Err(eyre!("{} oauth2 token_url and client_id must be set", name));
+        }
+
+        let client_secret = match (
+            non_empty(&oauth2.client_secret),
+            non_empty(&oauth2.client_secret_file),
+        ) {
+            (Some(_), Some(_)) => {
+                return Err(eyre!(
+                    "{} oauth2 client_secret and client_secret_file are both set, only set one",
+                    name
+                ))
+            }
+            (Some(client_secret), None) => client_secret,
+            (None, Some(path)) => read_secret_file(&path).map_err(|e| {
+                eyre!(
+                    "Unable to read {} oauth2 client_secret_file {}: {}",
+                    name,
+                    path,
+                    e
+                )
+            })?,
+            (None, None) => return Err(eyre!("{} oauth2 client_secret is not set", name)),
+        };
+
+        info!(
+            "{} authenticating with OAuth2 client {} at {}",
+            name, oauth2.client_id, oauth2.token_url
+        );
+
+        Ok(OAuth2Token {
+            name: name.to_string(),
+            token_url: oauth2.token_url.clone(),
+            client_id: oauth2.client_id.clone(),
+            client_secret,
+            scope: non_empty(&oauth2.scope),
+            audience: non_empty(&oauth2.audience),
+            http_client,
+            token: Mutex::new(None),
+        })
+    }
+
+    async fn fetch(&self) -> Result<CachedToken, Error> {
+        let mut form = vec![("grant_type", "client_credentials")];
+        if let Some(scope) = &self.scope {
+            form.push(("scope", scope));
+        }
+        if let Some(audience) = &self.audience {
+            form.push(("audience", audience));
+        }
+
+        let response = self
+            .http_client
+            .client()
+            .post(&self.token_url)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&form)
+            .send()
+            .await?;
+        let status = response.status();
+
+        if !status.is_success() {
+            return Err(eyre!(
+                "{} OAuth2 token request to {} returned {}",
+                self.name,
+                self.token_url,
+                status
+            ));
+        }
+
+        let token: TokenResponse = response.json().await?;
+        let refresh_at = token.expires_in.map(|expires_in| {
+            let expires_in = Duration::from_secs(expires_in);
+
+            Instant::now() + expires_in - REFRESH_MARGIN.min(expires_in / 2)
+        });
+
+        debug!(
+            "{} fetched an OAuth2 token, expires in {:?}",
+            self.name, token.expires_in
+        );
+
+        Ok(CachedToken {
+            access_token: token.access_token,
+            refresh_at,
+        })
+    }
+
+    /// The current access token, fetching a new one if it's missing or about to expire
+    pub(crate) async fn access_token(&self) -> Result<String, Error> {
+        // Held while fetching, so concurrent deliveries wait for one request
+        let mut token = self.token.lock().await;
+
+        let fresh = token.as_ref().is_some_and(|token| {
+            token
+                .refresh_at
+                .is_none_or(|refresh_at| Instant::now() < refresh_at)
+        });
+
+        if !fresh {
+            *token = Some(self.fetch().await?);
+        }
+
+        Ok(token.as_ref().unwrap().access_token.clone())
+    }
+
+    /// Drops the token after the endpoint rejected it, e.g. it was revoked early
+    pub(crate) async fn invalidate(&self) {
+        *self.token.lock().await = None;
+    }
+}
@@ -0,0 +1,143 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use color_eyre::eyre::Error;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tracing::{error, info};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct QueuedDelivery {
+    pub(crate) idempotency_key: String,
+    pub(crate) iris: Vec<String>,
+    pub(crate) body: String,
+}
+
+/// Undelivered forwarder payloads persisted in insertion order
+///
+/// Tracks which IRIs have something queued so newer podpings for the same feed
+/// are queued behind the older ones rather than overtaking them.
+pub(crate) struct DeliveryQueue {
+    db: sled::Db,
+    pending_iris: Mutex<HashMap<String, usize>>,
+    notify: Notify,
+}
+
+impl DeliveryQueue {
+    pub(crate) fn open(path: &Path) -> Result<DeliveryQueue, Error> {
+        let db = sled::open(path)?;
+
+        let mut pending_iris: HashMap<String, usize> = HashMap::new();
+
+        for entry in db.iter() {
+            let (_, value) = entry?;
+
+            // Unreadable entries are dropped by pending()
+            if let Ok(delivery) = serde_json::from_slice::<QueuedDelivery>(&value) {
+                for iri in delivery.iris {
+                    *pending_iris.entry(iri).or_insert(0) += 1;
+                }
+            }
+        }
+
+        info!(
+            "Opened forwarder queue {} with {} pending deliveries",
+            path.to_string_lossy(),
+            db.len()
+        );
+
+        Ok(DeliveryQueue {
+            db,
+            pending_iris: Mutex::new(pending_iris),
+            notify: Notify::new(),
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.db.is_empty()
+    }
+
+    pub(crate) fn is_blocked(&self, iris: &[String]) -> bool {
+        let pending_iris = self.pending_iris.lock().unwrap();
+
+        iris.iter().any(|iri| pending_iris.contains_key(iri))
+    }
+
+    pub(crate) async fn push(&self, delivery: QueuedDelivery) -> Result<(), Error> {
+        // Big endian so sled's key ordering matches insertion order
+        let id = self.db.generate_id()?.to_be_bytes();
+
+        self.db.insert(id, serde_json::to_vec(&delivery)?)?;
+        self.db.flush_async().await?;
+
+        let mut pending_iris = self.pending_iris.lock().unwrap();
+        for iri in delivery.iris {
+            *pending_iris.entry(iri).or_insert(0) += 1;
+        }
+        drop(pending_iris);
+
+        self.notify.notify_one();
+
+        Ok(())
+    }
+
+    pub(crate) fn pending(&self) -> Vec<([u8; 8], QueuedDelivery)> {
+        self.db
+            .iter()
+            .filter_map(|entry| {
+                let (key, value) = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        error!("Error reading forwarder queue: {}", e);
+                        return None;
+                    }
+                };
+
+                let id: [u8; 8] = key.as_ref().try_into().ok()?;
+
+                match serde_json::from_slice::<QueuedDelivery>(&value) {
+                    Ok(delivery) => Some((id, delivery)),
+                    Err(e) => {
+                        error!("Dropping unreadable forwarder queue entry: {}", e);
+                        let _ = self.db.remove(key);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) async fn remove(&self, id: [u8; 8], delivery: &QueuedDelivery) -> Result<(), Error> {
+        self.db.remove(id)?;
+        self.db.flush_async().await?;
+
+        let mut pending_iris = self.pending_iris.lock().unwrap();
+        for iri in &delivery.iris {
+            if let Some(count) = pending_iris.get_mut(iri) {
+                *count -= 1;
+
+                if *count == 0 {
+                    pending_iris.remove(iri);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn notified(&self) {
+        self.notify.notified().await
+    }
+}
@@ -0,0 +1,105 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+pub const SIGNATURE_HEADER: &str = "X-Podping-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Podping-Timestamp";
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs `<timestamp>.<payload>`, returning `sha256=<hex digest>`
+///
+/// The timestamp is signed along with the payload so a receiver can refuse a captured request
+/// sent again later.
+pub fn sign_payload(secret: &str, timestamp: i64, payload: &[u8]) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(payload);
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// The timestamp and signature headers for a payload sent now
+pub fn signature_headers(secret: &str, payload: &[u8]) -> [(&'static str, String); 2] {
+    let timestamp = Utc::now().timestamp();
+
+    [
+        (TIMESTAMP_HEADER, timestamp.to_string()),
+        (SIGNATURE_HEADER, sign_payload(secret, timestamp, payload)),
+    ]
+}
+
+pub fn idempotency_key(block_num: u64, tx_id: &str, index: usize) -> String {
+    format!("{}/{}/{}", block_num, tx_id, index)
+}
+
+/// One key for a batch, the same whatever order its podpings were queued in
+pub fn batch_idempotency_key<'a>(keys: impl IntoIterator<Item = &'a str>) -> String {
+    let mut keys = keys.into_iter().collect::<Vec<_>>();
+    keys.sort_unstable();
+
+    format!("batch/{}", hex::encode(Sha256::digest(keys.join("\n"))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_are_signed_with_their_timestamp() {
+        let payload = b"The quick brown fox jumps over the lazy dog";
+
+        assert_eq!(
+            sign_payload("key", 1700000000, payload),
+            "sha256=2f658d6aef4f246e91cd741bbcded7479e9605f9d41c9e248122a117e0e1765b"
+        );
+        assert_ne!(
+            sign_payload("key", 1700000001, payload),
+            sign_payload("key", 1700000000, payload)
+        );
+        assert_ne!(
+            sign_payload("other key", 1700000000, payload),
+            sign_payload("key", 1700000000, payload)
+        );
+    }
+
+    #[test]
+    fn the_headers_carry_the_signed_timestamp() {
+        let [(timestamp_header, timestamp), (signature_header, signature)] =
+            signature_headers("key", b"{}");
+        let timestamp = timestamp.parse::<i64>().unwrap();
+
+        assert_eq!(timestamp_header, TIMESTAMP_HEADER);
+        assert_eq!(signature_header, SIGNATURE_HEADER);
+        assert!((Utc::now().timestamp() - timestamp).abs() <= 1);
+        assert_eq!(signature, sign_payload("key", timestamp, b"{}"));
+    }
+
+    #[test]
+    fn idempotency_keys_are_where_the_podping_is_on_chain() {
+        assert_eq!(idempotency_key(90000000, "abc", 2), "90000000/abc/2");
+    }
+
+    #[test]
+    fn batch_keys_dont_depend_on_order() {
+        let key = batch_idempotency_key(["1/a/0", "1/a/1", "2/b/0"]);
+
+        assert_eq!(key, batch_idempotency_key(["2/b/0", "1/a/0", "1/a/1"]));
+        assert_ne!(key, batch_idempotency_key(["1/a/0", "1/a/1"]));
+        assert!(key.starts_with("batch/"));
+        assert_eq!(key.len(), "batch/".len() + 64);
+    }
+}
@@ -0,0 +1,180 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Http;
+use crate::http_client::client_builder;
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use reqwest::{Certificate, Client, Identity};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::time::sleep;
+use tracing::{error, info};
+
+/// Client certificate and key for mutual TLS, and a private CA, either or both
+#[derive(Debug, Clone)]
+pub(crate) struct ClientTls {
+    // Certificate and key files
+    identity: Option<(PathBuf, PathBuf)>,
+    ca_file: Option<PathBuf>,
+}
+
+impl ClientTls {
+    pub(crate) fn from_settings(
+        name: &str,
+        cert_file: &Option<String>,
+        key_file: &Option<String>,
+        ca_file: &Option<String>,
+    ) -> Result<Option<ClientTls>, Error> {
+        let non_empty = |file: &Option<String>| file.clone().filter(|f| !f.is_empty());
+
+        let identity = match (non_empty(cert_file), non_empty(key_file)) {
+            (Some(cert_file), Some(key_file)) => {
+                Some((PathBuf::from(cert_file), PathBuf::from(key_file)))
+            }
+            (None, None) => None,
+            _ => {
+                return Err(eyre!(
+                    "{} tls_client_cert and tls_client_key must be set together!",
+                    name
+                ))
+            }
+        };
+        let ca_file = non_empty(ca_file).map(PathBuf::from);
+
+        match (&identity, &ca_file) {
+            (None, None) => Ok(None),
+            _ => Ok(Some(ClientTls { identity, ca_file })),
+        }
+    }
+
+    fn files(&self) -> Vec<&PathBuf> {
+        let mut files = vec![];
+        if let Some((cert_file, key_file)) = &self.identity {
+            files.extend([cert_file, key_file]);
+        }
+        files.extend(self.ca_file.iter());
+
+        files
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.files()
+            .into_iter()
+            .map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+fn build_client(tls: Option<&ClientTls>, http: &Http) -> Result<Client, Error> {
+    let mut builder = client_builder(http)?;
+
+    if let Some(tls) = tls {
+        if let Some((cert_file, key_file)) = &tls.identity {
+            // reqwest with rustls wants the certificate chain and key in one PEM
+            let mut pem = std::fs::read(cert_file)?;
+            pem.push(b'\n');
+            pem.extend(std::fs::read(key_file)?);
+
+            builder = builder.identity(Identity::from_pem(&pem)?);
+        }
+
+        if let Some(ca_file) = &tls.ca_file {
+            builder =
+                builder.add_root_certificate(Certificate::from_pem(&std::fs::read(ca_file)?)?);
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+/// HTTP client that rebuilds itself when its TLS client certificate files change
+///
+/// Lets short lived certificates be rotated on disk without restarting podpingd.
+pub(crate) struct ReloadingClient {
+    name: String,
+    tls: Option<ClientTls>,
+    http: Http,
+    client: RwLock<Client>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl ReloadingClient {
+    pub(crate) fn new(
+        name: &str,
+        tls: Option<ClientTls>,
+        http: &Http,
+    ) -> Result<ReloadingClient, Error> {
+        let client = build_client(tls.as_ref(), http)?;
+        let modified = tls.as_ref().map(|tls| tls.modified()).unwrap_or_default();
+
+        if let Some((cert_file, _)) = tls.as_ref().and_then(|tls| tls.identity.as_ref()) {
+            info!(
+                "{} using TLS client certificate {}",
+                name,
+                cert_file.display()
+            );
+        }
+
+        Ok(ReloadingClient {
+            name: name.to_string(),
+            tls,
+            http: http.clone(),
+            client: RwLock::new(client),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Current client, cheap to clone and safe to hold across a reload
+    pub(crate) fn client(&self) -> Client {
+        self.client.read().unwrap().clone()
+    }
+
+    fn reload_if_changed(&self) {
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => return,
+        };
+
+        let modified = tls.modified();
+
+        if *self.modified.lock().unwrap() == modified {
+            return;
+        }
+
+        // Keep the old client if the new files don't load, they may be mid-rotation
+        match build_client(Some(tls), &self.http) {
+            Ok(client) => {
+                *self.client.write().unwrap() = client;
+                *self.modified.lock().unwrap() = modified;
+
+                info!("{} reloaded TLS client certificate", self.name);
+            }
+            Err(e) => error!(
+                "{} error reloading TLS client certificate: {:#}",
+                self.name, e
+            ),
+        }
+    }
+
+    /// Polls the certificate files for changes, forever
+    pub(crate) async fn watch(self: Arc<Self>, interval: Duration) {
+        if self.tls.is_none() {
+            return;
+        }
+
+        loop {
+            sleep(interval).await;
+            self.reload_if_changed();
+        }
+    }
+}
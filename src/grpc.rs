@@ -0,0 +1,275 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::event_bus::Subscription;
+use crate::hive::scanner::{BlockBatch, HiveBlockWithNum};
+use crate::podping::{event_id, podping_iris, podping_medium, podping_reason, PodpingFilter};
+use crate::replay::{archive_days, ReplaySource};
+use color_eyre::eyre::Error;
+use proto::podpings_server::{Podpings, PodpingsServer};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use tracing::{debug, error, info};
+
+pub(crate) mod proto {
+    tonic::include_proto!("podpingd.v1");
+}
+
+const DEFAULT_GRPC_LISTEN: &str = "127.0.0.1:50051";
+const DEFAULT_SUBSCRIBER_BUFFER: usize = 1024;
+/// Podpings queued for each stream, past this the stream waits on HTTP/2 flow control
+const STREAM_BUFFER: usize = 128;
+
+/// Hands every block the writer gets to the gRPC subscribers
+///
+/// Subscribers each read at their own pace from a broadcast channel, so a slow one never holds up
+/// the scan. One that falls `subscriber_buffer` blocks behind is ended instead.
+pub(crate) struct PodpingHub {
+    tx: broadcast::Sender<Arc<HiveBlockWithNum>>,
+}
+
+impl PodpingHub {
+    fn publish(&self, block: HiveBlockWithNum) {
+        // Only fails when nobody is subscribed
+        let _ = self.tx.send(Arc::new(block));
+    }
+
+    pub(crate) async fn start(&self, mut rx: Subscription<HiveBlockWithNum>) -> Result<(), Error> {
+        while let Some(event) = rx.recv().await {
+            self.publish(event.payload);
+            rx.ack(event.seq);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn start_batch(&self, mut rx: Subscription<BlockBatch>) -> Result<(), Error> {
+        while let Some(event) = rx.recv().await {
+            for block in event.payload.blocks {
+                self.publish(block);
+            }
+            rx.ack(event.seq);
+        }
+
+        Ok(())
+    }
+}
+
+impl From<proto::PodpingFilter> for PodpingFilter {
+    fn from(filter: proto::PodpingFilter) -> Self {
+        PodpingFilter {
+            reasons: filter.reasons.into_iter().collect(),
+            mediums: filter.mediums.into_iter().collect(),
+            iris: filter.iris.into_iter().collect(),
+        }
+    }
+}
+
+/// The block's podpings that pass the filter, in block order
+fn podping_messages(block: &HiveBlockWithNum, filter: &PodpingFilter) -> Vec<proto::Podping> {
+    let timestamp = block.timestamp.to_rfc3339();
+    let replaces_block_id = block
+        .replaces
+        .as_ref()
+        .map(|replaced| replaced.block_id.clone())
+        .unwrap_or_default();
+
+    block
+        .transactions
+        .iter()
+        .flat_map(|tx| {
+            tx.indexed_podpings()
+                .map(move |(i, podping)| (tx, i, podping))
+        })
+        .filter(|(_, _, podping)| filter.matches(podping))
+        .map(|(tx, i, podping)| proto::Podping {
+            event_id: event_id(block.block_num, &tx.tx_id, i),
+            block_num: block.block_num,
+            block_id: block.block_id.clone(),
+            timestamp: timestamp.clone(),
+            tx_id: tx.tx_id.clone(),
+            index: i as u32,
+            reason: podping_reason(podping).unwrap_or_default(),
+            medium: podping_medium(podping).unwrap_or_default(),
+            iris: podping_iris(podping),
+            json: serde_json::to_string(podping).unwrap_or_default(),
+            replaces_block_id: replaces_block_id.clone(),
+        })
+        .collect()
+}
+
+struct PodpingService {
+    hub: Arc<PodpingHub>,
+    archive: Option<Arc<ReplaySource>>,
+}
+
+type PodpingStream = ReceiverStream<Result<proto::Podping, Status>>;
+
+#[tonic::async_trait]
+impl Podpings for PodpingService {
+    type SubscribeStream = PodpingStream;
+    type GetRangeStream = PodpingStream;
+
+    async fn subscribe(
+        &self,
+        request: Request<proto::PodpingFilter>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let filter = PodpingFilter::from(request.into_inner());
+        let mut blocks = self.hub.tx.subscribe();
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+
+        debug!("gRPC: new subscriber");
+
+        tokio::spawn(async move {
+            let mut last_block: Option<u64> = None;
+
+            loop {
+                let block = match blocks.recv().await {
+                    Ok(block) => block,
+                    Err(RecvError::Lagged(missed)) => {
+                        let message = match last_block {
+                            Some(last_block) => format!(
+                                "Subscriber fell {} blocks behind, GetRange can fill the gap after block {}",
+                                missed, last_block
+                            ),
+                            None => format!("Subscriber fell {} blocks behind", missed),
+                        };
+
+                        let _ = tx.send(Err(Status::resource_exhausted(message))).await;
+                        return;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+
+                for podping in podping_messages(&block, &filter) {
+                    // The client went away
+                    if tx.send(Ok(podping)).await.is_err() {
+                        return;
+                    }
+                }
+
+                last_block = Some(block.block_num);
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn get_range(
+        &self,
+        request: Request<proto::GetRangeRequest>,
+    ) -> Result<Response<Self::GetRangeStream>, Status> {
+        let request = request.into_inner();
+
+        let archive = match &self.archive {
+            Some(archive) => archive.clone(),
+            None => {
+                return Err(Status::failed_precondition(
+                    "GetRange needs the disk or object storage writer, podpingd isn't keeping an archive",
+                ))
+            }
+        };
+
+        if request.start_block > request.end_block {
+            return Err(Status::invalid_argument("start_block is after end_block"));
+        }
+
+        let filter = PodpingFilter::from(request.filter.unwrap_or_default());
+        let mut days = archive_days(archive, Some(request.start_block), Some(request.end_block));
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+
+        tokio::spawn(async move {
+            while let Some(day) = days.recv().await {
+                let blocks = match day {
+                    Ok((_, blocks)) => blocks,
+                    Err(e) => {
+                        error!("gRPC: error reading the archive: {:#}", e);
+                        let _ = tx
+                            .send(Err(Status::internal(format!(
+                                "Error reading the archive: {:#}",
+                                e
+                            ))))
+                            .await;
+                        return;
+                    }
+                };
+
+                for block in &blocks {
+                    for podping in podping_messages(block, &filter) {
+                        // The client went away, dropping `days` stops the archive read too
+                        if tx.send(Ok(podping)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// Starts the gRPC server in the background when it's enabled
+///
+/// Returns the hub the syncer publishes blocks to. Panics if the listen address can't be bound.
+pub(crate) async fn start_server(settings: &Settings) -> Option<Arc<PodpingHub>> {
+    if !settings.grpc.enabled {
+        return None;
+    }
+
+    let listen = settings
+        .grpc
+        .listen
+        .clone()
+        .unwrap_or(DEFAULT_GRPC_LISTEN.to_string());
+    let addr = match listen.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(e) => panic!("Invalid grpc listen address {}: {}", listen, e),
+    };
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => panic!("Unable to listen for gRPC on {}: {}", addr, e),
+    };
+
+    let subscriber_buffer = settings
+        .grpc
+        .subscriber_buffer
+        .unwrap_or(DEFAULT_SUBSCRIBER_BUFFER)
+        .max(1);
+    let (tx, _) = broadcast::channel(subscriber_buffer);
+    let hub = Arc::new(PodpingHub { tx });
+
+    let service = PodpingService {
+        hub: hub.clone(),
+        archive: ReplaySource::from_writer(settings).map(Arc::new),
+    };
+
+    info!("gRPC server listening on {}", addr);
+
+    tokio::spawn(async move {
+        if let Err(e) = Server::builder()
+            .add_service(PodpingsServer::new(service))
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .await
+        {
+            error!("gRPC server stopped: {}", e);
+        }
+    });
+
+    Some(hub)
+}
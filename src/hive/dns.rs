@@ -0,0 +1,407 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::status::SYNC_STATUS;
+use hickory_resolver::config::{LookupIpStrategy, ResolverConfig, ResolverOpts};
+use hickory_resolver::system_conf::read_system_conf;
+use hickory_resolver::TokioAsyncResolver;
+use hyper::Uri;
+use hyper_util::client::legacy::connect::dns::Name;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioIo;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use std::vec;
+use tokio::net::TcpStream;
+use tower::{BoxError, Service};
+use tracing::{info, warn};
+
+/// How long an IPv6 connection gets before IPv4 is tried alongside it
+const HAPPY_EYEBALLS_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Failed connects in a row before a node is looked up again past the cache
+///
+/// A node that's down for a moment shouldn't cost a DNS query on every request to it.
+const CONNECT_FAILURES_BEFORE_REFRESH: u32 = 3;
+
+struct CachedLookup {
+    addresses: Vec<IpAddr>,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct Hosts {
+    lookups: HashMap<String, CachedLookup>,
+    connect_failures: HashMap<String, u32>,
+}
+
+/// Looks up RPC nodes, caching each answer for its TTL
+///
+/// Clones share the cache, so every client to a node reuses its lookup. Answers are cached per
+/// node rather than in hickory, so a node that moved is looked up again without the others.
+#[derive(Clone)]
+pub(crate) struct HiveResolver {
+    resolver: Arc<TokioAsyncResolver>,
+    max_ttl: Duration,
+    hosts: Arc<Mutex<Hosts>>,
+}
+
+impl HiveResolver {
+    /// Answers are kept for their TTL, up to `max_ttl`
+    ///
+    /// None when the system's DNS config can't be read, jsonrpsee's uncached lookups are used then.
+    pub(crate) fn new(max_ttl: Duration) -> Option<HiveResolver> {
+        match read_system_conf() {
+            Ok((config, options)) => Some(HiveResolver::with_config(config, options, max_ttl)),
+            Err(e) => {
+                warn!(
+                    "Unable to read the system's DNS config, RPC node lookups won't be cached: {}",
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    fn with_config(
+        config: ResolverConfig,
+        mut options: ResolverOpts,
+        max_ttl: Duration,
+    ) -> HiveResolver {
+        // Both families, so a node with a broken IPv6 route is still reached over IPv4
+        options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        options.cache_size = 0;
+
+        HiveResolver {
+            resolver: Arc::new(TokioAsyncResolver::tokio(config, options)),
+            max_ttl,
+            hosts: Arc::default(),
+        }
+    }
+
+    fn cached(&self, host: &str) -> Option<Vec<IpAddr>> {
+        self.hosts
+            .lock()
+            .unwrap()
+            .lookups
+            .get(host)
+            .filter(|lookup| lookup.expires > Instant::now())
+            .map(|lookup| lookup.addresses.clone())
+    }
+
+    async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, io::Error> {
+        match self.cached(host) {
+            Some(addresses) => Ok(addresses),
+            None => self.resolve(host).await,
+        }
+    }
+
+    /// Looks the host up past the cache and caches the answer, IPv6 addresses first so they're
+    /// tried first
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, io::Error> {
+        let lookup = self
+            .resolver
+            .lookup_ip(host)
+            .await
+            .map_err(io::Error::other)?;
+
+        let mut addresses: Vec<IpAddr> = lookup.iter().collect();
+        addresses.sort_by_key(IpAddr::is_ipv4);
+
+        self.hosts.lock().unwrap().lookups.insert(
+            host.to_string(),
+            CachedLookup {
+                addresses: addresses.clone(),
+                expires: lookup.valid_until().min(Instant::now() + self.max_ttl),
+            },
+        );
+
+        Ok(addresses)
+    }
+
+    fn connected(&self, host: &str) {
+        self.hosts.lock().unwrap().connect_failures.remove(host);
+    }
+
+    /// Counts a failed connect, `true` once the host has failed enough in a row to look it up again
+    fn connect_failed(&self, host: &str) -> bool {
+        let mut hosts = self.hosts.lock().unwrap();
+        let failures = hosts.connect_failures.entry(host.to_string()).or_default();
+        *failures += 1;
+
+        if *failures < CONNECT_FAILURES_BEFORE_REFRESH {
+            return false;
+        }
+
+        hosts.connect_failures.remove(host);
+        true
+    }
+
+    /// Looks the host up again past the cache, `true` if it has new addresses
+    async fn refresh(&self, host: &str) -> bool {
+        let cached = self
+            .hosts
+            .lock()
+            .unwrap()
+            .lookups
+            .remove(host)
+            .map(|lookup| lookup.addresses);
+
+        match self.resolve(host).await {
+            Ok(addresses) => {
+                !addresses.is_empty() && cached.is_some_and(|cached| cached != addresses)
+            }
+            Err(e) => {
+                warn!("Unable to look up RPC node {} again: {}", host, e);
+                false
+            }
+        }
+    }
+}
+
+impl Service<Name> for HiveResolver {
+    type Response = vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolver = self.clone();
+
+        Box::pin(async move {
+            // The connector fills in the port
+            let addresses: Vec<SocketAddr> = resolver
+                .lookup(name.as_str())
+                .await?
+                .into_iter()
+                .map(|address| SocketAddr::new(address, 0))
+                .collect();
+
+            Ok(addresses.into_iter())
+        })
+    }
+}
+
+/// Connects to RPC nodes, racing IPv6 and IPv4 and looking a node up again when it keeps failing
+/// to connect
+#[derive(Clone)]
+pub(crate) struct HiveConnector {
+    inner: HttpConnector<HiveResolver>,
+    resolver: HiveResolver,
+}
+
+impl HiveConnector {
+    pub(crate) fn new(resolver: &HiveResolver) -> HiveConnector {
+        let mut inner = HttpConnector::new_with_resolver(resolver.clone());
+        // https URLs come through here from the TLS connector
+        inner.enforce_http(false);
+        inner.set_nodelay(true);
+        inner.set_happy_eyeballs_timeout(Some(HAPPY_EYEBALLS_TIMEOUT));
+
+        HiveConnector {
+            inner,
+            resolver: resolver.clone(),
+        }
+    }
+}
+
+impl Service<Uri> for HiveConnector {
+    type Response = TokioIo<TcpStream>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let resolver = self.resolver.clone();
+
+        Box::pin(async move {
+            let host = uri
+                .host()
+                .unwrap_or_default()
+                .trim_matches(['[', ']'])
+                .to_string();
+
+            let error = match inner.call(uri.clone()).await {
+                Ok(stream) => {
+                    resolver.connected(&host);
+                    return Ok(stream);
+                }
+                Err(e) => e,
+            };
+
+            // A node that moved is only found once the cached addresses stop answering
+            if host.parse::<IpAddr>().is_ok()
+                || !resolver.connect_failed(&host)
+                || !resolver.refresh(&host).await
+            {
+                return Err(error.into());
+            }
+
+            info!(
+                "Unable to connect to RPC node {} ({}), trying its new address",
+                host, error
+            );
+            SYNC_STATUS.increment_counter("rpc_node_address_changes", 1);
+
+            Ok(inner.call(uri).await?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const NODE: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER_NODE: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    // No name servers, only the hosts file and localhost answer
+    fn resolver() -> HiveResolver {
+        HiveResolver::with_config(
+            ResolverConfig::new(),
+            ResolverOpts::default(),
+            Duration::from_secs(60),
+        )
+    }
+
+    fn cache(resolver: &HiveResolver, host: &str, addresses: Vec<IpAddr>, expires: Instant) {
+        resolver
+            .hosts
+            .lock()
+            .unwrap()
+            .lookups
+            .insert(host.to_string(), CachedLookup { addresses, expires });
+    }
+
+    #[tokio::test]
+    async fn hosts_are_looked_up_again_after_failing_in_a_row() {
+        let resolver = resolver();
+
+        for _ in 1..CONNECT_FAILURES_BEFORE_REFRESH {
+            assert!(!resolver.connect_failed("node.invalid"));
+        }
+        assert!(!resolver.connect_failed("other.invalid"));
+        assert!(resolver.connect_failed("node.invalid"));
+
+        // Counting starts over once it's been looked up again
+        assert!(!resolver.connect_failed("node.invalid"));
+    }
+
+    #[tokio::test]
+    async fn connecting_resets_the_failures() {
+        let resolver = resolver();
+
+        for _ in 1..CONNECT_FAILURES_BEFORE_REFRESH {
+            assert!(!resolver.connect_failed("node.invalid"));
+        }
+        resolver.connected("node.invalid");
+
+        assert!(!resolver.connect_failed("node.invalid"));
+    }
+
+    #[tokio::test]
+    async fn lookups_are_cached_until_they_expire() {
+        let resolver = resolver();
+        cache(
+            &resolver,
+            "node.invalid",
+            vec![NODE],
+            Instant::now() + Duration::from_secs(60),
+        );
+        cache(&resolver, "expired.invalid", vec![NODE], Instant::now());
+
+        assert_eq!(resolver.lookup("node.invalid").await.unwrap(), [NODE]);
+        assert!(resolver.lookup("expired.invalid").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn refreshing_a_host_leaves_the_others_cached() {
+        let resolver = resolver();
+        let expires = Instant::now() + Duration::from_secs(60);
+        cache(&resolver, "localhost", vec![NODE], expires);
+        cache(&resolver, "other.invalid", vec![OTHER_NODE], expires);
+
+        assert!(resolver.refresh("localhost").await);
+
+        let addresses = resolver.cached("localhost").unwrap();
+        assert!(addresses.iter().all(IpAddr::is_loopback));
+        assert!(addresses
+            .iter()
+            .is_sorted_by_key(|address| address.is_ipv4()));
+        assert_eq!(resolver.cached("other.invalid").unwrap(), [OTHER_NODE]);
+    }
+
+    #[tokio::test]
+    async fn unchanged_or_failed_refreshes_arent_new_addresses() {
+        let resolver = resolver();
+        let localhost = resolver.resolve("localhost").await.unwrap();
+        assert!(
+            localhost.contains(&IpAddr::V4(Ipv4Addr::LOCALHOST))
+                || localhost.contains(&IpAddr::V6(Ipv6Addr::LOCALHOST))
+        );
+
+        assert!(!resolver.refresh("localhost").await);
+
+        cache(
+            &resolver,
+            "node.invalid",
+            vec![NODE],
+            Instant::now() + Duration::from_secs(60),
+        );
+        assert!(!resolver.refresh("node.invalid").await);
+        // Nothing was cached, so there was nothing stale to replace
+        assert!(!resolver.refresh("uncached.invalid").await);
+    }
+
+    #[tokio::test]
+    async fn the_connector_moves_to_new_addresses_after_failing_in_a_row() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri: Uri = format!(
+            "http://localhost:{}/",
+            listener.local_addr().unwrap().port()
+        )
+        .parse()
+        .unwrap();
+        let resolver = resolver();
+        // Loopback, but nothing listens there
+        cache(
+            &resolver,
+            "localhost",
+            vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))],
+            Instant::now() + Duration::from_secs(60),
+        );
+        let mut connector = HiveConnector::new(&resolver);
+
+        for _ in 1..CONNECT_FAILURES_BEFORE_REFRESH {
+            assert!(connector.call(uri.clone()).await.is_err());
+        }
+
+        assert!(connector.call(uri).await.is_ok());
+        assert!(resolver
+            .cached("localhost")
+            .unwrap()
+            .contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    }
+}
@@ -0,0 +1,99 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::hive::jsonrpc::block_api;
+use crate::hive::jsonrpc::client::HiveHttpClient;
+use crate::hive::jsonrpc::request_params::GetBlockParams;
+use crate::hive::podping_ids::PodpingIds;
+use crate::hive::scanner::{block_response_to_hive_block, HiveBlockWithNum};
+use jsonrpsee::core::client::Error;
+use std::collections::VecDeque;
+use tracing::{info, warn};
+
+// Hive blocks become irreversible ~20 blocks behind head, so a fork can't reach further back
+const FORK_HISTORY_BLOCKS: usize = 64;
+
+/// Remembers recently processed head blocks to notice when the chain replaces one
+pub(crate) struct ForkDetector {
+    history: VecDeque<HiveBlockWithNum>,
+}
+
+impl ForkDetector {
+    pub(crate) fn new() -> ForkDetector {
+        ForkDetector {
+            history: VecDeque::with_capacity(FORK_HISTORY_BLOCKS),
+        }
+    }
+
+    pub(crate) fn record(&mut self, block: &HiveBlockWithNum) {
+        if self.history.len() == FORK_HISTORY_BLOCKS {
+            self.history.pop_front();
+        }
+        self.history.push_back(block.clone());
+    }
+
+    /// Whether the block doesn't build on the last block we processed
+    pub(crate) fn is_fork(&self, block: &HiveBlockWithNum) -> bool {
+        match self.history.back() {
+            Some(last) => last.block_num + 1 == block.block_num && last.block_id != block.previous,
+            None => false,
+        }
+    }
+
+    /// Re-fetches processed blocks back to the common ancestor
+    ///
+    /// Returns the replacement blocks oldest first, each carrying the block it replaces.
+    pub(crate) async fn resolve(
+        &mut self,
+        client: &HiveHttpClient,
+        podping_ids: &PodpingIds,
+    ) -> Result<Vec<HiveBlockWithNum>, Error> {
+        let mut corrections = vec![];
+
+        for old_block in self.history.iter().rev() {
+            let params = GetBlockParams {
+                block_num: &old_block.block_num,
+            };
+            let response = block_api::get_block(client, params).await?;
+            let mut new_block =
+                block_response_to_hive_block(old_block.block_num, podping_ids, response);
+
+            if new_block.block_id == old_block.block_id {
+                break;
+            }
+
+            warn!(
+                "Fork: block {} {} was replaced by {}",
+                old_block.block_num, old_block.block_id, new_block.block_id
+            );
+
+            new_block.replaces = Some(Box::new(old_block.clone()));
+            corrections.push(new_block);
+        }
+
+        if corrections.len() == self.history.len() {
+            warn!("Fork is deeper than the {FORK_HISTORY_BLOCKS} remembered blocks, older replaced blocks won't be corrected");
+        }
+
+        corrections.reverse();
+
+        for entry in self.history.iter_mut() {
+            if let Some(correction) = corrections.iter().find(|c| c.block_num == entry.block_num) {
+                *entry = correction.clone();
+                entry.replaces = None;
+            }
+        }
+
+        info!("Fork resolved, {} block(s) corrected", corrections.len());
+
+        Ok(corrections)
+    }
+}
@@ -0,0 +1,233 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::hive::podping_ids::PodpingIds;
+use crate::hive::posting_authority::PODPING_SENDERS;
+use crate::hive::scanner::{HiveBlockWithNum, HiveTransactionWithTxId};
+use crate::hive::tls::platform_tls_config;
+use crate::parse::{parse_podping_or_quarantine, MalformedPodping};
+use chrono::NaiveDateTime;
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tokio_postgres::Client;
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tracing::{info, instrument, warn};
+
+pub(crate) const DEFAULT_HAF_BATCH_SIZE: u64 = 1000;
+const RETRY_WAIT: Duration = Duration::from_secs(5);
+// HAF's op_type_id for custom_json_operation
+const CUSTOM_JSON_OPERATION: i16 = 18;
+
+const BLOCKS_QUERY: &str = "\
+    SELECT b.num, encode(b.hash, 'hex'), encode(b.prev, 'hex'), b.created_at, a.name \
+    FROM hive.blocks_view b \
+    LEFT JOIN hive.accounts_view a ON a.id = b.producer_account_id \
+    WHERE b.num BETWEEN $1 AND $2 \
+    ORDER BY b.num";
+
+// The id is matched against the podping ids again, this only narrows it down in the database
+// when they're all globs
+const PODPINGS_QUERY: &str = "\
+    SELECT o.block_num, encode(t.trx_hash, 'hex'), o.body->'value'->>'id', o.body->'value'->>'json', \
+        o.body->'value'->>'required_auths', o.body->'value'->>'required_posting_auths', o.op_pos \
+    FROM hive.operations_view o \
+    JOIN hive.transactions_view t ON t.block_num = o.block_num AND t.trx_in_block = o.trx_in_block \
+    WHERE o.block_num BETWEEN $1 AND $2 AND o.op_type_id = $3 \
+        AND ($4::text[] IS NULL OR o.body->'value'->>'id' ILIKE ANY($4::text[])) \
+    ORDER BY o.block_num, o.trx_in_block, o.op_pos";
+
+fn block_num_param(block_num: u64) -> Result<i32, Error> {
+    i32::try_from(block_num).map_err(|_| eyre!("Block {} is past what HAF can store", block_num))
+}
+
+/// Catch-up from a HAF database's podping operations instead of whole blocks over JSON-RPC
+///
+/// Only the block headers and the podping custom_json operations are read, a small fraction of
+/// the blocks. One connection each, made on first use and again after an error.
+pub(crate) struct HafClient {
+    database_url: String,
+    client: Mutex<Option<Arc<Client>>>,
+}
+
+impl HafClient {
+    pub(crate) fn new(database_url: &str) -> HafClient {
+        HafClient {
+            database_url: database_url.to_string(),
+            client: Mutex::new(None),
+        }
+    }
+
+    async fn client(&self) -> Result<Arc<Client>, Error> {
+        let mut client = self.client.lock().await;
+
+        if let Some(client) = client.as_ref().filter(|client| !client.is_closed()) {
+            return Ok(client.clone());
+        }
+
+        // TLS when the server offers it
+        let (new_client, connection) = tokio_postgres::connect(
+            &self.database_url,
+            MakeRustlsConnect::new(platform_tls_config()?),
+        )
+        .await?;
+
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                warn!("HAF database connection closed: {}", e);
+            }
+        });
+
+        info!("Connected to the HAF database");
+
+        let new_client = Arc::new(new_client);
+        *client = Some(new_client.clone());
+
+        Ok(new_client)
+    }
+
+    /// The blocks in `chunk`, with only their podping transactions
+    ///
+    /// Retries until the database answers for every block, including while HAF is still
+    /// behind the chunk.
+    #[instrument(
+        level = "debug",
+        name = "fetch_haf_chunk",
+        skip_all,
+        fields(first_block = chunk.first().copied(), blocks = chunk.len())
+    )]
+    pub(crate) async fn fetch_block_chunk(
+        &self,
+        podping_ids: &PodpingIds,
+        chunk: &[u64],
+    ) -> Result<Vec<HiveBlockWithNum>, Error> {
+        loop {
+            match self.try_fetch_block_chunk(podping_ids, chunk).await {
+                Ok(blocks) => return Ok(blocks),
+                Err(e) => {
+                    warn!(
+                        "Error reading blocks {} to {} from HAF, retrying in {:?}: {:#}",
+                        chunk.first().unwrap(),
+                        chunk.last().unwrap(),
+                        RETRY_WAIT,
+                        e
+                    );
+                    // Start over on a new connection in case this one's gone bad
+                    *self.client.lock().await = None;
+                    sleep(RETRY_WAIT).await;
+                }
+            }
+        }
+    }
+
+    async fn try_fetch_block_chunk(
+        &self,
+        podping_ids: &PodpingIds,
+        chunk: &[u64],
+    ) -> Result<Vec<HiveBlockWithNum>, Error> {
+        let first_block = block_num_param(*chunk.first().unwrap())?;
+        let last_block = block_num_param(*chunk.last().unwrap())?;
+        let client = self.client().await?;
+
+        let block_rows = client
+            .query(BLOCKS_QUERY, &[&first_block, &last_block])
+            .await?;
+
+        if block_rows.len() != chunk.len() {
+            return Err(eyre!(
+                "HAF only has {} of the {} blocks, it may still be syncing",
+                block_rows.len(),
+                chunk.len()
+            ));
+        }
+
+        let mut transactions = BTreeMap::<u64, Vec<HiveTransactionWithTxId>>::new();
+        let mut malformed = BTreeMap::<u64, Vec<MalformedPodping>>::new();
+
+        for row in client
+            .query(
+                PODPINGS_QUERY,
+                &[
+                    &first_block,
+                    &last_block,
+                    &CUSTOM_JSON_OPERATION,
+                    &podping_ids.like_patterns(),
+                ],
+            )
+            .await?
+        {
+            let block_num = row.try_get::<_, i32>(0)? as u64;
+            let tx_id: String = row.try_get(1)?;
+            let id: Option<String> = row.try_get(2)?;
+            let json: Option<String> = row.try_get(3)?;
+            let op_index = row.try_get::<_, i32>(6)? as usize;
+            let signers = |column| -> Result<Vec<String>, Error> {
+                let signers: Option<String> = row.try_get(column)?;
+                Ok(signers
+                    .and_then(|signers| serde_json::from_str(&signers).ok())
+                    .unwrap_or_default())
+            };
+
+            let id = match id.filter(|id| podping_ids.is_match(id)) {
+                Some(id) => id,
+                None => continue,
+            };
+
+            if !PODPING_SENDERS.allows(&signers(4)?, &signers(5)?) {
+                continue;
+            }
+
+            // Like over JSON-RPC, anything that isn't a valid podping is quarantined
+            let podping = match json.and_then(|json| {
+                parse_podping_or_quarantine(
+                    &id,
+                    &json,
+                    &tx_id,
+                    malformed.entry(block_num).or_default(),
+                )
+            }) {
+                Some(podping) => podping,
+                None => continue,
+            };
+
+            let block_transactions = transactions.entry(block_num).or_default();
+
+            match block_transactions.last_mut() {
+                Some(tx) if tx.tx_id == tx_id => tx.push(op_index, podping),
+                _ => {
+                    block_transactions.push(HiveTransactionWithTxId::new(tx_id, op_index, podping))
+                }
+            }
+        }
+
+        block_rows
+            .into_iter()
+            .map(|row| -> Result<HiveBlockWithNum, Error> {
+                let block_num = row.try_get::<_, i32>(0)? as u64;
+
+                Ok(HiveBlockWithNum {
+                    block_num,
+                    block_id: row.try_get(1)?,
+                    previous: row.try_get(2)?,
+                    witness: row.try_get::<_, Option<String>>(4)?.unwrap_or_default(),
+                    timestamp: row.try_get::<_, NaiveDateTime>(3)?.and_utc(),
+                    transactions: transactions.remove(&block_num).unwrap_or_default(),
+                    malformed: malformed.remove(&block_num).unwrap_or_default(),
+                    replaces: None,
+                })
+            })
+            .collect()
+    }
+}
@@ -9,22 +9,28 @@
  *
  *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
  */
-use jsonrpsee::http_client::HttpClient;
-use jsonrpsee::core::client::{BatchResponse, ClientT, Error};
-use jsonrpsee::core::params::BatchRequestBuilder;
-use jsonrpsee_http_client::transport::HttpBackend;
-use tower_http::compression::Compression;
-use tower_http::decompression::Decompression;
+use crate::hive::jsonrpc::client::HiveHttpClient;
 use crate::hive::jsonrpc::request_params::GetBlockParams;
 use crate::hive::jsonrpc::responses::GetBlockResponse;
+use jsonrpsee::core::client::{BatchResponse, ClientT, Error};
+use jsonrpsee::core::params::BatchRequestBuilder;
+use serde_json::value::RawValue;
 
 pub async fn get_block(
-    client: &HttpClient<Decompression<Compression<HttpBackend>>>,
-    params: GetBlockParams<'_>
+    client: &HiveHttpClient,
+    params: GetBlockParams<'_>,
 ) -> Result<GetBlockResponse, Error> {
     client.request("block_api.get_block", params).await
 }
 
+/// The response exactly as the node sent it
+pub async fn get_block_raw(
+    client: &HiveHttpClient,
+    params: GetBlockParams<'_>,
+) -> Result<Box<RawValue>, Error> {
+    client.request("block_api.get_block", params).await
+}
+
 pub fn build_get_block_batch_params(
     params: GetBlockParams,
     batch_request_builder: &mut BatchRequestBuilder,
@@ -33,8 +39,8 @@ pub fn build_get_block_batch_params(
 }
 
 pub async fn get_block_batch(
-    client: &HttpClient<Decompression<Compression<HttpBackend>>>,
+    client: &HiveHttpClient,
     batch_request_builder: BatchRequestBuilder<'static>,
 ) -> Result<BatchResponse<'static, GetBlockResponse>, Error> {
     client.batch_request(batch_request_builder).await
-}
\ No newline at end of file
+}
@@ -1,58 +1,201 @@
-use std::time::Duration;
+use crate::config::Settings;
+use crate::hive::dns::HiveResolver;
+use crate::hive::jsonrpc::transport::{HiveTransport, HiveTransportLayer};
+use crate::hive::node_scores::RPC_NODE_SCORES;
+use crate::hive::rate_limit::{RpcRateLimit, RpcRateLimitLayer, RPC_RATE_LIMITS};
+use crate::hive::tls::{platform_tls_config, rpc_tls_config};
+use crate::reload::LIVE_SETTINGS;
 use color_eyre::Report;
 use jsonrpsee::core::client::Error;
 use jsonrpsee::http_client::HttpClient;
-use jsonrpsee_http_client::transport::HttpBackend;
+use jsonrpsee_http_client::CustomCertStore;
+use std::time::Duration;
 use tower_http::compression::Compression;
 use tower_http::decompression::Decompression;
 use tracing::info;
 
+/// Requests go through the node's rate limit, get compressed, then go out over [`HiveTransport`]
+pub(crate) type HiveHttpClient =
+    HttpClient<RpcRateLimit<Decompression<Compression<HiveTransport>>>>;
+
+const DEFAULT_DNS_CACHE_MAX_TTL: Duration = Duration::from_secs(300);
+
+/// What every client to the RPC nodes is built with
+///
+/// jsonrpsee's transport only takes a request timeout from `[http]`, the rest of it doesn't
+/// reach the RPC nodes.
+#[derive(Clone)]
+pub(crate) struct RpcClientOptions {
+    pub(crate) tls: CustomCertStore,
+    pub(crate) request_timeout: Duration,
+    // Shared by every client built with these options
+    pub(crate) resolver: Option<HiveResolver>,
+}
+
+impl RpcClientOptions {
+    pub(crate) fn from_settings(settings: &Settings) -> Result<RpcClientOptions, Report> {
+        let tls = match rpc_tls_config(&settings.scanner)? {
+            Some(tls) => tls,
+            None => platform_tls_config()?,
+        };
+
+        Ok(RpcClientOptions {
+            tls,
+            request_timeout: settings.http.timeout(),
+            resolver: HiveResolver::new(
+                settings
+                    .scanner
+                    .dns_cache_max_ttl
+                    .unwrap_or(DEFAULT_DNS_CACHE_MAX_TTL),
+            ),
+        })
+    }
+}
+
 pub(crate) trait JsonRpcClient {
-    fn new(rpc_nodes: Vec<String>) -> Result<Self, Report> where Self: Sized;
-    fn build_client(first_rpc_node: &String) -> Result<HttpClient<Decompression<Compression<HttpBackend>>>, Error>;
-    fn get_client(&self) -> &HttpClient<Decompression<Compression<HttpBackend>>>;
+    fn new(rpc_nodes: Vec<String>, slot: usize, options: RpcClientOptions) -> Result<Self, Report>
+    where
+        Self: Sized;
+    fn build_client(
+        first_rpc_node: &String,
+        options: &RpcClientOptions,
+    ) -> Result<HiveHttpClient, Error>;
+    fn get_client(&self) -> &HiveHttpClient;
     fn rotate_node(&mut self) -> Result<(), Report>;
+    fn reload_nodes(&mut self) -> Result<(), Report>;
 }
 
 pub(crate) struct JsonRpcClientImpl {
-    client: HttpClient<Decompression<Compression<HttpBackend>>>,
+    client: HiveHttpClient,
     rpc_nodes: Vec<String>,
-    current_node: usize
+    current_node: usize,
+    // Position in the node ranking this client starts at, so parallel clients spread out
+    slot: usize,
+    settings_generation: u64,
+    scores_generation: u64,
+    // Kept to rebuild the client when rotating nodes
+    options: RpcClientOptions,
+}
+
+impl JsonRpcClientImpl {
+    /// Stays on the current node if it's still listed
+    fn apply_settings(&mut self, settings: &Settings) -> Result<(), Report> {
+        RPC_RATE_LIMITS.configure(&settings.scanner);
+
+        let rpc_nodes = RPC_NODE_SCORES.rank(&settings.scanner.rpc_nodes);
+
+        if rpc_nodes.is_empty() {
+            return Ok(());
+        }
+
+        let current = self.rpc_nodes[self.current_node].clone();
+
+        if let Some(i) = rpc_nodes.iter().position(|node| *node == current) {
+            self.rpc_nodes = rpc_nodes;
+            self.current_node = i;
+            return Ok(());
+        }
+
+        self.current_node %= rpc_nodes.len();
+        self.rpc_nodes = rpc_nodes;
+
+        let next_node = &self.rpc_nodes[self.current_node];
+
+        info!("RPC node list reloaded, using RPC Node: {}", next_node);
+
+        self.client = Self::build_client(next_node, &self.options)?;
+
+        Ok(())
+    }
+
+    /// Moves to this client's slot in the new ranking, the best node for the first client
+    fn apply_scores(&mut self) -> Result<(), Report> {
+        let current = self.rpc_nodes[self.current_node].clone();
+
+        self.rpc_nodes = RPC_NODE_SCORES.rank(&self.rpc_nodes);
+        self.current_node = self.slot % self.rpc_nodes.len();
+
+        let next_node = &self.rpc_nodes[self.current_node];
+
+        if *next_node == current {
+            return Ok(());
+        }
+
+        info!(
+            "Switching from RPC Node {} to {}, it benchmarked better",
+            current, next_node
+        );
+
+        self.client = Self::build_client(next_node, &self.options)?;
+
+        Ok(())
+    }
 }
 
 impl JsonRpcClient for JsonRpcClientImpl {
-    fn new(rpc_nodes: Vec<String>) -> Result<JsonRpcClientImpl, Report> {
-        let first_rpc_node = rpc_nodes.get(0).expect("No RPC Nodes defined!").clone();
+    fn new(
+        rpc_nodes: Vec<String>,
+        slot: usize,
+        options: RpcClientOptions,
+    ) -> Result<JsonRpcClientImpl, Report> {
+        assert!(!rpc_nodes.is_empty(), "No RPC Nodes defined!");
+
+        // Starts from the current ranking, so only a newer one moves it
+        let mut scores_generation = 0;
+        RPC_NODE_SCORES.changed_since(&mut scores_generation);
+
+        let rpc_nodes = RPC_NODE_SCORES.rank(&rpc_nodes);
+        let current_node = slot % rpc_nodes.len();
+        let first_rpc_node = rpc_nodes[current_node].clone();
 
         info!("Using first RPC Node: {}", first_rpc_node);
 
         Ok(JsonRpcClientImpl {
             rpc_nodes,
-            current_node: 0,
-            client: Self::build_client(&first_rpc_node)?
+            current_node,
+            slot,
+            settings_generation: 0,
+            scores_generation,
+            client: Self::build_client(&first_rpc_node, &options)?,
+            options,
         })
     }
 
-    fn build_client(first_rpc_node: &String) -> Result<HttpClient<Decompression<Compression<HttpBackend>>>, Error> {
+    fn build_client(
+        first_rpc_node: &String,
+        options: &RpcClientOptions,
+    ) -> Result<HiveHttpClient, Error> {
         let middleware_stack = tower::ServiceBuilder::new()
+            .layer(RpcRateLimitLayer::new(first_rpc_node))
             .layer(
                 tower_http::decompression::DecompressionLayer::new()
-                    .gzip(true).deflate(true).br(true).zstd(true),
+                    .gzip(true)
+                    .deflate(true)
+                    .br(true)
+                    .zstd(true),
             )
             .layer(
                 tower_http::compression::CompressionLayer::new()
-                    .gzip(true).deflate(true).br(true).zstd(true),
-            );
+                    .gzip(true)
+                    .deflate(true)
+                    .br(true)
+                    .zstd(true),
+            )
+            .layer(HiveTransportLayer::new(
+                options.resolver.as_ref(),
+                options.tls.clone(),
+            ));
 
         HttpClient::builder()
             .max_request_size(50 * 1024 * 1024)
             .max_response_size(50 * 1024 * 1024)
-            .request_timeout(Duration::from_secs(30))
+            .request_timeout(options.request_timeout)
+            .with_custom_cert_store(options.tls.clone())
             .set_http_middleware(middleware_stack)
             .build(first_rpc_node)
     }
 
-    fn get_client(&self) -> &HttpClient<Decompression<Compression<HttpBackend>>> {
+    fn get_client(&self) -> &HiveHttpClient {
         &self.client
     }
 
@@ -67,7 +210,20 @@ impl JsonRpcClient for JsonRpcClientImpl {
 
         info!("Using next RPC Node: {}", next_node);
 
-        self.client = Self::build_client(next_node)?;
+        self.client = Self::build_client(next_node, &self.options)?;
+
+        Ok(())
+    }
+
+    /// Picks up a node list reloaded on SIGHUP and a new node ranking from a benchmark
+    fn reload_nodes(&mut self) -> Result<(), Report> {
+        if let Some(settings) = LIVE_SETTINGS.changed_since(&mut self.settings_generation) {
+            self.apply_settings(&settings)?;
+        }
+
+        if RPC_NODE_SCORES.changed_since(&mut self.scores_generation) {
+            self.apply_scores()?;
+        }
 
         Ok(())
     }
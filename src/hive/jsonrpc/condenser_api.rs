@@ -9,16 +9,30 @@
  *
  *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
  */
-use jsonrpsee::http_client::HttpClient;
+use crate::hive::jsonrpc::client::HiveHttpClient;
+use crate::hive::jsonrpc::request_params::{EmptyParams, GetAccountsParams};
+use crate::hive::jsonrpc::responses::{
+    GetAccountsResponse, GetDynamicGlobalPropertiesResponse, GetVersionResponse,
+};
 use jsonrpsee::core::client::{ClientT, Error};
-use jsonrpsee_http_client::transport::HttpBackend;
-use tower_http::compression::Compression;
-use tower_http::decompression::Decompression;
-use crate::hive::jsonrpc::request_params::EmptyParams;
-use crate::hive::jsonrpc::responses::GetDynamicGlobalPropertiesResponse;
 
 pub async fn get_dynamic_global_properties(
-    client: &HttpClient<Decompression<Compression<HttpBackend>>>
+    client: &HiveHttpClient,
 ) -> Result<GetDynamicGlobalPropertiesResponse, Error> {
-    client.request("condenser_api.get_dynamic_global_properties", EmptyParams).await
-}
\ No newline at end of file
+    client
+        .request("condenser_api.get_dynamic_global_properties", EmptyParams)
+        .await
+}
+
+pub async fn get_version(client: &HiveHttpClient) -> Result<GetVersionResponse, Error> {
+    client
+        .request("condenser_api.get_version", EmptyParams)
+        .await
+}
+
+pub async fn get_accounts(
+    client: &HiveHttpClient,
+    params: GetAccountsParams<'_>,
+) -> Result<GetAccountsResponse, Error> {
+    client.request("condenser_api.get_accounts", params).await
+}
@@ -10,7 +10,8 @@
  *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
  */
 pub mod block_api;
+pub mod client;
 pub mod condenser_api;
 pub mod request_params;
 pub mod responses;
-pub mod client;
\ No newline at end of file
+pub mod transport;
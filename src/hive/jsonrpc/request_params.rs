@@ -22,13 +22,23 @@ impl ToRpcParams for EmptyParams {
     }
 }
 
+/// condenser_api takes the account names as its one positional parameter
+#[derive(Serialize, Debug)]
+pub(crate) struct GetAccountsParams<'a>(pub(crate) [&'a [String]; 1]);
+
+impl ToRpcParams for GetAccountsParams<'_> {
+    fn to_rpc_params(self) -> Result<Option<Box<RawValue>>, serde_json::Error> {
+        Ok(Some(serde_json::value::to_raw_value(&self)?))
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub(crate) struct GetBlockParams<'a> {
-    pub(crate) block_num: &'a u64
+    pub(crate) block_num: &'a u64,
 }
 
 impl ToRpcParams for GetBlockParams<'_> {
     fn to_rpc_params(self) -> Result<Option<Box<RawValue>>, serde_json::Error> {
         Ok(Some(serde_json::value::to_raw_value(&self)?))
     }
-}
\ No newline at end of file
+}
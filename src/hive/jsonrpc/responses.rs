@@ -9,11 +9,10 @@
  *
  *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
  */
-use serde_with::DefaultOnError;
-use serde_with::serde_as;
-use serde::Deserialize;
 use chrono::{DateTime, Utc};
-use podping_schemas::org::podcastindex::podping::podping_json::Podping;
+use serde::Deserialize;
+use serde_with::serde_as;
+use serde_with::DefaultOnError;
 
 // chrono doesn't appear to support ISO8601 without timezone offsets
 // https://github.com/chronotope/chrono/issues/587
@@ -24,9 +23,7 @@ pub(crate) mod hive_datetime_format {
 
     const FORMAT: &'static str = "%Y-%m-%dT%H:%M:%S";
 
-    pub fn deserialize<'de, D>(
-        deserializer: D,
-    ) -> Result<DateTime<Utc>, D::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
     where
         D: Deserializer<'de>,
     {
@@ -36,34 +33,35 @@ pub(crate) mod hive_datetime_format {
     }
 }
 
-pub(crate) mod json_string {
-    use serde_json;
-    use serde::de::{Deserialize, DeserializeOwned, Deserializer};
-
-    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
-    where T: DeserializeOwned,
-          D: Deserializer<'de>
-    {
-        let json = String::deserialize(deserializer)?;
-        let result = serde_json::from_str(&json);
-
-        match result {
-            Ok(val) => Ok(val),
-            // TODO: This will silently ignore any Podping deserialization errors
-            // Theoretically that will exclude anything not compliant with the JSON schema
-            _ => Ok(None)
-        }
-    }
-}
-
 #[derive(Deserialize, Debug)]
 pub(crate) struct GetDynamicGlobalPropertiesResponse {
     // There are a lot more fields, but this is all we care about
     pub(crate) head_block_number: u64,
+    pub(crate) last_irreversible_block_num: u64,
     #[serde(with = "hive_datetime_format")]
     pub(crate) time: DateTime<Utc>,
 }
 
+#[derive(Deserialize, Debug)]
+pub(crate) struct GetVersionResponse {
+    pub(crate) chain_id: String,
+}
+
+pub(crate) type GetAccountsResponse = Vec<HiveAccount>;
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct HiveAccount {
+    // There are a lot more fields, but this is all we care about
+    pub(crate) name: String,
+    pub(crate) posting: HiveAuthority,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct HiveAuthority {
+    /// Other accounts that can sign with this authority, and their weights
+    pub(crate) account_auths: Vec<(String, u32)>,
+}
+
 #[derive(Deserialize, Debug)]
 pub(crate) struct GetBlockResponse {
     pub(crate) block: HiveBlock,
@@ -72,6 +70,9 @@ pub(crate) struct GetBlockResponse {
 #[derive(Deserialize, Debug)]
 pub(crate) struct HiveBlock {
     // There are a lot more fields, but this is all we care about
+    pub(crate) block_id: String,
+    pub(crate) previous: String,
+    pub(crate) witness: String,
     #[serde(with = "hive_datetime_format")]
     pub(crate) timestamp: DateTime<Utc>,
     pub(crate) transaction_ids: Vec<String>,
@@ -81,7 +82,7 @@ pub(crate) struct HiveBlock {
 #[derive(Deserialize, Debug)]
 pub(crate) struct HiveTransaction {
     // There are a lot more fields, but this is all we care about
-    pub(crate) operations: Vec<HiveOperation>
+    pub(crate) operations: Vec<HiveOperation>,
 }
 
 #[serde_as]
@@ -90,12 +91,17 @@ pub(crate) struct HiveOperation {
     #[serde(rename = "type")]
     pub(crate) type_: String,
     #[serde_as(deserialize_as = "DefaultOnError")]
-    pub(crate) value: Option<PodpingOperation>
+    pub(crate) value: Option<PodpingOperation>,
 }
 
 #[derive(Deserialize, Debug)]
 pub(crate) struct PodpingOperation {
     pub(crate) id: Option<String>,
-    #[serde(deserialize_with = "json_string::deserialize")]
-    pub(crate) json: Option<Podping>
-}
\ No newline at end of file
+    // The accounts whose keys signed it
+    #[serde(default)]
+    pub(crate) required_auths: Vec<String>,
+    #[serde(default)]
+    pub(crate) required_posting_auths: Vec<String>,
+    // Parsed once the id shows it's a podping, see parse::parse_podping
+    pub(crate) json: Option<String>,
+}
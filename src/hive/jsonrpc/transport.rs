@@ -0,0 +1,188 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::hive::dns::{HiveConnector, HiveResolver};
+use hyper::body::Incoming;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use jsonrpsee::core::http_helpers::HttpError;
+use jsonrpsee_http_client::transport::{Error, HttpBackend};
+use jsonrpsee_http_client::{CustomCertStore, HttpBody, HttpRequest, HttpResponse};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+type HiveHyperClient = Client<HttpsConnector<HiveConnector>, HttpBody>;
+
+/// Sends the RPC requests over [`HiveConnector`], or the backend jsonrpsee built without a
+/// [`HiveResolver`]
+///
+/// jsonrpsee's backend looks nodes up with getaddrinfo and has no way to swap the resolver, so
+/// with one requests go through a client on [`HiveConnector`] instead. Each layer has its own
+/// connection pool, so a client rebuilt for another node doesn't reuse old connections.
+pub(crate) struct HiveTransportLayer {
+    client: Option<HiveHyperClient>,
+}
+
+impl HiveTransportLayer {
+    pub(crate) fn new(resolver: Option<&HiveResolver>, tls: CustomCertStore) -> HiveTransportLayer {
+        let client = resolver.map(|resolver| {
+            let connector = HttpsConnectorBuilder::new()
+                .with_tls_config(tls)
+                .https_or_http()
+                .enable_all_versions()
+                .wrap_connector(HiveConnector::new(resolver));
+
+            Client::builder(TokioExecutor::new()).build(connector)
+        });
+
+        HiveTransportLayer { client }
+    }
+}
+
+impl<S> Layer<S> for HiveTransportLayer {
+    type Service = HiveTransport<S>;
+
+    fn layer(&self, backend: S) -> Self::Service {
+        HiveTransport {
+            client: self.client.clone(),
+            backend,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct HiveTransport<S = HttpBackend> {
+    client: Option<HiveHyperClient>,
+    backend: S,
+}
+
+impl<S> Service<HttpRequest> for HiveTransport<S>
+where
+    S: Service<HttpRequest, Response = HttpResponse<Incoming>, Error = Error>,
+    S::Future: Send + 'static,
+{
+    type Response = HttpResponse<Incoming>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match &self.client {
+            Some(_) => Poll::Ready(Ok(())),
+            None => self.backend.poll_ready(cx),
+        }
+    }
+
+    fn call(&mut self, request: HttpRequest) -> Self::Future {
+        let Some(client) = &self.client else {
+            return Box::pin(self.backend.call(request));
+        };
+
+        let response = client.request(request);
+
+        Box::pin(async move {
+            response
+                .await
+                .map_err(|e| Error::Http(HttpError::Stream(e.into())))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hive::tls::platform_tls_config;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tower::ServiceExt;
+
+    /// Stands in for jsonrpsee's backend, counting the requests that reach it
+    #[derive(Clone, Default)]
+    struct Backend {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Service<HttpRequest> for Backend {
+        type Response = HttpResponse<Incoming>;
+        type Error = Error;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _request: HttpRequest) -> Self::Future {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            Box::pin(async { Err(Error::Url("sent to the backend".to_string())) })
+        }
+    }
+
+    /// Answers every request with `200 OK`
+    async fn node() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}",
+                    )
+                    .await;
+            }
+        });
+
+        port
+    }
+
+    fn request(port: u16) -> HttpRequest {
+        HttpRequest::post(format!("http://localhost:{}/", port))
+            .body(HttpBody::from("{}"))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_go_through_the_hive_connector() {
+        let port = node().await;
+        let resolver = HiveResolver::new(Duration::from_secs(60)).unwrap();
+        let layer = HiveTransportLayer::new(Some(&resolver), platform_tls_config().unwrap());
+        let backend = Backend::default();
+
+        let response = layer
+            .layer(backend.clone())
+            .oneshot(request(port))
+            .await
+            .unwrap();
+
+        assert!(response.status().is_success());
+        assert_eq!(backend.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn requests_go_to_the_backend_without_a_resolver() {
+        let port = node().await;
+        let layer = HiveTransportLayer::new(None, platform_tls_config().unwrap());
+        let backend = Backend::default();
+
+        let response = layer.layer(backend.clone()).oneshot(request(port)).await;
+
+        assert!(matches!(response, Err(Error::Url(_))));
+        assert_eq!(backend.calls.load(Ordering::SeqCst), 1);
+    }
+}
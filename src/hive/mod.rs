@@ -9,5 +9,16 @@
  *
  *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
  */
+pub mod dns;
+pub mod fork;
+#[cfg(feature = "haf")]
+pub mod haf;
 pub mod jsonrpc;
-pub mod scanner;
\ No newline at end of file
+pub mod network;
+pub mod node_scores;
+pub mod podping_ids;
+pub mod posting_authority;
+pub mod rate_limit;
+pub mod scanner;
+pub mod throttle;
+pub mod tls;
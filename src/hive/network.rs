@@ -0,0 +1,114 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::{Scanner, ScannerNetwork};
+use crate::hive::jsonrpc::client::{JsonRpcClient, RpcClientOptions};
+use crate::hive::jsonrpc::condenser_api;
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+pub(crate) const MAINNET_CHAIN_ID: &str =
+    "beeab0de00000000000000000000000000000000000000000000000000000000";
+// The public Hive testnet, testnet.openhive.network
+pub(crate) const TESTNET_CHAIN_ID: &str =
+    "18dcf0a285365fc58b71f18b3d3fec954aa0c141c44e4e5cb4cf777b9eab274e";
+// The first podping on mainnet, a testnet's podpings can be anywhere
+const MAINNET_FIRST_PODPING_BLOCK: u64 = 53_691_004;
+const CHAIN_ID_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn network(scanner: &Scanner) -> ScannerNetwork {
+    scanner.network.unwrap_or(ScannerNetwork::Mainnet)
+}
+
+/// The chain the RPC nodes have to be on, `scanner.chain_id` or the network's
+pub(crate) fn chain_id(scanner: &Scanner) -> &str {
+    match (scanner.chain_id.as_deref(), network(scanner)) {
+        (Some(chain_id), _) => chain_id,
+        (None, ScannerNetwork::Mainnet) => MAINNET_CHAIN_ID,
+        (None, ScannerNetwork::Testnet) => TESTNET_CHAIN_ID,
+    }
+}
+
+fn describe(scanner: &Scanner) -> String {
+    match scanner.chain_id {
+        Some(_) => format!("chain {}", chain_id(scanner)),
+        None => format!("the {:?} chain {}", network(scanner), chain_id(scanner)),
+    }
+}
+
+/// Where `scanner.start = "beginning"` starts
+pub(crate) fn first_podping_block(scanner: &Scanner) -> u64 {
+    match (network(scanner), scanner.chain_id.as_deref()) {
+        (ScannerNetwork::Mainnet, None | Some(MAINNET_CHAIN_ID)) => MAINNET_FIRST_PODPING_BLOCK,
+        _ => 1,
+    }
+}
+
+/// Errors if any of `rpc_nodes` is on a different chain
+///
+/// A node that doesn't answer is only warned about, it's retried like any other once scanning.
+pub(crate) async fn check_rpc_nodes<J: JsonRpcClient + 'static>(
+    scanner: &Scanner,
+    options: &RpcClientOptions,
+) -> Result<(), Error> {
+    let expected_chain_id = chain_id(scanner);
+    let mut joinset = JoinSet::new();
+
+    for rpc_node in &scanner.rpc_nodes {
+        let rpc_node = rpc_node.clone();
+        let options = options.clone();
+
+        joinset.spawn(async move {
+            let version = timeout(CHAIN_ID_TIMEOUT, async {
+                let client = J::build_client(&rpc_node, &options)?;
+                condenser_api::get_version(&client).await
+            })
+            .await;
+
+            (rpc_node, version)
+        });
+    }
+
+    let mut wrong_chain = vec![];
+
+    for (rpc_node, version) in joinset.join_all().await {
+        match version {
+            Ok(Ok(version)) if version.chain_id == expected_chain_id => {}
+            Ok(Ok(version)) => wrong_chain.push(format!("{} is on {}", rpc_node, version.chain_id)),
+            Ok(Err(e)) => warn!(
+                "RPC node {} didn't answer for its chain id: {:#}",
+                rpc_node, e
+            ),
+            Err(_) => warn!(
+                "RPC node {} didn't answer for its chain id within {:?}",
+                rpc_node, CHAIN_ID_TIMEOUT
+            ),
+        }
+    }
+
+    if !wrong_chain.is_empty() {
+        return Err(eyre!(
+            "RPC nodes aren't on {}: {}",
+            describe(scanner),
+            wrong_chain.join(", ")
+        ));
+    }
+
+    if network(scanner) != ScannerNetwork::Mainnet || scanner.chain_id.is_some() {
+        info!("Scanning {}", describe(scanner));
+    }
+
+    Ok(())
+}
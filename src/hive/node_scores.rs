@@ -0,0 +1,245 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::hive::jsonrpc::client::{JsonRpcClient, RpcClientOptions};
+use crate::hive::jsonrpc::request_params::GetBlockParams;
+use crate::hive::jsonrpc::{block_api, condenser_api};
+use crate::reload::LIVE_SETTINGS;
+use crate::status::{RpcNodeScore, SYNC_STATUS};
+use chrono::Utc;
+use color_eyre::eyre::eyre;
+use color_eyre::{Report, Result};
+use jsonrpsee::core::params::BatchRequestBuilder;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, RwLock};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Instant};
+use tracing::{debug, info, warn};
+
+/// The latest benchmark of every RPC node, shared by all the scanner's clients
+pub(crate) static RPC_NODE_SCORES: LazyLock<RpcNodeScores> = LazyLock::new(RpcNodeScores::new);
+
+pub(crate) const DEFAULT_BENCHMARK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// A node that takes longer than this for the whole benchmark is unhealthy
+const BENCHMARK_TIMEOUT: Duration = Duration::from_secs(5);
+// About 30 seconds, a node further behind the highest head block than this is unhealthy
+const MAX_BLOCKS_BEHIND: u64 = 10;
+// Blocks before head fetched in one batch, like catch-up does
+const BATCH_BLOCKS: u64 = 3;
+
+/// What a node answered to a benchmark
+struct Benchmark {
+    latency: Duration,
+    head_block: u64,
+    batch_support: bool,
+}
+
+/// Node scores, numbered so each client can tell when there's a new ranking
+///
+/// Generation 0 means nothing has been benchmarked and the configured order still applies.
+pub(crate) struct RpcNodeScores {
+    generation: AtomicU64,
+    scores: RwLock<HashMap<String, RpcNodeScore>>,
+}
+
+impl RpcNodeScores {
+    fn new() -> RpcNodeScores {
+        RpcNodeScores {
+            generation: AtomicU64::new(0),
+            scores: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Whether there's a newer ranking than `seen_generation`, which is moved up to date
+    pub(crate) fn changed_since(&self, seen_generation: &mut u64) -> bool {
+        let generation = self.generation.load(Ordering::Acquire);
+
+        if generation == *seen_generation {
+            return false;
+        }
+
+        *seen_generation = generation;
+        true
+    }
+
+    /// `rpc_nodes` best first
+    ///
+    /// Healthy nodes that answer batches come first, fastest first, then healthy nodes that
+    /// don't, then nodes that haven't been benchmarked, then unhealthy ones. Ties keep the
+    /// configured order.
+    pub(crate) fn rank(&self, rpc_nodes: &[String]) -> Vec<String> {
+        let scores = self.scores.read().unwrap();
+        let mut ranked = rpc_nodes.to_vec();
+
+        ranked.sort_by_key(|node| match scores.get(node) {
+            Some(score) if score.healthy => (
+                u8::from(!score.batch_support.unwrap_or(false)),
+                score.latency_ms.unwrap_or(u64::MAX),
+            ),
+            None => (2, 0),
+            Some(_) => (3, 0),
+        });
+
+        ranked
+    }
+
+    fn publish(&self, scores: HashMap<String, RpcNodeScore>) {
+        SYNC_STATUS.record_rpc_node_scores(&scores);
+        *self.scores.write().unwrap() = scores;
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+async fn benchmark_node<J: JsonRpcClient + 'static>(
+    rpc_node: &String,
+    options: &RpcClientOptions,
+) -> Result<Benchmark, Report> {
+    let client = J::build_client(rpc_node, options)?;
+
+    let started = Instant::now();
+    let properties = condenser_api::get_dynamic_global_properties(&client).await?;
+    let latency = started.elapsed();
+
+    let block_nums = (1..=BATCH_BLOCKS)
+        .map(|behind| properties.head_block_number.saturating_sub(behind))
+        .collect::<Vec<_>>();
+    let mut batch_request_builder = BatchRequestBuilder::new();
+
+    for block_num in &block_nums {
+        block_api::build_get_block_batch_params(
+            GetBlockParams { block_num },
+            &mut batch_request_builder,
+        )?;
+    }
+
+    // A node behind a proxy that doesn't pass batches on errors or answers them partly
+    let batch_support = match block_api::get_block_batch(&client, batch_request_builder).await {
+        Ok(batch_response) => batch_response.num_successful_calls() == block_nums.len(),
+        Err(e) => {
+            debug!("RPC node {} didn't answer a batch: {:#}", rpc_node, e);
+            false
+        }
+    };
+
+    Ok(Benchmark {
+        latency,
+        head_block: properties.head_block_number,
+        batch_support,
+    })
+}
+
+/// Benchmarks every node at once and publishes their scores for the clients to rank them by
+pub(crate) async fn benchmark<J: JsonRpcClient + 'static>(
+    rpc_nodes: &[String],
+    options: &RpcClientOptions,
+) {
+    let mut joinset = JoinSet::new();
+
+    for rpc_node in rpc_nodes {
+        let rpc_node = rpc_node.clone();
+        let options = options.clone();
+
+        joinset.spawn(async move {
+            let benchmark = timeout(BENCHMARK_TIMEOUT, benchmark_node::<J>(&rpc_node, &options))
+                .await
+                .unwrap_or_else(|_| Err(eyre!("timed out after {:?}", BENCHMARK_TIMEOUT)));
+
+            (rpc_node, benchmark)
+        });
+    }
+
+    let benchmarks = joinset.join_all().await;
+
+    let best_head_block = benchmarks
+        .iter()
+        .filter_map(|(_, benchmark)| benchmark.as_ref().ok())
+        .map(|benchmark| benchmark.head_block)
+        .max();
+
+    let scores = benchmarks
+        .into_iter()
+        .map(|(rpc_node, benchmark)| {
+            let score = match benchmark {
+                Ok(benchmark) => {
+                    let blocks_behind = best_head_block
+                        .unwrap_or(benchmark.head_block)
+                        .saturating_sub(benchmark.head_block);
+
+                    RpcNodeScore {
+                        healthy: blocks_behind <= MAX_BLOCKS_BEHIND,
+                        latency_ms: Some(benchmark.latency.as_millis() as u64),
+                        head_block: Some(benchmark.head_block),
+                        blocks_behind: Some(blocks_behind),
+                        batch_support: Some(benchmark.batch_support),
+                        error: None,
+                        benchmarked_at: Utc::now(),
+                    }
+                }
+                Err(e) => {
+                    warn!("RPC node {} failed its benchmark: {:#}", rpc_node, e);
+
+                    RpcNodeScore {
+                        healthy: false,
+                        latency_ms: None,
+                        head_block: None,
+                        blocks_behind: None,
+                        batch_support: None,
+                        error: Some(format!("{:#}", e)),
+                        benchmarked_at: Utc::now(),
+                    }
+                }
+            };
+            debug!("RPC node {} scored {:?}", rpc_node, score);
+
+            (rpc_node, score)
+        })
+        .collect::<HashMap<_, _>>();
+
+    let healthy = scores.values().filter(|score| score.healthy).count();
+
+    RPC_NODE_SCORES.publish(scores);
+
+    match RPC_NODE_SCORES.rank(rpc_nodes).first() {
+        Some(best) if healthy > 0 => info!(
+            "Benchmarked {} RPC nodes, {} healthy, preferring {}",
+            rpc_nodes.len(),
+            healthy,
+            best
+        ),
+        _ => warn!(
+            "Benchmarked {} RPC nodes, none of them healthy",
+            rpc_nodes.len()
+        ),
+    }
+}
+
+/// Benchmarks the nodes every interval, forever, picking up a node list reloaded on SIGHUP
+pub(crate) async fn benchmark_periodically<J: JsonRpcClient + 'static>(
+    mut rpc_nodes: Vec<String>,
+    options: RpcClientOptions,
+    interval: Duration,
+) {
+    let mut settings_generation = 0;
+
+    loop {
+        sleep(interval).await;
+
+        if let Some(settings) = LIVE_SETTINGS.changed_since(&mut settings_generation) {
+            if !settings.scanner.rpc_nodes.is_empty() {
+                rpc_nodes = settings.scanner.rpc_nodes.clone();
+            }
+        }
+
+        benchmark::<J>(&rpc_nodes, &options).await;
+    }
+}
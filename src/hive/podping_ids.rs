@@ -0,0 +1,84 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Scanner;
+use crate::filter::pattern_regex;
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use regex::RegexSet;
+
+/// The podping ids since the Podping v1.0 schema, and `podping` before it
+pub(crate) const DEFAULT_PODPING_IDS: [&str; 2] = ["podping", "pp_*_*"];
+
+/// Which custom_json ids are read as podpings
+///
+/// An id is a podping if it matches one of `scanner.podping_ids` and none of
+/// `scanner.podping_ids_exclude`.
+#[derive(Clone, Debug)]
+pub(crate) struct PodpingIds {
+    include: RegexSet,
+    exclude: RegexSet,
+    // The globs in include as SQL LIKE patterns, None if one of them is a regex
+    like_patterns: Option<Vec<String>>,
+}
+
+/// A glob as a LIKE pattern, with LIKE's own wildcards escaped
+fn like_pattern(pattern: &str) -> Option<String> {
+    if pattern.len() > 1 && pattern.starts_with('/') && pattern.ends_with('/') {
+        return None;
+    }
+
+    Some(
+        pattern
+            .replace('\\', r"\\")
+            .replace('%', r"\%")
+            .replace('_', r"\_")
+            .replace('*', "%")
+            .replace('?', "_"),
+    )
+}
+
+fn pattern_set(setting: &str, patterns: &[String]) -> Result<RegexSet, Error> {
+    RegexSet::new(patterns.iter().map(|pattern| pattern_regex(pattern)))
+        .map_err(|e| eyre!("Invalid pattern in scanner.{}: {}", setting, e))
+}
+
+impl PodpingIds {
+    pub(crate) fn from_settings(scanner: &Scanner) -> Result<PodpingIds, Error> {
+        let include = scanner.podping_ids.clone().unwrap_or_else(|| {
+            DEFAULT_PODPING_IDS
+                .iter()
+                .map(|id| id.to_string())
+                .collect()
+        });
+        let exclude = scanner.podping_ids_exclude.clone().unwrap_or_default();
+
+        Ok(PodpingIds {
+            include: pattern_set("podping_ids", &include)?,
+            exclude: pattern_set("podping_ids_exclude", &exclude)?,
+            like_patterns: include
+                .iter()
+                .map(|pattern| like_pattern(pattern))
+                .collect(),
+        })
+    }
+
+    pub(crate) fn is_match(&self, id: &str) -> bool {
+        self.include.is_match(id) && !self.exclude.is_match(id)
+    }
+
+    /// Case insensitive LIKE patterns covering every podping id, for a database to narrow
+    /// operations down with before `is_match`
+    #[cfg_attr(not(feature = "haf"), allow(unused))]
+    pub(crate) fn like_patterns(&self) -> Option<&[String]> {
+        self.like_patterns.as_deref()
+    }
+}
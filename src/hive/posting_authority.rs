@@ -0,0 +1,219 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::hive::jsonrpc::client::{JsonRpcClient, RpcClientOptions};
+use crate::hive::jsonrpc::condenser_api;
+use crate::hive::jsonrpc::request_params::GetAccountsParams;
+use crate::status::SYNC_STATUS;
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use std::collections::HashSet;
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+pub(crate) const DEFAULT_POSTING_AUTHORITY_REFRESH_INTERVAL: Duration =
+    Duration::from_secs(60 * 60);
+
+/// The accounts podpings are accepted from, when `filter.posting_accounts` or
+/// `filter.posting_authority_account` is set
+pub(crate) static PODPING_SENDERS: LazyLock<PodpingSenders> = LazyLock::new(PodpingSenders::new);
+
+pub(crate) struct PodpingSenders {
+    // None accepts every podping, like before senders were checked
+    accounts: RwLock<Option<Arc<HashSet<String>>>>,
+}
+
+impl PodpingSenders {
+    fn new() -> PodpingSenders {
+        PodpingSenders {
+            accounts: RwLock::new(None),
+        }
+    }
+
+    fn set(&self, accounts: HashSet<String>) {
+        *self.accounts.write().unwrap() = Some(Arc::new(accounts));
+    }
+
+    /// Whether a custom_json signed by these accounts is from an allowed sender
+    ///
+    /// One allowed signer is enough. The rest are counted as `podpings_unauthorized`.
+    pub(crate) fn allows(
+        &self,
+        required_auths: &[String],
+        required_posting_auths: &[String],
+    ) -> bool {
+        let accounts = match self.accounts.read().unwrap().clone() {
+            Some(accounts) => accounts,
+            None => return true,
+        };
+
+        let allowed = required_posting_auths
+            .iter()
+            .chain(required_auths)
+            .any(|account| accounts.contains(account));
+
+        if !allowed {
+            debug!(
+                "Dropping a podping signed by {:?}, not an allowed sender",
+                required_posting_auths
+                    .iter()
+                    .chain(required_auths)
+                    .collect::<Vec<_>>()
+            );
+            SYNC_STATUS.increment_counter("podpings_unauthorized", 1);
+        }
+
+        allowed
+    }
+}
+
+/// `authority_account` and the accounts it's given its posting authority to
+///
+/// Each node is tried in turn until one answers.
+async fn delegated_accounts<J: JsonRpcClient>(
+    authority_account: &str,
+    rpc_nodes: &[String],
+    options: &RpcClientOptions,
+) -> Result<HashSet<String>, Error> {
+    let names = [authority_account.to_string()];
+    let mut last_error = eyre!("No RPC nodes configured");
+
+    for rpc_node in rpc_nodes {
+        let response = match J::build_client(rpc_node, options) {
+            Ok(client) => condenser_api::get_accounts(&client, GetAccountsParams([&names])).await,
+            Err(e) => Err(e),
+        };
+
+        match response {
+            Ok(accounts) => {
+                let account = accounts
+                    .into_iter()
+                    .find(|account| account.name == authority_account)
+                    .ok_or_else(|| eyre!("Account {} doesn't exist", authority_account))?;
+
+                return Ok(account
+                    .posting
+                    .account_auths
+                    .into_iter()
+                    .map(|(name, _weight)| name)
+                    .chain([account.name])
+                    .collect());
+            }
+            Err(e) => {
+                warn!(
+                    "RPC node {} didn't answer for the accounts of {}: {:#}",
+                    rpc_node, authority_account, e
+                );
+                last_error = e.into();
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Reads the posting authority again every interval, forever, keeping the last list on errors
+async fn refresh_periodically<J: JsonRpcClient>(
+    configured: HashSet<String>,
+    authority_account: String,
+    rpc_nodes: Vec<String>,
+    options: RpcClientOptions,
+    interval: Duration,
+) {
+    loop {
+        sleep(interval).await;
+
+        match delegated_accounts::<J>(&authority_account, &rpc_nodes, &options).await {
+            Ok(delegated) => {
+                debug!(
+                    "{} has given its posting authority to {} accounts",
+                    authority_account,
+                    delegated.len() - 1
+                );
+                PODPING_SENDERS.set(configured.union(&delegated).cloned().collect());
+            }
+            Err(e) => warn!(
+                "Error reading the posting authority of {}, keeping the current senders: {:#}",
+                authority_account, e
+            ),
+        }
+    }
+}
+
+/// Starts checking podping senders, if `filter.posting_accounts` or
+/// `filter.posting_authority_account` is set
+///
+/// Errors if the posting authority can't be read at startup.
+pub(crate) async fn start<J: JsonRpcClient + 'static>(
+    settings: &Settings,
+    options: &RpcClientOptions,
+) -> Result<(), Error> {
+    let configured = settings
+        .filter
+        .posting_accounts
+        .iter()
+        .flatten()
+        .cloned()
+        .collect::<HashSet<_>>();
+    let authority_account = settings
+        .filter
+        .posting_authority_account
+        .clone()
+        .filter(|account| !account.is_empty());
+
+    let authority_account = match authority_account {
+        Some(authority_account) => authority_account,
+        None if settings.filter.posting_accounts.is_some() => {
+            info!("Accepting podpings from {} accounts", configured.len());
+            PODPING_SENDERS.set(configured);
+            return Ok(());
+        }
+        None => return Ok(()),
+    };
+
+    let delegated =
+        delegated_accounts::<J>(&authority_account, &settings.scanner.rpc_nodes, options)
+            .await
+            .map_err(|e| {
+                eyre!(
+                    "Unable to read the posting authority of {}: {:#}",
+                    authority_account,
+                    e
+                )
+            })?;
+    let accounts = configured
+        .union(&delegated)
+        .cloned()
+        .collect::<HashSet<_>>();
+
+    info!(
+        "Accepting podpings from {} accounts with {}'s posting authority or listed",
+        accounts.len(),
+        authority_account
+    );
+    PODPING_SENDERS.set(accounts);
+
+    tokio::spawn(refresh_periodically::<J>(
+        configured,
+        authority_account,
+        settings.scanner.rpc_nodes.clone(),
+        options.clone(),
+        settings
+            .filter
+            .posting_authority_refresh_interval
+            .unwrap_or(DEFAULT_POSTING_AUTHORITY_REFRESH_INTERVAL),
+    ));
+
+    Ok(())
+}
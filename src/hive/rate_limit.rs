@@ -0,0 +1,478 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Scanner;
+use crate::status::SYNC_STATUS;
+use hyper::header::RETRY_AFTER;
+use hyper::StatusCode;
+use jsonrpsee_http_client::{HttpRequest, HttpResponse};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+use tower::{Layer, Service};
+use tracing::{info, warn};
+
+/// Request budget for every RPC node, shared by all the scanner's connections to it
+pub(crate) static RPC_RATE_LIMITS: LazyLock<RpcRateLimits> = LazyLock::new(RpcRateLimits::new);
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A rate cut by a 429 or 503 never goes below this
+const MIN_REQUESTS_PER_SECOND: f64 = 0.1;
+/// Successful requests it takes to climb back from a cut to the configured rate
+const RECOVERY_REQUESTS: f64 = 20.0;
+
+#[derive(Clone, Copy, PartialEq)]
+struct RateLimitSettings {
+    // None for no limit, the node can still pause us with a 429 or 503
+    requests_per_second: Option<f64>,
+    burst: f64,
+}
+
+/// Token bucket for one node, slowed down while it's pushing back
+struct NodeState {
+    tokens: f64,
+    last_refill: Instant,
+    // Below the configured rate after a 429 or 503, climbs back with each success
+    requests_per_second: Option<f64>,
+    paused_until: Option<Instant>,
+    backoff: Duration,
+}
+
+impl NodeState {
+    fn new(settings: RateLimitSettings) -> NodeState {
+        NodeState {
+            tokens: settings.burst,
+            last_refill: Instant::now(),
+            requests_per_second: settings.requests_per_second,
+            paused_until: None,
+            backoff: MIN_BACKOFF,
+        }
+    }
+
+    /// Takes a token, or says how long to wait for one
+    fn try_acquire(&mut self, settings: RateLimitSettings) -> Option<Duration> {
+        let now = Instant::now();
+
+        if let Some(paused_until) = self.paused_until.filter(|until| *until > now) {
+            return Some(paused_until - now);
+        }
+
+        let requests_per_second = self.requests_per_second?;
+
+        self.tokens = (self.tokens
+            + now.duration_since(self.last_refill).as_secs_f64() * requests_per_second)
+            .min(settings.burst);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+
+        Some(Duration::from_secs_f64(
+            (1.0 - self.tokens) / requests_per_second,
+        ))
+    }
+}
+
+/// Per node token buckets, see `scanner.rpc_requests_per_second`
+pub(crate) struct RpcRateLimits {
+    settings: Mutex<RateLimitSettings>,
+    nodes: Mutex<HashMap<String, NodeState>>,
+}
+
+impl RpcRateLimits {
+    fn new() -> RpcRateLimits {
+        RpcRateLimits {
+            settings: Mutex::new(RateLimitSettings {
+                requests_per_second: None,
+                burst: 1.0,
+            }),
+            nodes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Applies the scanner's limits, at startup and again when the config is reloaded
+    pub(crate) fn configure(&self, scanner: &Scanner) {
+        let requests_per_second = scanner
+            .rpc_requests_per_second
+            .filter(|requests_per_second| *requests_per_second > 0.0);
+        let settings = RateLimitSettings {
+            requests_per_second,
+            burst: scanner
+                .rpc_burst
+                .unwrap_or(requests_per_second.unwrap_or(1.0).ceil() as u32)
+                .max(1) as f64,
+        };
+
+        let mut current = self.settings.lock().unwrap();
+        if *current == settings {
+            return;
+        }
+        *current = settings;
+
+        // Start every node over on the new limits
+        self.nodes.lock().unwrap().clear();
+
+        match settings.requests_per_second {
+            Some(requests_per_second) => info!(
+                "RPC requests limited to {} per second per node, bursts of {}",
+                requests_per_second, settings.burst
+            ),
+            None => info!("RPC requests aren't rate limited"),
+        }
+    }
+
+    fn settings(&self) -> RateLimitSettings {
+        *self.settings.lock().unwrap()
+    }
+
+    /// Waits until `node` can take another request
+    async fn acquire(&self, node: &str) {
+        loop {
+            let settings = self.settings();
+            let wait = self
+                .nodes
+                .lock()
+                .unwrap()
+                .entry(node.to_string())
+                .or_insert_with(|| NodeState::new(settings))
+                .try_acquire(settings);
+
+            match wait {
+                Some(wait) => sleep(wait).await,
+                None => return,
+            }
+        }
+    }
+
+    fn record_success(&self, node: &str) {
+        let settings = self.settings();
+        let mut nodes = self.nodes.lock().unwrap();
+        let state = match nodes.get_mut(node) {
+            Some(state) => state,
+            None => return,
+        };
+
+        state.backoff = MIN_BACKOFF;
+
+        if let (Some(current), Some(configured)) =
+            (state.requests_per_second, settings.requests_per_second)
+        {
+            if current < configured {
+                state.requests_per_second =
+                    Some((current + configured / RECOVERY_REQUESTS).min(configured));
+            }
+        }
+    }
+
+    /// The node answered 429 or 503, pause it and halve its rate
+    fn record_rejected(&self, node: &str, status: StatusCode, retry_after: Option<Duration>) {
+        let settings = self.settings();
+        let mut nodes = self.nodes.lock().unwrap();
+        let state = nodes
+            .entry(node.to_string())
+            .or_insert_with(|| NodeState::new(settings));
+
+        let pause = retry_after.unwrap_or(state.backoff).min(MAX_BACKOFF);
+        state.paused_until = Some(Instant::now() + pause);
+        state.backoff = (state.backoff * 2).min(MAX_BACKOFF);
+        // Tokens only start adding up again once the pause is over
+        state.tokens = 0.0;
+        state.last_refill = Instant::now() + pause;
+        state.requests_per_second = state
+            .requests_per_second
+            .map(|requests_per_second| (requests_per_second / 2.0).max(MIN_REQUESTS_PER_SECOND));
+
+        warn!(
+            "RPC node {} answered {}, pausing it for {:?}{}",
+            node,
+            status,
+            pause,
+            match state.requests_per_second {
+                Some(requests_per_second) => format!(
+                    " and slowing to {:.1} requests per second",
+                    requests_per_second
+                ),
+                None => String::new(),
+            }
+        );
+        SYNC_STATUS.increment_counter("rpc_rate_limited", 1);
+    }
+}
+
+fn retry_after(headers: &hyper::HeaderMap) -> Option<Duration> {
+    // Only the delay in seconds form, nodes don't send the HTTP date form
+    headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Middleware holding each request to the node's rate limit
+#[derive(Clone)]
+pub(crate) struct RpcRateLimitLayer {
+    node: Arc<str>,
+}
+
+impl RpcRateLimitLayer {
+    pub(crate) fn new(node: &str) -> RpcRateLimitLayer {
+        RpcRateLimitLayer {
+            node: Arc::from(node),
+        }
+    }
+}
+
+impl<S> Layer<S> for RpcRateLimitLayer {
+    type Service = RpcRateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcRateLimit {
+            inner,
+            node: self.node.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct RpcRateLimit<S> {
+    inner: S,
+    node: Arc<str>,
+}
+
+impl<S, B> Service<HttpRequest> for RpcRateLimit<S>
+where
+    S: Service<HttpRequest, Response = HttpResponse<B>> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: HttpRequest) -> Self::Future {
+        // The clone that was polled ready makes the call, see tower's Service docs
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let node = self.node.clone();
+
+        Box::pin(async move {
+            RPC_RATE_LIMITS.acquire(&node).await;
+
+            let response = inner.call(request).await?;
+
+            match response.status() {
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => RPC_RATE_LIMITS
+                    .record_rejected(&node, response.status(), retry_after(response.headers())),
+                _ => RPC_RATE_LIMITS.record_success(&node),
+            }
+
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::HeaderMap;
+    use jsonrpsee_http_client::HttpBody;
+
+    fn limited(requests_per_second: f64, burst: f64) -> RateLimitSettings {
+        RateLimitSettings {
+            requests_per_second: Some(requests_per_second),
+            burst,
+        }
+    }
+
+    fn limits(settings: RateLimitSettings) -> RpcRateLimits {
+        let limits = RpcRateLimits::new();
+        *limits.settings.lock().unwrap() = settings;
+
+        limits
+    }
+
+    fn node_state(limits: &RpcRateLimits, node: &str) -> (Option<f64>, Option<Instant>, Duration) {
+        let nodes = limits.nodes.lock().unwrap();
+        let state = &nodes[node];
+
+        (state.requests_per_second, state.paused_until, state.backoff)
+    }
+
+    #[test]
+    fn the_bucket_holds_a_burst_and_refills_at_the_rate() {
+        let settings = limited(10.0, 2.0);
+        let mut state = NodeState::new(settings);
+
+        assert!(state.try_acquire(settings).is_none());
+        assert!(state.try_acquire(settings).is_none());
+        let wait = state.try_acquire(settings).unwrap();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100));
+
+        // A second's worth of tokens, but the bucket only holds the burst
+        state.last_refill -= Duration::from_secs(1);
+        assert!(state.try_acquire(settings).is_none());
+        assert!(state.try_acquire(settings).is_none());
+        assert!(state.try_acquire(settings).is_some());
+    }
+
+    #[test]
+    fn without_a_rate_nothing_waits() {
+        let settings = RateLimitSettings {
+            requests_per_second: None,
+            burst: 1.0,
+        };
+        let mut state = NodeState::new(settings);
+
+        for _ in 0..100 {
+            assert!(state.try_acquire(settings).is_none());
+        }
+    }
+
+    #[test]
+    fn a_rejection_pauses_the_node_and_halves_its_rate() {
+        let limits = limits(limited(10.0, 10.0));
+
+        limits.record_rejected("node", StatusCode::TOO_MANY_REQUESTS, None);
+
+        let (requests_per_second, paused_until, backoff) = node_state(&limits, "node");
+        assert_eq!(requests_per_second, Some(5.0));
+        assert!(paused_until.unwrap() > Instant::now() + MIN_BACKOFF / 2);
+        assert_eq!(backoff, MIN_BACKOFF * 2);
+
+        let wait = limits
+            .nodes
+            .lock()
+            .unwrap()
+            .get_mut("node")
+            .unwrap()
+            .try_acquire(limits.settings());
+        assert!(wait.is_some_and(|wait| wait <= MIN_BACKOFF));
+    }
+
+    #[test]
+    fn rejections_back_off_and_honor_retry_after() {
+        let limits = limits(limited(1.0, 1.0));
+
+        limits.record_rejected("node", StatusCode::SERVICE_UNAVAILABLE, None);
+        limits.record_rejected("node", StatusCode::SERVICE_UNAVAILABLE, None);
+        let (_, paused_until, backoff) = node_state(&limits, "node");
+        assert!(paused_until.unwrap() > Instant::now() + MIN_BACKOFF);
+        assert_eq!(backoff, MIN_BACKOFF * 4);
+
+        limits.record_rejected(
+            "node",
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(Duration::from_secs(3600)),
+        );
+        let (requests_per_second, paused_until, _) = node_state(&limits, "node");
+        assert!(paused_until.unwrap() <= Instant::now() + MAX_BACKOFF);
+        assert!(paused_until.unwrap() > Instant::now() + MAX_BACKOFF / 2);
+        assert_eq!(requests_per_second, Some(0.125));
+
+        limits.record_rejected("node", StatusCode::TOO_MANY_REQUESTS, None);
+        assert_eq!(node_state(&limits, "node").0, Some(MIN_REQUESTS_PER_SECOND));
+    }
+
+    #[test]
+    fn successes_climb_back_to_the_configured_rate() {
+        let limits = limits(limited(10.0, 10.0));
+        limits.record_rejected("node", StatusCode::TOO_MANY_REQUESTS, None);
+
+        limits.record_success("node");
+        let (requests_per_second, _, backoff) = node_state(&limits, "node");
+        assert_eq!(requests_per_second, Some(5.5));
+        assert_eq!(backoff, MIN_BACKOFF);
+
+        for _ in 0..RECOVERY_REQUESTS as usize {
+            limits.record_success("node");
+        }
+        assert_eq!(node_state(&limits, "node").0, Some(10.0));
+    }
+
+    #[test]
+    fn nodes_are_limited_separately() {
+        let limits = limits(limited(10.0, 10.0));
+
+        limits.record_rejected("node", StatusCode::TOO_MANY_REQUESTS, None);
+        limits.record_success("other");
+
+        assert!(node_state(&limits, "node").1.is_some());
+        assert!(!limits.nodes.lock().unwrap().contains_key("other"));
+    }
+
+    #[test]
+    fn retry_after_is_read_in_seconds() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, " 7 ".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(7)));
+
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    /// Answers every request with its status and a Retry-After of two seconds
+    #[derive(Clone)]
+    struct Node {
+        status: StatusCode,
+    }
+
+    impl Service<HttpRequest> for Node {
+        type Response = HttpResponse<HttpBody>;
+        type Error = std::convert::Infallible;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _request: HttpRequest) -> Self::Future {
+            let response = HttpResponse::builder()
+                .status(self.status)
+                .header(RETRY_AFTER, "2")
+                .body(HttpBody::empty())
+                .unwrap();
+
+            Box::pin(async move { Ok(response) })
+        }
+    }
+
+    #[tokio::test]
+    async fn the_layer_records_what_the_node_answered() {
+        let node = "http://rate-limit-layer.invalid";
+        let mut service = RpcRateLimitLayer::new(node).layer(Node {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+        });
+
+        let response = service.call(HttpRequest::default()).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let (_, paused_until, _) = node_state(&RPC_RATE_LIMITS, node);
+        assert!(paused_until.unwrap() > Instant::now() + Duration::from_secs(1));
+    }
+}
@@ -9,35 +9,197 @@
  *
  *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
  */
+use crate::config::ScannerFollow;
+use crate::event_bus::EventBus;
+use crate::hive::fork::ForkDetector;
+#[cfg(feature = "haf")]
+use crate::hive::haf::HafClient;
 use crate::hive::jsonrpc::client::JsonRpcClient;
 use crate::hive::jsonrpc::request_params::GetBlockParams;
 use crate::hive::jsonrpc::responses::{GetBlockResponse, GetDynamicGlobalPropertiesResponse};
 use crate::hive::jsonrpc::{block_api, condenser_api};
+use crate::hive::podping_ids::PodpingIds;
+use crate::hive::posting_authority::PODPING_SENDERS;
+use crate::hive::throttle::{CatchupBatchSize, CatchupMemory, CatchupThrottle, MemoryReservation};
+use crate::parse::{parse_podping_or_quarantine, MalformedPodping};
+use crate::status::SYNC_STATUS;
 use chrono::{DateTime, TimeDelta, Utc};
 use color_eyre::{Report, Result};
 use jsonrpsee::core::params::BatchRequestBuilder;
 use jsonrpsee::core::ClientError::{ParseError, RestartNeeded, Transport};
 use podping_schemas::org::podcastindex::podping::podping_json::Podping;
 use rand::Rng;
-use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::broadcast::Sender;
 use tokio::sync::Mutex;
-use tokio::time::sleep;
-use tracing::{error, trace, warn};
+use tokio::task::{self, JoinError, JoinSet};
+use tokio::time::{sleep, Instant};
+use tracing::{debug_span, error, info, instrument, trace, warn, Instrument};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct HiveBlockWithNum {
     pub(crate) block_num: u64,
+    pub(crate) block_id: String,
+    pub(crate) previous: String,
+    pub(crate) witness: String,
     pub(crate) timestamp: DateTime<Utc>,
     pub(crate) transactions: Vec<HiveTransactionWithTxId>,
+    /// Podpings that didn't parse, for the writers to quarantine
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) malformed: Vec<MalformedPodping>,
+    /// Set on a correction, the previously delivered block this one replaced in a fork
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) replaces: Option<Box<HiveBlockWithNum>>,
+}
+
+impl HiveBlockWithNum {
+    /// Roughly how much memory the block takes, for `scanner.catchup_memory_limit_mb`
+    pub(crate) fn estimated_size(&self) -> u64 {
+        let podpings = self
+            .transactions
+            .iter()
+            .map(|tx| {
+                tx.tx_id.len()
+                    + tx.podpings
+                        .iter()
+                        .map(|podping| serialized_len(podping) + size_of::<Podping>())
+                        .sum::<usize>()
+            })
+            .sum::<usize>();
+        let malformed = self
+            .malformed
+            .iter()
+            .map(|malformed| malformed.json.len() + malformed.error.len() + 128)
+            .sum::<usize>();
+        // A correction carries the block it replaced
+        let replaces = self
+            .replaces
+            .as_ref()
+            .map(|replaced| replaced.estimated_size())
+            .unwrap_or(0);
+
+        (512 + podpings + malformed) as u64 + replaces
+    }
 }
 
+/// Length of the podping as JSON, close to what its strings take in memory
+fn serialized_len(podping: &Podping) -> usize {
+    let mut counter = ByteCounter(0);
+
+    match serde_json::to_writer(&mut counter, podping) {
+        Ok(_) => counter.0,
+        Err(_) => 0,
+    }
+}
+
+struct ByteCounter(usize);
+
+impl std::io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Blocks caught up together, holding their share of `scanner.catchup_memory_limit_mb` until
+/// every writer is done with them
+///
+/// Moving `blocks` out keeps the reservation with the rest of the batch, so it's given back when
+/// the batch is dropped.
 #[derive(Debug, Clone)]
+pub(crate) struct BlockBatch {
+    pub(crate) blocks: Vec<HiveBlockWithNum>,
+    // Only held to be dropped with the last copy of the batch
+    _memory: Option<Arc<MemoryReservation>>,
+}
+
+impl BlockBatch {
+    pub(crate) fn new(blocks: Vec<HiveBlockWithNum>) -> BlockBatch {
+        BlockBatch {
+            blocks,
+            _memory: None,
+        }
+    }
+
+    fn reserved(blocks: Vec<HiveBlockWithNum>, memory: MemoryReservation) -> BlockBatch {
+        BlockBatch {
+            blocks,
+            _memory: Some(Arc::new(memory)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct HiveTransactionWithTxId {
     pub(crate) tx_id: String,
     pub(crate) podpings: Vec<Podping>,
+    /// Each podping's operation index in the transaction, in step with `podpings`
+    ///
+    /// File names, event ids and idempotency keys come from it, so they stay the same when a
+    /// stage drops the podpings before one. Blocks saved before it was recorded fall back to the
+    /// position in `podpings`.
+    #[serde(default)]
+    pub(crate) op_indices: Vec<usize>,
+}
+
+impl HiveTransactionWithTxId {
+    pub(crate) fn new(tx_id: String, op_index: usize, podping: Podping) -> HiveTransactionWithTxId {
+        HiveTransactionWithTxId {
+            tx_id,
+            podpings: vec![podping],
+            op_indices: vec![op_index],
+        }
+    }
+
+    pub(crate) fn push(&mut self, op_index: usize, podping: Podping) {
+        if self.op_indices.len() != self.podpings.len() {
+            self.op_indices = (0..self.podpings.len()).collect();
+        }
+
+        self.podpings.push(podping);
+        self.op_indices.push(op_index);
+    }
+
+    /// Each podping with its operation index
+    pub(crate) fn indexed_podpings(&self) -> impl Iterator<Item = (usize, &Podping)> {
+        let recorded = self.op_indices.len() == self.podpings.len();
+
+        self.podpings
+            .iter()
+            .enumerate()
+            .map(move |(i, podping)| (if recorded { self.op_indices[i] } else { i }, podping))
+    }
+
+    /// Takes every podping out with its operation index, for stages that put them back with
+    /// [`HiveTransactionWithTxId::push`]
+    pub(crate) fn take_podpings(&mut self) -> Vec<(usize, Podping)> {
+        let op_indices = self.indexed_podpings().map(|(i, _)| i).collect::<Vec<_>>();
+        self.op_indices.clear();
+
+        op_indices
+            .into_iter()
+            .zip(std::mem::take(&mut self.podpings))
+            .collect()
+    }
+
+    /// Keeps the podpings `f` returns `true` for, along with their operation indices
+    pub(crate) fn retain_podpings<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&Podping) -> bool,
+    {
+        for (op_index, podping) in self.take_podpings() {
+            if f(&podping) {
+                self.push(op_index, podping);
+            }
+        }
+    }
 }
 
 pub(crate) async fn get_dynamic_global_properties(
@@ -55,7 +217,10 @@ pub(crate) async fn get_dynamic_global_properties(
         );
 
         match response {
-            Ok(r) => return Ok(r),
+            Ok(r) => {
+                SYNC_STATUS.record_head_block(r.head_block_number);
+                return Ok(r);
+            }
             Err(e) => {
                 warn!("get_dynamic_global_properties error: {:#?}", e);
                 jpc.rotate_node()?;
@@ -66,139 +231,133 @@ pub(crate) async fn get_dynamic_global_properties(
     }
 }
 
+/// Newest block the scanner should process in the given follow mode
+pub(crate) fn last_followed_block(
+    dynamic_global_properties: &GetDynamicGlobalPropertiesResponse,
+    follow: ScannerFollow,
+) -> u64 {
+    match follow {
+        ScannerFollow::Head => dynamic_global_properties.head_block_number,
+        ScannerFollow::Irreversible => dynamic_global_properties.last_irreversible_block_num,
+    }
+}
+
+#[instrument(level = "debug", name = "parse_block", skip(podping_ids, response))]
 pub fn block_response_to_hive_block(
     block_num: u64,
-    id_regex: &Regex,
+    podping_ids: &PodpingIds,
     response: GetBlockResponse,
 ) -> HiveBlockWithNum {
-    HiveBlockWithNum {
-        block_num,
-        timestamp: response.block.timestamp,
-        transactions: response
-            .block
-            .transactions
-            .into_iter()
-            .enumerate()
-            .flat_map(|(i, tx)| {
-                Some(HiveTransactionWithTxId {
-                    tx_id: response.block.transaction_ids[i].to_string(),
-                    podpings: tx
-                        .operations
-                        .into_iter()
-                        .filter_map(|op| -> Option<Podping> {
-                            // I tried to move this into its own function,
-                            // but failed miserably because I needed a closure
-                            // and probably violated some lifetime thing
-                            //
-                            // Don't judge me.
-
-                            if op.type_ != "custom_json_operation" {
-                                return None;
-                            }
+    let mut malformed = vec![];
+
+    let transactions = response
+        .block
+        .transactions
+        .into_iter()
+        .enumerate()
+        .flat_map(|(i, tx)| {
+            let (op_indices, podpings): (Vec<usize>, Vec<Podping>) = tx
+                .operations
+                .into_iter()
+                .enumerate()
+                .filter_map(|(op_index, op)| -> Option<(usize, Podping)> {
+                    // I tried to move this into its own function,
+                    // but failed miserably because I needed a closure
+                    // and probably violated some lifetime thing
+                    //
+                    // Don't judge me.
+
+                    if op.type_ != "custom_json_operation" {
+                        return None;
+                    }
 
-                            match &op.value {
-                                Some(op_value) => match &op_value.id {
-                                    Some(id) => {
-                                        if id_regex.is_match(id) {
-                                            match &op.value {
-                                                Some(op_value) => match &op_value.json {
-                                                    Some(podping) => Some(podping.clone()),
-                                                    None => None,
-                                                },
-                                                None => None,
-                                            }
-                                        } else {
-                                            None
-                                        }
+                    match &op.value {
+                        Some(op_value) => match &op_value.id {
+                            Some(id) => {
+                                if podping_ids.is_match(id)
+                                    && PODPING_SENDERS.allows(
+                                        &op_value.required_auths,
+                                        &op_value.required_posting_auths,
+                                    )
+                                {
+                                    match &op.value {
+                                        Some(op_value) => match &op_value.json {
+                                            Some(json) => parse_podping_or_quarantine(
+                                                id,
+                                                json,
+                                                &response.block.transaction_ids[i],
+                                                &mut malformed,
+                                            )
+                                            .map(|podping| (op_index, podping)),
+                                            None => None,
+                                        },
+                                        None => None,
                                     }
-                                    None => None,
-                                },
-                                None => None,
+                                } else {
+                                    None
+                                }
                             }
-                        })
-                        .collect::<Vec<_>>(),
+                            None => None,
+                        },
+                        None => None,
+                    }
                 })
+                .unzip();
+
+            Some(HiveTransactionWithTxId {
+                tx_id: response.block.transaction_ids[i].to_string(),
+                podpings,
+                op_indices,
             })
-            .filter(|tx| !tx.podpings.is_empty())
-            .collect::<Vec<_>>(),
-    }
-}
+        })
+        .filter(|tx| !tx.podpings.is_empty())
+        .collect::<Vec<_>>();
 
-async fn send_block<S: Send, T: ToOwned<Owned = S>>(tx: &Sender<S>, block: T) {
-    loop {
-        match tx.send(block.to_owned()) {
-            Ok(_) => break,
-            Err(e) => {
-                if e.to_string() == "channel closed" {
-                    panic!("Scanner send error {}", e);
-                }
-                warn!("Scanner send error {}", e);
-                sleep(Duration::from_millis(10)).await;
-            }
-        }
+    HiveBlockWithNum {
+        block_num,
+        block_id: response.block.block_id,
+        previous: response.block.previous,
+        witness: response.block.witness,
+        timestamp: response.block.timestamp,
+        transactions,
+        malformed,
+        replaces: None,
     }
 }
 
-struct BlockRangeChunks {
-    start_block: u64,
-    end_block: u64,
-    batch_size: u64,
-}
-
-impl BlockRangeChunks {
-    pub fn iter(&self) -> BlockRangeChunksIterator {
-        BlockRangeChunksIterator {
-            chunks: self,
-            next_start: self.start_block,
-        }
+pub(crate) async fn send_block<T: Clone>(bus: &EventBus<T>, block: T) {
+    if let Err(e) = bus.publish(block).await {
+        panic!("Scanner send error {}", e);
     }
 }
 
-struct BlockRangeChunksIterator<'a> {
-    chunks: &'a BlockRangeChunks,
-    next_start: u64,
-}
-
-impl Iterator for BlockRangeChunksIterator<'_> {
-    type Item = Vec<u64>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.next_start > self.chunks.end_block {
-            return None;
-        }
-
-        let range_start = self.next_start;
-
-        let range_end = if range_start + self.chunks.batch_size <= self.chunks.end_block {
-            range_start + self.chunks.batch_size
-        } else {
-            self.chunks.end_block + 1
-        };
+/// Sends a catch-up batch on, returning how long it waited for the writers to take it
+async fn send_chunk(bus: &EventBus<BlockBatch>, batch: BlockBatch) -> Duration {
+    let last = batch.blocks.last().unwrap();
+    SYNC_STATUS.record_scanned_block(last.block_num, last.timestamp);
 
-        self.next_start = range_end;
-
-        Some((range_start..range_end).collect())
-    }
+    let started = Instant::now();
+    send_block(bus, batch).await;
+    started.elapsed()
 }
 
-pub async fn catchup_chain(
-    start_block: u64,
-    end_block: u64,
-    tx: Sender<Vec<HiveBlockWithNum>>,
+#[instrument(
+    level = "debug",
+    skip_all,
+    fields(first_block = chunk.first().copied(), blocks = chunk.len())
+)]
+pub(crate) async fn fetch_block_chunk(
     json_rpc_client: Arc<Mutex<impl JsonRpcClient>>,
-) -> Result<(), Report> {
+    podping_ids: PodpingIds,
+    chunk: Vec<u64>,
+) -> Result<Vec<HiveBlockWithNum>, Report> {
     let mut jpc = json_rpc_client.lock().await;
-    let mut client = jpc.get_client();
-
-    let id_regex: Regex = Regex::new(r"^pp_(.*)_(.*)|podping$")?;
 
-    let chunks = BlockRangeChunks {
-        start_block,
-        end_block,
-        batch_size: 100,
-    };
+    loop {
+        // Picks up rpc_nodes reloaded on SIGHUP
+        jpc.reload_nodes()?;
+        let client = jpc.get_client();
 
-    for chunk in chunks.iter() {
         let mut batch_request_builder = BatchRequestBuilder::new();
 
         for block_num in &chunk {
@@ -216,31 +375,26 @@ pub async fn catchup_chain(
         match batch_response {
             Ok(batch_response) => {
                 let responses_with_block_num = chunk.into_iter().zip(batch_response);
-                let blocks = responses_with_block_num
+                return Ok(responses_with_block_num
                     .map(|(block_num, entry)| {
                         let response = entry.unwrap();
-                        block_response_to_hive_block(block_num, &id_regex, response)
+                        block_response_to_hive_block(block_num, &podping_ids, response)
                     })
-                    .collect::<Vec<_>>();
-
-                send_block(&tx, blocks).await;
+                    .collect::<Vec<_>>());
             }
             Err(ParseError(e)) => {
                 warn!("Parse error; {}", e);
                 jpc.rotate_node()?;
-                client = jpc.get_client();
                 warn!("Retrying block_chunk")
             }
             Err(RestartNeeded(e)) => {
                 warn!("Restart needed error: {:#?}", e);
                 jpc.rotate_node()?;
-                client = jpc.get_client();
                 warn!("Retrying block_chunk")
             }
             Err(Transport(e)) => {
                 warn!("Transport error: {:#?}", e);
                 jpc.rotate_node()?;
-                client = jpc.get_client();
                 warn!("Retrying block_chunk")
             }
             Err(e) => {
@@ -250,21 +404,181 @@ pub async fn catchup_chain(
                 // TODO: There's probably a better way to handle it, I just haven't spent the time
                 error!("Unknown error: {:#?}", e);
                 jpc.rotate_node()?;
-                client = jpc.get_client();
             }
         };
     }
+}
+
+/// Where catch-up fetches a chunk from, one per chunk in flight
+pub(crate) enum CatchupSource<J> {
+    Rpc(Arc<Mutex<J>>),
+    #[cfg(feature = "haf")]
+    Haf(Arc<HafClient>),
+}
+
+impl<J> Clone for CatchupSource<J> {
+    fn clone(&self) -> Self {
+        match self {
+            CatchupSource::Rpc(json_rpc_client) => CatchupSource::Rpc(json_rpc_client.clone()),
+            #[cfg(feature = "haf")]
+            CatchupSource::Haf(haf_client) => CatchupSource::Haf(haf_client.clone()),
+        }
+    }
+}
+
+impl<J: JsonRpcClient> CatchupSource<J> {
+    async fn fetch_block_chunk(
+        self,
+        podping_ids: PodpingIds,
+        chunk: Vec<u64>,
+    ) -> Result<Vec<HiveBlockWithNum>, Report> {
+        match self {
+            CatchupSource::Rpc(json_rpc_client) => {
+                fetch_block_chunk(json_rpc_client, podping_ids, chunk).await
+            }
+            #[cfg(feature = "haf")]
+            CatchupSource::Haf(haf_client) => {
+                haf_client.fetch_block_chunk(&podping_ids, &chunk).await
+            }
+        }
+    }
+}
+
+/// Chunk fetches in flight, handed back in the order they were started
+///
+/// Dropping it, e.g. when catch-up returns on a failed fetch, aborts the fetches still running.
+struct OrderedFetches<T> {
+    tasks: JoinSet<T>,
+    order: VecDeque<task::Id>,
+    finished: HashMap<task::Id, T>,
+}
+
+impl<T> Default for OrderedFetches<T> {
+    fn default() -> Self {
+        OrderedFetches {
+            tasks: JoinSet::new(),
+            order: VecDeque::new(),
+            finished: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Send + 'static> OrderedFetches<T> {
+    fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    fn spawn(&mut self, fetch: impl Future<Output = T> + Send + 'static) {
+        let task = self.tasks.spawn(fetch);
+        self.order.push_back(task.id());
+    }
+
+    /// The oldest fetch's result, once it's done, `None` with nothing in flight
+    async fn next(&mut self) -> Option<Result<T, JoinError>> {
+        let id = self.order.pop_front()?;
+
+        loop {
+            if let Some(result) = self.finished.remove(&id) {
+                return Some(Ok(result));
+            }
+
+            match self.tasks.join_next_with_id().await? {
+                Ok((finished_id, result)) => {
+                    self.finished.insert(finished_id, result);
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Fetches blocks in chunks, with up to one chunk in flight per source
+///
+/// Chunks are handed round robin to `sources` but always sent on in block order. Each chunk is
+/// sized by `batch_size` from how long the previous ones took to fetch and send on, and only
+/// fetched once `memory` has room for it.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn catchup_chain<J: JsonRpcClient + Send + 'static>(
+    start_block: u64,
+    end_block: u64,
+    bus: EventBus<BlockBatch>,
+    sources: Vec<CatchupSource<J>>,
+    throttle: Arc<CatchupThrottle>,
+    mut batch_size: CatchupBatchSize,
+    memory: Arc<CatchupMemory>,
+    podping_ids: PodpingIds,
+) -> Result<(), Report> {
+    let mut in_flight = OrderedFetches::default();
+    let mut next_start = start_block;
+    let mut i = 0;
+
+    while next_start <= end_block {
+        if in_flight.len() == sources.len() {
+            let (batch, fetch_time) = in_flight.next().await.unwrap()??;
+            let send_wait = send_chunk(&bus, batch).await;
+            batch_size.record(fetch_time, send_wait);
+        }
+
+        let chunk: Vec<u64> = (next_start..=end_block)
+            .take(batch_size.current() as usize)
+            .collect();
+        next_start += chunk.len() as u64;
+
+        // Fetched chunks waiting to be sent on hold memory too, send them on rather than wait
+        // for memory they'd never give back
+        let estimate = memory.estimate(chunk.len() as u64);
+        let mut reservation = loop {
+            if let Some(reservation) = memory.try_reserve(estimate) {
+                break reservation;
+            }
+
+            match in_flight.next().await {
+                Some(fetch) => {
+                    let (batch, fetch_time) = fetch??;
+                    let send_wait = send_chunk(&bus, batch).await;
+                    batch_size.record(fetch_time, send_wait);
+                }
+                None => break memory.reserve(estimate).await,
+            }
+        };
+
+        throttle.acquire(chunk.len() as u64).await;
+
+        let source = sources[i % sources.len()].clone();
+        let podping_ids = podping_ids.clone();
+        i += 1;
+
+        in_flight.spawn(async move {
+            let started = Instant::now();
+            let blocks = source.fetch_block_chunk(podping_ids, chunk).await?;
+            let fetch_time = started.elapsed();
+
+            reservation.settle(
+                blocks.len() as u64,
+                blocks.iter().map(HiveBlockWithNum::estimated_size).sum(),
+            );
+
+            Ok::<_, Report>((BlockBatch::reserved(blocks, reservation), fetch_time))
+        });
+    }
+
+    while let Some(fetch) = in_flight.next().await {
+        let (batch, _) = fetch??;
+        send_chunk(&bus, batch).await;
+    }
 
     Ok(())
 }
 
 pub async fn scan_chain(
     start_block: u64,
-    tx: Sender<HiveBlockWithNum>,
+    end_block: Option<u64>,
+    follow: ScannerFollow,
+    bus: EventBus<HiveBlockWithNum>,
     json_rpc_client: Arc<Mutex<impl JsonRpcClient>>,
+    podping_ids: PodpingIds,
 ) -> Result<(), Report> {
     let mut jpc = json_rpc_client.lock().await;
-    let mut client = jpc.get_client();
     let mut retry_num: u32 = 0;
     let retry_wait_millis: u64 = 10;
     let max_retry_wait_millis: u64 = 300000;
@@ -272,25 +586,79 @@ pub async fn scan_chain(
     let mut block_num = start_block;
 
     let block_duration = TimeDelta::seconds(3);
-    let id_regex: Regex = Regex::new(r"^pp_(.*)_(.*)|podping$")?;
+
+    let mut last_irreversible_block: u64 = 0;
+    // Irreversible blocks can't be replaced, so only head mode needs to watch for forks
+    let mut fork_detector = match follow {
+        ScannerFollow::Head => Some(ForkDetector::new()),
+        ScannerFollow::Irreversible => None,
+    };
 
     loop {
+        // Picks up rpc_nodes reloaded on SIGHUP
+        jpc.reload_nodes()?;
+        let client = jpc.get_client();
+
+        if end_block.is_some_and(|end_block| block_num > end_block) {
+            info!("Reached end block {}", block_num - 1);
+            return Ok(());
+        }
+
+        // Wait for the block to become irreversible, roughly 45 seconds behind head
+        if follow == ScannerFollow::Irreversible && block_num > last_irreversible_block {
+            match condenser_api::get_dynamic_global_properties(&client).await {
+                Ok(dynamic_global_properties) => {
+                    SYNC_STATUS.record_head_block(dynamic_global_properties.head_block_number);
+                    last_irreversible_block =
+                        last_followed_block(&dynamic_global_properties, ScannerFollow::Irreversible)
+                }
+                Err(e) => warn!("get_dynamic_global_properties error: {:#?}", e),
+            }
+
+            if block_num > last_irreversible_block {
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        }
+
         let start_time = Utc::now();
 
         let params = GetBlockParams {
             block_num: &block_num,
         };
 
-        let response: Result<GetBlockResponse, _> = block_api::get_block(&client, params).await;
+        let response: Result<GetBlockResponse, _> = block_api::get_block(&client, params)
+            .instrument(debug_span!("fetch_block", block_num))
+            .await;
         trace!("block_api::get_block response: {:?}", response);
 
         match response {
             Ok(response) => {
-                let block = block_response_to_hive_block(block_num, &id_regex, response);
+                let block = block_response_to_hive_block(block_num, &podping_ids, response);
+
+                if let Some(fork_detector) = fork_detector.as_mut() {
+                    if fork_detector.is_fork(&block) {
+                        match fork_detector.resolve(client, &podping_ids).await {
+                            Ok(corrections) => {
+                                for correction in corrections {
+                                    send_block(&bus, correction).await;
+                                }
+                            }
+                            Err(e) => {
+                                // The block is fetched again so the fork is checked again
+                                warn!("Error resolving fork at block {}: {:#?}", block_num, e);
+                                continue;
+                            }
+                        }
+                    }
+
+                    fork_detector.record(&block);
+                }
 
                 let block_timestamp = block.timestamp.clone();
 
-                send_block(&tx, block).await;
+                SYNC_STATUS.record_scanned_block(block_num, block_timestamp);
+                send_block(&bus, block).await;
 
                 block_num += 1;
 
@@ -311,7 +679,6 @@ pub async fn scan_chain(
             Err(ParseError(e)) => {
                 warn!("Parse error {}", e);
                 jpc.rotate_node()?;
-                client = jpc.get_client();
                 // This is usually because the requested block doesn't exist yet, so sleep a little
                 sleep(Duration::from_millis(500)).await;
                 warn!("Retrying block {}", block_num)
@@ -319,7 +686,6 @@ pub async fn scan_chain(
             Err(RestartNeeded(e)) => {
                 warn!("{:#?}", e);
                 jpc.rotate_node()?;
-                client = jpc.get_client();
                 warn!("Retrying block {}", block_num)
             }
             Err(Transport(e)) => {
@@ -342,7 +708,6 @@ pub async fn scan_chain(
                 // TODO: There's probably a better way to handle it, I just haven't spent the time
                 error!("{:#?}", e);
                 jpc.rotate_node()?;
-                client = jpc.get_client();
             }
         };
     }
@@ -0,0 +1,260 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::status::SYNC_STATUS;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, info};
+
+/// Caps how fast catch-up pulls blocks from the RPC nodes
+///
+/// The limit is shared by every catch-up client and can be changed while running, 0 means unlimited.
+pub(crate) struct CatchupThrottle {
+    max_blocks_per_second: AtomicU64,
+    next_allowed: Mutex<Instant>,
+}
+
+impl CatchupThrottle {
+    pub(crate) fn new(max_blocks_per_second: Option<u64>) -> CatchupThrottle {
+        CatchupThrottle {
+            max_blocks_per_second: AtomicU64::new(max_blocks_per_second.unwrap_or(0)),
+            next_allowed: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub(crate) fn max_blocks_per_second(&self) -> Option<u64> {
+        match self.max_blocks_per_second.load(Ordering::Relaxed) {
+            0 => None,
+            max_blocks_per_second => Some(max_blocks_per_second),
+        }
+    }
+
+    #[allow(unused)]
+    pub(crate) fn set_max_blocks_per_second(&self, max_blocks_per_second: Option<u64>) {
+        self.max_blocks_per_second
+            .store(max_blocks_per_second.unwrap_or(0), Ordering::Relaxed);
+
+        match max_blocks_per_second {
+            Some(max_blocks_per_second) => info!(
+                "Catch-up throttled to {} blocks per second",
+                max_blocks_per_second
+            ),
+            None => info!("Catch-up throttle removed"),
+        }
+    }
+
+    /// Waits until `blocks` more blocks can be fetched without going over the limit
+    pub(crate) async fn acquire(&self, blocks: u64) {
+        let max_blocks_per_second = match self.max_blocks_per_second() {
+            Some(max_blocks_per_second) => max_blocks_per_second,
+            None => return,
+        };
+
+        let mut next_allowed = self.next_allowed.lock().await;
+        let now = Instant::now();
+
+        if *next_allowed > now {
+            sleep_until(*next_allowed).await;
+        }
+
+        let spacing = Duration::from_secs_f64(blocks as f64 / max_blocks_per_second as f64);
+        *next_allowed = (*next_allowed).max(now) + spacing;
+    }
+}
+
+/// Batches never shrink below this many blocks
+const MIN_BATCH_SIZE: u64 = 10;
+/// A batch fetched faster than this leaves room to grow
+const FAST_FETCH: Duration = Duration::from_secs(2);
+/// A batch slower than this is straining the node, and closing in on the 30s request timeout
+const SLOW_FETCH: Duration = Duration::from_secs(10);
+
+/// Sizes catch-up batches from how the last ones went
+///
+/// Grows while the node answers quickly and the writers take every batch straight away,
+/// shrinks when the node slows down or the writers fall behind.
+#[derive(Clone)]
+pub(crate) struct CatchupBatchSize {
+    current: u64,
+    max: u64,
+    adaptive: bool,
+}
+
+impl CatchupBatchSize {
+    pub(crate) fn new(
+        batch_size: Option<u64>,
+        max_batch_size: Option<u64>,
+        adaptive: Option<bool>,
+    ) -> CatchupBatchSize {
+        let max = max_batch_size.unwrap_or(250).max(MIN_BATCH_SIZE);
+
+        CatchupBatchSize {
+            current: batch_size.unwrap_or(100).clamp(MIN_BATCH_SIZE, max),
+            max,
+            adaptive: adaptive.unwrap_or(true),
+        }
+    }
+
+    pub(crate) fn current(&self) -> u64 {
+        self.current
+    }
+
+    /// Adjusts the size after a batch that took `fetch_time` to fetch and waited `send_wait`
+    /// for the writers to take it
+    pub(crate) fn record(&mut self, fetch_time: Duration, send_wait: Duration) {
+        if !self.adaptive {
+            return;
+        }
+
+        let previous = self.current;
+
+        self.current = if send_wait > fetch_time {
+            // The writers are the bottleneck, fetching more at once only queues it up
+            previous - previous / 4
+        } else if fetch_time > SLOW_FETCH {
+            previous / 2
+        } else if fetch_time < FAST_FETCH && send_wait < fetch_time / 10 {
+            previous + (previous / 8).max(1)
+        } else {
+            previous
+        }
+        .clamp(MIN_BATCH_SIZE, self.max);
+
+        if self.current != previous {
+            debug!(
+                "Catch-up batch size {} -> {} (fetch took {:?}, writers took {:?})",
+                previous, self.current, fetch_time, send_wait
+            );
+        }
+    }
+}
+
+pub(crate) const DEFAULT_CATCHUP_MEMORY_LIMIT_MB: u64 = 256;
+/// What a block is guessed to take until some have been fetched, a few podpings' worth
+const INITIAL_BYTES_PER_BLOCK: u64 = 2048;
+
+/// Bounds the fetched catch-up blocks held in memory, see `scanner.catchup_memory_limit_mb`
+///
+/// Each batch reserves its estimated size before it's fetched and gives it back once every
+/// writer is done with it, so fetching waits while the writers work through what's already in
+/// memory. A batch is counted once, however many writers get a copy.
+#[derive(Debug)]
+pub(crate) struct CatchupMemory {
+    limit: u64,
+    used: std::sync::Mutex<u64>,
+    freed: Notify,
+    // Running totals behind the estimate for the next batch
+    fetched_bytes: AtomicU64,
+    fetched_blocks: AtomicU64,
+}
+
+impl CatchupMemory {
+    pub(crate) fn new(limit_mb: Option<u64>) -> Arc<CatchupMemory> {
+        Arc::new(CatchupMemory {
+            limit: limit_mb.unwrap_or(DEFAULT_CATCHUP_MEMORY_LIMIT_MB) * 1024 * 1024,
+            used: std::sync::Mutex::new(0),
+            freed: Notify::new(),
+            fetched_bytes: AtomicU64::new(0),
+            fetched_blocks: AtomicU64::new(0),
+        })
+    }
+
+    /// The expected size of `blocks` blocks, from the average of those fetched so far
+    pub(crate) fn estimate(&self, blocks: u64) -> u64 {
+        let fetched_blocks = self.fetched_blocks.load(Ordering::Relaxed);
+
+        let bytes_per_block = match fetched_blocks {
+            0 => INITIAL_BYTES_PER_BLOCK,
+            _ => self.fetched_bytes.load(Ordering::Relaxed) / fetched_blocks,
+        };
+
+        blocks * bytes_per_block.max(1)
+    }
+
+    /// Reserves `bytes` if there's room for them now
+    ///
+    /// A batch larger than the whole limit only needs everything else to have been freed.
+    pub(crate) fn try_reserve(self: &Arc<Self>, bytes: u64) -> Option<MemoryReservation> {
+        let mut used = self.used.lock().unwrap();
+
+        if *used > 0 && *used + bytes > self.limit {
+            return None;
+        }
+
+        *used += bytes;
+
+        Some(MemoryReservation {
+            memory: self.clone(),
+            bytes,
+        })
+    }
+
+    /// Waits until there's room for `bytes` and reserves them
+    pub(crate) async fn reserve(self: &Arc<Self>, bytes: u64) -> MemoryReservation {
+        loop {
+            // Registered before checking, so a batch freed in between still wakes it
+            let freed = self.freed.notified();
+
+            if let Some(reservation) = self.try_reserve(bytes) {
+                return reservation;
+            }
+
+            debug!(
+                "Catch-up waiting for the writers, {} MB of blocks in memory",
+                *self.used.lock().unwrap() / 1024 / 1024
+            );
+            SYNC_STATUS.increment_counter("catchup_memory_waits", 1);
+            freed.await;
+        }
+    }
+
+    fn adjust(&self, from: u64, to: u64) {
+        let mut used = self.used.lock().unwrap();
+        *used = (*used + to).saturating_sub(from);
+        drop(used);
+
+        if to < from {
+            self.freed.notify_waiters();
+        }
+    }
+}
+
+/// A batch's share of the catch-up memory, given back when it's dropped
+#[derive(Debug)]
+pub(crate) struct MemoryReservation {
+    memory: Arc<CatchupMemory>,
+    bytes: u64,
+}
+
+impl MemoryReservation {
+    /// Swaps the estimate for what the fetched blocks actually take, without waiting
+    ///
+    /// The next batch waits if this goes over the limit.
+    pub(crate) fn settle(&mut self, blocks: u64, bytes: u64) {
+        self.memory
+            .fetched_blocks
+            .fetch_add(blocks, Ordering::Relaxed);
+        self.memory
+            .fetched_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+        self.memory.adjust(self.bytes, bytes);
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.memory.adjust(self.bytes, 0);
+    }
+}
@@ -0,0 +1,88 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Scanner;
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use jsonrpsee_http_client::CustomCertStore;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ClientConfig;
+use rustls_platform_verifier::Verifier;
+use std::sync::Arc;
+use tracing::info;
+
+fn read_certificates(file: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
+    CertificateDer::pem_file_iter(file)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| eyre!("Unable to read certificates from {}: {}", file, e))
+}
+
+/// TLS for nodes behind mutual TLS or signed by a private CA
+///
+/// `None` when nothing's set, leaving [`platform_tls_config`]. The private CA is trusted on top
+/// of the platform's trusted roots.
+pub(crate) fn rpc_tls_config(scanner: &Scanner) -> Result<Option<CustomCertStore>, Error> {
+    let non_empty = |file: &Option<String>| file.clone().filter(|f| !f.is_empty());
+    let cert_file = non_empty(&scanner.tls_client_cert);
+    let key_file = non_empty(&scanner.tls_client_key);
+    let ca_file = non_empty(&scanner.tls_ca_cert);
+
+    if cert_file.is_none() && key_file.is_none() && ca_file.is_none() {
+        return Ok(None);
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let verifier = match &ca_file {
+        Some(ca_file) => {
+            info!("Trusting the CA in {} for RPC nodes", ca_file);
+            Verifier::new_with_extra_roots(read_certificates(ca_file)?)?
+        }
+        None => Verifier::new(),
+    }
+    .with_provider(provider.clone());
+
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+
+    let config = match (cert_file, key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            let key = PrivateKeyDer::from_pem_file(&key_file)
+                .map_err(|e| eyre!("Unable to read private key from {}: {}", key_file, e))?;
+
+            info!("RPC nodes using TLS client certificate {}", cert_file);
+            builder.with_client_auth_cert(read_certificates(&cert_file)?, key)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(eyre!(
+                "scanner tls_client_cert and tls_client_key must be set together!"
+            ))
+        }
+    };
+
+    Ok(Some(config))
+}
+
+/// TLS checked against the platform's trusted roots
+pub(crate) fn platform_tls_config() -> Result<ClientConfig, Error> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = Verifier::new().with_provider(provider.clone());
+
+    Ok(ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
@@ -0,0 +1,72 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Http;
+#[cfg(any(feature = "object-storage", feature = "forwarder"))]
+use crate::config::HttpVersion;
+#[cfg(any(feature = "object-storage", feature = "forwarder"))]
+use color_eyre::eyre::{eyre, Error};
+#[cfg(any(feature = "object-storage", feature = "forwarder"))]
+use color_eyre::Result;
+#[cfg(any(feature = "object-storage", feature = "forwarder"))]
+use reqwest::{ClientBuilder, NoProxy, Proxy};
+use std::time::Duration;
+
+#[cfg(any(feature = "object-storage", feature = "forwarder"))]
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl Http {
+    #[cfg(any(feature = "object-storage", feature = "forwarder"))]
+    pub(crate) fn connect_timeout(&self) -> Duration {
+        self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT)
+    }
+
+    pub(crate) fn timeout(&self) -> Duration {
+        self.timeout.unwrap_or(DEFAULT_TIMEOUT)
+    }
+
+    /// The configured proxy, None to leave it to HTTP_PROXY and friends
+    pub(crate) fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref().filter(|proxy| !proxy.is_empty())
+    }
+}
+
+/// A reqwest client builder with the `[http]` settings applied
+#[cfg(any(feature = "object-storage", feature = "forwarder"))]
+pub(crate) fn client_builder(http: &Http) -> Result<ClientBuilder, Error> {
+    let mut builder = ClientBuilder::new()
+        .connect_timeout(http.connect_timeout())
+        .timeout(http.timeout());
+
+    if let Some(max_idle) = http.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(idle_timeout) = http.pool_idle_timeout {
+        builder = builder.pool_idle_timeout(idle_timeout);
+    }
+
+    builder = match http.version.unwrap_or(HttpVersion::Auto) {
+        HttpVersion::Auto => builder,
+        HttpVersion::Http1 => builder.http1_only(),
+        HttpVersion::Http2 => builder.http2_prior_knowledge(),
+    };
+
+    if let Some(proxy) = http.proxy() {
+        let proxy = Proxy::all(proxy)
+            .map_err(|e| eyre!("Error parsing http.proxy {}: {}", proxy, e))?
+            .no_proxy(http.no_proxy.as_deref().and_then(NoProxy::from_string));
+
+        builder = builder.proxy(proxy);
+    }
+
+    Ok(builder)
+}
@@ -0,0 +1,341 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::{InvalidIriPolicy, Iri};
+use crate::hive::scanner::HiveBlockWithNum;
+use crate::podping::{map_podping_iris, podping_iris};
+use crate::stage::BlockStage;
+use crate::status::SYNC_STATUS;
+use std::collections::HashMap;
+use thiserror::Error;
+use tracing::warn;
+use url::Url;
+
+pub(crate) const DEFAULT_IRI_MAX_LENGTH: usize = 2048;
+pub(crate) const DEFAULT_ALLOWED_SCHEMES: [&str; 4] = ["http", "https", "ipfs", "ipns"];
+
+#[derive(Debug, Error)]
+pub(crate) enum IriError {
+    #[error("longer than {0} characters")]
+    TooLong(usize),
+    #[error("{0}")]
+    Parse(#[from] url::ParseError),
+    #[error("scheme {0} is not allowed")]
+    Scheme(String),
+}
+
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+/// Decodes percent-encoded unreserved characters and uppercases the rest (RFC 3986 6.2.2)
+fn normalize_percent_encoding(iri: &str) -> String {
+    let bytes = iri.as_bytes();
+    let mut normalized = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && i + 2 < bytes.len()
+            && bytes[i + 1].is_ascii_hexdigit()
+            && bytes[i + 2].is_ascii_hexdigit()
+        {
+            let hex = &iri[i + 1..i + 3];
+            let decoded = u8::from_str_radix(hex, 16).unwrap();
+
+            match is_unreserved(decoded) {
+                true => normalized.push(decoded),
+                false => {
+                    normalized.push(b'%');
+                    normalized.extend(hex.to_ascii_uppercase().bytes());
+                }
+            }
+
+            i += 3;
+        } else {
+            normalized.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(normalized).unwrap_or(iri.to_string())
+}
+
+/// Checks the IRI and returns its normalized form
+///
+/// Parsing lowercases the scheme and host, punycodes international hosts (rejecting invalid
+/// punycode) and drops default ports.
+pub(crate) fn normalize_iri(
+    iri: &str,
+    allowed_schemes: &[String],
+    max_length: usize,
+) -> Result<String, IriError> {
+    if iri.chars().count() > max_length {
+        return Err(IriError::TooLong(max_length));
+    }
+
+    let url = Url::parse(iri)?;
+
+    if !allowed_schemes.iter().any(|scheme| scheme == url.scheme()) {
+        return Err(IriError::Scheme(url.scheme().to_string()));
+    }
+
+    let normalized = normalize_percent_encoding(url.as_str());
+
+    // Punycode can make a host longer
+    if normalized.len() > max_length {
+        return Err(IriError::TooLong(max_length));
+    }
+
+    Ok(normalized)
+}
+
+/// The normalized IRI for comparing against other IRIs, or the IRI as is if it doesn't parse
+pub(crate) fn canonical_iri(iri: &str) -> String {
+    match Url::parse(iri.trim()) {
+        Ok(url) => normalize_percent_encoding(url.as_str()),
+        Err(_) => iri.trim().to_string(),
+    }
+}
+
+/// Validates and optionally normalizes every podping IRI
+pub(crate) struct IriStage {
+    normalize: bool,
+    invalid_policy: InvalidIriPolicy,
+    allowed_schemes: Vec<String>,
+    max_length: usize,
+}
+
+impl IriStage {
+    pub(crate) fn new(settings: &Iri) -> IriStage {
+        IriStage {
+            normalize: settings.normalize,
+            invalid_policy: settings.invalid_policy.unwrap_or(InvalidIriPolicy::Flag),
+            allowed_schemes: settings.allowed_schemes.clone().unwrap_or(
+                DEFAULT_ALLOWED_SCHEMES
+                    .iter()
+                    .map(|scheme| scheme.to_string())
+                    .collect(),
+            ),
+            max_length: settings.max_length.unwrap_or(DEFAULT_IRI_MAX_LENGTH),
+        }
+    }
+}
+
+impl BlockStage for IriStage {
+    fn process_block(&mut self, mut block: HiveBlockWithNum) -> HiveBlockWithNum {
+        let mut normalized: u64 = 0;
+        let mut invalid: u64 = 0;
+        let mut dropped: u64 = 0;
+
+        for tx in &mut block.transactions {
+            for (op_index, podping) in tx.take_podpings() {
+                // Replacements for the IRIs that change, None removes one
+                let mut replacements: HashMap<String, Option<String>> = HashMap::new();
+
+                for iri in podping_iris(&podping) {
+                    match normalize_iri(&iri, &self.allowed_schemes, self.max_length) {
+                        Ok(normalized_iri) => {
+                            if self.normalize && normalized_iri != iri {
+                                normalized += 1;
+                                replacements.insert(iri, Some(normalized_iri));
+                            }
+                        }
+                        Err(e) => {
+                            invalid += 1;
+                            warn!(
+                                "Invalid IRI in block {} tx {}: {}: {}",
+                                block.block_num, tx.tx_id, iri, e
+                            );
+
+                            if self.invalid_policy == InvalidIriPolicy::Drop {
+                                replacements.insert(iri, None);
+                            }
+                        }
+                    }
+                }
+
+                if replacements.is_empty() {
+                    tx.push(op_index, podping);
+                    continue;
+                }
+
+                let rewritten = map_podping_iris(&podping, |iri| match replacements.get(iri) {
+                    Some(replacement) => replacement.clone(),
+                    None => Some(iri.to_string()),
+                });
+
+                match rewritten {
+                    Some(rewritten) if podping_iris(&rewritten).is_empty() => dropped += 1,
+                    Some(rewritten) => tx.push(op_index, rewritten),
+                    None => {
+                        warn!(
+                            "Unable to rewrite IRIs in block {} tx {}, keeping the podping as is",
+                            block.block_num, tx.tx_id
+                        );
+                        tx.push(op_index, podping);
+                    }
+                }
+            }
+        }
+
+        block.transactions.retain(|tx| !tx.podpings.is_empty());
+
+        if normalized > 0 {
+            SYNC_STATUS.increment_counter("iris_normalized", normalized);
+        }
+        if invalid > 0 {
+            SYNC_STATUS.increment_counter("iris_invalid", invalid);
+        }
+        if dropped > 0 {
+            SYNC_STATUS.increment_counter("podpings_dropped_invalid_iris", dropped);
+        }
+
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hive::scanner::HiveTransactionWithTxId;
+    use chrono::Utc;
+
+    fn schemes() -> Vec<String> {
+        DEFAULT_ALLOWED_SCHEMES
+            .iter()
+            .map(|scheme| scheme.to_string())
+            .collect()
+    }
+
+    fn normalize(iri: &str) -> Result<String, IriError> {
+        normalize_iri(iri, &schemes(), DEFAULT_IRI_MAX_LENGTH)
+    }
+
+    fn block(iris: &[&str]) -> HiveBlockWithNum {
+        HiveBlockWithNum {
+            block_num: 1,
+            block_id: "00000001".to_string(),
+            previous: "00000000".to_string(),
+            witness: "w".to_string(),
+            timestamp: Utc::now(),
+            transactions: vec![HiveTransactionWithTxId::new(
+                "tx".to_string(),
+                0,
+                serde_json::from_value(serde_json::json!({
+                    "version": "1.0",
+                    "medium": "podcast",
+                    "reason": "update",
+                    "iris": iris,
+                }))
+                .unwrap(),
+            )],
+            malformed: vec![],
+            replaces: None,
+        }
+    }
+
+    fn stage(normalize: bool, invalid_policy: InvalidIriPolicy) -> IriStage {
+        IriStage::new(&Iri {
+            validate: true,
+            normalize,
+            invalid_policy: Some(invalid_policy),
+            allowed_schemes: None,
+            max_length: None,
+        })
+    }
+
+    fn block_iris(block: &HiveBlockWithNum) -> Vec<String> {
+        block
+            .transactions
+            .iter()
+            .flat_map(|tx| tx.podpings.iter().flat_map(podping_iris))
+            .collect()
+    }
+
+    #[test]
+    fn scheme_host_and_default_port_are_normalized() {
+        assert_eq!(
+            normalize("HTTPS://Example.COM:443/Feed.xml").unwrap(),
+            "https://example.com/Feed.xml"
+        );
+        assert_eq!(
+            normalize("http://example.com:8080/feed").unwrap(),
+            "http://example.com:8080/feed"
+        );
+    }
+
+    #[test]
+    fn percent_encoding_is_normalized() {
+        assert_eq!(
+            normalize("https://example.com/%7Euser/a%2fb%41").unwrap(),
+            "https://example.com/~user/a%2FbA"
+        );
+    }
+
+    #[test]
+    fn international_hosts_are_punycoded() {
+        assert_eq!(
+            normalize("https://bücher.example/feed").unwrap(),
+            "https://xn--bcher-kva.example/feed"
+        );
+    }
+
+    #[test]
+    fn invalid_iris_are_rejected() {
+        assert!(matches!(
+            normalize("ftp://example.com/feed"),
+            Err(IriError::Scheme(scheme)) if scheme == "ftp"
+        ));
+        assert!(matches!(normalize("not an iri"), Err(IriError::Parse(_))));
+        assert!(matches!(
+            normalize_iri("https://example.com/feed", &schemes(), 10),
+            Err(IriError::TooLong(10))
+        ));
+    }
+
+    #[test]
+    fn canonical_iris_fall_back_to_the_trimmed_iri() {
+        assert_eq!(
+            canonical_iri(" HTTPS://Example.com/feed "),
+            "https://example.com/feed"
+        );
+        assert_eq!(canonical_iri(" not an iri "), "not an iri");
+    }
+
+    #[test]
+    fn the_stage_normalizes_when_asked() {
+        let iris = ["HTTPS://Example.com/feed", "https://example.com/other"];
+
+        let kept = stage(false, InvalidIriPolicy::Flag).process_block(block(&iris));
+        assert_eq!(block_iris(&kept), iris);
+
+        let normalized = stage(true, InvalidIriPolicy::Flag).process_block(block(&iris));
+        assert_eq!(
+            block_iris(&normalized),
+            ["https://example.com/feed", "https://example.com/other"]
+        );
+    }
+
+    #[test]
+    fn the_stage_drops_invalid_iris_only_when_asked() {
+        let iris = ["ftp://example.com/feed", "https://example.com/feed"];
+
+        let flagged = stage(false, InvalidIriPolicy::Flag).process_block(block(&iris));
+        assert_eq!(block_iris(&flagged), iris);
+
+        let dropped = stage(false, InvalidIriPolicy::Drop).process_block(block(&iris));
+        assert_eq!(block_iris(&dropped), ["https://example.com/feed"]);
+
+        let emptied = stage(false, InvalidIriPolicy::Drop).process_block(block(&["ftp://a/b"]));
+        assert!(emptied.transactions.is_empty());
+    }
+}
@@ -0,0 +1,511 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+mod api;
+mod cli;
+mod config;
+mod config_check;
+#[cfg(any(feature = "disk", feature = "object-storage", feature = "elasticsearch"))]
+mod dead_letter;
+#[cfg(feature = "forwarder")]
+mod dedupe;
+mod event_bus;
+mod filter;
+mod fixtures;
+#[cfg(feature = "forwarder")]
+mod forwarder;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod hive;
+mod http_client;
+mod iri;
+pub mod parse;
+mod podping;
+mod reload;
+mod replay;
+mod routing;
+mod secret_provider;
+mod secrets;
+#[cfg(feature = "service")]
+mod service;
+mod shutdown;
+mod source;
+mod stage;
+mod status;
+mod syncer;
+mod telemetry;
+mod template;
+mod verify;
+mod watchlist;
+mod writer;
+
+#[cfg(any(feature = "disk", feature = "object-storage", feature = "elasticsearch"))]
+use crate::cli::DlqAction;
+use crate::cli::{Cli, Command, ConfigAction};
+use crate::config::{
+    ConsoleFormat, LogFormat, Settings, SourceType, WriterFailurePolicy, WriterType,
+    CARGO_PKG_VERSION,
+};
+use crate::hive::jsonrpc::client::JsonRpcClientImpl;
+use crate::hive::rate_limit::RPC_RATE_LIMITS;
+use crate::shutdown::{ShutdownReason, ShutdownReport};
+use crate::source::fixture_source::FixtureSource;
+use crate::source::hive_source::HiveSource;
+use crate::source::replay_source::ArchiveSource;
+use crate::source::simulator_source::SimulatorSource;
+#[cfg(feature = "websocket")]
+use crate::source::websocket_source::WebsocketSource;
+use crate::syncer::Syncer;
+use crate::writer::console_writer::ConsoleWriter;
+#[cfg(feature = "disk")]
+use crate::writer::disk_writer::DiskWriter;
+#[cfg(feature = "elasticsearch")]
+use crate::writer::elasticsearch_writer::ElasticsearchWriter;
+#[cfg(feature = "object-storage")]
+use crate::writer::object_storage_writer::ObjectStorageWriter;
+#[cfg(feature = "parquet")]
+use crate::writer::parquet_writer::ParquetWriter;
+#[cfg(unix)]
+use crate::writer::socket_writer::SocketWriter;
+use crate::writer::writer::Writer;
+use clap::Parser;
+use color_eyre::eyre::{eyre, Result};
+use tracing::level_filters::LevelFilter;
+use tracing::{info, warn};
+use tracing_subscriber::fmt;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
+use tracing_subscriber::util::SubscriberInitExt;
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+
+/// Everything the podpingd binary does, kept in the library so fuzz targets can link the parser
+pub fn main() -> Result<()> {
+    color_eyre::install()?;
+
+    let cli = Cli::parse();
+
+    #[cfg(all(windows, feature = "service"))]
+    if let Some(Command::Service) = &cli.command {
+        return service::run_windows_service();
+    }
+
+    let settings = match cli.command() {
+        Command::Config {
+            action: ConfigAction::Check,
+        } => return config_check::print_config_check(),
+        Command::Parse { files } => return parse::print_parse(&files),
+        // Only needs to know where to ask
+        Command::Status { .. } => config::read_config()?,
+        command => config::load_config(&command)?,
+    };
+
+    // Forking has to happen before the runtime starts its threads
+    #[cfg(all(unix, feature = "service"))]
+    if let Command::Run(run_args) = cli.command() {
+        if run_args.daemon {
+            service::daemonize(&settings, run_args.pid_file.as_deref())?;
+        }
+    }
+
+    run_on_runtime(cli, settings)
+}
+
+/// Runs podpingd on a new Tokio runtime until it exits
+fn run_on_runtime(cli: Cli, settings: Settings) -> Result<()> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(start(cli, settings))
+}
+
+async fn start(cli: Cli, mut settings: Settings) -> Result<()> {
+    if let Command::Status { json } = cli.command() {
+        return status::print_status(&settings, json).await;
+    }
+
+    if cli.quiet
+        && settings.writer.console_format.unwrap_or(ConsoleFormat::Log) == ConsoleFormat::Log
+    {
+        settings.writer.console_format = Some(ConsoleFormat::Ndjson);
+    }
+
+    // A retry that fails again has to leave its dead letter in place, not write a new one
+    if matches!(cli.command, Some(Command::Dlq { .. })) {
+        settings.writer.failure_policy = Some(WriterFailurePolicy::Abort);
+    }
+
+    let log_level = reload::log_level(&settings);
+
+    //let log_level = Level::ERROR;
+
+    // The level sits behind a reload layer so SIGHUP can change it.  It only filters the log
+    // output, the OTLP export has its own filter.
+    let (log_level_filter, log_level_handle) =
+        tracing_subscriber::reload::Layer::new(LevelFilter::from_level(log_level));
+
+    // --quiet leaves stdout to the console writer's podpings
+    let log_writer = match (settings.log.file.as_deref(), cli.quiet) {
+        (Some(file), _) if !file.is_empty() => {
+            match OpenOptions::new().create(true).append(true).open(file) {
+                Ok(file) => BoxMakeWriter::new(std::sync::Mutex::new(file)),
+                Err(e) => return Err(eyre!("Unable to open log file {}: {}", file, e)),
+            }
+        }
+        (_, true) => BoxMakeWriter::new(std::io::stderr),
+        (_, false) => BoxMakeWriter::new(std::io::stdout),
+    };
+
+    // Boxed so every format fits the same subscriber
+    let log_format_layer = match settings.log.format.unwrap_or(LogFormat::Full) {
+        LogFormat::Full => fmt::layer()
+            .with_writer(log_writer)
+            .with_target(false)
+            .boxed(),
+        LogFormat::Compact => fmt::layer()
+            .compact()
+            .with_writer(log_writer)
+            .with_target(false)
+            .boxed(),
+        LogFormat::Pretty => fmt::layer()
+            .pretty()
+            .with_writer(log_writer)
+            .with_target(false)
+            .boxed(),
+        // json() formats fields as JSON too, the JSON event format alone choked on span fields
+        // from external libraries (jsonrpsee) recorded as plain text.  Spans are left out
+        // entirely so each line is just the flattened event.
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_writer(log_writer)
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(false)
+            .with_target(false)
+            .boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(log_format_layer.with_filter(log_level_filter))
+        .with(telemetry::otlp_layer(&settings))
+        .init();
+
+    //let span = span!(Level::INFO, "main").entered();
+
+    let version = CARGO_PKG_VERSION.unwrap_or("VERSION_NOT_FOUND");
+    info!("{}", format!("Starting podpingd version {}", version));
+
+    secret_provider::resolve(&mut settings).await?;
+
+    tokio::spawn(reload::reload_on_sighup(log_level_handle));
+
+    if let Some(status_file) = settings.status.file.clone().filter(|file| !file.is_empty()) {
+        info!("Writing sync status to {}", status_file);
+
+        tokio::spawn(status::write_status_file_periodically(
+            PathBuf::from(status_file),
+            settings
+                .status
+                .interval
+                .unwrap_or(status::DEFAULT_STATUS_INTERVAL),
+        ));
+    }
+
+    status::SYNC_STATUS.set_write_latency_budget(settings.status.write_latency_budget);
+    status::start_server(&settings).await;
+
+    shutdown::install_panic_report();
+
+    RPC_RATE_LIMITS.configure(&settings.scanner);
+
+    watchlist::start(&settings).await?;
+
+    api::start_server(&settings).await;
+
+    let result = tokio::select! {
+        result = run(&settings, cli.command()) => result,
+        signal = shutdown::shutdown_signal() => {
+            info!("{} received, shutting down", signal);
+
+            ShutdownReport::new(ShutdownReason::Signal, Some(signal.to_string()))
+                .emit(settings.status.shutdown_report_webhook.as_deref())
+                .await;
+            telemetry::shutdown();
+
+            return Ok(());
+        }
+    };
+
+    let report = match &result {
+        Ok(_) => ShutdownReport::new(ShutdownReason::Completed, None),
+        Err(e) => ShutdownReport::new(ShutdownReason::Error, Some(format!("{:#}", e))),
+    };
+    report
+        .emit(settings.status.shutdown_report_webhook.as_deref())
+        .await;
+    telemetry::shutdown();
+
+    //span.exit();
+
+    result
+}
+
+async fn run(settings: &Settings, command: Command) -> Result<()> {
+    match command {
+        #[cfg(any(feature = "disk", feature = "object-storage", feature = "elasticsearch"))]
+        Command::Backfill {
+            from_block,
+            to_block,
+        } => {
+            let one_shot = verify::OneShot::Backfill {
+                start_block: from_block,
+                end_block: to_block,
+            };
+
+            run_one_shot(settings, one_shot).await
+        }
+        Command::Replay {
+            source,
+            from_block,
+            to_block,
+        } => run_replay(settings, &source, from_block, to_block).await,
+        #[cfg(any(feature = "disk", feature = "object-storage", feature = "elasticsearch"))]
+        Command::Verify {
+            from_block,
+            to_block,
+        } => {
+            let one_shot = verify::OneShot::Verify {
+                start_block: from_block,
+                end_block: to_block,
+            };
+
+            run_one_shot(settings, one_shot).await
+        }
+        Command::Capture {
+            directory,
+            from_block,
+            to_block,
+        } => {
+            fixtures::capture::<JsonRpcClientImpl>(settings, &directory, from_block, to_block).await
+        }
+        #[cfg(any(feature = "disk", feature = "object-storage", feature = "elasticsearch"))]
+        Command::Dlq {
+            action: DlqAction::Retry,
+        } => run_dlq_retry(settings).await,
+        // config_check turns these away first, none of the writers they need are built
+        #[cfg(not(any(feature = "disk", feature = "object-storage", feature = "elasticsearch")))]
+        Command::Backfill { .. } | Command::Verify { .. } | Command::Dlq { .. } => Err(eyre!(
+            "This command needs podpingd built with the disk, object-storage or elasticsearch feature"
+        )),
+        // Handled before logging is set up
+        Command::Status { .. } | Command::Config { .. } | Command::Parse { .. } => Ok(()),
+        _ => follow(settings).await,
+    }
+}
+
+#[cfg(any(feature = "disk", feature = "object-storage", feature = "elasticsearch"))]
+async fn run_dlq_retry(settings: &Settings) -> Result<()> {
+    let report: dead_letter::RetryReport = match settings.writer.type_ {
+        #[cfg(feature = "disk")]
+        Some(WriterType::Disk) => dead_letter::retry::<DiskWriter>(settings, "disk").await?,
+        #[cfg(feature = "object-storage")]
+        Some(WriterType::ObjectStorage) => {
+            dead_letter::retry::<ObjectStorageWriter>(settings, "object storage").await?
+        }
+        #[cfg(feature = "elasticsearch")]
+        Some(WriterType::Elasticsearch) => {
+            dead_letter::retry::<ElasticsearchWriter>(settings, "elasticsearch").await?
+        }
+        _ => return Err(eyre!("dlq retry can't use the configured writer")),
+    };
+
+    if report.failed > 0 {
+        return Err(eyre!("{} dead letters are still failing", report.failed));
+    }
+
+    Ok(())
+}
+
+#[cfg(any(feature = "disk", feature = "object-storage", feature = "elasticsearch"))]
+async fn run_one_shot(settings: &Settings, one_shot: verify::OneShot) -> Result<()> {
+    match settings.writer.type_ {
+        #[cfg(feature = "disk")]
+        Some(WriterType::Disk) => check_from_source::<DiskWriter>(settings, one_shot).await,
+        #[cfg(feature = "object-storage")]
+        Some(WriterType::ObjectStorage) => {
+            check_from_source::<ObjectStorageWriter>(settings, one_shot).await
+        }
+        #[cfg(feature = "elasticsearch")]
+        Some(WriterType::Elasticsearch) => {
+            check_from_source::<ElasticsearchWriter>(settings, one_shot).await
+        }
+        _ => Err(eyre!("{} can't use the configured writer", one_shot.name())),
+    }
+}
+
+/// Runs a one-shot verify or backfill from the configured source into writer `W`
+#[cfg(any(feature = "disk", feature = "object-storage", feature = "elasticsearch"))]
+async fn check_from_source<W: Writer + Send + Sync + 'static>(
+    settings: &Settings,
+    one_shot: verify::OneShot,
+) -> Result<()> {
+    match settings.source.type_.unwrap_or(SourceType::Hive) {
+        SourceType::Hive | SourceType::Haf => {
+            verify::run_once::<HiveSource<JsonRpcClientImpl>, W>(settings, one_shot).await
+        }
+        SourceType::Replay => verify::run_once::<ArchiveSource, W>(settings, one_shot).await,
+        SourceType::Fixtures => verify::run_once::<FixtureSource, W>(settings, one_shot).await,
+        SourceType::PodpingWebsocket | SourceType::Simulator => Err(eyre!(
+            "{} needs a source with history, the configured source only has new blocks",
+            one_shot.name()
+        )),
+    }
+}
+
+async fn run_replay(
+    settings: &Settings,
+    source: &str,
+    from_block: Option<u64>,
+    to_block: Option<u64>,
+) -> Result<()> {
+    let source = replay::ReplaySource::from_arg(settings, source);
+
+    match settings.writer.enabled {
+        true => match settings.writer.type_ {
+            #[cfg(feature = "disk")]
+            Some(WriterType::Disk) => {
+                replay::replay::<DiskWriter>(settings, source, from_block, to_block).await?;
+            }
+            #[cfg(feature = "object-storage")]
+            Some(WriterType::ObjectStorage) => {
+                replay::replay::<ObjectStorageWriter>(settings, source, from_block, to_block)
+                    .await?;
+            }
+            #[cfg(unix)]
+            Some(WriterType::Socket) => {
+                replay::replay::<SocketWriter>(settings, source, from_block, to_block).await?;
+            }
+            #[cfg(feature = "elasticsearch")]
+            Some(WriterType::Elasticsearch) => {
+                replay::replay::<ElasticsearchWriter>(settings, source, from_block, to_block)
+                    .await?;
+            }
+            #[cfg(feature = "parquet")]
+            Some(WriterType::Parquet) => {
+                replay::replay::<ParquetWriter>(settings, source, from_block, to_block).await?;
+            }
+            _ => return Err(eyre!("replay can't use the configured writer")),
+        },
+        false => {
+            replay::replay::<ConsoleWriter>(settings, source, from_block, to_block).await?;
+        }
+    };
+
+    Ok(())
+}
+
+/// Runs the syncer from the configured source into writer `W`
+async fn sync<W: Writer + Send + Sync + 'static>(settings: &Settings) -> Result<()> {
+    match settings.source.type_.unwrap_or(SourceType::Hive) {
+        SourceType::Hive | SourceType::Haf => {
+            let syncer = Syncer::<HiveSource<JsonRpcClientImpl>, W>::new(settings).await?;
+
+            syncer.start().await?;
+        }
+        #[cfg(feature = "websocket")]
+        SourceType::PodpingWebsocket => {
+            let syncer = Syncer::<WebsocketSource, W>::new(settings).await?;
+
+            syncer.start().await?;
+        }
+        SourceType::Replay => {
+            let syncer = Syncer::<ArchiveSource, W>::new(settings).await?;
+
+            syncer.start().await?;
+        }
+        SourceType::Fixtures => {
+            let syncer = Syncer::<FixtureSource, W>::new(settings).await?;
+
+            syncer.start().await?;
+        }
+        SourceType::Simulator => {
+            let syncer = Syncer::<SimulatorSource, W>::new(settings).await?;
+
+            syncer.start().await?;
+        }
+        #[cfg(not(feature = "websocket"))]
+        SourceType::PodpingWebsocket => {
+            return Err(eyre!(
+                "A podping websocket source needs podpingd built with the websocket feature"
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+async fn follow(settings: &Settings) -> Result<()> {
+    match settings.writer.enabled {
+        true => {
+            match settings.writer.type_ {
+                #[cfg(feature = "disk")]
+                Some(WriterType::Disk) => {
+                    info!("Writing podpings to the local disk.");
+                    sync::<DiskWriter>(settings).await?;
+                }
+                #[cfg(feature = "object-storage")]
+                Some(WriterType::ObjectStorage) => {
+                    info!("Writing podpings to object storage.");
+                    sync::<ObjectStorageWriter>(settings).await?;
+                }
+                #[cfg(unix)]
+                Some(WriterType::Socket) => {
+                    info!("Streaming podpings to a socket.");
+                    sync::<SocketWriter>(settings).await?;
+                }
+                #[cfg(feature = "elasticsearch")]
+                Some(WriterType::Elasticsearch) => {
+                    info!("Indexing podpings into Elasticsearch.");
+                    sync::<ElasticsearchWriter>(settings).await?;
+                }
+                #[cfg(feature = "parquet")]
+                Some(WriterType::Parquet) => {
+                    info!("Writing podpings to Parquet files.");
+                    sync::<ParquetWriter>(settings).await?;
+                }
+                _ => return Err(eyre!("The configured writer type isn't available")),
+            };
+        }
+        false => {
+            if !settings.writer.disable_persistence_warnings {
+                warn!("The persistent writer is disabled in settings!");
+
+                if settings.scanner.start_block.is_some()
+                    || settings.scanner.start_datetime.is_some()
+                {
+                    warn!("A start block/date is set.  Without persistence, the scan will start at the values *every time*.")
+                }
+            }
+
+            info!("Writing podpings to the console.");
+
+            sync::<ConsoleWriter>(settings).await?;
+        }
+    }
+
+    if settings.scanner.end_block.is_some() || settings.scanner.end_datetime.is_some() {
+        info!("Bounded scan complete, exiting");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,326 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::podping::{podping_iris, podping_medium, podping_reason, podping_version};
+use crate::status::SYNC_STATUS;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use podping_schemas::org::podcastindex::podping::podping_json::Podping;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use thiserror::Error;
+use tracing::warn;
+
+/// Hive rejects a custom_json with a longer json, so a longer one can't have come from the chain
+pub const MAX_CUSTOM_JSON_LENGTH: usize = 8192;
+
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("{0} bytes, longer than a custom_json can be")]
+    Oversized(usize),
+    #[error("not a podping in any schema version: {0}")]
+    Malformed(#[from] serde_json::Error),
+    // The first schema versions have no required fields, so any object parses as one of them
+    #[error("no IRIs, not a podping")]
+    NoIris,
+}
+
+/// A podping custom_json's json, in whichever schema version it was written
+pub fn parse_podping(json: &str) -> Result<Podping, ParseError> {
+    if json.len() > MAX_CUSTOM_JSON_LENGTH {
+        return Err(ParseError::Oversized(json.len()));
+    }
+
+    let podping = serde_json::from_str(json)?;
+
+    match podping_iris(&podping).is_empty() {
+        true => Err(ParseError::NoIris),
+        false => Ok(podping),
+    }
+}
+
+/// A podping custom_json that didn't parse, kept with its block for the writers to quarantine,
+/// see `writer.quarantine_malformed`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MalformedPodping {
+    pub(crate) tx_id: String,
+    pub(crate) id: String,
+    pub(crate) json: String,
+    pub(crate) error: String,
+}
+
+/// parse_podping, logging and counting any podping that doesn't parse and adding it to
+/// `malformed`
+///
+/// Counted under `podpings_oversized` and `podpings_malformed`, so a schema change that stops
+/// podpings parsing shows up rather than quietly losing them.
+pub fn parse_podping_or_quarantine(
+    id: &str,
+    json: &str,
+    tx_id: &str,
+    malformed: &mut Vec<MalformedPodping>,
+) -> Option<Podping> {
+    match parse_podping(json) {
+        Ok(podping) => Some(podping),
+        Err(e) => {
+            warn!("Malformed podping in transaction {}: {}", tx_id, e);
+
+            SYNC_STATUS.increment_counter(
+                match e {
+                    ParseError::Oversized(_) => "podpings_oversized",
+                    ParseError::Malformed(_) | ParseError::NoIris => "podpings_malformed",
+                },
+                1,
+            );
+
+            malformed.push(MalformedPodping {
+                tx_id: tx_id.to_string(),
+                id: id.to_string(),
+                json: json.to_string(),
+                error: e.to_string(),
+            });
+
+            None
+        }
+    }
+}
+
+/// One line on how a payload parsed
+fn describe(podping: &Podping) -> String {
+    let iris = podping_iris(podping);
+
+    [
+        Some(format!(
+            "version {}",
+            podping_version(podping).unwrap_or_else(|| "0".to_string())
+        )),
+        podping_medium(podping),
+        podping_reason(podping),
+        Some(format!(
+            "{} IRI{}",
+            iris.len(),
+            if iris.len() == 1 { "" } else { "s" }
+        )),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+/// Parses each file as a podping custom_json's json, stdin if there are none, and prints how
+/// each one parsed
+///
+/// Errors if any of them didn't.
+pub(crate) fn print_parse(files: &[String]) -> Result<()> {
+    let payloads = match files.is_empty() {
+        true => {
+            let mut payload = String::new();
+            std::io::stdin().read_to_string(&mut payload)?;
+
+            vec![("-".to_string(), payload)]
+        }
+        false => files
+            .iter()
+            .map(|file| Ok((file.clone(), std::fs::read_to_string(file)?)))
+            .collect::<Result<Vec<_>>>()?,
+    };
+    let mut failed = 0;
+
+    for (name, payload) in &payloads {
+        match parse_podping(payload.trim_end()) {
+            Ok(podping) => println!("{}: {}", name, describe(&podping)),
+            Err(e) => {
+                println!("{}: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        _ => Err(eyre!(
+            "{} of {} payloads didn't parse",
+            failed,
+            payloads.len()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use serde_json::{json, Value};
+    use std::path::Path;
+
+    // Every file in testdata/podpings, with the schema version it parses as or why it's rejected
+    const GOLDEN: &[(&str, Result<Option<&str>, &str>)] = &[
+        ("malformed-not-a-podping.json", Err("NotAPodping")),
+        ("malformed-truncated.json", Err("InvalidJson")),
+        ("oversized.json", Err("Oversized")),
+        ("v0-url.json", Ok(None)),
+        ("v0-urls.json", Ok(None)),
+        ("v0.2.json", Ok(Some("0.2"))),
+        ("v0.3.json", Ok(Some("0.3"))),
+        ("v1.0-music-live.json", Ok(Some("1.0"))),
+        ("v1.0.json", Ok(Some("1.0"))),
+        ("v1.1.json", Ok(Some("1.1"))),
+    ];
+
+    fn outcome(json: &str) -> Result<Option<String>, &'static str> {
+        match parse_podping(json) {
+            Ok(podping) => Ok(podping_version(&podping)),
+            Err(ParseError::Oversized(_)) => Err("Oversized"),
+            Err(ParseError::Malformed(e)) if e.is_syntax() || e.is_eof() => Err("InvalidJson"),
+            // Depending on the schema version it's matched against, valid JSON that isn't a
+            // podping fails to deserialize or deserializes without IRIs
+            Err(ParseError::Malformed(_)) | Err(ParseError::NoIris) => Err("NotAPodping"),
+        }
+    }
+
+    #[test]
+    fn golden_podpings() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/podpings");
+        let mut files = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        files.sort();
+
+        assert_eq!(
+            files,
+            GOLDEN.iter().map(|(file, _)| *file).collect::<Vec<_>>(),
+            "every testdata/podpings file needs an expected outcome"
+        );
+
+        for (file, expected) in GOLDEN {
+            let json = std::fs::read_to_string(directory.join(file)).unwrap();
+
+            assert_eq!(
+                outcome(json.trim_end()),
+                expected.map(|version| version.map(str::to_string)),
+                "{}",
+                file
+            );
+        }
+    }
+
+    const MEDIUMS: &[&str] = &[
+        "podcast",
+        "music",
+        "video",
+        "film",
+        "audiobook",
+        "newsletter",
+        "blog",
+    ];
+    const REASONS: &[&str] = &["update", "live", "liveEnd"];
+
+    fn iri() -> impl Strategy<Value = String> {
+        "(https|http|ipns)://[a-z0-9]{1,16}\\.example\\.com/[a-z0-9/]{0,32}"
+    }
+
+    fn iris() -> impl Strategy<Value = Vec<String>> {
+        vec(iri(), 1..8)
+    }
+
+    /// Parses, serializes and parses again, checking nothing about the podping changed
+    fn assert_round_trips(json: Value, version: Option<&str>, iris: &[String]) {
+        let podping = parse_podping(&json.to_string()).unwrap();
+
+        assert_eq!(podping_version(&podping).as_deref(), version);
+        assert_eq!(podping_iris(&podping), iris);
+
+        let serialized = serde_json::to_string(&podping).unwrap();
+        let reparsed = parse_podping(&serialized).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&reparsed).unwrap(),
+            serde_json::to_value(&podping).unwrap()
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn v0_url_round_trips(iri in iri()) {
+            assert_round_trips(json!({"url": iri}), None, std::slice::from_ref(&iri));
+        }
+
+        #[test]
+        fn v0_urls_round_trips(iris in iris()) {
+            assert_round_trips(json!({"urls": iris}), None, &iris);
+        }
+
+        #[test]
+        fn v0_2_round_trips(iris in iris()) {
+            assert_round_trips(
+                json!({
+                    "version": "0.2",
+                    "num_urls": iris.len(),
+                    "reason": "feed_update",
+                    "urls": iris,
+                }),
+                Some("0.2"),
+                &iris,
+            );
+        }
+
+        #[test]
+        fn v0_3_round_trips(
+            medium in prop::sample::select(vec!["podcast", "music"]),
+            reason in prop::sample::select(vec!["update", "live"]),
+            iris in iris(),
+        ) {
+            assert_round_trips(
+                json!({"version": "0.3", "medium": medium, "reason": reason, "iris": iris}),
+                Some("0.3"),
+                &iris,
+            );
+        }
+
+        #[test]
+        fn v1_0_round_trips(
+            medium in prop::sample::select(MEDIUMS),
+            reason in prop::sample::select(REASONS),
+            iris in iris(),
+        ) {
+            assert_round_trips(
+                json!({"version": "1.0", "medium": medium, "reason": reason, "iris": iris}),
+                Some("1.0"),
+                &iris,
+            );
+        }
+
+        #[test]
+        fn v1_1_round_trips(
+            medium in prop::sample::select(MEDIUMS),
+            reason in prop::sample::select(REASONS),
+            iris in iris(),
+            timestamp_ns in any::<u64>(),
+            session_id in any::<u64>(),
+        ) {
+            assert_round_trips(
+                json!({
+                    "version": "1.1",
+                    "medium": medium,
+                    "reason": reason,
+                    "iris": iris,
+                    "timestampNs": timestamp_ns,
+                    "sessionId": session_id,
+                }),
+                Some("1.1"),
+                &iris,
+            );
+        }
+    }
+}
@@ -0,0 +1,177 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::hive::scanner::{HiveBlockWithNum, HiveTransactionWithTxId};
+use chrono::{DateTime, Utc};
+use podping_schemas::org::podcastindex::podping::podping_json::Podping;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+// The schema versions don't share a common shape (v0.x uses url/urls, v1.x uses iris),
+// so read fields off the serialized form rather than matching every version's struct
+
+fn podping_value(podping: &Podping) -> Value {
+    serde_json::to_value(podping).unwrap_or(Value::Null)
+}
+
+fn value_strings(value: Option<&Value>) -> Vec<String> {
+    match value {
+        Some(Value::String(s)) => vec![s.clone()],
+        Some(Value::Array(a)) => a
+            .iter()
+            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+pub fn podping_iris(podping: &Podping) -> Vec<String> {
+    let value = podping_value(podping);
+
+    ["iris", "urls", "url"]
+        .iter()
+        .flat_map(|field| value_strings(value.get(field)))
+        .collect()
+}
+
+/// The schema version, the first podpings don't have one
+pub fn podping_version(podping: &Podping) -> Option<String> {
+    podping_value(podping)
+        .get("version")
+        .and_then(|version| version.as_str())
+        .map(|version| version.to_string())
+}
+
+/// The update reason, v0.x podpings don't have one
+pub fn podping_reason(podping: &Podping) -> Option<String> {
+    podping_value(podping)
+        .get("reason")
+        .and_then(|reason| reason.as_str())
+        .map(|reason| reason.to_string())
+}
+
+/// The medium, e.g. podcast or music, v0.x podpings don't have one
+pub fn podping_medium(podping: &Podping) -> Option<String> {
+    podping_value(podping)
+        .get("medium")
+        .and_then(|medium| medium.as_str())
+        .map(|medium| medium.to_string())
+}
+
+/// Rebuilds the podping with each IRI replaced by `f`'s result, or removed when it gives `None`
+///
+/// Returns `None` if the rewritten podping no longer fits its schema version.
+pub fn map_podping_iris<F>(podping: &Podping, mut f: F) -> Option<Podping>
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    let mut value = podping_value(podping);
+    let object = value.as_object_mut()?;
+
+    for field in ["iris", "urls", "url"] {
+        let mapped = match object.get(field) {
+            Some(Value::String(s)) => f(s).map(Value::String).unwrap_or(Value::Null),
+            Some(Value::Array(a)) => Value::Array(
+                a.iter()
+                    .filter_map(|v| match v.as_str() {
+                        Some(s) => f(s).map(Value::String),
+                        None => Some(v.clone()),
+                    })
+                    .collect(),
+            ),
+            _ => continue,
+        };
+
+        object.insert(field.to_string(), mapped);
+    }
+
+    // v0.2 carries a count of its urls
+    if object.contains_key("num_urls") {
+        let num_urls = value_strings(object.get("urls")).len();
+        object.insert("num_urls".to_string(), Value::from(num_urls));
+    }
+
+    serde_json::from_value(value).ok()
+}
+
+/// Identifies a podping across every output, the same on a restart or a re-scan
+///
+/// `index` is the podping's operation index in its transaction, so the id is a hash of where it
+/// is on the chain.
+/// A fork correction's podpings are in new transactions and get new ids, `replaces` has the old ones.
+pub(crate) fn event_id(block_num: u64, tx_id: &str, index: usize) -> String {
+    let hash = Sha256::digest(format!("{}/{}/{}", block_num, tx_id, index).as_bytes());
+
+    hex::encode(&hash[..16])
+}
+
+/// One podping with the block and transaction it came from, as the JSON outputs show it
+#[derive(Serialize)]
+pub(crate) struct PodpingRecord<'a> {
+    pub(crate) event_id: String,
+    pub(crate) block_num: u64,
+    pub(crate) block_id: &'a str,
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) tx_id: &'a str,
+    pub(crate) index: usize,
+    /// Set on a correction, the id of the block this one replaced in a fork
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) replaces: Option<&'a str>,
+    pub(crate) podping: &'a Podping,
+}
+
+impl<'a> PodpingRecord<'a> {
+    pub(crate) fn new(
+        block: &'a HiveBlockWithNum,
+        tx: &'a HiveTransactionWithTxId,
+        index: usize,
+        podping: &'a Podping,
+    ) -> PodpingRecord<'a> {
+        PodpingRecord {
+            event_id: event_id(block.block_num, &tx.tx_id, index),
+            block_num: block.block_num,
+            block_id: &block.block_id,
+            timestamp: block.timestamp,
+            tx_id: &tx.tx_id,
+            index,
+            replaces: block
+                .replaces
+                .as_ref()
+                .map(|replaced| replaced.block_id.as_str()),
+            podping,
+        }
+    }
+}
+
+/// Picks podpings by reason, medium and IRI for the query APIs
+///
+/// Empty sets match everything, a podping has to match every set that isn't empty.
+#[derive(Debug, Default)]
+pub(crate) struct PodpingFilter {
+    pub(crate) reasons: HashSet<String>,
+    pub(crate) mediums: HashSet<String>,
+    pub(crate) iris: HashSet<String>,
+}
+
+impl PodpingFilter {
+    pub(crate) fn matches(&self, podping: &Podping) -> bool {
+        (self.reasons.is_empty()
+            || podping_reason(podping).is_some_and(|reason| self.reasons.contains(&reason)))
+            && (self.mediums.is_empty()
+                || podping_medium(podping).is_some_and(|medium| self.mediums.contains(&medium)))
+            && (self.iris.is_empty()
+                || podping_iris(podping)
+                    .iter()
+                    .any(|iri| self.iris.contains(iri)))
+    }
+}
@@ -0,0 +1,126 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::cli::{Command, RunArgs};
+use crate::config::{load_config, Settings};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, RwLock};
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info, Level};
+use tracing_subscriber::{reload, Registry};
+
+/// Settings re-read on SIGHUP, for the parts of podpingd that can pick up changes while running
+pub(crate) static LIVE_SETTINGS: LazyLock<LiveSettings> = LazyLock::new(LiveSettings::new);
+
+pub(crate) type LogLevelHandle = reload::Handle<LevelFilter, Registry>;
+
+/// The latest reloaded settings, numbered so each user can tell when they've changed
+///
+/// Generation 0 means nothing has been reloaded and the startup settings still apply.
+pub(crate) struct LiveSettings {
+    generation: AtomicU64,
+    settings: RwLock<Option<Arc<Settings>>>,
+}
+
+impl LiveSettings {
+    fn new() -> LiveSettings {
+        LiveSettings {
+            generation: AtomicU64::new(0),
+            settings: RwLock::new(None),
+        }
+    }
+
+    /// Reloaded settings if there are newer ones than `seen_generation`, which is moved up to date
+    pub(crate) fn changed_since(&self, seen_generation: &mut u64) -> Option<Arc<Settings>> {
+        let generation = self.generation.load(Ordering::Acquire);
+
+        if generation == *seen_generation {
+            return None;
+        }
+
+        *seen_generation = generation;
+        self.settings.read().unwrap().clone()
+    }
+
+    fn publish(&self, settings: Settings) {
+        *self.settings.write().unwrap() = Some(Arc::new(settings));
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+pub(crate) fn log_level(settings: &Settings) -> Level {
+    match settings.debug {
+        false => Level::INFO,
+        true => Level::DEBUG,
+    }
+}
+
+/// Re-reads the config files and environment every time podpingd gets a SIGHUP
+///
+/// The log level applies straight away. The Hive node list and forwarder endpoints are picked
+/// up by the scanner and forwarder on their next request. Anything else needs a restart.
+#[cfg(unix)]
+pub(crate) async fn reload_on_sighup(log_level_handle: LogLevelHandle) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!(
+                "Unable to listen for SIGHUP, config reload is disabled: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        info!("SIGHUP received, reloading config");
+
+        let settings = match load_config(&Command::Run(RunArgs::default())) {
+            Ok(settings) => settings,
+            Err(e) => {
+                error!("Config reload failed, keeping the current settings: {}", e);
+                continue;
+            }
+        };
+
+        let level = log_level(&settings);
+        if let Err(e) = log_level_handle.modify(|filter| *filter = LevelFilter::from_level(level)) {
+            error!("Error changing the log level: {}", e);
+        }
+
+        info!(
+            "Config reloaded: log level {}, {} rpc nodes, forwarder endpoints {}",
+            level,
+            settings.scanner.rpc_nodes.len(),
+            match &settings.forwarder.endpoints {
+                Some(endpoints) if !endpoints.is_empty() => endpoints
+                    .iter()
+                    .map(|endpoint| endpoint.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                _ => settings
+                    .forwarder
+                    .endpoint
+                    .clone()
+                    .unwrap_or("unset".to_string()),
+            }
+        );
+
+        LIVE_SETTINGS.publish(settings);
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn reload_on_sighup(_log_level_handle: LogLevelHandle) {
+    info!("SIGHUP isn't available on this platform, config reload is disabled");
+}
@@ -0,0 +1,653 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::{Settings, WriterType};
+use crate::event_bus::EventBus;
+#[cfg(feature = "forwarder")]
+use crate::forwarder::forwarder::Forwarder;
+use crate::hive::scanner::{BlockBatch, HiveBlockWithNum, HiveTransactionWithTxId};
+use crate::writer::body_encoding::decode_body;
+#[cfg(feature = "object-storage")]
+use crate::writer::storage::Storage;
+use crate::writer::writer::{is_temp_file, new_writer, Writer};
+use chrono::{DateTime, TimeZone, Utc};
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::{Report, Result};
+use podping_schemas::org::podcastindex::podping::podping_json::Podping;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+use walkdir::WalkDir;
+
+const REPLAY_BATCH_SIZE: usize = 100;
+/// Days read ahead of whoever is consuming the archive
+const ARCHIVE_READ_AHEAD_DAYS: usize = 1;
+
+/// The parts of a block metadata sidecar needed to rebuild the block
+#[derive(Deserialize)]
+struct StoredBlockMetadata {
+    block_id: String,
+    previous: String,
+    producer: String,
+    #[serde(default)]
+    keys: Vec<String>,
+}
+
+/// An existing podpingd archive to read podpings back from
+pub(crate) enum ReplaySource {
+    Disk(PathBuf),
+    #[cfg(feature = "object-storage")]
+    ObjectStorage(Storage),
+}
+
+impl ReplaySource {
+    /// `s3://<bucket>` reads a bucket on the writer's object storage endpoint, anything else is a directory
+    pub(crate) fn from_arg(settings: &Settings, source: &str) -> ReplaySource {
+        match source.strip_prefix("s3://") {
+            Some(bucket_name) => {
+                ReplaySource::object_storage(settings, bucket_name.trim_end_matches('/'))
+            }
+            None => {
+                let directory = PathBuf::from(source);
+
+                if !directory.is_dir() {
+                    panic!("Replay source {} is not a directory", directory.display());
+                }
+
+                ReplaySource::Disk(directory)
+            }
+        }
+    }
+
+    /// The archive the configured writer keeps, if it keeps one
+    pub(crate) fn from_writer(settings: &Settings) -> Option<ReplaySource> {
+        if !settings.writer.enabled {
+            return None;
+        }
+
+        match settings.writer.type_ {
+            Some(WriterType::Disk) => settings
+                .writer
+                .disk_directory
+                .as_ref()
+                .map(|directory| ReplaySource::Disk(PathBuf::from(directory))),
+            Some(WriterType::ObjectStorage) => settings
+                .writer
+                .object_storage_bucket_name
+                .as_ref()
+                .map(|bucket_name| ReplaySource::object_storage(settings, bucket_name)),
+            _ => None,
+        }
+    }
+
+    #[cfg(feature = "object-storage")]
+    fn object_storage(settings: &Settings, bucket_name: &str) -> ReplaySource {
+        match Storage::from_settings(settings, bucket_name) {
+            Ok(storage) => ReplaySource::ObjectStorage(storage),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[cfg(not(feature = "object-storage"))]
+    fn object_storage(_settings: &Settings, bucket_name: &str) -> ReplaySource {
+        panic!(
+            "Replaying s3://{} needs podpingd built with the object-storage feature",
+            bucket_name
+        );
+    }
+
+    /// Numeric child directories of `dir` in numeric order, i.e. the years, months or days
+    async fn list_numeric_dirs(&self, dir: &Path) -> Result<Vec<u32>, Error> {
+        let names = match self {
+            ReplaySource::Disk(root) => {
+                let mut names = vec![];
+                let mut entries = tokio::fs::read_dir(root.join(dir)).await?;
+
+                while let Some(entry) = entries.next_entry().await? {
+                    if entry.file_type().await?.is_dir() {
+                        names.push(entry.file_name().to_string_lossy().to_string());
+                    }
+                }
+
+                names
+            }
+            #[cfg(feature = "object-storage")]
+            ReplaySource::ObjectStorage(storage) => {
+                let prefix = object_prefix(dir);
+
+                storage
+                    .list_common_prefixes(&prefix)
+                    .await?
+                    .into_iter()
+                    .map(|common_prefix| {
+                        common_prefix
+                            .trim_start_matches(prefix.as_str())
+                            .trim_end_matches('/')
+                            .to_string()
+                    })
+                    .collect()
+            }
+        };
+
+        let mut numbers = names
+            .iter()
+            .filter_map(|name| name.parse::<u32>().ok())
+            .collect::<Vec<_>>();
+        numbers.sort();
+
+        Ok(numbers)
+    }
+
+    /// Every file under `dir`, relative to the archive root
+    async fn list_files(&self, dir: &Path) -> Result<Vec<PathBuf>, Error> {
+        match self {
+            ReplaySource::Disk(root) => Ok(WalkDir::new(root.join(dir))
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_file() && !is_temp_file(entry.path()))
+                .filter_map(|entry| {
+                    entry
+                        .path()
+                        .strip_prefix(root)
+                        .ok()
+                        .map(|path| path.to_path_buf())
+                })
+                .collect()),
+            #[cfg(feature = "object-storage")]
+            ReplaySource::ObjectStorage(storage) => Ok(storage
+                .list(&object_prefix(dir))
+                .await?
+                .into_iter()
+                .map(PathBuf::from)
+                .collect()),
+        }
+    }
+
+    async fn read_file(&self, path: &Path) -> Result<String, Error> {
+        let bytes = match self {
+            ReplaySource::Disk(root) => tokio::fs::read(root.join(path)).await?,
+            #[cfg(feature = "object-storage")]
+            ReplaySource::ObjectStorage(storage) => match storage.get(path).await? {
+                Some(bytes) => bytes,
+                None => return Err(eyre!("{} not found", path.display())),
+            },
+        };
+
+        Ok(decode_body(&bytes)?)
+    }
+}
+
+#[cfg(feature = "object-storage")]
+fn object_prefix(dir: &Path) -> String {
+    match dir.as_os_str().is_empty() {
+        true => String::new(),
+        false => format!("{}/", dir.to_string_lossy().replace('\\', "/")),
+    }
+}
+
+/// Block timestamp from a `YYYY/M/D/h/m/s/<file>` path
+fn path_timestamp(path: &Path) -> Option<DateTime<Utc>> {
+    let parts = path
+        .components()
+        .take(6)
+        .map(|c| c.as_os_str().to_string_lossy().parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    match parts.as_slice() {
+        [year, month, day, hour, minute, second] => Utc
+            .with_ymd_and_hms(*year as i32, *month, *day, *hour, *minute, *second)
+            .single(),
+        _ => None,
+    }
+}
+
+enum ArchiveFile {
+    Podping {
+        block_num: u64,
+        tx_id: String,
+        // v1.1 podpings are named after their session and timestamp instead
+        index: Option<usize>,
+        timestamp_ns: Option<u64>,
+    },
+    BlockMetadata {
+        block_num: u64,
+    },
+}
+
+/// Recognizes podping and block metadata files from their names, anything else is skipped
+fn parse_file_name(path: &Path) -> Option<ArchiveFile> {
+    let stem = path.file_name()?.to_str()?.strip_suffix(".json")?;
+    let mut parts = stem.split('_');
+    let block_num = parts.next()?.parse::<u64>().ok()?;
+
+    match parts.next()? {
+        "block" => Some(ArchiveFile::BlockMetadata { block_num }),
+        tx_id => {
+            let rest = parts.collect::<Vec<_>>();
+
+            Some(ArchiveFile::Podping {
+                block_num,
+                tx_id: tx_id.to_string(),
+                index: match rest.as_slice() {
+                    [index] => index.parse().ok(),
+                    _ => None,
+                },
+                timestamp_ns: match rest.as_slice() {
+                    [_session_id, timestamp_ns] => timestamp_ns.parse().ok(),
+                    _ => None,
+                },
+            })
+        }
+    }
+}
+
+/// A podping file to read, with what its name says about where it sits in its block
+struct PodpingFile {
+    path: PathBuf,
+    block_num: u64,
+    tx_id: String,
+    index: Option<usize>,
+    timestamp_ns: Option<u64>,
+}
+
+/// Rebuilds the blocks stored under one day's directory, in block order
+///
+/// Also returns whether the day holds blocks past `end_block`, so later days can be skipped.
+async fn read_day(
+    source: &ReplaySource,
+    day: &Path,
+    start_block: Option<u64>,
+    end_block: Option<u64>,
+) -> Result<(Vec<HiveBlockWithNum>, bool), Error> {
+    let mut podping_files = vec![];
+    let mut metadata: BTreeMap<u64, StoredBlockMetadata> = BTreeMap::new();
+    let mut past_end = false;
+
+    for path in source.list_files(day).await? {
+        let file = match parse_file_name(&path) {
+            Some(file) => file,
+            None => continue,
+        };
+
+        let block_num = match &file {
+            ArchiveFile::Podping { block_num, .. } | ArchiveFile::BlockMetadata { block_num } => {
+                *block_num
+            }
+        };
+
+        if end_block.is_some_and(|end_block| block_num > end_block) {
+            past_end = true;
+            continue;
+        }
+
+        if start_block.is_some_and(|start_block| block_num < start_block) {
+            continue;
+        }
+
+        match file {
+            ArchiveFile::Podping {
+                block_num,
+                tx_id,
+                index,
+                timestamp_ns,
+            } => podping_files.push(PodpingFile {
+                path,
+                block_num,
+                tx_id,
+                index,
+                timestamp_ns,
+            }),
+            ArchiveFile::BlockMetadata { block_num } => {
+                let contents = source.read_file(&path).await?;
+                let stored = serde_json::from_str::<StoredBlockMetadata>(&contents)
+                    .map_err(|e| eyre!("Error reading {}: {}", path.display(), e))?;
+
+                metadata.insert(block_num, stored);
+            }
+        }
+    }
+
+    // Names don't say where a transaction sits in its block, the block metadata lists every
+    // podping's key in block order.  Without it transactions are kept apart by id.
+    podping_files.sort_by_cached_key(|file| {
+        let position = metadata.get(&file.block_num).and_then(|stored| {
+            stored
+                .keys
+                .iter()
+                .position(|key| Path::new(key).file_name() == file.path.file_name())
+        });
+
+        (
+            file.block_num,
+            position.unwrap_or(usize::MAX),
+            file.tx_id.clone(),
+            file.index,
+            file.timestamp_ns,
+        )
+    });
+
+    let mut blocks: BTreeMap<u64, HiveBlockWithNum> = BTreeMap::new();
+
+    for PodpingFile {
+        path,
+        block_num,
+        tx_id,
+        index,
+        ..
+    } in podping_files
+    {
+        let timestamp = match path_timestamp(&path) {
+            Some(timestamp) => timestamp,
+            None => {
+                warn!(
+                    "replay: skipping {}, not in a block directory",
+                    path.display()
+                );
+                continue;
+            }
+        };
+
+        let contents = source.read_file(&path).await?;
+        let block = blocks.entry(block_num).or_insert(HiveBlockWithNum {
+            block_num,
+            block_id: String::new(),
+            previous: String::new(),
+            witness: String::new(),
+            timestamp,
+            transactions: vec![],
+            malformed: vec![],
+            replaces: None,
+        });
+
+        let podping = match serde_json::from_str::<Podping>(&contents) {
+            Ok(podping) => podping,
+            Err(e) => {
+                warn!("replay: skipping {}: {}", path.display(), e);
+                continue;
+            }
+        };
+
+        // Without an index in the name, the podping's position among the transaction's files
+        match block.transactions.iter_mut().find(|tx| tx.tx_id == tx_id) {
+            Some(tx) => {
+                let index = index.unwrap_or(tx.podpings.len());
+                tx.push(index, podping)
+            }
+            None => block.transactions.push(HiveTransactionWithTxId::new(
+                tx_id,
+                index.unwrap_or(0),
+                podping,
+            )),
+        }
+    }
+
+    for (block_num, stored) in metadata {
+        if let Some(block) = blocks.get_mut(&block_num) {
+            block.block_id = stored.block_id;
+            block.previous = stored.previous;
+            block.witness = stored.producer;
+        }
+    }
+
+    let blocks = blocks
+        .into_values()
+        .filter(|block| !block.transactions.is_empty())
+        .collect();
+
+    Ok((blocks, past_end))
+}
+
+/// Archived day directory and its blocks in the range, or the error that stopped the read
+pub(crate) type ArchiveDay = Result<(PathBuf, Vec<HiveBlockWithNum>), Error>;
+
+/// Every day directory in the archive, oldest first
+async fn list_days(source: &ReplaySource) -> Result<Vec<PathBuf>, Error> {
+    let mut days = vec![];
+
+    for year in source.list_numeric_dirs(Path::new("")).await? {
+        let year_path = PathBuf::from(year.to_string());
+
+        for month in source.list_numeric_dirs(&year_path).await? {
+            let month_path = year_path.join(month.to_string());
+
+            for day in source.list_numeric_dirs(&month_path).await? {
+                days.push(month_path.join(day.to_string()));
+            }
+        }
+    }
+
+    Ok(days)
+}
+
+/// Lowest block stored under `dir`, from the first file in its earliest second
+///
+/// `None` if that second's directory has no podping files, e.g. after a partial trim.
+async fn first_block_in(source: &ReplaySource, dir: &Path) -> Result<Option<u64>, Error> {
+    let mut dir = dir.to_path_buf();
+
+    while let Some(first) = source.list_numeric_dirs(&dir).await?.first() {
+        dir = dir.join(first.to_string());
+    }
+
+    Ok(source
+        .list_files(&dir)
+        .await?
+        .iter()
+        .filter_map(|path| match parse_file_name(path) {
+            Some(ArchiveFile::Podping { block_num, .. })
+            | Some(ArchiveFile::BlockMetadata { block_num }) => Some(block_num),
+            None => None,
+        })
+        .min())
+}
+
+/// Newest block in the archive and its time, from the files in the latest second stored
+pub(crate) async fn last_block(
+    source: &ReplaySource,
+) -> Result<Option<(u64, DateTime<Utc>)>, Error> {
+    for day in list_days(source).await?.into_iter().rev() {
+        let mut dir = day;
+
+        while let Some(last) = source.list_numeric_dirs(&dir).await?.last() {
+            dir = dir.join(last.to_string());
+        }
+
+        let last = source
+            .list_files(&dir)
+            .await?
+            .into_iter()
+            .filter_map(|path| match parse_file_name(&path) {
+                Some(ArchiveFile::Podping { block_num, .. })
+                | Some(ArchiveFile::BlockMetadata { block_num }) => Some((block_num, path)),
+                None => None,
+            })
+            .max_by_key(|(block_num, _)| *block_num);
+
+        if let Some((block_num, timestamp)) =
+            last.and_then(|(block_num, path)| Some((block_num, path_timestamp(&path)?)))
+        {
+            return Ok(Some((block_num, timestamp)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Index of the first day that can hold `start_block`
+///
+/// Binary searches on each day's first block, so a range late in a long archive doesn't list
+/// every day before it. Days whose first block can't be found are treated as past the start,
+/// which only means reading from an earlier day than needed.
+async fn first_day_index(
+    source: &ReplaySource,
+    days: &[PathBuf],
+    start_block: u64,
+) -> Result<usize, Error> {
+    let mut first = 0;
+    let (mut low, mut high) = (0, days.len());
+
+    while low < high {
+        let middle = (low + high) / 2;
+
+        match first_block_in(source, &days[middle]).await? {
+            Some(block_num) if block_num <= start_block => {
+                first = middle;
+                low = middle + 1;
+            }
+            _ => high = middle,
+        }
+    }
+
+    Ok(first)
+}
+
+async fn read_archive(
+    source: &ReplaySource,
+    start_block: Option<u64>,
+    end_block: Option<u64>,
+    tx: &mpsc::Sender<ArchiveDay>,
+) -> Result<(), Error> {
+    let days = list_days(source).await?;
+    let first_day = match start_block {
+        Some(start_block) => first_day_index(source, &days, start_block).await?,
+        None => 0,
+    };
+
+    for day_path in days.into_iter().skip(first_day) {
+        let (blocks, past_end) = read_day(source, &day_path, start_block, end_block).await?;
+
+        // The receiver went away, nobody wants the rest
+        if tx.send(Ok((day_path, blocks))).await.is_err() || past_end {
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the archive day by day in the background, oldest first
+///
+/// Days before `start_block` are skipped without reading them, and reading stops at `end_block`
+/// or once the receiver is dropped.
+pub(crate) fn archive_days(
+    source: Arc<ReplaySource>,
+    start_block: Option<u64>,
+    end_block: Option<u64>,
+) -> mpsc::Receiver<ArchiveDay> {
+    let (tx, rx) = mpsc::channel(ARCHIVE_READ_AHEAD_DAYS);
+
+    tokio::spawn(async move {
+        if let Err(e) = read_archive(&source, start_block, end_block, &tx).await {
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+
+    rx
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ReplayReport {
+    pub(crate) blocks: u64,
+    pub(crate) podpings: u64,
+}
+
+/// Reads an existing archive day by day and sends it through the writer and forwarder
+///
+/// Blocks go through the same batch path as catch-up, so the target's last_updated_block
+/// ends up at the last replayed block.
+pub(crate) async fn replay<W>(
+    settings: &Settings,
+    source: ReplaySource,
+    start_block: Option<u64>,
+    end_block: Option<u64>,
+) -> Result<ReplayReport, Report>
+where
+    W: Writer + Send + Sync + 'static,
+{
+    let writer = new_writer::<W>(settings).await?;
+    writer.preflight().await?;
+
+    let bus = EventBus::<BlockBatch>::new(1);
+    let mut joinset = JoinSet::new();
+
+    let rx = bus.subscribe("writer");
+    joinset.spawn(async move { writer.start_batch(rx).await });
+
+    #[cfg(feature = "forwarder")]
+    if settings.forwarder.enabled {
+        let forwarder = new_writer::<Forwarder>(settings).await?;
+        forwarder.preflight().await?;
+
+        let forwarder_rx = bus.subscribe("forwarder");
+        joinset.spawn(async move {
+            forwarder.start_batch(forwarder_rx).await?;
+            // Feed updates from the last blocks are still in their windows
+            forwarder.finish().await
+        });
+    }
+
+    let report = publish_archive(Arc::new(source), start_block, end_block, &bus).await?;
+
+    // Closes the subscriptions so the writer and forwarder finish up
+    drop(bus);
+
+    for result in joinset.join_all().await {
+        result?;
+    }
+
+    info!(
+        "replay: sent {} podpings from {} blocks",
+        report.podpings, report.blocks
+    );
+
+    Ok(report)
+}
+
+/// Sends the archive's blocks in the range on in batches, like catch-up
+pub(crate) async fn publish_archive(
+    source: Arc<ReplaySource>,
+    start_block: Option<u64>,
+    end_block: Option<u64>,
+    bus: &EventBus<BlockBatch>,
+) -> Result<ReplayReport, Error> {
+    let mut report = ReplayReport::default();
+    let mut days = archive_days(source, start_block, end_block);
+
+    while let Some(day) = days.recv().await {
+        let (day_path, blocks) = day?;
+
+        if !blocks.is_empty() {
+            info!(
+                "replay: {}, blocks {} to {}",
+                day_path.display(),
+                blocks.first().unwrap().block_num,
+                blocks.last().unwrap().block_num
+            );
+        }
+
+        for chunk in blocks.chunks(REPLAY_BATCH_SIZE) {
+            report.blocks += chunk.len() as u64;
+            report.podpings += chunk
+                .iter()
+                .flat_map(|block| &block.transactions)
+                .map(|tx| tx.podpings.len() as u64)
+                .sum::<u64>();
+
+            bus.publish(BlockBatch::new(chunk.to_vec())).await?;
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,258 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::hive::scanner::HiveBlockWithNum;
+use crate::podping::podping_iris;
+use crate::stage::BlockStage;
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use tracing::debug;
+use url::{Host, Url};
+
+/// The archive writer's name in a route's `to`
+pub(crate) const WRITER_TARGET: &str = "writer";
+/// The single `forwarder.endpoint`'s name in a route's `to`, named endpoints go by their name
+pub(crate) const FORWARDER_TARGET: &str = "forwarder";
+
+/// A feed host, or `*.` and a domain for every host under it
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum HostPattern {
+    Exact(String),
+    Subdomains(String),
+}
+
+impl HostPattern {
+    pub(crate) fn parse(pattern: &str) -> Result<HostPattern, Error> {
+        let pattern = pattern.trim().trim_end_matches('.').to_lowercase();
+        let (domain, subdomains) = match pattern.strip_prefix("*.") {
+            Some(domain) => (domain, true),
+            None => (pattern.as_str(), false),
+        };
+
+        if domain.contains('*') {
+            return Err(eyre!(
+                "{:?} isn't a host pattern, * is only allowed as *.domain",
+                pattern
+            ));
+        }
+
+        // Punycodes internationalized names the same way feed IRIs are
+        let domain = match Host::parse(domain) {
+            Ok(host) => host.to_string(),
+            Err(e) => return Err(eyre!("{:?} isn't a host name: {}", pattern, e)),
+        };
+
+        Ok(match subdomains {
+            true => HostPattern::Subdomains(domain),
+            false => HostPattern::Exact(domain),
+        })
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(pattern) => host == pattern,
+            HostPattern::Subdomains(domain) => host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+        }
+    }
+}
+
+fn iri_host(iri: &str) -> Option<String> {
+    Url::parse(iri)
+        .ok()?
+        .host_str()
+        .map(|host| host.trim_end_matches('.').to_lowercase())
+}
+
+/// The feed hosts a writer or endpoint is limited to by the routes that name it
+#[derive(Debug, Clone)]
+pub(crate) struct HostRoutes {
+    patterns: Vec<HostPattern>,
+}
+
+impl HostRoutes {
+    /// None when no route names `target`, it gets every podping
+    pub(crate) fn for_target(settings: &Settings, target: &str) -> Result<Option<HostRoutes>> {
+        let mut patterns = vec![];
+        let mut routed = false;
+
+        for route in settings.routes.iter().flatten() {
+            if !route.to.iter().any(|to| to == target) {
+                continue;
+            }
+
+            routed = true;
+            for host in &route.hosts {
+                patterns.push(HostPattern::parse(host)?);
+            }
+        }
+
+        Ok(routed.then_some(HostRoutes { patterns }))
+    }
+
+    /// Whether any of a podping's IRIs is on one of the hosts
+    pub(crate) fn matches(&self, iris: &[String]) -> bool {
+        iris.iter()
+            .filter_map(|iri| iri_host(iri))
+            .any(|host| self.patterns.iter().any(|pattern| pattern.matches(&host)))
+    }
+}
+
+/// Drops podpings the routes don't send to the archive writer
+pub(crate) struct WriterRoutes {
+    routes: HostRoutes,
+}
+
+impl WriterRoutes {
+    pub(crate) fn new(routes: HostRoutes) -> WriterRoutes {
+        WriterRoutes { routes }
+    }
+}
+
+impl BlockStage for WriterRoutes {
+    fn process_block(&mut self, mut block: HiveBlockWithNum) -> HiveBlockWithNum {
+        let mut dropped: u64 = 0;
+
+        for tx in &mut block.transactions {
+            tx.retain_podpings(|podping| {
+                let routed = self.routes.matches(&podping_iris(podping));
+
+                if !routed {
+                    dropped += 1;
+                }
+
+                routed
+            });
+        }
+
+        block.transactions.retain(|tx| !tx.podpings.is_empty());
+
+        if dropped > 0 {
+            debug!(
+                "routes: not archiving {} podpings in block {}",
+                dropped, block.block_num
+            );
+        }
+
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hive::scanner::HiveTransactionWithTxId;
+    use chrono::Utc;
+
+    fn routes(hosts: &[&str]) -> HostRoutes {
+        HostRoutes {
+            patterns: hosts
+                .iter()
+                .map(|host| HostPattern::parse(host).unwrap())
+                .collect(),
+        }
+    }
+
+    fn iris(iris: &[&str]) -> Vec<String> {
+        iris.iter().map(|iri| iri.to_string()).collect()
+    }
+
+    #[test]
+    fn host_patterns_are_normalized() {
+        assert_eq!(
+            HostPattern::parse(" Feeds.Example.COM. ").unwrap(),
+            HostPattern::Exact("feeds.example.com".to_string())
+        );
+        assert_eq!(
+            HostPattern::parse("*.bücher.example").unwrap(),
+            HostPattern::Subdomains("xn--bcher-kva.example".to_string())
+        );
+    }
+
+    #[test]
+    fn stars_are_only_allowed_in_front() {
+        assert!(HostPattern::parse("feeds.*.com").is_err());
+        assert!(HostPattern::parse("*example.com").is_err());
+        assert!(HostPattern::parse("exa mple.com").is_err());
+    }
+
+    #[test]
+    fn subdomain_patterns_only_match_below_the_domain() {
+        let pattern = HostPattern::parse("*.example.com").unwrap();
+
+        assert!(pattern.matches("feeds.example.com"));
+        assert!(pattern.matches("a.b.example.com"));
+        assert!(!pattern.matches("example.com"));
+        assert!(!pattern.matches("badexample.com"));
+    }
+
+    #[test]
+    fn any_iri_on_a_host_matches() {
+        let routes = routes(&["feeds.example.com", "*.podcasts.example"]);
+
+        assert!(routes.matches(&iris(&[
+            "https://other.example/feed",
+            "https://FEEDS.example.com./feed"
+        ])));
+        assert!(routes.matches(&iris(&["https://a.podcasts.example/feed"])));
+        assert!(!routes.matches(&iris(&["https://podcasts.example/feed", "not an iri"])));
+        assert!(!routes.matches(&[]));
+    }
+
+    #[test]
+    fn the_writer_only_keeps_routed_podpings() {
+        let podping = |iri: &str| {
+            serde_json::from_value(serde_json::json!({
+                "version": "1.0",
+                "medium": "podcast",
+                "reason": "update",
+                "iris": [iri],
+            }))
+            .unwrap()
+        };
+        let mut tx = HiveTransactionWithTxId::new(
+            "tx".to_string(),
+            0,
+            podping("https://feeds.example.com/a"),
+        );
+        tx.push(1, podping("https://other.example/b"));
+        let block = HiveBlockWithNum {
+            block_num: 1,
+            block_id: "00000001".to_string(),
+            previous: "00000000".to_string(),
+            witness: "w".to_string(),
+            timestamp: Utc::now(),
+            transactions: vec![
+                tx,
+                HiveTransactionWithTxId::new(
+                    "other".to_string(),
+                    0,
+                    podping("https://other.example/c"),
+                ),
+            ],
+            malformed: vec![],
+            replaces: None,
+        };
+
+        let routed = WriterRoutes::new(routes(&["feeds.example.com"])).process_block(block);
+
+        assert_eq!(routed.transactions.len(), 1);
+        assert_eq!(
+            routed.transactions[0]
+                .indexed_podpings()
+                .map(|(i, podping)| (i, podping_iris(podping)))
+                .collect::<Vec<_>>(),
+            [(0, vec!["https://feeds.example.com/a".to_string()])]
+        );
+    }
+}
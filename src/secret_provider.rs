@@ -0,0 +1,409 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::{SecretValue, Secrets, SecretsProvider, Settings};
+use crate::secrets::secret_env_var;
+use chrono::{DateTime, TimeDelta, Utc};
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{LazyLock, RwLock};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+/// Object storage keys a secrets provider can supply, read by the S3 credentials provider
+pub(crate) const AWS_ACCESS_KEY_ID: &str = "aws_access_key_id";
+pub(crate) const AWS_SECRET_ACCESS_KEY: &str = "aws_secret_access_key";
+pub(crate) const AWS_SESSION_TOKEN: &str = "aws_session_token";
+
+/// Every setting `secrets.values` can fill in
+pub(crate) const PROVIDED_SETTINGS: &[&str] = &[
+    "forwarder.secret",
+    "scanner.haf_database_url",
+    "writer.azure_sas_token",
+    "writer.object_storage_sse_customer_key",
+    "writer.elasticsearch_api_key",
+    "writer.elasticsearch_password",
+    "status.shutdown_report_webhook",
+    AWS_ACCESS_KEY_ID,
+    AWS_SECRET_ACCESS_KEY,
+    AWS_SESSION_TOKEN,
+];
+
+const VAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const REFRESH_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Values fetched from the secrets provider, by setting
+pub(crate) static SECRET_STORE: LazyLock<SecretStore> = LazyLock::new(SecretStore::new);
+
+#[derive(Clone)]
+struct StoredSecret {
+    value: String,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+pub(crate) struct SecretStore {
+    secrets: RwLock<HashMap<String, StoredSecret>>,
+}
+
+impl SecretStore {
+    fn new() -> SecretStore {
+        SecretStore {
+            secrets: RwLock::new(HashMap::new()),
+        }
+    }
+
+    #[cfg(feature = "object-storage")]
+    pub(crate) fn contains(&self, setting: &str) -> bool {
+        self.secrets.read().unwrap().contains_key(setting)
+    }
+
+    /// The setting's current value, and when its lease runs out
+    pub(crate) fn get(&self, setting: &str) -> Option<(String, Option<DateTime<Utc>>)> {
+        self.secrets
+            .read()
+            .unwrap()
+            .get(setting)
+            .map(|secret| (secret.value.clone(), secret.expires_at))
+    }
+
+    fn set(&self, setting: &str, secret: StoredSecret) {
+        self.secrets
+            .write()
+            .unwrap()
+            .insert(setting.to_string(), secret);
+    }
+}
+
+#[derive(Deserialize)]
+struct VaultResponse {
+    #[serde(default)]
+    lease_duration: u64,
+    data: Value,
+}
+
+enum Provider {
+    Vault {
+        http_client: Client,
+        address: String,
+        token: String,
+        namespace: Option<String>,
+    },
+    Sops {
+        file: PathBuf,
+    },
+}
+
+impl Provider {
+    fn from_settings(settings: &Secrets, provider: SecretsProvider) -> Result<Provider, Error> {
+        match provider {
+            SecretsProvider::Vault => {
+                let address = match settings
+                    .vault_address
+                    .clone()
+                    .filter(|address| !address.is_empty())
+                    .or(std::env::var("VAULT_ADDR").ok())
+                {
+                    Some(address) => address.trim_end_matches('/').to_string(),
+                    None => return Err(eyre!("secrets.vault_address or VAULT_ADDR must be set")),
+                };
+
+                let token = match settings
+                    .vault_token
+                    .clone()
+                    .filter(|token| !token.is_empty())
+                {
+                    Some(token) => token,
+                    None => secret_env_var("VAULT_TOKEN")?
+                        .ok_or_else(|| eyre!("secrets.vault_token or VAULT_TOKEN must be set"))?,
+                };
+
+                Ok(Provider::Vault {
+                    http_client: Client::builder().timeout(VAULT_HTTP_TIMEOUT).build()?,
+                    address,
+                    token,
+                    namespace: settings
+                        .vault_namespace
+                        .clone()
+                        .filter(|namespace| !namespace.is_empty()),
+                })
+            }
+            SecretsProvider::Sops => match settings.sops_file.clone() {
+                Some(file) if !file.is_empty() => Ok(Provider::Sops {
+                    file: PathBuf::from(file),
+                }),
+                _ => Err(eyre!("secrets.sops_file must be set")),
+            },
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Provider::Vault { .. } => "Vault",
+            Provider::Sops { .. } => "SOPS",
+        }
+    }
+
+    /// Everything at `path`, and how long it's leased for
+    async fn read(&self, path: &str) -> Result<(Value, Option<Duration>), Error> {
+        match self {
+            Provider::Vault {
+                http_client,
+                address,
+                token,
+                namespace,
+            } => {
+                let mut request = http_client
+                    .get(format!("{}/v1/{}", address, path.trim_start_matches('/')))
+                    .header("X-Vault-Token", token);
+                if let Some(namespace) = namespace {
+                    request = request.header("X-Vault-Namespace", namespace);
+                }
+
+                let response = request.send().await?;
+                if !response.status().is_success() {
+                    return Err(eyre!(
+                        "Vault answered {} reading {}",
+                        response.status(),
+                        path
+                    ));
+                }
+
+                let response: VaultResponse = response.json().await?;
+
+                // KV v2 nests the secret under data.data, next to its metadata
+                let data = match response.data.get("data") {
+                    Some(data) if data.is_object() && response.data.get("metadata").is_some() => {
+                        data.clone()
+                    }
+                    _ => response.data,
+                };
+                let lease = match response.lease_duration {
+                    0 => None,
+                    seconds => Some(Duration::from_secs(seconds)),
+                };
+
+                Ok((data, lease))
+            }
+            Provider::Sops { file } => {
+                let output = Command::new("sops")
+                    .arg("--decrypt")
+                    .arg("--output-type")
+                    .arg("json")
+                    .arg(file)
+                    .output()
+                    .await
+                    .map_err(|e| eyre!("Unable to run sops: {}", e))?;
+
+                if !output.status.success() {
+                    return Err(eyre!(
+                        "sops couldn't decrypt {}: {}",
+                        file.display(),
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ));
+                }
+
+                Ok((serde_json::from_slice(&output.stdout)?, None))
+            }
+        }
+    }
+}
+
+/// Looks up a dotted key, e.g. `forwarder.secret` in `{"forwarder": {"secret": ...}}`
+fn lookup(data: &Value, key: &str) -> Option<String> {
+    // A flat key with dots in it wins over the nested path
+    let value = match data.get(key) {
+        Some(value) => value,
+        None => key
+            .split('.')
+            .try_fold(data, |value, part| value.get(part))?,
+    };
+
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Fetches every value into the store, returns the shortest lease
+///
+/// Values sharing a path are read together, so the keys of dynamic credentials match.
+async fn fetch_all(provider: &Provider, values: &[SecretValue]) -> Result<Option<Duration>, Error> {
+    let mut by_path: HashMap<&str, Vec<&SecretValue>> = HashMap::new();
+    for value in values {
+        by_path
+            .entry(value.path.as_deref().unwrap_or_default())
+            .or_default()
+            .push(value);
+    }
+
+    let mut shortest_lease: Option<Duration> = None;
+
+    for (path, values) in by_path {
+        let (data, lease) = provider.read(path).await?;
+        let expires_at =
+            lease.map(|lease| Utc::now() + TimeDelta::from_std(lease).unwrap_or_default());
+
+        for value in values {
+            let secret = lookup(&data, &value.key).ok_or_else(|| {
+                eyre!(
+                    "{} has no {} for {}",
+                    match path.is_empty() {
+                        true => provider.name(),
+                        false => path,
+                    },
+                    value.key,
+                    value.setting
+                )
+            })?;
+
+            SECRET_STORE.set(
+                &value.setting,
+                StoredSecret {
+                    value: secret,
+                    expires_at,
+                },
+            );
+        }
+
+        if let Some(lease) = lease {
+            shortest_lease = Some(shortest_lease.map_or(lease, |shortest| shortest.min(lease)));
+        }
+    }
+
+    Ok(shortest_lease)
+}
+
+/// Copies fetched values into the settings that are read at startup
+fn apply(settings: &mut Settings) {
+    let setting_fields = [
+        ("forwarder.secret", &mut settings.forwarder.secret),
+        (
+            "scanner.haf_database_url",
+            &mut settings.scanner.haf_database_url,
+        ),
+        (
+            "writer.azure_sas_token",
+            &mut settings.writer.azure_sas_token,
+        ),
+        (
+            "writer.object_storage_sse_customer_key",
+            &mut settings.writer.object_storage_sse_customer_key,
+        ),
+        (
+            "writer.elasticsearch_api_key",
+            &mut settings.writer.elasticsearch_api_key,
+        ),
+        (
+            "writer.elasticsearch_password",
+            &mut settings.writer.elasticsearch_password,
+        ),
+        (
+            "status.shutdown_report_webhook",
+            &mut settings.status.shutdown_report_webhook,
+        ),
+    ];
+
+    for (setting, field) in setting_fields {
+        if let Some((value, _)) = SECRET_STORE.get(setting) {
+            *field = Some(value);
+        }
+    }
+}
+
+/// Fetches `secrets.values` from the configured provider into the settings
+///
+/// Keeps fetching them again in the background before their leases run out, or every
+/// `refresh_interval`.
+pub(crate) async fn resolve(settings: &mut Settings) -> Result<(), Error> {
+    let provider = match settings.secrets.provider {
+        Some(provider) => Provider::from_settings(&settings.secrets, provider)?,
+        None => return Ok(()),
+    };
+
+    let values = settings.secrets.values.take().unwrap_or_default();
+    if values.is_empty() {
+        warn!(
+            "A {} secrets provider is set but secrets.values is empty",
+            provider.name()
+        );
+        return Ok(());
+    }
+
+    let lease = fetch_all(&provider, &values)
+        .await
+        .map_err(|e| e.wrap_err(format!("Error fetching secrets from {}", provider.name())))?;
+    info!(
+        "Fetched {} secret(s) from {}",
+        values.len(),
+        provider.name()
+    );
+
+    apply(settings);
+
+    let refresh_interval = settings.secrets.refresh_interval;
+    if next_refresh(lease, refresh_interval).is_some() {
+        tokio::spawn(refresh_periodically(
+            provider,
+            values,
+            lease,
+            refresh_interval,
+        ));
+    }
+
+    Ok(())
+}
+
+/// When to fetch the values again, at two thirds of the shortest lease or `refresh_interval`
+fn next_refresh(lease: Option<Duration>, refresh_interval: Option<Duration>) -> Option<Duration> {
+    match (lease.map(|lease| lease * 2 / 3), refresh_interval) {
+        (Some(lease), Some(interval)) => Some(lease.min(interval)),
+        (Some(delay), None) | (None, Some(delay)) => Some(delay),
+        (None, None) => None,
+    }
+}
+
+async fn refresh_periodically(
+    provider: Provider,
+    values: Vec<SecretValue>,
+    lease: Option<Duration>,
+    refresh_interval: Option<Duration>,
+) {
+    let mut delay = next_refresh(lease, refresh_interval);
+
+    while let Some(wait) = delay {
+        sleep(wait).await;
+
+        delay = match fetch_all(&provider, &values).await {
+            Ok(lease) => {
+                debug!("Refreshed secrets from {}", provider.name());
+                next_refresh(lease, refresh_interval)
+            }
+            Err(e) => {
+                warn!(
+                    "Error refreshing secrets from {}, trying again in {:?}: {:#}",
+                    provider.name(),
+                    REFRESH_RETRY_INTERVAL,
+                    e
+                );
+                Some(REFRESH_RETRY_INTERVAL)
+            }
+        };
+    }
+}
@@ -0,0 +1,90 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use config::{Config, ConfigError};
+use std::env;
+
+/// Settings that can be read from a file named by `<setting>_file` instead, e.g.
+/// `forwarder.secret_file` or PODPINGD__FORWARDER__SECRET_FILE
+const SECRET_SETTINGS: &[&str] = &[
+    "forwarder.secret",
+    "scanner.haf_database_url",
+    "writer.azure_sas_token",
+    "writer.object_storage_sse_customer_key",
+    "writer.elasticsearch_api_key",
+    "writer.elasticsearch_password",
+    "status.shutdown_report_webhook",
+    "secrets.vault_token",
+];
+
+/// A secret mounted as a file, without the trailing newline most tools write
+pub(crate) fn read_secret_file(path: &str) -> std::io::Result<String> {
+    Ok(std::fs::read_to_string(path)?
+        .trim_end_matches(['\r', '\n'])
+        .to_string())
+}
+
+fn non_empty_env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Whether the secret is in `name` or a file named by `<name>_FILE`
+pub(crate) fn secret_env_var_is_set(name: &str) -> bool {
+    non_empty_env_var(name).is_some() || non_empty_env_var(&format!("{}_FILE", name)).is_some()
+}
+
+/// The secret in environment variable `name`, or in the file `<name>_FILE` names
+///
+/// Docker and Kubernetes secrets are mounted as files, the variable wins if both are set.
+pub(crate) fn secret_env_var(name: &str) -> Result<Option<String>, Error> {
+    if let Some(value) = non_empty_env_var(name) {
+        return Ok(Some(value));
+    }
+
+    match non_empty_env_var(&format!("{}_FILE", name)) {
+        Some(path) => match read_secret_file(&path) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => Err(eyre!("Unable to read {}_FILE {}: {}", name, path, e)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Fills in every secret setting that has a `_file` set with the file's contents
+pub(crate) fn apply_secret_files(config: Config) -> Result<Config, ConfigError> {
+    let mut builder = Config::builder().add_source(config.clone());
+
+    for setting in SECRET_SETTINGS {
+        let path = match config.get_string(&format!("{}_file", setting)) {
+            Ok(path) if !path.is_empty() => path,
+            _ => continue,
+        };
+
+        if config
+            .get_string(setting)
+            .is_ok_and(|value| !value.is_empty())
+        {
+            return Err(ConfigError::Message(format!(
+                "{} and {}_file are both set, only set one",
+                setting, setting
+            )));
+        }
+
+        let value = read_secret_file(&path).map_err(|e| {
+            ConfigError::Message(format!("Unable to read {}_file {}: {}", setting, path, e))
+        })?;
+        builder = builder.set_override(*setting, value)?;
+    }
+
+    builder.build()
+}
@@ -0,0 +1,139 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+#[cfg(unix)]
+pub(crate) use unix::daemonize;
+#[cfg(windows)]
+pub(crate) use windows::run_windows_service;
+
+#[cfg(unix)]
+mod unix {
+    use crate::config::Settings;
+    use color_eyre::eyre::{eyre, Error};
+    use color_eyre::Result;
+    use daemonize::Daemonize;
+    use std::fs::OpenOptions;
+    use std::path::Path;
+
+    /// Forks into the background, keeping the working directory so relative config and data
+    /// paths still resolve
+    ///
+    /// stdout and stderr go to `log.file` when it's set, so panics end up next to the logs.
+    pub(crate) fn daemonize(settings: &Settings, pid_file: Option<&Path>) -> Result<(), Error> {
+        let mut daemon = Daemonize::new().working_directory(std::env::current_dir()?);
+
+        if let Some(pid_file) = pid_file {
+            daemon = daemon.pid_file(pid_file);
+        }
+
+        match settings.log.file.as_deref().filter(|file| !file.is_empty()) {
+            Some(file) => {
+                let stdout = OpenOptions::new().create(true).append(true).open(file)?;
+                let stderr = stdout.try_clone()?;
+
+                daemon = daemon.stdout(stdout).stderr(stderr);
+            }
+            None => eprintln!("log.file isn't set, the daemon's log output will be discarded"),
+        }
+
+        daemon
+            .start()
+            .map_err(|e| eyre!("Unable to daemonize: {}", e))
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use crate::shutdown::SERVICE_STOP;
+    use crate::{config, run_on_runtime, Cli};
+    use clap::Parser;
+    use color_eyre::eyre::Error;
+    use color_eyre::Result;
+    use std::ffi::OsString;
+    use std::time::Duration;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    const SERVICE_NAME: &str = "podpingd";
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Hands the process to the Service Control Manager, returning once the service stops
+    pub(crate) fn run_windows_service() -> Result<(), Error> {
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)?;
+
+        Ok(())
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        if let Err(e) = run_service() {
+            // Logging may not be set up, the event log has the exit code
+            eprintln!("podpingd service failed: {:#}", e);
+        }
+    }
+
+    fn service_status(state: ServiceState, exit_code: ServiceExitCode) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: match state {
+                ServiceState::Running => {
+                    ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+                }
+                _ => ServiceControlAccept::empty(),
+            },
+            exit_code,
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        }
+    }
+
+    fn run_service() -> Result<(), Error> {
+        let status_handle =
+            service_control_handler::register(SERVICE_NAME, |control| match control {
+                ServiceControl::Stop | ServiceControl::Shutdown => {
+                    SERVICE_STOP.notify_one();
+                    ServiceControlHandlerResult::NoError
+                }
+                ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+                _ => ServiceControlHandlerResult::NotImplemented,
+            })?;
+
+        status_handle.set_service_status(service_status(
+            ServiceState::Running,
+            ServiceExitCode::NO_ERROR,
+        ))?;
+
+        // Services start in System32, config and data paths are relative to the executable
+        if let Some(dir) = std::env::current_exe()?.parent() {
+            std::env::set_current_dir(dir)?;
+        }
+
+        let cli = Cli::parse();
+        let result =
+            config::load_config(&cli.command()).and_then(|settings| run_on_runtime(cli, settings));
+
+        status_handle.set_service_status(service_status(
+            ServiceState::Stopped,
+            match result {
+                Ok(_) => ServiceExitCode::NO_ERROR,
+                Err(_) => ServiceExitCode::ServiceSpecific(1),
+            },
+        ))?;
+
+        result
+    }
+}
@@ -0,0 +1,162 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::status::SYNC_STATUS;
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::Serialize;
+use std::collections::BTreeMap;
+#[cfg(all(windows, feature = "service"))]
+use std::sync::LazyLock;
+use std::time::Duration;
+#[cfg(all(windows, feature = "service"))]
+use tokio::sync::Notify;
+use tracing::{error, info};
+
+const SHUTDOWN_WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ShutdownReason {
+    /// A bounded scan, replay or verify finished
+    Completed,
+    Signal,
+    Error,
+    Panic,
+}
+
+/// Summary of a run, emitted once as podpingd exits
+#[derive(Debug, Serialize)]
+pub(crate) struct ShutdownReport {
+    pub(crate) reason: ShutdownReason,
+    /// The signal name, error or panic message
+    pub(crate) detail: Option<String>,
+    pub(crate) version: &'static str,
+    pub(crate) started_at: DateTime<Utc>,
+    pub(crate) stopped_at: DateTime<Utc>,
+    pub(crate) duration_seconds: u64,
+    pub(crate) last_committed_block: Option<u64>,
+    pub(crate) events_written: u64,
+    pub(crate) writer_errors: BTreeMap<&'static str, u64>,
+}
+
+impl ShutdownReport {
+    pub(crate) fn new(reason: ShutdownReason, detail: Option<String>) -> ShutdownReport {
+        let status = SYNC_STATUS.snapshot();
+
+        ShutdownReport {
+            reason,
+            detail,
+            version: status.version,
+            started_at: status.started_at,
+            stopped_at: status.updated_at,
+            duration_seconds: status.uptime_seconds,
+            last_committed_block: status.written_block,
+            events_written: status.writers.values().map(|health| health.writes).sum(),
+            writer_errors: status
+                .writers
+                .iter()
+                .map(|(name, health)| (*name, health.errors))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn log(&self) {
+        let json = serde_json::to_string(self).unwrap_or_default();
+
+        match self.reason {
+            ShutdownReason::Completed | ShutdownReason::Signal => {
+                info!("Shutdown report: {}", json)
+            }
+            ShutdownReason::Error | ShutdownReason::Panic => {
+                error!("Shutdown report: {}", json)
+            }
+        }
+    }
+
+    /// Logs the report, then POSTs it to `webhook` if one is set
+    ///
+    /// A failed POST is only logged, it mustn't hold up the exit.
+    pub(crate) async fn emit(&self, webhook: Option<&str>) {
+        self.log();
+
+        let webhook = match webhook.filter(|webhook| !webhook.is_empty()) {
+            Some(webhook) => webhook,
+            None => return,
+        };
+
+        let result = Client::new()
+            .post(webhook)
+            .timeout(SHUTDOWN_WEBHOOK_TIMEOUT)
+            .json(self)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        if let Err(e) = result {
+            error!("Error sending the shutdown report to {}: {}", webhook, e);
+        }
+    }
+}
+
+/// Logs a shutdown report before the existing panic hook runs
+///
+/// Only panics on the main thread end the process, ones in spawned tasks are caught by tokio
+/// (or by the config reload) so they don't get a report. Only the log is written, there's no
+/// runtime to send the webhook from a panic.
+pub(crate) fn install_panic_report() {
+    let previous_hook = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |panic_info| {
+        if std::thread::current().name() == Some("main") {
+            ShutdownReport::new(ShutdownReason::Panic, Some(panic_info.to_string())).log();
+        }
+        previous_hook(panic_info);
+    }));
+}
+
+/// Resolves with the signal's name once podpingd is asked to stop
+#[cfg(unix)]
+pub(crate) async fn shutdown_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            error!("Unable to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+/// Set by the Windows service's control handler when the Service Control Manager stops it
+#[cfg(all(windows, feature = "service"))]
+pub(crate) static SERVICE_STOP: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+#[cfg(all(windows, feature = "service"))]
+pub(crate) async fn shutdown_signal() -> &'static str {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "Ctrl-C",
+        _ = SERVICE_STOP.notified() => "Service stop",
+    }
+}
+
+#[cfg(not(any(unix, all(windows, feature = "service"))))]
+pub(crate) async fn shutdown_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "Ctrl-C"
+}
@@ -0,0 +1,151 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::event_bus::EventBus;
+use crate::fixtures::{fixture_blocks, read_fixture};
+use crate::hive::podping_ids::PodpingIds;
+use crate::hive::scanner::{
+    block_response_to_hive_block, send_block, BlockBatch, HiveBlockWithNum,
+};
+use crate::source::source::{Source, SourceHead};
+use crate::status::SYNC_STATUS;
+use color_eyre::eyre::Error;
+use color_eyre::{Report, Result};
+use std::path::PathBuf;
+use tracing::info;
+
+// Blocks per catch-up batch, like an RPC batch
+const FIXTURE_BATCH_SIZE: usize = 100;
+
+/// Blocks saved by `podpingd capture`, parsed like a node's responses
+///
+/// Only the blocks with a fixture are sent on, in order, ending after the last one.
+pub(crate) struct FixtureSource {
+    directory: PathBuf,
+    blocks: Vec<u64>,
+    podping_ids: PodpingIds,
+}
+
+impl FixtureSource {
+    async fn read_block(&self, block_num: u64) -> Result<HiveBlockWithNum, Error> {
+        let response = read_fixture(&self.directory, block_num).await?;
+
+        Ok(block_response_to_hive_block(
+            block_num,
+            &self.podping_ids,
+            response,
+        ))
+    }
+
+    fn blocks_between(&self, start_block: u64, end_block: Option<u64>) -> &[u64] {
+        let start = self.blocks.partition_point(|&block| block < start_block);
+        let end = match end_block {
+            Some(end_block) => self.blocks.partition_point(|&block| block <= end_block),
+            None => self.blocks.len(),
+        };
+
+        &self.blocks[start..end.max(start)]
+    }
+}
+
+impl Source for FixtureSource {
+    async fn new(settings: &Settings) -> Result<FixtureSource, Report> {
+        let directory = PathBuf::from(settings.source.fixtures_path.as_deref().unwrap_or(""));
+        let blocks = fixture_blocks(&directory).await?;
+
+        info!(
+            "Reading {} block fixtures from {}",
+            blocks.len(),
+            directory.display()
+        );
+
+        Ok(FixtureSource {
+            directory,
+            blocks,
+            podping_ids: PodpingIds::from_settings(&settings.scanner)?,
+        })
+    }
+
+    async fn head(&self) -> Result<Option<SourceHead>, Error> {
+        let (last_block, time) = match self.blocks.last() {
+            Some(&last_block) => (last_block, self.read_block(last_block).await?.timestamp),
+            // Nothing to catch up on or follow
+            None => Default::default(),
+        };
+
+        Ok(Some(SourceHead {
+            head_block: last_block,
+            time,
+            last_block,
+            // Every fixture unless told otherwise
+            default_start_block: 0,
+        }))
+    }
+
+    async fn catch_up(
+        &self,
+        start_block: u64,
+        end_block: u64,
+        bus: EventBus<BlockBatch>,
+    ) -> Result<(), Error> {
+        for chunk in self
+            .blocks_between(start_block, Some(end_block))
+            .chunks(FIXTURE_BATCH_SIZE)
+        {
+            let mut blocks = Vec::with_capacity(chunk.len());
+
+            for &block_num in chunk {
+                blocks.push(self.read_block(block_num).await?);
+            }
+
+            if let Some(last) = blocks.last() {
+                SYNC_STATUS.record_scanned_block(last.block_num, last.timestamp);
+            }
+
+            send_block(&bus, BlockBatch::new(blocks)).await;
+        }
+
+        Ok(())
+    }
+
+    async fn follow(
+        &self,
+        start_block: u64,
+        end_block: Option<u64>,
+        bus: EventBus<HiveBlockWithNum>,
+    ) -> Result<(), Error> {
+        for &block_num in self.blocks_between(start_block, end_block) {
+            let block = self.read_block(block_num).await?;
+
+            SYNC_STATUS.record_scanned_block(block.block_num, block.timestamp);
+            send_block(&bus, block).await;
+        }
+
+        info!("Reached the end of the fixtures");
+
+        Ok(())
+    }
+
+    async fn fetch_blocks(
+        &self,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<Vec<HiveBlockWithNum>, Error> {
+        let mut blocks = vec![];
+
+        for &block_num in self.blocks_between(start_block, Some(end_block)) {
+            blocks.push(self.read_block(block_num).await?);
+        }
+
+        Ok(blocks)
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::{ScannerCatchupSource, ScannerFollow, Settings, SourceType};
+use crate::event_bus::EventBus;
+#[cfg(feature = "haf")]
+use crate::hive::haf::{HafClient, DEFAULT_HAF_BATCH_SIZE};
+use crate::hive::jsonrpc::client::{JsonRpcClient, RpcClientOptions};
+use crate::hive::network;
+use crate::hive::node_scores;
+use crate::hive::podping_ids::PodpingIds;
+use crate::hive::posting_authority;
+use crate::hive::scanner;
+use crate::hive::scanner::{BlockBatch, CatchupSource, HiveBlockWithNum};
+use crate::hive::throttle::{CatchupBatchSize, CatchupMemory, CatchupThrottle};
+use crate::source::source::{Source, SourceHead};
+#[cfg(not(feature = "haf"))]
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::Error;
+use color_eyre::{Report, Result};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::info;
+
+/// Hive itself, followed over JSON-RPC and caught up over JSON-RPC or from a HAF database
+pub(crate) struct HiveSource<J>
+where
+    J: JsonRpcClient + Send,
+{
+    json_rpc_client: Arc<Mutex<J>>,
+    catchup_sources: Vec<CatchupSource<J>>,
+    catchup_throttle: Arc<CatchupThrottle>,
+    catchup_batch_size: CatchupBatchSize,
+    catchup_memory: Arc<CatchupMemory>,
+    follow: ScannerFollow,
+    podping_ids: PodpingIds,
+}
+
+impl<J: JsonRpcClient + Send + 'static> Source for HiveSource<J> {
+    async fn new(settings: &Settings) -> Result<HiveSource<J>, Report> {
+        let rpc_options = RpcClientOptions::from_settings(settings)?;
+
+        network::check_rpc_nodes::<J>(&settings.scanner, &rpc_options).await?;
+
+        // Rank the nodes before any client picks one
+        if settings.scanner.rpc_node_benchmark.unwrap_or(true) {
+            node_scores::benchmark::<J>(&settings.scanner.rpc_nodes, &rpc_options).await;
+
+            tokio::spawn(node_scores::benchmark_periodically::<J>(
+                settings.scanner.rpc_nodes.clone(),
+                rpc_options.clone(),
+                settings
+                    .scanner
+                    .rpc_node_benchmark_interval
+                    .unwrap_or(node_scores::DEFAULT_BENCHMARK_INTERVAL),
+            ));
+        }
+
+        posting_authority::start::<J>(settings, &rpc_options).await?;
+
+        let json_rpc_client = Arc::new(Mutex::new(J::new(
+            settings.scanner.rpc_nodes.clone(),
+            0,
+            rpc_options.clone(),
+        )?));
+
+        let catchup_concurrency = settings.scanner.catchup_concurrency.unwrap_or(1).max(1);
+        let catchup_source = match settings.source.type_ {
+            Some(SourceType::Haf) => ScannerCatchupSource::Haf,
+            _ => settings
+                .scanner
+                .catchup_source
+                .unwrap_or(ScannerCatchupSource::Rpc),
+        };
+        let (catchup_sources, catchup_batch_size) = match catchup_source {
+            #[cfg(feature = "haf")]
+            ScannerCatchupSource::Haf => {
+                let database_url = settings.scanner.haf_database_url.as_deref().unwrap_or("");
+                let haf_batch_size = settings
+                    .scanner
+                    .haf_batch_size
+                    .unwrap_or(DEFAULT_HAF_BATCH_SIZE);
+
+                info!("Catching up from the HAF database");
+
+                (
+                    (0..catchup_concurrency)
+                        .map(|_| CatchupSource::Haf(Arc::new(HafClient::new(database_url))))
+                        .collect(),
+                    // A query covers far more blocks than an RPC batch, and takes about as long
+                    CatchupBatchSize::new(Some(haf_batch_size), Some(haf_batch_size), Some(false)),
+                )
+            }
+            #[cfg(not(feature = "haf"))]
+            ScannerCatchupSource::Haf => {
+                return Err(eyre!(
+                    "Catching up from HAF needs podpingd built with the haf feature"
+                ))
+            }
+            ScannerCatchupSource::Rpc => {
+                // Extra catch-up clients start on different nodes to spread the load
+                let mut catchup_sources = vec![CatchupSource::Rpc(json_rpc_client.clone())];
+                for i in 1..catchup_concurrency {
+                    catchup_sources.push(CatchupSource::Rpc(Arc::new(Mutex::new(J::new(
+                        settings.scanner.rpc_nodes.clone(),
+                        i,
+                        rpc_options.clone(),
+                    )?))));
+                }
+
+                (
+                    catchup_sources,
+                    CatchupBatchSize::new(
+                        settings.scanner.catchup_batch_size,
+                        settings.scanner.catchup_max_batch_size,
+                        settings.scanner.catchup_adaptive_batch_size,
+                    ),
+                )
+            }
+        };
+
+        let catchup_throttle = Arc::new(CatchupThrottle::new(
+            settings.scanner.catchup_max_blocks_per_second,
+        ));
+
+        if let Some(max_blocks_per_second) = catchup_throttle.max_blocks_per_second() {
+            info!(
+                "Catch-up is throttled to {} blocks per second",
+                max_blocks_per_second
+            );
+        }
+
+        let follow = settings.scanner.follow.unwrap_or(ScannerFollow::Head);
+        info!("Following {:?} blocks", follow);
+
+        Ok(HiveSource {
+            json_rpc_client,
+            catchup_sources,
+            catchup_throttle,
+            catchup_batch_size,
+            catchup_memory: CatchupMemory::new(settings.scanner.catchup_memory_limit_mb),
+            follow,
+            podping_ids: PodpingIds::from_settings(&settings.scanner)?,
+        })
+    }
+
+    async fn head(&self) -> Result<Option<SourceHead>, Error> {
+        let dynamic_global_properties =
+            scanner::get_dynamic_global_properties(self.json_rpc_client.clone()).await?;
+
+        let last_block = scanner::last_followed_block(&dynamic_global_properties, self.follow);
+
+        // The chain's history is only scanned when asked for
+        Ok(Some(SourceHead {
+            head_block: dynamic_global_properties.head_block_number,
+            time: dynamic_global_properties.time,
+            last_block,
+            default_start_block: last_block,
+        }))
+    }
+
+    async fn catch_up(
+        &self,
+        start_block: u64,
+        end_block: u64,
+        bus: EventBus<BlockBatch>,
+    ) -> Result<(), Error> {
+        scanner::catchup_chain(
+            start_block,
+            end_block,
+            bus,
+            self.catchup_sources.clone(),
+            self.catchup_throttle.clone(),
+            self.catchup_batch_size.clone(),
+            self.catchup_memory.clone(),
+            self.podping_ids.clone(),
+        )
+        .await
+    }
+
+    async fn follow(
+        &self,
+        start_block: u64,
+        end_block: Option<u64>,
+        bus: EventBus<HiveBlockWithNum>,
+    ) -> Result<(), Error> {
+        scanner::scan_chain(
+            start_block,
+            end_block,
+            self.follow,
+            bus,
+            self.json_rpc_client.clone(),
+            self.podping_ids.clone(),
+        )
+        .await
+    }
+
+    /// Always over RPC, without the catch-up throttle or the scan status
+    async fn fetch_blocks(
+        &self,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<Vec<HiveBlockWithNum>, Error> {
+        scanner::fetch_block_chunk(
+            self.json_rpc_client.clone(),
+            self.podping_ids.clone(),
+            (start_block..=end_block).collect(),
+        )
+        .await
+    }
+}
@@ -0,0 +1,96 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::event_bus::EventBus;
+use crate::hive::scanner::{send_block, BlockBatch, HiveBlockWithNum};
+use crate::replay::{archive_days, last_block, publish_archive, ReplaySource};
+use crate::source::source::{Source, SourceHead};
+use crate::status::SYNC_STATUS;
+use color_eyre::eyre::Error;
+use color_eyre::{Report, Result};
+use std::sync::Arc;
+use tracing::info;
+
+/// An existing podpingd archive, read back as if its blocks were coming off the chain
+///
+/// Ends at the archive's newest block rather than waiting for more.
+pub(crate) struct ArchiveSource {
+    archive: Arc<ReplaySource>,
+}
+
+impl Source for ArchiveSource {
+    async fn new(settings: &Settings) -> Result<ArchiveSource, Report> {
+        let replay_path = settings.source.replay_path.as_deref().unwrap_or("");
+        info!("Reading podpings from the archive at {}", replay_path);
+
+        Ok(ArchiveSource {
+            archive: Arc::new(ReplaySource::from_arg(settings, replay_path)),
+        })
+    }
+
+    async fn head(&self) -> Result<Option<SourceHead>, Error> {
+        // An empty archive has nothing to catch up on or follow
+        let (block_num, time) = last_block(&self.archive).await?.unwrap_or_default();
+
+        Ok(Some(SourceHead {
+            head_block: block_num,
+            time,
+            last_block: block_num,
+            // The whole archive unless told otherwise
+            default_start_block: 0,
+        }))
+    }
+
+    async fn catch_up(
+        &self,
+        start_block: u64,
+        end_block: u64,
+        bus: EventBus<BlockBatch>,
+    ) -> Result<(), Error> {
+        let report = publish_archive(
+            self.archive.clone(),
+            Some(start_block),
+            Some(end_block),
+            &bus,
+        )
+        .await?;
+
+        info!(
+            "Read {} podpings from {} archived blocks",
+            report.podpings, report.blocks
+        );
+
+        Ok(())
+    }
+
+    async fn follow(
+        &self,
+        start_block: u64,
+        end_block: Option<u64>,
+        bus: EventBus<HiveBlockWithNum>,
+    ) -> Result<(), Error> {
+        let mut days = archive_days(self.archive.clone(), Some(start_block), end_block);
+
+        while let Some(day) = days.recv().await {
+            let (_, blocks) = day?;
+
+            for block in blocks {
+                SYNC_STATUS.record_scanned_block(block.block_num, block.timestamp);
+                send_block(&bus, block).await;
+            }
+        }
+
+        info!("Reached the end of the archive");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::event_bus::EventBus;
+use crate::hive::scanner::{send_block, BlockBatch, HiveBlockWithNum, HiveTransactionWithTxId};
+use crate::source::source::{Source, SourceHead};
+use crate::status::SYNC_STATUS;
+use chrono::Utc;
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::{Report, Result};
+use podping_schemas::org::podcastindex::podping::podping_json::Podping;
+use rand::Rng;
+use serde_json::json;
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, info};
+
+const DEFAULT_PODPINGS_PER_SECOND: f64 = 5.0;
+const DEFAULT_BLOCK_INTERVAL: Duration = Duration::from_secs(3);
+const DEFAULT_BURST_PODPINGS: u64 = 1000;
+const DEFAULT_FEEDS: u64 = 10_000;
+// Simulated podpings and bursts are spread over chain time, a block every 3 seconds
+const HIVE_BLOCK_SECONDS: f64 = 3.0;
+// podping.cloud batches several feeds into one podping
+const MAX_IRIS_PER_PODPING: usize = 5;
+const MEDIUMS: [&str; 7] = [
+    "podcast",
+    "music",
+    "video",
+    "film",
+    "audiobook",
+    "newsletter",
+    "blog",
+];
+const REASONS: [&str; 3] = ["update", "live", "liveEnd"];
+
+/// Made up podpings in made up blocks, for load testing the writers without a Hive connection
+///
+/// Most are v1.0 podcast updates like mainnet's, with some of every other schema version.
+pub(crate) struct SimulatorSource {
+    podpings_per_block: f64,
+    block_interval: Duration,
+    burst_every_blocks: Option<u64>,
+    burst_podpings: u64,
+    feeds: u64,
+}
+
+fn random_hex(rng: &mut impl Rng, len: usize) -> String {
+    (0..len)
+        .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap())
+        .collect()
+}
+
+/// A podping for up to MAX_IRIS_PER_PODPING of `feeds` made up feeds, in a random schema version
+fn simulated_podping(rng: &mut impl Rng, feeds: u64) -> Option<Podping> {
+    let iris = (0..rng.gen_range(1..=MAX_IRIS_PER_PODPING))
+        .map(|_| {
+            format!(
+                "https://feeds.example.com/{}/feed.xml",
+                rng.gen_range(0..feeds)
+            )
+        })
+        .collect::<Vec<_>>();
+    let medium = MEDIUMS[rng.gen_range(0..MEDIUMS.len())];
+    let reason = REASONS[rng.gen_range(0..REASONS.len())];
+
+    let podping = match rng.gen_range(0..100) {
+        0..=1 => json!({"url": iris[0]}),
+        2..=3 => json!({"urls": iris}),
+        4..=5 => json!({
+            "version": "0.2",
+            "num_urls": iris.len(),
+            "reason": "feed_update",
+            "urls": iris,
+        }),
+        6..=7 => json!({
+            "version": "0.3",
+            "medium": "podcast",
+            "reason": "update",
+            "iris": iris,
+        }),
+        8..=19 => json!({
+            "version": "1.1",
+            "medium": medium,
+            "reason": reason,
+            "iris": iris,
+            "timestampNs": Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+            "sessionId": rng.gen::<u64>(),
+        }),
+        20..=29 => json!({
+            "version": "1.0",
+            "medium": medium,
+            "reason": reason,
+            "iris": iris,
+        }),
+        _ => json!({
+            "version": "1.0",
+            "medium": "podcast",
+            "reason": "update",
+            "iris": iris,
+        }),
+    };
+
+    serde_json::from_value(podping).ok()
+}
+
+impl SimulatorSource {
+    /// How many podpings go in `block_num`, about podpings_per_block on average plus any burst
+    fn podping_count(&self, rng: &mut impl Rng, block_num: u64) -> u64 {
+        let mut count = (rng.gen::<f64>() * 2.0 * self.podpings_per_block).round() as u64;
+
+        if self
+            .burst_every_blocks
+            .is_some_and(|burst_every_blocks| block_num.is_multiple_of(burst_every_blocks))
+        {
+            debug!(
+                "Simulating a burst of {} podpings in block {}",
+                self.burst_podpings, block_num
+            );
+            count += self.burst_podpings;
+        }
+
+        count
+    }
+
+    fn simulated_block(&self, block_num: u64, previous: String) -> HiveBlockWithNum {
+        let mut rng = rand::thread_rng();
+        let count = self.podping_count(&mut rng, block_num);
+
+        HiveBlockWithNum {
+            block_num,
+            // Hive block ids start with the block number
+            block_id: format!("{:08x}{}", block_num as u32, random_hex(&mut rng, 32)),
+            previous,
+            witness: "simulator".to_string(),
+            timestamp: Utc::now(),
+            transactions: (0..count)
+                .filter_map(|_| {
+                    Some(HiveTransactionWithTxId::new(
+                        random_hex(&mut rng, 40),
+                        0,
+                        simulated_podping(&mut rng, self.feeds)?,
+                    ))
+                })
+                .collect(),
+            malformed: vec![],
+            replaces: None,
+        }
+    }
+}
+
+impl Source for SimulatorSource {
+    async fn new(settings: &Settings) -> Result<SimulatorSource, Report> {
+        let source = &settings.source;
+        let podpings_per_second = source
+            .simulator_podpings_per_second
+            .unwrap_or(DEFAULT_PODPINGS_PER_SECOND);
+        let block_interval = source
+            .simulator_block_interval
+            .unwrap_or(DEFAULT_BLOCK_INTERVAL);
+
+        info!(
+            "Simulating {} podpings per second, a block every {:?}",
+            podpings_per_second, block_interval
+        );
+
+        Ok(SimulatorSource {
+            podpings_per_block: podpings_per_second * HIVE_BLOCK_SECONDS,
+            block_interval,
+            burst_every_blocks: source.simulator_burst_interval.map(|burst_interval| {
+                ((burst_interval.as_secs_f64() / HIVE_BLOCK_SECONDS).round() as u64).max(1)
+            }),
+            burst_podpings: source
+                .simulator_burst_podpings
+                .unwrap_or(DEFAULT_BURST_PODPINGS),
+            feeds: source.simulator_feeds.unwrap_or(DEFAULT_FEEDS).max(1),
+        })
+    }
+
+    async fn head(&self) -> Result<Option<SourceHead>, Error> {
+        Ok(None)
+    }
+
+    async fn catch_up(
+        &self,
+        _start_block: u64,
+        _end_block: u64,
+        _bus: EventBus<BlockBatch>,
+    ) -> Result<(), Error> {
+        Err(eyre!("The simulator has no history to catch up on"))
+    }
+
+    /// Makes up a block every block_interval, or as fast as the writers take them if it's zero
+    async fn follow(
+        &self,
+        start_block: u64,
+        end_block: Option<u64>,
+        bus: EventBus<HiveBlockWithNum>,
+    ) -> Result<(), Error> {
+        let mut block_num = start_block.max(1);
+        let mut previous = format!("{:08x}{}", block_num as u32 - 1, "0".repeat(32));
+        let mut next_block_at = Instant::now();
+
+        loop {
+            if end_block.is_some_and(|end_block| block_num > end_block) {
+                info!("Reached end block {}", block_num - 1);
+                return Ok(());
+            }
+
+            sleep_until(next_block_at).await;
+            next_block_at += self.block_interval;
+
+            let block = self.simulated_block(block_num, previous);
+            previous = block.block_id.clone();
+
+            SYNC_STATUS.record_scanned_block(block.block_num, block.timestamp);
+            send_block(&bus, block).await;
+
+            block_num += 1;
+        }
+    }
+}
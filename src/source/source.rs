@@ -0,0 +1,82 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::event_bus::EventBus;
+use crate::hive::scanner::{BlockBatch, HiveBlockWithNum};
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::Error;
+use color_eyre::{Report, Result};
+
+/// Where a source's chain is up to
+#[derive(Debug, Clone)]
+pub(crate) struct SourceHead {
+    /// The newest block there is and its time, to count blocks from a datetime
+    pub(crate) head_block: u64,
+    pub(crate) time: DateTime<Utc>,
+    /// The newest block the source would send, e.g. the last irreversible one
+    pub(crate) last_block: u64,
+    /// Where to start with nothing persisted or configured
+    pub(crate) default_start_block: u64,
+}
+
+/// Where blocks come from, the other end of the pipeline from a `Writer`
+pub(crate) trait Source {
+    async fn new(settings: &Settings) -> Result<Self, Report>
+    where
+        Self: Sized;
+    /// How far the source goes right now
+    ///
+    /// `None` for a source that can only be joined where it is, which is followed without
+    /// catching up first.
+    fn head(&self) -> impl std::future::Future<Output = Result<Option<SourceHead>, Error>> + Send;
+    /// Sends `start_block` to `end_block` on in batches
+    fn catch_up(
+        &self,
+        start_block: u64,
+        end_block: u64,
+        bus: EventBus<BlockBatch>,
+    ) -> impl std::future::Future<Output = Result<(), Error>> + Send;
+    /// Sends blocks on one at a time from `start_block`, until `end_block` or the source ends
+    fn follow(
+        &self,
+        start_block: u64,
+        end_block: Option<u64>,
+        bus: EventBus<HiveBlockWithNum>,
+    ) -> impl std::future::Future<Output = Result<(), Error>> + Send;
+    /// `start_block` to `end_block` as the source has them, to check what was written against
+    ///
+    /// Collects what `catch_up` sends unless the source can fetch them more directly.
+    fn fetch_blocks(
+        &self,
+        start_block: u64,
+        end_block: u64,
+    ) -> impl std::future::Future<Output = Result<Vec<HiveBlockWithNum>, Error>> + Send
+    where
+        Self: Sync,
+    {
+        async move {
+            let bus = EventBus::<BlockBatch>::new(1);
+            let mut rx = bus.subscribe("verify");
+            let mut blocks = vec![];
+
+            let (caught_up, _) = tokio::join!(self.catch_up(start_block, end_block, bus), async {
+                while let Some(event) = rx.recv().await {
+                    blocks.extend(event.payload.blocks.iter().cloned());
+                    rx.ack(event.seq);
+                }
+            });
+            caught_up?;
+
+            Ok(blocks)
+        }
+    }
+}
@@ -0,0 +1,317 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::event_bus::EventBus;
+use crate::hive::scanner::{send_block, BlockBatch, HiveBlockWithNum, HiveTransactionWithTxId};
+use crate::hive::tls::platform_tls_config;
+use crate::source::source::{Source, SourceHead};
+use crate::status::SYNC_STATUS;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::{Report, Result};
+use futures::StreamExt;
+use podping_schemas::org::podcastindex::podping::podping_json::Podping;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Instant};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{
+    connect_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream,
+};
+use tracing::{debug, info, warn};
+
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+// A block's records arrive together, one quiet for this long is complete
+const BLOCK_WAIT: Duration = Duration::from_secs(1);
+const MIN_RECONNECT_WAIT: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_WAIT: Duration = Duration::from_secs(60);
+
+type Stream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A podping record as podpingd's JSON outputs write it
+#[derive(Deserialize)]
+struct RelayedPodping {
+    block_num: u64,
+    block_id: String,
+    timestamp: DateTime<Utc>,
+    tx_id: String,
+    // Missing from relays that don't send it, the podping's position in the transaction then
+    index: Option<usize>,
+    replaces: Option<String>,
+    podping: Podping,
+}
+
+/// Why a connection stopped being read
+enum Stopped {
+    Closed,
+    ReachedEndBlock,
+}
+
+/// Gathers relayed records back into blocks
+///
+/// The records don't say how many a block has, so a block is complete once a record from
+/// another block arrives, or none have for a moment.
+struct RelayedBlocks {
+    next_block: u64,
+    end_block: Option<u64>,
+    pending: Option<HiveBlockWithNum>,
+}
+
+impl RelayedBlocks {
+    /// Adds a record, returning false once it's past the end block
+    async fn push(&mut self, record: RelayedPodping, bus: &EventBus<HiveBlockWithNum>) -> bool {
+        if self
+            .end_block
+            .is_some_and(|end_block| record.block_num > end_block)
+        {
+            self.flush(bus).await;
+            return false;
+        }
+
+        let same_block = self.pending.as_ref().is_some_and(|pending| {
+            pending.block_id == record.block_id
+                && pending.replaces.as_ref().map(|replaced| &replaced.block_id)
+                    == record.replaces.as_ref()
+        });
+
+        if !same_block {
+            self.flush(bus).await;
+
+            // Already written, e.g. sent again after a reconnect. Corrections always go through
+            if record.replaces.is_none() && record.block_num < self.next_block {
+                debug!(
+                    "Skipping podping in block {}, already past it",
+                    record.block_num
+                );
+                return true;
+            }
+
+            self.pending = Some(HiveBlockWithNum {
+                block_num: record.block_num,
+                block_id: record.block_id,
+                // Not in the records
+                previous: String::new(),
+                witness: String::new(),
+                timestamp: record.timestamp,
+                transactions: vec![],
+                malformed: vec![],
+                // Only the id of the replaced block is known, not what was in it
+                replaces: record.replaces.map(|block_id| {
+                    Box::new(HiveBlockWithNum {
+                        block_num: record.block_num,
+                        block_id,
+                        previous: String::new(),
+                        witness: String::new(),
+                        timestamp: record.timestamp,
+                        transactions: vec![],
+                        malformed: vec![],
+                        replaces: None,
+                    })
+                }),
+            });
+        }
+
+        let transactions = &mut self.pending.as_mut().unwrap().transactions;
+
+        match transactions.last_mut() {
+            Some(tx) if tx.tx_id == record.tx_id => {
+                let index = record.index.unwrap_or(tx.podpings.len());
+                tx.push(index, record.podping)
+            }
+            _ => transactions.push(HiveTransactionWithTxId::new(
+                record.tx_id,
+                record.index.unwrap_or(0),
+                record.podping,
+            )),
+        }
+
+        true
+    }
+
+    /// Sends the block being gathered on, if there is one
+    async fn flush(&mut self, bus: &EventBus<HiveBlockWithNum>) {
+        if let Some(block) = self.pending.take() {
+            self.next_block = self.next_block.max(block.block_num + 1);
+
+            SYNC_STATUS.record_scanned_block(block.block_num, block.timestamp);
+            send_block(bus, block).await;
+        }
+    }
+}
+
+/// The URL without its path and query, which may carry a token
+fn display_url(url: &str) -> String {
+    match url::Url::parse(url) {
+        Ok(url) => format!("{}://{}", url.scheme(), url.authority()),
+        Err(_) => "the websocket".to_string(),
+    }
+}
+
+async fn connect(url: &str) -> Result<Stream, Error> {
+    let connector = Connector::Rustls(Arc::new(platform_tls_config()?));
+    let (stream, _) = connect_async_tls_with_config(url, None, false, Some(connector)).await?;
+
+    Ok(stream)
+}
+
+async fn read_stream(
+    stream: &mut Stream,
+    blocks: &mut RelayedBlocks,
+    idle_timeout: Duration,
+    bus: &EventBus<HiveBlockWithNum>,
+) -> Result<Stopped, Error> {
+    let mut last_received = Instant::now();
+
+    loop {
+        let wait = match blocks.pending {
+            Some(_) => BLOCK_WAIT,
+            None => idle_timeout.saturating_sub(last_received.elapsed()),
+        };
+
+        let message = match timeout(wait, stream.next()).await {
+            Err(_) if blocks.pending.is_some() => {
+                blocks.flush(bus).await;
+                continue;
+            }
+            Err(_) => return Err(eyre!("nothing received for {:?}", idle_timeout)),
+            Ok(None) | Ok(Some(Ok(Message::Close(_)))) => return Ok(Stopped::Closed),
+            Ok(Some(Err(e))) => return Err(e.into()),
+            Ok(Some(Ok(message))) => message,
+        };
+        last_received = Instant::now();
+
+        let text = match &message {
+            Message::Text(text) => text.as_str(),
+            Message::Binary(bytes) => match std::str::from_utf8(bytes) {
+                Ok(text) => text,
+                Err(_) => {
+                    warn!("Skipping a binary websocket message that isn't UTF-8");
+                    continue;
+                }
+            },
+            // Pings are answered by tungstenite on the next read
+            _ => continue,
+        };
+
+        // One record per message, or several as NDJSON
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            match serde_json::from_str::<RelayedPodping>(line) {
+                Ok(record) => {
+                    if !blocks.push(record, bus).await {
+                        return Ok(Stopped::ReachedEndBlock);
+                    }
+                }
+                Err(e) => warn!(
+                    "Skipping a websocket message that isn't a podping record: {}",
+                    e
+                ),
+            }
+        }
+    }
+}
+
+/// Podpings another podpingd relays over a websocket, instead of scanning Hive
+///
+/// Only ever followed, from wherever the relay is when podpingd connects. Reconnects with a
+/// backoff whenever the connection drops or goes quiet for the idle timeout; nothing sent while
+/// disconnected is fetched again.
+pub(crate) struct WebsocketSource {
+    url: String,
+    idle_timeout: Duration,
+}
+
+impl Source for WebsocketSource {
+    async fn new(settings: &Settings) -> Result<WebsocketSource, Report> {
+        Ok(WebsocketSource {
+            url: settings.source.websocket_url.clone().unwrap_or_default(),
+            idle_timeout: settings
+                .source
+                .websocket_idle_timeout
+                .unwrap_or(DEFAULT_IDLE_TIMEOUT),
+        })
+    }
+
+    async fn head(&self) -> Result<Option<SourceHead>, Error> {
+        Ok(None)
+    }
+
+    async fn catch_up(
+        &self,
+        _start_block: u64,
+        _end_block: u64,
+        _bus: EventBus<BlockBatch>,
+    ) -> Result<(), Error> {
+        Err(eyre!(
+            "A websocket relay can't be caught up from, only followed"
+        ))
+    }
+
+    /// Podpings from before `start_block` are skipped
+    async fn follow(
+        &self,
+        start_block: u64,
+        end_block: Option<u64>,
+        bus: EventBus<HiveBlockWithNum>,
+    ) -> Result<(), Error> {
+        let url = &self.url;
+        let mut blocks = RelayedBlocks {
+            next_block: start_block,
+            end_block,
+            pending: None,
+        };
+        let mut reconnect_wait = MIN_RECONNECT_WAIT;
+
+        loop {
+            match connect(url).await {
+                Ok(mut stream) => {
+                    info!("Following podpings from {}", display_url(url));
+                    reconnect_wait = MIN_RECONNECT_WAIT;
+
+                    let stopped =
+                        read_stream(&mut stream, &mut blocks, self.idle_timeout, &bus).await;
+                    // What's gathered so far is all of the block that's coming
+                    blocks.flush(&bus).await;
+
+                    match stopped {
+                        Ok(Stopped::ReachedEndBlock) => {
+                            info!("Reached end block {}", end_block.unwrap_or_default());
+                            let _ = stream.close(None).await;
+                            return Ok(());
+                        }
+                        Ok(Stopped::Closed) => warn!(
+                            "{} closed the websocket, reconnecting in {:?}",
+                            display_url(url),
+                            reconnect_wait
+                        ),
+                        Err(e) => warn!(
+                            "Websocket error from {}, reconnecting in {:?}: {:#}",
+                            display_url(url),
+                            reconnect_wait,
+                            e
+                        ),
+                    }
+                }
+                Err(e) => warn!(
+                    "Unable to connect to {}, retrying in {:?}: {:#}",
+                    display_url(url),
+                    reconnect_wait,
+                    e
+                ),
+            }
+
+            sleep(reconnect_wait).await;
+            reconnect_wait = (reconnect_wait * 2).min(MAX_RECONNECT_WAIT);
+        }
+    }
+}
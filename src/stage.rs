@@ -0,0 +1,185 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+#[cfg(feature = "forwarder")]
+use crate::dedupe::DedupeWindow;
+use crate::event_bus::{EventBus, Subscription};
+use crate::filter::IriFilter;
+use crate::hive::scanner::{BlockBatch, HiveBlockWithNum};
+use crate::iri::IriStage;
+use crate::routing::{HostRoutes, WriterRoutes, WRITER_TARGET};
+use crate::status::PodpingStatsStage;
+use color_eyre::eyre::Error;
+use color_eyre::Result;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinSet;
+
+/// A processing step blocks pass through on their way from the scanner to the writers
+///
+/// Stages keep their state between blocks, so they're shared across catch-up rounds and the
+/// live scan.
+pub(crate) trait BlockStage: Send + 'static {
+    fn process_block(&mut self, block: HiveBlockWithNum) -> HiveBlockWithNum;
+}
+
+pub(crate) type SharedStage = Arc<Mutex<dyn BlockStage>>;
+
+/// A bus payload that can be run through the stages
+pub(crate) trait StagePayload: Clone + Send + 'static {
+    fn process(self, stage: &mut dyn BlockStage) -> Self;
+}
+
+impl StagePayload for HiveBlockWithNum {
+    fn process(self, stage: &mut dyn BlockStage) -> Self {
+        stage.process_block(self)
+    }
+}
+
+impl StagePayload for Vec<HiveBlockWithNum> {
+    fn process(self, stage: &mut dyn BlockStage) -> Self {
+        self.into_iter()
+            .map(|block| stage.process_block(block))
+            .collect()
+    }
+}
+
+impl StagePayload for BlockBatch {
+    fn process(mut self, stage: &mut dyn BlockStage) -> Self {
+        self.blocks = self.blocks.process(stage);
+        self
+    }
+}
+
+/// Stages for everything coming off the chain, before it's written or forwarded
+pub(crate) fn stages(settings: &Settings) -> Result<Vec<SharedStage>, Error> {
+    let mut stages: Vec<SharedStage> = vec![];
+
+    if settings.iri.validate || settings.iri.normalize {
+        stages.push(Arc::new(Mutex::new(IriStage::new(&settings.iri))));
+    }
+
+    // After normalizing, so patterns match the canonical IRI
+    if let Some(filter) = IriFilter::from_settings(&settings.filter)? {
+        stages.push(Arc::new(Mutex::new(filter)));
+    }
+
+    Ok(stages)
+}
+
+/// The shared stages for a sync, counting what gets through them for the status
+///
+/// Not for re-reads like verify's, which would count the same podpings again.
+pub(crate) fn sync_stages(settings: &Settings) -> Result<Vec<SharedStage>, Error> {
+    let mut stages = stages(settings)?;
+    stages.push(Arc::new(Mutex::new(PodpingStatsStage)));
+
+    Ok(stages)
+}
+
+/// Stages only the archive writer's feed goes through, after the shared ones
+pub(crate) fn writer_stages(settings: &Settings) -> Result<Vec<SharedStage>, Error> {
+    let mut stages: Vec<SharedStage> = vec![];
+
+    if let Some(routes) = HostRoutes::for_target(settings, WRITER_TARGET)? {
+        stages.push(Arc::new(Mutex::new(WriterRoutes::new(routes))));
+    }
+
+    Ok(stages)
+}
+
+/// Stages only the forwarder's feed goes through, after the shared ones
+#[cfg(feature = "forwarder")]
+pub(crate) fn forwarder_stages(settings: &Settings) -> Vec<SharedStage> {
+    let mut stages: Vec<SharedStage> = vec![];
+
+    if let Some(window) = settings.forwarder.dedupe_window {
+        stages.push(Arc::new(Mutex::new(DedupeWindow::new(
+            window,
+            settings.forwarder.dedupe_max_entries,
+        ))));
+    }
+
+    stages
+}
+
+pub(crate) fn apply_stages<T: StagePayload>(payload: T, stages: &[SharedStage]) -> T {
+    stages.iter().fold(payload, |payload, stage| {
+        payload.process(&mut *stage.lock().unwrap())
+    })
+}
+
+/// Republishes everything from `rx` on `bus` after running it through `stages` in order
+///
+/// Ends, closing `bus`, when `rx` does.
+pub(crate) async fn run_stages<T: StagePayload>(
+    mut rx: Subscription<T>,
+    bus: EventBus<T>,
+    stages: Vec<SharedStage>,
+) -> Result<(), Error> {
+    while let Some(event) = rx.recv().await {
+        bus.publish(apply_stages(event.payload, &stages)).await?;
+        rx.ack(event.seq);
+    }
+
+    Ok(())
+}
+
+/// Subscribes `name` to `bus`, through `stages` when there are any
+///
+/// The stages get their own task in `joinset` and a bus of `queue_capacity` in front of the
+/// subscriber.
+pub(crate) fn subscribe_through_stages<T: StagePayload>(
+    bus: &EventBus<T>,
+    name: &str,
+    stages: &[SharedStage],
+    queue_capacity: usize,
+    joinset: &mut JoinSet<Result<(), Error>>,
+) -> Subscription<T> {
+    if stages.is_empty() {
+        return bus.subscribe(name);
+    }
+
+    let staged_bus = EventBus::<T>::new(queue_capacity);
+    let rx = staged_bus.subscribe(name);
+
+    joinset.spawn(run_stages(
+        bus.subscribe(&format!("{} stages", name)),
+        staged_bus,
+        stages.to_vec(),
+    ));
+
+    rx
+}
+
+/// The bus for the scanner to publish on so `stages` run before anything subscribed to `bus`
+///
+/// `bus` moves into the stages' task, so everything must subscribe to it first.
+pub(crate) fn scanner_bus<T: StagePayload>(
+    bus: EventBus<T>,
+    stages: &[SharedStage],
+    queue_capacity: usize,
+    joinset: &mut JoinSet<Result<(), Error>>,
+) -> EventBus<T> {
+    if stages.is_empty() {
+        return bus;
+    }
+
+    let scanner_bus = EventBus::<T>::new(queue_capacity);
+
+    joinset.spawn(run_stages(
+        scanner_bus.subscribe("stages"),
+        bus,
+        stages.to_vec(),
+    ));
+
+    scanner_bus
+}
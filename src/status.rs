@@ -0,0 +1,825 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::api::{error_response, json_response};
+use crate::config::{Settings, CARGO_PKG_VERSION};
+use crate::hive::scanner::HiveBlockWithNum;
+use crate::podping::{podping_medium, podping_reason, podping_version};
+use crate::stage::BlockStage;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+use tracing::{debug, error, info, warn};
+
+pub(crate) const DEFAULT_STATUS_INTERVAL: Duration = Duration::from_secs(5);
+// Latest live writes each writer's latency percentiles are taken over
+const WRITE_LATENCY_SAMPLES: usize = 1000;
+
+/// Process wide sync progress, updated by the scanner and writers as they go
+pub(crate) static SYNC_STATUS: LazyLock<SyncStatus> = LazyLock::new(SyncStatus::new);
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct WriterHealth {
+    pub(crate) healthy: bool,
+    pub(crate) last_error: Option<String>,
+    pub(crate) last_error_at: Option<DateTime<Utc>>,
+    pub(crate) last_success_at: Option<DateTime<Utc>>,
+    /// Successful writes since startup
+    pub(crate) writes: u64,
+    /// Failed write attempts since startup, retries included
+    pub(crate) errors: u64,
+}
+
+impl WriterHealth {
+    #[cfg_attr(
+        not(any(
+            feature = "disk",
+            feature = "object-storage",
+            feature = "elasticsearch",
+            feature = "parquet",
+            feature = "forwarder"
+        )),
+        allow(dead_code)
+    )]
+    fn new() -> WriterHealth {
+        WriterHealth {
+            healthy: true,
+            last_error: None,
+            last_error_at: None,
+            last_success_at: None,
+            writes: 0,
+            errors: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+// Only the forwarder's endpoints have circuits
+#[cfg_attr(not(feature = "forwarder"), allow(dead_code))]
+pub(crate) enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// A forwarder endpoint's circuit breaker
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct CircuitHealth {
+    pub(crate) state: CircuitState,
+    pub(crate) opened_at: Option<DateTime<Utc>>,
+    /// Times the circuit opened since startup, failed probes not included
+    pub(crate) times_opened: u64,
+}
+
+/// A Hive node's last benchmark, see `scanner.rpc_node_benchmark`
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct RpcNodeScore {
+    pub(crate) healthy: bool,
+    /// Round trip for get_dynamic_global_properties
+    pub(crate) latency_ms: Option<u64>,
+    pub(crate) head_block: Option<u64>,
+    /// Behind the highest head block any node reported
+    pub(crate) blocks_behind: Option<u64>,
+    /// Whether it answered a batch of get_block requests, which catch-up needs
+    pub(crate) batch_support: Option<bool>,
+    pub(crate) error: Option<String>,
+    pub(crate) benchmarked_at: DateTime<Utc>,
+}
+
+/// Podpings written or forwarded since startup, by schema version, medium and reason
+///
+/// Versions before 0.2 count as "0", and podpings without a medium or reason as "none".
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct PodpingStats {
+    pub(crate) total: u64,
+    pub(crate) by_version: BTreeMap<String, u64>,
+    pub(crate) by_medium: BTreeMap<String, u64>,
+    pub(crate) by_reason: BTreeMap<String, u64>,
+}
+
+/// How long after they were produced a writer's recent live blocks were written, see
+/// `status.write_latency_budget`
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct WriteLatency {
+    pub(crate) p50_ms: u64,
+    pub(crate) p90_ms: u64,
+    pub(crate) p99_ms: u64,
+    pub(crate) max_ms: u64,
+    /// Live blocks written since startup, the percentiles are over the last 1000
+    pub(crate) blocks: u64,
+    /// Of those, the blocks written later than the budget
+    pub(crate) over_budget: u64,
+}
+
+#[derive(Debug, Default)]
+struct LatencyWindow {
+    samples: VecDeque<Duration>,
+    blocks: u64,
+    over_budget: u64,
+    sum: Duration,
+}
+
+impl LatencyWindow {
+    fn push(&mut self, latency: Duration, over_budget: bool) {
+        if self.samples.len() == WRITE_LATENCY_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+        self.blocks += 1;
+        self.over_budget += u64::from(over_budget);
+        self.sum += latency;
+    }
+
+    fn percentiles(&self) -> WriteLatency {
+        let mut samples = self.samples.iter().copied().collect::<Vec<_>>();
+        samples.sort();
+
+        let percentile = |p: usize| {
+            samples
+                .get((samples.len() * p / 100).min(samples.len().saturating_sub(1)))
+                .map_or(0, |latency| latency.as_millis() as u64)
+        };
+
+        WriteLatency {
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p99_ms: percentile(99),
+            max_ms: samples
+                .last()
+                .map_or(0, |latency| latency.as_millis() as u64),
+            blocks: self.blocks,
+            over_budget: self.over_budget,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct PodpingKind {
+    version: String,
+    medium: String,
+    reason: String,
+}
+
+#[derive(Debug, Default)]
+struct StatusState {
+    head_block: Option<u64>,
+    scanned_block: Option<u64>,
+    scanned_block_time: Option<DateTime<Utc>>,
+    written_block: Option<u64>,
+    writers: BTreeMap<&'static str, WriterHealth>,
+    circuits: BTreeMap<String, CircuitHealth>,
+    rpc_nodes: BTreeMap<String, RpcNodeScore>,
+    counters: BTreeMap<&'static str, u64>,
+    podpings: BTreeMap<PodpingKind, u64>,
+    write_latency: BTreeMap<&'static str, LatencyWindow>,
+    write_latency_budget: Option<Duration>,
+}
+
+pub(crate) struct SyncStatus {
+    started: Instant,
+    started_at: DateTime<Utc>,
+    state: Mutex<StatusState>,
+}
+
+/// What's written to the status file
+#[derive(Debug, Serialize)]
+pub(crate) struct StatusSnapshot {
+    pub(crate) version: &'static str,
+    pub(crate) updated_at: DateTime<Utc>,
+    pub(crate) started_at: DateTime<Utc>,
+    pub(crate) uptime_seconds: u64,
+    /// Last block persisted by the writer, or scanned if nothing has been persisted
+    pub(crate) current_block: Option<u64>,
+    pub(crate) scanned_block: Option<u64>,
+    pub(crate) written_block: Option<u64>,
+    pub(crate) head_block: Option<u64>,
+    pub(crate) lag_blocks: Option<u64>,
+    /// How far the last scanned block's timestamp is behind the clock
+    pub(crate) lag_seconds: Option<i64>,
+    /// Blocks scanned but not yet persisted by the writer
+    pub(crate) queued_blocks: Option<u64>,
+    pub(crate) healthy: bool,
+    pub(crate) writers: BTreeMap<&'static str, WriterHealth>,
+    pub(crate) circuits: BTreeMap<String, CircuitHealth>,
+    /// Each RPC node's last benchmark, empty until one has run
+    pub(crate) rpc_nodes: BTreeMap<String, RpcNodeScore>,
+    /// Running totals kept by the processing stages, e.g. suppressed duplicates
+    pub(crate) counters: BTreeMap<&'static str, u64>,
+    pub(crate) podpings: PodpingStats,
+    /// Each writer's latency from block timestamp to written, live blocks only
+    pub(crate) write_latency: BTreeMap<&'static str, WriteLatency>,
+}
+
+impl SyncStatus {
+    fn new() -> SyncStatus {
+        SyncStatus {
+            started: Instant::now(),
+            started_at: Utc::now(),
+            state: Mutex::new(StatusState::default()),
+        }
+    }
+
+    pub(crate) fn record_head_block(&self, block_num: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.head_block = state.head_block.max(Some(block_num));
+    }
+
+    pub(crate) fn record_scanned_block(&self, block_num: u64, timestamp: DateTime<Utc>) {
+        let mut state = self.state.lock().unwrap();
+        state.scanned_block = Some(block_num);
+        state.scanned_block_time = Some(timestamp);
+        // A freshly fetched block is at least the head we last saw
+        state.head_block = state.head_block.max(Some(block_num));
+    }
+
+    #[cfg_attr(
+        not(any(
+            feature = "disk",
+            feature = "object-storage",
+            feature = "elasticsearch",
+            feature = "parquet"
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) fn record_written_block(&self, block_num: u64) {
+        self.state.lock().unwrap().written_block = Some(block_num);
+    }
+
+    #[cfg_attr(
+        not(any(
+            feature = "disk",
+            feature = "object-storage",
+            feature = "elasticsearch",
+            feature = "parquet",
+            feature = "forwarder"
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) fn record_writer_ok(&self, name: &'static str) {
+        let mut state = self.state.lock().unwrap();
+        let health = state.writers.entry(name).or_insert_with(WriterHealth::new);
+        health.healthy = true;
+        health.last_success_at = Some(Utc::now());
+        health.writes += 1;
+    }
+
+    #[cfg_attr(
+        not(any(
+            feature = "disk",
+            feature = "object-storage",
+            feature = "elasticsearch",
+            feature = "parquet",
+            feature = "forwarder"
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) fn record_writer_error(&self, name: &'static str, err: &Error) {
+        let mut state = self.state.lock().unwrap();
+        let health = state.writers.entry(name).or_insert_with(WriterHealth::new);
+        health.healthy = false;
+        health.last_error = Some(format!("{:#}", err));
+        health.last_error_at = Some(Utc::now());
+        health.errors += 1;
+    }
+
+    #[cfg(feature = "forwarder")]
+    pub(crate) fn record_circuit_state(&self, name: &str, circuit_state: CircuitState) {
+        let mut state = self.state.lock().unwrap();
+        let circuit = state
+            .circuits
+            .entry(name.to_string())
+            .or_insert(CircuitHealth {
+                state: CircuitState::Closed,
+                opened_at: None,
+                times_opened: 0,
+            });
+
+        match (circuit.state, circuit_state) {
+            (CircuitState::Closed, CircuitState::Open) => {
+                circuit.opened_at = Some(Utc::now());
+                circuit.times_opened += 1;
+            }
+            (_, CircuitState::Closed) => circuit.opened_at = None,
+            _ => {}
+        }
+        circuit.state = circuit_state;
+    }
+
+    /// Replaces every node's score, dropping nodes that are no longer configured
+    pub(crate) fn record_rpc_node_scores(&self, scores: &HashMap<String, RpcNodeScore>) {
+        self.state.lock().unwrap().rpc_nodes = scores
+            .iter()
+            .map(|(node, score)| (node.clone(), score.clone()))
+            .collect();
+    }
+
+    pub(crate) fn increment_counter(&self, name: &'static str, by: u64) {
+        *self.state.lock().unwrap().counters.entry(name).or_insert(0) += by;
+    }
+
+    /// Live blocks written later than this are logged as an alert, see `status.write_latency_budget`
+    pub(crate) fn set_write_latency_budget(&self, budget: Option<Duration>) {
+        self.state.lock().unwrap().write_latency_budget = budget;
+    }
+
+    /// Records how long after it was produced a live block was written
+    ///
+    /// Catch-up blocks aren't recorded, their latency is how far behind the start was.
+    pub(crate) fn record_write_latency(
+        &self,
+        writer: &'static str,
+        block_num: u64,
+        timestamp: DateTime<Utc>,
+    ) {
+        // Zero if the producer's clock is ahead of ours
+        let latency = (Utc::now() - timestamp).to_std().unwrap_or_default();
+        let mut state = self.state.lock().unwrap();
+        let budget = state.write_latency_budget;
+        let over_budget = budget.is_some_and(|budget| latency > budget);
+
+        state
+            .write_latency
+            .entry(writer)
+            .or_default()
+            .push(latency, over_budget);
+
+        if let (true, Some(budget)) = (over_budget, budget) {
+            let latency_ms = latency.as_millis() as u64;
+            let budget_ms = budget.as_millis() as u64;
+
+            warn!(
+                writer,
+                block_num,
+                latency_ms,
+                budget_ms,
+                "ALERT: {} writer: block {} written {}ms after it was produced, over the {}ms budget",
+                writer,
+                block_num,
+                latency_ms,
+                budget_ms
+            );
+        }
+    }
+
+    /// Counts the block's podpings by kind, see PodpingStatsStage
+    pub(crate) fn record_podpings(&self, block: &HiveBlockWithNum) {
+        let mut state = self.state.lock().unwrap();
+
+        for podping in block.transactions.iter().flat_map(|tx| &tx.podpings) {
+            let kind = PodpingKind {
+                version: podping_version(podping).unwrap_or_else(|| "0".to_string()),
+                medium: podping_medium(podping).unwrap_or_else(|| "none".to_string()),
+                reason: podping_reason(podping).unwrap_or_else(|| "none".to_string()),
+            };
+
+            *state.podpings.entry(kind).or_insert(0) += 1;
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> StatusSnapshot {
+        let state = self.state.lock().unwrap();
+        let current_block = state.written_block.or(state.scanned_block);
+
+        StatusSnapshot {
+            version: CARGO_PKG_VERSION.unwrap_or("VERSION_NOT_FOUND"),
+            updated_at: Utc::now(),
+            started_at: self.started_at,
+            uptime_seconds: self.started.elapsed().as_secs(),
+            current_block,
+            scanned_block: state.scanned_block,
+            written_block: state.written_block,
+            head_block: state.head_block,
+            lag_blocks: match (state.head_block, current_block) {
+                (Some(head_block), Some(current_block)) => {
+                    Some(head_block.saturating_sub(current_block))
+                }
+                _ => None,
+            },
+            lag_seconds: state
+                .scanned_block_time
+                .map(|timestamp| (Utc::now() - timestamp).num_seconds().max(0)),
+            queued_blocks: match (state.scanned_block, state.written_block) {
+                (Some(scanned_block), Some(written_block)) => {
+                    Some(scanned_block.saturating_sub(written_block))
+                }
+                _ => None,
+            },
+            healthy: state.writers.values().all(|health| health.healthy),
+            writers: state.writers.clone(),
+            circuits: state.circuits.clone(),
+            rpc_nodes: state.rpc_nodes.clone(),
+            counters: state.counters.clone(),
+            podpings: state.podpings.iter().fold(
+                PodpingStats::default(),
+                |mut stats, (kind, count)| {
+                    stats.total += count;
+                    *stats.by_version.entry(kind.version.clone()).or_insert(0) += count;
+                    *stats.by_medium.entry(kind.medium.clone()).or_insert(0) += count;
+                    *stats.by_reason.entry(kind.reason.clone()).or_insert(0) += count;
+                    stats
+                },
+            ),
+            write_latency: state
+                .write_latency
+                .iter()
+                .map(|(writer, window)| (*writer, window.percentiles()))
+                .collect(),
+        }
+    }
+
+    /// The status as Prometheus metrics, for `GET /metrics`
+    ///
+    /// Counters are `podpingd_<counter>_total`, and podpings are counted by all three of version,
+    /// medium and reason so any breakdown can be summed up from them.
+    pub(crate) fn metrics(&self) -> String {
+        let snapshot = self.snapshot();
+        let (podpings, latency_sums) = {
+            let state = self.state.lock().unwrap();
+
+            (
+                state.podpings.clone(),
+                state
+                    .write_latency
+                    .iter()
+                    .map(|(writer, window)| (*writer, window.sum))
+                    .collect::<BTreeMap<_, _>>(),
+            )
+        };
+        let mut metrics = String::new();
+
+        for (name, value) in [
+            ("podpingd_uptime_seconds", Some(snapshot.uptime_seconds)),
+            ("podpingd_current_block", snapshot.current_block),
+            ("podpingd_head_block", snapshot.head_block),
+            ("podpingd_lag_blocks", snapshot.lag_blocks),
+            (
+                "podpingd_lag_seconds",
+                snapshot.lag_seconds.map(|lag| lag as u64),
+            ),
+            ("podpingd_queued_blocks", snapshot.queued_blocks),
+        ] {
+            if let Some(value) = value {
+                let _ = writeln!(metrics, "# TYPE {} gauge\n{} {}", name, name, value);
+            }
+        }
+
+        let _ = writeln!(metrics, "# TYPE podpingd_writer_healthy gauge");
+        for (writer, health) in &snapshot.writers {
+            let _ = writeln!(
+                metrics,
+                "podpingd_writer_healthy{{writer=\"{}\"}} {}",
+                writer,
+                u8::from(health.healthy)
+            );
+        }
+
+        for (name, count) in &snapshot.counters {
+            let _ = writeln!(
+                metrics,
+                "# TYPE podpingd_{}_total counter\npodpingd_{}_total {}",
+                name, name, count
+            );
+        }
+
+        let _ = writeln!(metrics, "# TYPE podpingd_podpings_total counter");
+        for (kind, count) in &podpings {
+            let _ = writeln!(
+                metrics,
+                "podpingd_podpings_total{{version=\"{}\",medium=\"{}\",reason=\"{}\"}} {}",
+                label_value(&kind.version),
+                label_value(&kind.medium),
+                label_value(&kind.reason),
+                count
+            );
+        }
+
+        let _ = writeln!(metrics, "# TYPE podpingd_write_latency_seconds summary");
+        for (writer, latency) in &snapshot.write_latency {
+            for (quantile, ms) in [
+                ("0.5", latency.p50_ms),
+                ("0.9", latency.p90_ms),
+                ("0.99", latency.p99_ms),
+            ] {
+                let _ = writeln!(
+                    metrics,
+                    "podpingd_write_latency_seconds{{writer=\"{}\",quantile=\"{}\"}} {}",
+                    writer,
+                    quantile,
+                    ms as f64 / 1000.0
+                );
+            }
+            let _ = writeln!(
+                metrics,
+                "podpingd_write_latency_seconds_sum{{writer=\"{}\"}} {}\npodpingd_write_latency_seconds_count{{writer=\"{}\"}} {}",
+                writer,
+                latency_sums.get(writer).unwrap_or(&Duration::ZERO).as_secs_f64(),
+                writer,
+                latency.blocks
+            );
+        }
+
+        let _ = writeln!(
+            metrics,
+            "# TYPE podpingd_writes_over_latency_budget_total counter"
+        );
+        for (writer, latency) in &snapshot.write_latency {
+            let _ = writeln!(
+                metrics,
+                "podpingd_writes_over_latency_budget_total{{writer=\"{}\"}} {}",
+                writer, latency.over_budget
+            );
+        }
+
+        metrics
+    }
+}
+
+/// Escapes a Prometheus label value, mediums and reasons are the sender's to pick
+fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Counts podpings for the status, last of the stages so it sees what's written and forwarded
+pub(crate) struct PodpingStatsStage;
+
+impl BlockStage for PodpingStatsStage {
+    fn process_block(&mut self, block: HiveBlockWithNum) -> HiveBlockWithNum {
+        SYNC_STATUS.record_podpings(&block);
+        block
+    }
+}
+
+/// Writes the file next to its destination and renames it over, so readers never see half of it
+async fn write_status_file(path: &Path) -> Result<(), Error> {
+    let json = serde_json::to_string_pretty(&SYNC_STATUS.snapshot())?;
+
+    let mut temp_path = path.to_path_buf().into_os_string();
+    temp_path.push(".tmp");
+
+    tokio::fs::write(&temp_path, json).await?;
+    tokio::fs::rename(&temp_path, path).await?;
+
+    Ok(())
+}
+
+/// Rewrites the status file every interval, forever
+pub(crate) async fn write_status_file_periodically(path: PathBuf, interval: Duration) {
+    loop {
+        if let Err(e) = write_status_file(&path).await {
+            error!(
+                "Error writing status file {}: {:#}",
+                path.to_string_lossy(),
+                e
+            );
+        }
+
+        sleep(interval).await;
+    }
+}
+
+async fn handle(request: Request<hyper::body::Incoming>) -> Response<Full<Bytes>> {
+    if request.method() != Method::GET {
+        return error_response(StatusCode::METHOD_NOT_ALLOWED, "Only GET is supported");
+    }
+
+    match request.uri().path().trim_end_matches('/') {
+        "/status" => json_response(StatusCode::OK, &SYNC_STATUS.snapshot()),
+        "/metrics" => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Full::new(Bytes::from(SYNC_STATUS.metrics())))
+            .unwrap(),
+        _ => error_response(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+fn listen_addr(listen: &str) -> SocketAddr {
+    match listen.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(e) => panic!("Invalid status listen address {}: {}", listen, e),
+    }
+}
+
+/// Serves the status snapshot at `GET /status`, and as Prometheus metrics at `GET /metrics`, in
+/// the background when `status.listen` is set
+///
+/// Panics if the listen address can't be bound.
+pub(crate) async fn start_server(settings: &Settings) {
+    let addr = match settings
+        .status
+        .listen
+        .as_deref()
+        .filter(|listen| !listen.is_empty())
+    {
+        Some(listen) => listen_addr(listen),
+        None => return,
+    };
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => panic!(
+            "Unable to listen for the status endpoint on {}: {}",
+            addr, e
+        ),
+    };
+
+    info!("Status endpoint listening on {}", addr);
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("status: error accepting a connection: {}", e);
+                    continue;
+                }
+            };
+
+            tokio::spawn(async move {
+                let service =
+                    service_fn(|request| async move { Ok::<_, Infallible>(handle(request).await) });
+
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    debug!("status: connection closed: {}", e);
+                }
+            });
+        }
+    });
+}
+
+fn or_unknown(value: &Value) -> String {
+    match value {
+        Value::Null => "unknown".to_string(),
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Fetches the running podpingd's status from `status.listen` and prints it, for `podpingd status`
+pub(crate) async fn print_status(settings: &Settings, json: bool) -> Result<(), Error> {
+    let listen = match settings
+        .status
+        .listen
+        .as_deref()
+        .filter(|listen| !listen.is_empty())
+    {
+        Some(listen) => listen_addr(listen),
+        None => {
+            return Err(eyre!(
+                "status.listen is not set, so there's no status endpoint to query"
+            ))
+        }
+    };
+
+    let url = format!("http://{}/status", listen);
+    let status: Value = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| eyre!("Unable to reach podpingd at {}: {}", url, e))?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+
+    let uptime = Duration::from_secs(status["uptime_seconds"].as_u64().unwrap_or(0));
+
+    println!(
+        "podpingd {}, up {} since {}",
+        or_unknown(&status["version"]),
+        humantime_serde::re::humantime::format_duration(uptime),
+        or_unknown(&status["started_at"])
+    );
+    println!(
+        "Current block {}, head block {}",
+        or_unknown(&status["current_block"]),
+        or_unknown(&status["head_block"])
+    );
+    println!(
+        "Lag: {} blocks, {} seconds",
+        or_unknown(&status["lag_blocks"]),
+        or_unknown(&status["lag_seconds"])
+    );
+    println!("Queued blocks: {}", or_unknown(&status["queued_blocks"]));
+
+    if let Some(writers) = status["writers"].as_object() {
+        for (name, health) in writers {
+            println!(
+                "Writer {}: {}, {} writes, {} failed, last success {}",
+                name,
+                match health["healthy"].as_bool() {
+                    Some(true) => "healthy",
+                    _ => "unhealthy",
+                },
+                or_unknown(&health["writes"]),
+                or_unknown(&health["errors"]),
+                or_unknown(&health["last_success_at"])
+            );
+
+            if let Some(last_error) = health["last_error"].as_str() {
+                println!(
+                    "  last error at {}: {}",
+                    or_unknown(&health["last_error_at"]),
+                    last_error
+                );
+            }
+
+            let latency = &status["write_latency"][name];
+            if latency.is_object() {
+                println!(
+                    "  latency p50 {}ms, p90 {}ms, p99 {}ms, {} of {} blocks over budget",
+                    or_unknown(&latency["p50_ms"]),
+                    or_unknown(&latency["p90_ms"]),
+                    or_unknown(&latency["p99_ms"]),
+                    or_unknown(&latency["over_budget"]),
+                    or_unknown(&latency["blocks"])
+                );
+            }
+        }
+    }
+
+    if let Some(circuits) = status["circuits"].as_object() {
+        for (name, circuit) in circuits {
+            match circuit["state"].as_str() {
+                Some("closed") => println!(
+                    "{}: circuit closed, opened {} times",
+                    name,
+                    or_unknown(&circuit["times_opened"])
+                ),
+                _ => println!(
+                    "{}: circuit {} since {}",
+                    name,
+                    or_unknown(&circuit["state"]).replace('_', " "),
+                    or_unknown(&circuit["opened_at"])
+                ),
+            }
+        }
+    }
+
+    if let Some(counters) = status["counters"].as_object() {
+        for (name, count) in counters {
+            println!("{}: {}", name, count);
+        }
+    }
+
+    for (breakdown, title) in [
+        ("by_version", "Podpings by version"),
+        ("by_medium", "Podpings by medium"),
+        ("by_reason", "Podpings by reason"),
+    ] {
+        if let Some(counts) = status["podpings"][breakdown]
+            .as_object()
+            .filter(|counts| !counts.is_empty())
+        {
+            println!(
+                "{}: {}",
+                title,
+                counts
+                    .iter()
+                    .map(|(name, count)| format!("{} {}", name, count))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+    }
+
+    Ok(())
+}
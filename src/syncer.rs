@@ -9,132 +9,316 @@
  *
  *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
  */
-use crate::config::Settings;
-use crate::hive::jsonrpc::client::JsonRpcClient;
-use crate::hive::jsonrpc::responses::GetDynamicGlobalPropertiesResponse;
-use crate::hive::scanner;
-use crate::hive::scanner::HiveBlockWithNum;
-use crate::writer::writer::Writer;
+use crate::config::{ScannerStart, Settings};
+use crate::event_bus::EventBus;
+#[cfg(feature = "forwarder")]
+use crate::forwarder::forwarder::Forwarder;
+#[cfg(feature = "grpc")]
+use crate::grpc;
+use crate::hive::network;
+use crate::hive::scanner::{BlockBatch, HiveBlockWithNum};
+use crate::source::source::{Source, SourceHead};
+use crate::stage;
+use crate::stage::SharedStage;
+use crate::verify::Verifier;
+use crate::writer::writer::{new_writer, Writer};
 use chrono::{DateTime, Utc};
+use color_eyre::eyre::eyre;
 use color_eyre::Report;
 use std::sync::Arc;
+#[cfg(feature = "forwarder")]
 use tokio::sync::Mutex;
 use tokio::task::JoinSet;
 use tracing::info;
 
-async fn get_start_block_from_global_properties(
+/// The block start_datetime falls on, or where the source starts by default without one
+///
+/// A source without a head can't place a datetime, it's taken from wherever it is.
+fn get_start_block_from_head(
     start_datetime: Option<DateTime<Utc>>,
-    dynamic_global_properties: &GetDynamicGlobalPropertiesResponse,
+    head: Option<&SourceHead>,
 ) -> Result<u64, Report> {
+    let head = match head {
+        Some(head) => head,
+        None => return Ok(0),
+    };
+
     match start_datetime {
         Some(start_datetime) => {
-            if start_datetime > dynamic_global_properties.time {
-                panic!("start_datetime {} is in the future!", start_datetime)
+            if start_datetime > head.time {
+                return Err(eyre!(
+                    "start_datetime {} is after the source's head block at {}",
+                    start_datetime,
+                    head.time
+                ));
             }
 
-            let time_delta = dynamic_global_properties.time - start_datetime;
+            let time_delta = head.time - start_datetime;
             let num_blocks_ago = time_delta.num_seconds() / 3;
 
-            Ok(dynamic_global_properties.head_block_number - num_blocks_ago as u64)
+            Ok(head.head_block - num_blocks_ago as u64)
         }
-        None => Ok(dynamic_global_properties.head_block_number),
+        None => Ok(head.default_start_block),
     }
 }
 
+/// Last block to process, if the scan is bounded
+///
+/// end_datetime is converted to a block number the same way as start_datetime,
+/// counting forward from the head block when it's in the future.
+fn get_end_block(settings: &Settings, head: Option<&SourceHead>) -> Option<u64> {
+    if let Some(end_block) = settings.scanner.end_block {
+        return Some(end_block);
+    }
+
+    let head = head?;
+
+    settings.scanner.end_datetime.map(|end_datetime| {
+        let time_delta = end_datetime - head.time;
+        let num_blocks = time_delta.num_seconds() / 3;
+
+        head.head_block.saturating_add_signed(num_blocks)
+    })
+}
+
 async fn get_start_block(
     settings: &Settings,
-    writer: Arc<Mutex<impl Writer>>,
-    dynamic_global_properties: &GetDynamicGlobalPropertiesResponse,
+    writer: &impl Writer,
+    head: Option<&SourceHead>,
 ) -> Result<u64, Report> {
-    let last_updated_block_result = writer.lock().await.get_last_block().await;
+    match settings.scanner.start.unwrap_or(ScannerStart::Resume) {
+        ScannerStart::Head => {
+            info!("scanner.start is head, ignoring any persisted block");
+            return Ok(head.map_or(0, |head| head.last_block));
+        }
+        ScannerStart::Beginning => {
+            info!("scanner.start is beginning, ignoring any persisted block");
+            return Ok(network::first_podping_block(&settings.scanner));
+        }
+        ScannerStart::Resume => {}
+    }
+
+    let last_updated_block_result = writer.get_last_block().await;
 
     match last_updated_block_result {
         Ok(last_updated_block) => match last_updated_block {
             Some(last_updated_block) => Ok(last_updated_block + 1),
             None => match settings.scanner.start_block {
                 Some(start_block) => Ok(start_block),
-                None => {
-                    get_start_block_from_global_properties(
-                        settings.scanner.start_datetime,
-                        dynamic_global_properties,
-                    )
-                    .await
-                }
+                None => get_start_block_from_head(settings.scanner.start_datetime, head),
             },
         },
-        Err(err) => panic!("Error getting last_updated_block {}", err),
+        Err(err) => Err(eyre!("Error getting last_updated_block {}", err)),
     }
 }
 
-pub(crate) struct Syncer<'a, J, W>
+/// Waits for every task, stopping the rest at the first one that fails and returning its error
+///
+/// A writer only fails once its failure policy gives up on a block. Carrying on past that block
+/// would let the next checkpoint move beyond it, with the block never written.
+async fn join_tasks(mut joinset: JoinSet<Result<(), Report>>) -> Result<(), Report> {
+    while let Some(joined) = joinset.join_next().await {
+        if let Err(e) = joined.map_err(Report::from).and_then(|result| result) {
+            joinset.shutdown().await;
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs blocks from any source through the stages to the writer, forwarder and other outputs
+pub(crate) struct Syncer<'a, S, W>
 where
-    J: JsonRpcClient + Send,
-    W: Writer + Send,
+    S: Source + Send + Sync,
+    W: Writer + Send + Sync,
 {
-    json_rpc_client: Arc<Mutex<J>>,
-    writer: Arc<Mutex<W>>,
+    source: Arc<S>,
+    // Shared with the verifier, a writer only needs &self
+    writer: Arc<W>,
+    stages: Vec<SharedStage>,
+    writer_stages: Vec<SharedStage>,
+    #[cfg(feature = "forwarder")]
+    forwarder: Option<Arc<Mutex<Forwarder>>>,
+    #[cfg(feature = "forwarder")]
+    forwarder_stages: Vec<SharedStage>,
+    #[cfg(feature = "grpc")]
+    grpc_hub: Option<Arc<grpc::PodpingHub>>,
+    verifier: Option<Arc<Verifier<S, W>>>,
     settings: &'a Settings,
 }
 
-impl<J: JsonRpcClient + Send + 'static, W: Writer + Send + 'static> Syncer<'_, J, W> {
-    pub(crate) async fn new(settings: &Settings) -> Result<Syncer<J, W>, Report> {
+impl<S: Source + Send + Sync + 'static, W: Writer + Send + Sync + 'static> Syncer<'_, S, W> {
+    pub(crate) async fn new(settings: &Settings) -> Result<Syncer<S, W>, Report> {
+        let source = Arc::new(S::new(settings).await?);
+        let writer = Arc::new(new_writer::<W>(settings).await?);
+
         Ok(Syncer {
-            json_rpc_client: Arc::new(Mutex::new(J::new(settings.scanner.rpc_nodes.clone())?)),
-            writer: Arc::new(Mutex::new(W::new(&settings).await)),
+            verifier: match settings.verify.enabled && settings.writer.enabled {
+                true => Some(Arc::new(Verifier::new(
+                    settings,
+                    source.clone(),
+                    writer.clone(),
+                )?)),
+                false => None,
+            },
+            source,
+            writer,
+            stages: stage::sync_stages(settings)?,
+            writer_stages: stage::writer_stages(settings)?,
+            #[cfg(feature = "forwarder")]
+            forwarder: match settings.forwarder.enabled {
+                true => Some(Arc::new(Mutex::new(new_writer(settings).await?))),
+                false => None,
+            },
+            #[cfg(feature = "forwarder")]
+            forwarder_stages: stage::forwarder_stages(settings),
+            #[cfg(feature = "grpc")]
+            grpc_hub: grpc::start_server(settings).await,
             settings,
         })
     }
 
     pub(crate) async fn start(&self) -> Result<(), Report> {
-        let mut dynamic_global_properties =
-            scanner::get_dynamic_global_properties(self.json_rpc_client.clone()).await?;
-        let mut start_block = get_start_block(
-            self.settings,
-            self.writer.clone(),
-            &dynamic_global_properties,
-        )
-        .await?;
+        // Surface permission problems now rather than partway through a backfill
+        self.writer.preflight().await?;
+
+        #[cfg(feature = "forwarder")]
+        if let Some(forwarder) = &self.forwarder {
+            forwarder.lock().await.preflight().await?;
+        }
+
+        let mut head = self.source.head().await?;
+        let mut start_block =
+            get_start_block(self.settings, self.writer.as_ref(), head.as_ref()).await?;
+        let end_block = get_end_block(self.settings, head.as_ref());
 
         info!("Starting scan at block {}", start_block);
 
-        if start_block < dynamic_global_properties.head_block_number {
-            info!("Current block is behind... catching up");
+        if let Some(end_block) = end_block {
+            info!("Stopping scan after block {}", end_block);
+
+            if start_block > end_block {
+                info!("Start block is already past the end block, nothing to do");
+                return Ok(());
+            }
+        }
+
+        // A source without a head is only ever followed
+        if let Some(mut last_block) = head.as_ref().map(|head| head.last_block) {
+            if start_block < last_block {
+                info!("Current block is behind... catching up");
 
-            while start_block < dynamic_global_properties.head_block_number - 2 {
-                let (tx, rx) = tokio::sync::broadcast::channel::<Vec<HiveBlockWithNum>>(1);
+                while start_block < last_block.saturating_sub(2) {
+                    let catchup_end_block = match end_block {
+                        Some(end_block) => end_block.min(last_block),
+                        None => last_block,
+                    };
 
-                let mut catchup_joinset = JoinSet::new();
-                catchup_joinset.spawn(scanner::catchup_chain(
-                    start_block,
-                    dynamic_global_properties.head_block_number,
-                    tx,
-                    self.json_rpc_client.clone(),
-                ));
+                    let bus = EventBus::<BlockBatch>::new(1);
+                    let mut catchup_joinset = JoinSet::new();
 
-                let writer = self.writer.clone();
+                    let rx = stage::subscribe_through_stages(
+                        &bus,
+                        "writer",
+                        &self.writer_stages,
+                        1,
+                        &mut catchup_joinset,
+                    );
 
-                catchup_joinset.spawn(async move { writer.lock().await.start_batch(rx).await });
+                    #[cfg(feature = "forwarder")]
+                    if let Some(forwarder) = &self.forwarder {
+                        let forwarder = forwarder.clone();
+                        let forwarder_rx = stage::subscribe_through_stages(
+                            &bus,
+                            "forwarder",
+                            &self.forwarder_stages,
+                            1,
+                            &mut catchup_joinset,
+                        );
 
-                catchup_joinset.join_all().await;
-                start_block = dynamic_global_properties.head_block_number + 1;
-                dynamic_global_properties =
-                    scanner::get_dynamic_global_properties(self.json_rpc_client.clone()).await?;
-            }
+                        catchup_joinset.spawn(async move {
+                            forwarder.lock().await.start_batch(forwarder_rx).await
+                        });
+                    }
+
+                    #[cfg(feature = "grpc")]
+                    if let Some(grpc_hub) = &self.grpc_hub {
+                        let grpc_hub = grpc_hub.clone();
+                        let grpc_rx = bus.subscribe("grpc");
+
+                        catchup_joinset.spawn(async move { grpc_hub.start_batch(grpc_rx).await });
+                    }
+
+                    let bus = stage::scanner_bus(bus, &self.stages, 1, &mut catchup_joinset);
+
+                    let source = self.source.clone();
+                    catchup_joinset.spawn(async move {
+                        source.catch_up(start_block, catchup_end_block, bus).await
+                    });
+
+                    let writer = self.writer.clone();
+
+                    catchup_joinset.spawn(async move { writer.start_batch(rx).await });
+
+                    join_tasks(catchup_joinset).await?;
+                    start_block = catchup_end_block + 1;
+
+                    if end_block.is_some_and(|end_block| start_block > end_block) {
+                        info!("Reached end block {}", catchup_end_block);
+                        return Ok(());
+                    }
+
+                    head = self.source.head().await?;
+                    last_block = head.as_ref().map_or(last_block, |head| head.last_block);
+                }
 
-            info!("Done catching up! Now at block {}", start_block);
+                info!("Done catching up! Now at block {}", start_block);
+            }
         }
 
         let mut joinset = JoinSet::new();
-        let (tx, rx) = tokio::sync::broadcast::channel::<HiveBlockWithNum>(10);
+        let bus = EventBus::<HiveBlockWithNum>::new(10);
+        let rx =
+            stage::subscribe_through_stages(&bus, "writer", &self.writer_stages, 10, &mut joinset);
 
-        let jpc = self.json_rpc_client.clone();
-        joinset.spawn(async move { scanner::scan_chain(start_block, tx, jpc).await });
+        #[cfg(feature = "forwarder")]
+        if let Some(forwarder) = &self.forwarder {
+            let forwarder = forwarder.clone();
+            let forwarder_rx = stage::subscribe_through_stages(
+                &bus,
+                "forwarder",
+                &self.forwarder_stages,
+                10,
+                &mut joinset,
+            );
 
-        let writer = self.writer.clone();
-        joinset.spawn(async move { writer.lock().await.start(rx).await });
+            joinset.spawn(async move { forwarder.lock().await.start(forwarder_rx).await });
+        }
+
+        // A bounded scan exits once the scanner is done, so only verify while following the chain
+        if let (Some(verifier), None) = (&self.verifier, end_block) {
+            let verifier = verifier.clone();
+
+            joinset.spawn(async move { verifier.run().await });
+        }
 
-        joinset.join_all().await;
+        #[cfg(feature = "grpc")]
+        if let Some(grpc_hub) = &self.grpc_hub {
+            let grpc_hub = grpc_hub.clone();
+            let grpc_rx = bus.subscribe("grpc");
+
+            joinset.spawn(async move { grpc_hub.start(grpc_rx).await });
+        }
+
+        let bus = stage::scanner_bus(bus, &self.stages, 10, &mut joinset);
+
+        let source = self.source.clone();
+        joinset.spawn(async move { source.follow(start_block, end_block, bus).await });
+
+        let writer = self.writer.clone();
+        joinset.spawn(async move { writer.start(rx).await });
 
-        Ok(())
+        join_tasks(joinset).await
     }
 }
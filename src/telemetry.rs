@@ -0,0 +1,92 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+#[cfg(feature = "otlp")]
+static TRACER_PROVIDER: std::sync::OnceLock<opentelemetry_sdk::trace::TracerProvider> =
+    std::sync::OnceLock::new();
+
+/// Exports spans, plus warnings and errors as span events, to the OTLP collector
+///
+/// The scanner and writer spans are at debug level so they stay out of the normal log output,
+/// this layer has its own filter and always sees them.
+#[cfg(feature = "otlp")]
+pub(crate) fn otlp_layer<S>(settings: &Settings) -> Option<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, Resource};
+    use tracing::Level;
+    use tracing_subscriber::filter::filter_fn;
+
+    if !settings.telemetry.otlp_enabled {
+        return None;
+    }
+
+    let mut exporter_builder = opentelemetry_otlp::SpanExporter::builder().with_tonic();
+    if let Some(endpoint) = &settings.telemetry.otlp_endpoint {
+        exporter_builder = exporter_builder.with_endpoint(endpoint);
+    }
+    let exporter = exporter_builder
+        .build()
+        .expect("Error building the OTLP span exporter");
+
+    let service_name = settings
+        .telemetry
+        .service_name
+        .clone()
+        .unwrap_or("podpingd".to_string());
+
+    let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            service_name,
+        )]))
+        .build();
+    let tracer = provider.tracer("podpingd");
+
+    let _ = TRACER_PROVIDER.set(provider);
+
+    Some(
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(filter_fn(|metadata| {
+                metadata.is_span() || *metadata.level() <= Level::WARN
+            }))
+            .boxed(),
+    )
+}
+
+#[cfg(not(feature = "otlp"))]
+pub(crate) fn otlp_layer<S>(_settings: &Settings) -> Option<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    None
+}
+
+/// Sends any spans still batched up before the process exits
+pub(crate) fn shutdown() {
+    #[cfg(feature = "otlp")]
+    if let Some(provider) = TRACER_PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            tracing::error!("Error flushing OTLP spans: {}", e);
+        }
+    }
+}
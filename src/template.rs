@@ -0,0 +1,113 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::hive::scanner::{HiveBlockWithNum, HiveTransactionWithTxId};
+use crate::podping::{event_id, podping_iris};
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use minijinja::{context, Environment, Value};
+use podping_schemas::org::podcastindex::podping::podping_json::Podping;
+
+const PAYLOAD_TEMPLATE_NAME: &str = "payload";
+
+/// User supplied minijinja template for a single podping's output
+///
+/// Templates see `block` (block_num, block_id, previous, witness, timestamp),
+/// `tx` (tx_id), `index`, `event_id`, `iris` and `podping` (the podping as sent on chain).
+pub(crate) struct PayloadTemplate {
+    env: Environment<'static>,
+}
+
+impl PayloadTemplate {
+    pub(crate) fn new(source: String) -> Result<PayloadTemplate, Error> {
+        let mut env = Environment::new();
+        env.add_template_owned(PAYLOAD_TEMPLATE_NAME, source)?;
+
+        Ok(PayloadTemplate { env })
+    }
+
+    /// Loads the template from an inline string or a file, the inline string winning if both are set
+    pub(crate) fn from_settings(
+        name: &str,
+        inline: &Option<String>,
+        file: &Option<String>,
+    ) -> Result<Option<PayloadTemplate>, Error> {
+        let source = match (inline, file) {
+            (Some(inline), _) if !inline.is_empty() => inline.clone(),
+            (_, Some(file)) if !file.is_empty() => match std::fs::read_to_string(file) {
+                Ok(source) => source,
+                Err(e) => {
+                    return Err(eyre!(
+                        "Error reading {} template file {}: {}",
+                        name,
+                        file,
+                        e
+                    ))
+                }
+            },
+            _ => return Ok(None),
+        };
+
+        match PayloadTemplate::new(source) {
+            Ok(template) => Ok(Some(template)),
+            Err(e) => Err(eyre!("Error parsing {} template: {:#}", name, e)),
+        }
+    }
+
+    pub(crate) fn render(
+        &self,
+        block: &HiveBlockWithNum,
+        tx: &HiveTransactionWithTxId,
+        index: usize,
+        podping: &Podping,
+    ) -> Result<String, Error> {
+        let template = self.env.get_template(PAYLOAD_TEMPLATE_NAME)?;
+
+        Ok(template.render(context! {
+            block => context! {
+                block_num => block.block_num,
+                block_id => block.block_id,
+                previous => block.previous,
+                witness => block.witness,
+                timestamp => block.timestamp.to_rfc3339(),
+            },
+            tx => context! {
+                tx_id => tx.tx_id,
+            },
+            index => index,
+            event_id => event_id(block.block_num, &tx.tx_id, index),
+            iris => podping_iris(podping),
+            podping => Value::from_serialize(podping),
+        })?)
+    }
+
+    /// Renders and checks the output is valid JSON, for outputs that promise JSON to receivers
+    #[cfg(feature = "forwarder")]
+    pub(crate) fn render_json(
+        &self,
+        block: &HiveBlockWithNum,
+        tx: &HiveTransactionWithTxId,
+        index: usize,
+        podping: &Podping,
+    ) -> Result<String, Error> {
+        let rendered = self.render(block, tx, index, podping)?;
+
+        match serde_json::from_str::<serde_json::Value>(&rendered) {
+            Ok(_) => Ok(rendered),
+            Err(e) => Err(eyre!(
+                "Payload template rendered invalid JSON for block {}, tx {}: {}",
+                block.block_num,
+                tx.tx_id,
+                e
+            )),
+        }
+    }
+}
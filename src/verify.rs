@@ -0,0 +1,278 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::source::source::Source;
+use crate::stage;
+use crate::stage::SharedStage;
+#[cfg(any(
+    feature = "disk",
+    feature = "object-storage",
+    feature = "elasticsearch"
+))]
+use crate::writer::writer::new_writer;
+use crate::writer::writer::Writer;
+#[cfg(any(
+    feature = "disk",
+    feature = "object-storage",
+    feature = "elasticsearch"
+))]
+use color_eyre::eyre::eyre;
+use color_eyre::Report;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+pub(crate) const DEFAULT_VERIFY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+pub(crate) const DEFAULT_VERIFY_LOOKBACK_BLOCKS: u64 = 28_800;
+const VERIFY_CHUNK_SIZE: u64 = 100;
+
+#[derive(Debug, Default)]
+pub(crate) struct VerifyReport {
+    pub(crate) blocks_checked: u64,
+    pub(crate) blocks_repaired: u64,
+    pub(crate) files_missing: usize,
+}
+
+/// Compares written output against the chain and re-writes blocks with missing files
+///
+/// Shares the syncer's source and writer, and runs alongside them.
+pub(crate) struct Verifier<S, W>
+where
+    S: Source + Send + Sync,
+    W: Writer + Send + Sync,
+{
+    source: Arc<S>,
+    writer: Arc<W>,
+    stages: Vec<SharedStage>,
+    interval: Duration,
+    lookback_blocks: u64,
+}
+
+impl<S: Source + Send + Sync + 'static, W: Writer + Send + Sync + 'static> Verifier<S, W> {
+    pub(crate) fn new(
+        settings: &Settings,
+        source: Arc<S>,
+        writer: Arc<W>,
+    ) -> Result<Verifier<S, W>, Report> {
+        Ok(Verifier {
+            source,
+            writer,
+            stages: [stage::stages(settings)?, stage::writer_stages(settings)?].concat(),
+            interval: settings.verify.interval.unwrap_or(DEFAULT_VERIFY_INTERVAL),
+            lookback_blocks: settings
+                .verify
+                .lookback_blocks
+                .unwrap_or(DEFAULT_VERIFY_LOOKBACK_BLOCKS),
+        })
+    }
+
+    /// Default range to check, the lookback window ending at the writer's last_updated_block
+    pub(crate) async fn default_range(&self) -> Result<Option<(u64, u64)>, Report> {
+        Ok(self
+            .writer
+            .get_last_block()
+            .await?
+            .map(|last_block| (last_block.saturating_sub(self.lookback_blocks), last_block)))
+    }
+
+    pub(crate) async fn verify_range(
+        &self,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<VerifyReport, Report> {
+        let mut report = VerifyReport::default();
+
+        info!("verify: checking blocks {} to {}", start_block, end_block);
+
+        for chunk_start in (start_block..=end_block).step_by(VERIFY_CHUNK_SIZE as usize) {
+            let chunk_end = (chunk_start + VERIFY_CHUNK_SIZE - 1).min(end_block);
+            let blocks = self.source.fetch_blocks(chunk_start, chunk_end).await?;
+
+            // Check against what the writer was given, not the raw chain
+            let blocks = stage::apply_stages(blocks, &self.stages);
+
+            for block in blocks {
+                report.blocks_checked += 1;
+
+                let missing = self.writer.missing_files(&block).await?;
+
+                if missing.is_empty() {
+                    continue;
+                }
+
+                for path in &missing {
+                    warn!(
+                        "verify: block {} is missing {}",
+                        block.block_num,
+                        path.to_string_lossy()
+                    );
+                }
+
+                report.files_missing += missing.len();
+                report.blocks_repaired += 1;
+
+                self.writer.repair_block(block).await?;
+            }
+        }
+
+        info!(
+            "verify: checked {} blocks, re-wrote {} blocks with {} missing files",
+            report.blocks_checked, report.blocks_repaired, report.files_missing
+        );
+
+        Ok(report)
+    }
+
+    /// Writes every block in the range again, missing files or not
+    #[cfg(any(
+        feature = "disk",
+        feature = "object-storage",
+        feature = "elasticsearch"
+    ))]
+    pub(crate) async fn backfill_range(
+        &self,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<u64, Report> {
+        let mut blocks_written = 0;
+
+        info!("backfill: writing blocks {} to {}", start_block, end_block);
+
+        for chunk_start in (start_block..=end_block).step_by(VERIFY_CHUNK_SIZE as usize) {
+            let chunk_end = (chunk_start + VERIFY_CHUNK_SIZE - 1).min(end_block);
+            let blocks = self.source.fetch_blocks(chunk_start, chunk_end).await?;
+
+            for block in stage::apply_stages(blocks, &self.stages) {
+                self.writer.repair_block(block).await?;
+                blocks_written += 1;
+            }
+
+            info!("backfill: written up to block {}", chunk_end);
+        }
+
+        info!("backfill: wrote {} blocks", blocks_written);
+
+        Ok(blocks_written)
+    }
+
+    /// Checks the lookback window every interval, forever
+    pub(crate) async fn run(self: Arc<Self>) -> Result<(), Report> {
+        loop {
+            sleep(self.interval).await;
+
+            match self.default_range().await {
+                Ok(Some((start_block, end_block))) => {
+                    if let Err(e) = self.verify_range(start_block, end_block).await {
+                        error!("verify: error checking written podpings: {:#}", e);
+                    }
+                }
+                Ok(None) => info!("verify: nothing written yet, skipping"),
+                Err(e) => error!("verify: error getting last_updated_block: {:#}", e),
+            }
+        }
+    }
+}
+
+/// What `podpingd verify` or `podpingd backfill` was asked to do
+#[cfg(any(
+    feature = "disk",
+    feature = "object-storage",
+    feature = "elasticsearch"
+))]
+pub(crate) enum OneShot {
+    /// Without explicit heights, checks the lookback window ending at last_updated_block
+    Verify {
+        start_block: Option<u64>,
+        end_block: Option<u64>,
+    },
+    /// Writes a fixed range without touching last_updated_block
+    Backfill { start_block: u64, end_block: u64 },
+}
+
+#[cfg(any(
+    feature = "disk",
+    feature = "object-storage",
+    feature = "elasticsearch"
+))]
+impl OneShot {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            OneShot::Verify { .. } => "verify",
+            OneShot::Backfill { .. } => "backfill",
+        }
+    }
+}
+
+/// Runs a one-shot verify or backfill, fetching blocks from source `S` and writing them with `W`
+#[cfg(any(
+    feature = "disk",
+    feature = "object-storage",
+    feature = "elasticsearch"
+))]
+pub(crate) async fn run_once<S, W>(settings: &Settings, one_shot: OneShot) -> Result<(), Report>
+where
+    S: Source + Send + Sync + 'static,
+    W: Writer + Send + Sync + 'static,
+{
+    let verifier = Verifier::new(
+        settings,
+        Arc::new(S::new(settings).await?),
+        Arc::new(new_writer::<W>(settings).await?),
+    )?;
+
+    match one_shot {
+        OneShot::Verify {
+            start_block,
+            end_block,
+        } => {
+            let (default_start_block, default_end_block) = match verifier.default_range().await? {
+                Some(range) => range,
+                None if start_block.is_some() && end_block.is_some() => (0, 0),
+                None => {
+                    return Err(eyre!(
+                        "No last_updated_block to verify up to, set --from-block and --to-block"
+                    ))
+                }
+            };
+
+            let start_block = start_block.unwrap_or(default_start_block);
+            let end_block = end_block.unwrap_or(default_end_block);
+
+            if start_block > end_block {
+                return Err(eyre!(
+                    "Verify start block {} is after end block {}",
+                    start_block,
+                    end_block
+                ));
+            }
+
+            verifier.verify_range(start_block, end_block).await?;
+        }
+        OneShot::Backfill {
+            start_block,
+            end_block,
+        } => {
+            if start_block > end_block {
+                return Err(eyre!(
+                    "Backfill start block {} is after end block {}",
+                    start_block,
+                    end_block
+                ));
+            }
+
+            verifier.backfill_range(start_block, end_block).await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,254 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::iri::canonical_iri;
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use reqwest::StatusCode;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+pub(crate) const DEFAULT_WATCHLIST_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Feeds from `filter.watchlist_path`, kept up to date as the list changes
+pub(crate) static WATCHLIST: LazyLock<Watchlist> = LazyLock::new(Watchlist::new);
+
+enum WatchlistSource {
+    File(PathBuf),
+    Url(String),
+}
+
+impl WatchlistSource {
+    fn new(path: &str) -> WatchlistSource {
+        match path.starts_with("http://") || path.starts_with("https://") {
+            true => WatchlistSource::Url(path.to_string()),
+            false => WatchlistSource::File(PathBuf::from(path)),
+        }
+    }
+}
+
+/// What the list was last read at, to tell whether it's changed since
+#[derive(Clone, PartialEq)]
+enum Version {
+    Modified(Option<SystemTime>),
+    ETag(Option<String>),
+}
+
+/// The xmlUrl of every outline in an OPML subscription list, as podcast apps export them
+fn parse_opml(contents: &str) -> Result<Vec<String>, Error> {
+    let mut reader = Reader::from_str(contents);
+    let mut feeds = vec![];
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(outline) | Event::Empty(outline)
+                if outline
+                    .local_name()
+                    .as_ref()
+                    .eq_ignore_ascii_case(b"outline") =>
+            {
+                for attribute in outline.attributes() {
+                    let attribute = attribute?;
+
+                    if attribute
+                        .key
+                        .local_name()
+                        .as_ref()
+                        .eq_ignore_ascii_case(b"xmlurl")
+                    {
+                        feeds.push(attribute.unescape_value()?.to_string());
+                    }
+                }
+            }
+            Event::Eof => return Ok(feeds),
+            _ => {}
+        }
+    }
+}
+
+/// An OPML file, or one IRI per line skipping blank lines and lines starting with #
+///
+/// IRIs are normalized so they match podpings however the feed was written.
+fn parse_watchlist(contents: &str) -> Result<HashSet<String>, Error> {
+    let iris = match contents.trim_start().starts_with('<') {
+        true => parse_opml(contents).map_err(|e| eyre!("Invalid OPML: {}", e))?,
+        false => contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect(),
+    };
+
+    Ok(iris.iter().map(|iri| canonical_iri(iri)).collect())
+}
+
+pub(crate) struct Watchlist {
+    iris: RwLock<Arc<HashSet<String>>>,
+    version: Mutex<Option<Version>>,
+    http_client: reqwest::Client,
+}
+
+impl Watchlist {
+    fn new() -> Watchlist {
+        Watchlist {
+            iris: RwLock::new(Arc::new(HashSet::new())),
+            version: Mutex::new(None),
+            http_client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The feeds on the list right now, normalized
+    pub(crate) fn iris(&self) -> Arc<HashSet<String>> {
+        self.iris.read().unwrap().clone()
+    }
+
+    /// The list's contents, or None if it hasn't changed since it was last read
+    async fn fetch(&self, source: &WatchlistSource) -> Result<Option<(String, Version)>, Error> {
+        let last_version = self.version.lock().unwrap().clone();
+
+        match source {
+            WatchlistSource::File(path) => {
+                let version = Version::Modified(
+                    tokio::fs::metadata(path)
+                        .await
+                        .and_then(|metadata| metadata.modified())
+                        .ok(),
+                );
+
+                if last_version.as_ref() == Some(&version) {
+                    return Ok(None);
+                }
+
+                // A missing file is only reported once, not on every poll
+                *self.version.lock().unwrap() = Some(version.clone());
+
+                let contents = tokio::fs::read_to_string(path)
+                    .await
+                    .map_err(|e| eyre!("Unable to read {}: {}", path.display(), e))?;
+
+                Ok(Some((contents, version)))
+            }
+            WatchlistSource::Url(url) => {
+                let mut request = self.http_client.get(url);
+
+                if let Some(Version::ETag(Some(etag))) = &last_version {
+                    request = request.header(IF_NONE_MATCH, etag);
+                }
+
+                let response = request.send().await?;
+
+                if response.status() == StatusCode::NOT_MODIFIED {
+                    return Ok(None);
+                }
+
+                let response = response.error_for_status()?;
+                let version = Version::ETag(
+                    response
+                        .headers()
+                        .get(ETAG)
+                        .and_then(|etag| etag.to_str().ok())
+                        .map(|etag| etag.to_string()),
+                );
+                let contents = response.text().await?;
+
+                Ok(Some((contents, version)))
+            }
+        }
+    }
+
+    /// Reads the list again if it's changed, returning whether it was
+    async fn reload(&self, source: &WatchlistSource) -> Result<bool, Error> {
+        let (contents, version) = match self.fetch(source).await? {
+            Some(fetched) => fetched,
+            None => return Ok(false),
+        };
+
+        // Nor is a list that doesn't parse, until it changes again
+        *self.version.lock().unwrap() = Some(version);
+
+        let iris = parse_watchlist(&contents)?;
+        let changed = *self.iris() != iris;
+
+        if iris.is_empty() {
+            warn!("The watchlist is empty, every podping will be filtered out");
+        }
+
+        *self.iris.write().unwrap() = Arc::new(iris);
+
+        Ok(changed)
+    }
+
+    /// Polls the list for changes, forever
+    async fn watch(&self, source: WatchlistSource, interval: Duration) {
+        loop {
+            sleep(interval).await;
+
+            match self.reload(&source).await {
+                Ok(true) => info!("Watchlist reloaded, {} feeds", self.iris().len()),
+                Ok(false) => {}
+                Err(e) => warn!(
+                    "Error reloading the watchlist, keeping the current one: {:#}",
+                    e
+                ),
+            }
+        }
+    }
+}
+
+/// Loads `filter.watchlist_path` and watches it for changes in the background, if it's set
+///
+/// Errors if the list can't be read at startup.
+pub(crate) async fn start(settings: &Settings) -> Result<(), Error> {
+    let path = match settings
+        .filter
+        .watchlist_path
+        .as_deref()
+        .filter(|path| !path.is_empty())
+    {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let source = WatchlistSource::new(path);
+
+    WATCHLIST
+        .reload(&source)
+        .await
+        .map_err(|e| eyre!("Unable to load the watchlist {}: {:#}", path, e))?;
+    info!(
+        "Loaded watchlist {} with {} feeds",
+        path,
+        WATCHLIST.iris().len()
+    );
+
+    tokio::spawn(
+        WATCHLIST.watch(
+            source,
+            settings
+                .filter
+                .watchlist_reload_interval
+                .unwrap_or(DEFAULT_WATCHLIST_RELOAD_INTERVAL),
+        ),
+    );
+
+    Ok(())
+}
@@ -0,0 +1,102 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Writer;
+use crate::hive::scanner::HiveBlockWithNum;
+use crate::podping::event_id;
+use crate::writer::writer::{block_path, podping_file_name};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// Files written for a block besides its podpings, see `writer.block_metadata`,
+/// `writer.empty_block_markers` and `writer.quarantine_malformed`
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BlockSidecars {
+    metadata: bool,
+    // Empty blocks too, so a block without a sidecar is a gap in the archive
+    metadata_empty_blocks: bool,
+    empty_markers: bool,
+    quarantine: bool,
+}
+
+impl BlockSidecars {
+    pub(crate) fn from_settings(writer: &Writer) -> BlockSidecars {
+        BlockSidecars {
+            metadata: writer.block_metadata.unwrap_or(false),
+            metadata_empty_blocks: writer.block_metadata_empty_blocks.unwrap_or(false),
+            empty_markers: writer.empty_block_markers.unwrap_or(false),
+            quarantine: writer.quarantine_malformed.unwrap_or(false),
+        }
+    }
+
+    /// Whether the block gets a `<block_num>_block.json`
+    pub(crate) fn metadata(self, block: &HiveBlockWithNum) -> bool {
+        self.metadata && (self.metadata_empty_blocks || !block.transactions.is_empty())
+    }
+
+    /// Whether the block gets an empty `<block_num>.empty` marker
+    pub(crate) fn empty_marker(self, block: &HiveBlockWithNum) -> bool {
+        self.empty_markers && block.transactions.is_empty()
+    }
+
+    /// Whether the block has podpings that didn't parse to write under `malformed/`
+    pub(crate) fn quarantine(self, block: &HiveBlockWithNum) -> bool {
+        self.quarantine && !block.malformed.is_empty()
+    }
+}
+
+/// Sidecar describing everything written for a block, so consumers can check
+/// a block is complete without listing or fetching every podping object
+#[derive(Serialize, Debug)]
+pub(crate) struct BlockMetadata<'a> {
+    pub(crate) block_num: u64,
+    pub(crate) block_id: &'a str,
+    pub(crate) previous: &'a str,
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) producer: &'a str,
+    pub(crate) podping_count: usize,
+    pub(crate) keys: Vec<String>,
+    /// The event id of each podping in `keys`, in the same order
+    pub(crate) event_ids: Vec<String>,
+}
+
+impl BlockMetadata<'_> {
+    pub(crate) fn from_block(block: &HiveBlockWithNum) -> BlockMetadata<'_> {
+        let current_block_path = block_path(&block.timestamp);
+
+        let (keys, event_ids): (Vec<String>, Vec<String>) = block
+            .transactions
+            .iter()
+            .flat_map(|tx| {
+                tx.indexed_podpings().map(|(i, podping)| {
+                    (
+                        current_block_path
+                            .join(podping_file_name(block.block_num, &tx.tx_id, i, podping))
+                            .to_string_lossy()
+                            .to_string(),
+                        event_id(block.block_num, &tx.tx_id, i),
+                    )
+                })
+            })
+            .unzip();
+
+        BlockMetadata {
+            block_num: block.block_num,
+            block_id: &block.block_id,
+            previous: &block.previous,
+            timestamp: block.timestamp,
+            producer: &block.witness,
+            podping_count: keys.len(),
+            keys,
+            event_ids,
+        }
+    }
+}
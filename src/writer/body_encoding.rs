@@ -0,0 +1,68 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use flate2::read::{GzDecoder, ZlibDecoder};
+use std::io::{Error, ErrorKind, Read};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const UTF8_BOM: [u8; 3] = [0xef, 0xbb, 0xbf];
+
+fn is_zlib(bytes: &[u8]) -> bool {
+    // CMF says deflate with a window <= 32K, no preset dictionary and the header checksum holds
+    // The FDICT check matters, ASCII digits all have that bit set so a checkpoint like "80..." isn't zlib
+    bytes.len() >= 2
+        && bytes[0] & 0x0f == 8
+        && bytes[0] >> 4 <= 7
+        && bytes[1] & 0x20 == 0
+        && ((bytes[0] as u16) << 8 | bytes[1] as u16) % 31 == 0
+}
+
+/// Decodes a stored text body, tolerating what other tools leave behind
+///
+/// Bodies compressed without a Content-Encoding header (gzip or zlib deflate) are
+/// decompressed and a leading UTF-8 BOM is dropped.
+pub fn decode_body(bytes: &[u8]) -> Result<String, Error> {
+    let mut decompressed = Vec::new();
+
+    let bytes = if bytes.starts_with(&GZIP_MAGIC) {
+        GzDecoder::new(bytes).read_to_end(&mut decompressed)?;
+        &decompressed[..]
+    } else if is_zlib(bytes) {
+        ZlibDecoder::new(bytes).read_to_end(&mut decompressed)?;
+        &decompressed[..]
+    } else {
+        bytes
+    };
+
+    let bytes = bytes.strip_prefix(&UTF8_BOM).unwrap_or(bytes);
+
+    String::from_utf8(bytes.to_vec()).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Whether a Content-Type header's media type is one of `accepted`, ignoring parameters like charset
+///
+/// A missing Content-Type is accepted since plenty of S3-compatible tools don't set one.
+#[cfg(feature = "object-storage")]
+pub fn content_type_accepted(content_type: Option<&str>, accepted: &[&str]) -> bool {
+    match content_type {
+        Some(content_type) => {
+            let media_type = content_type
+                .split(';')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .to_ascii_lowercase();
+
+            accepted.iter().any(|a| *a == media_type)
+        }
+        None => true,
+    }
+}
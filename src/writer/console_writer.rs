@@ -9,23 +9,122 @@
  *
  *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
  */
-use crate::config::Settings;
-use crate::hive::scanner::HiveBlockWithNum;
-use crate::writer::writer::Writer;
+use crate::config::{ConsoleFormat, Settings};
+use crate::event_bus::Subscription;
+use crate::hive::scanner::{BlockBatch, HiveBlockWithNum, HiveTransactionWithTxId};
+use crate::podping::{podping_iris, podping_reason, PodpingRecord};
+use crate::template::PayloadTemplate;
+use crate::writer::reorder::{ReorderWindow, DEFAULT_REORDER_WINDOW};
+use crate::writer::writer::{Writer, WriterInitError};
 use color_eyre::eyre::Error;
 use color_eyre::Report;
-use tokio::sync::broadcast::error::RecvError;
-use tokio::sync::broadcast::Receiver;
+use podping_schemas::org::podcastindex::podping::podping_json::Podping;
+use std::io::Write;
+use std::path::PathBuf;
 use tracing::{error, info, warn};
 
-pub fn console_output_block_transactions(
+const CONSOLE_COLUMNS: &[&str] = &[
+    "block_num",
+    "block_id",
+    "timestamp",
+    "tx_id",
+    "index",
+    "reason",
+    "iris",
+];
+
+/// Quotes a CSV field when it has a separator, quote or line break in it
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+/// TSV has no quoting, tabs and line breaks inside a field become spaces
+fn tsv_field(field: &str) -> String {
+    field.replace(['\t', '\n', '\r'], " ")
+}
+
+fn delimited_line(fields: &[String], format: ConsoleFormat) -> String {
+    match format {
+        ConsoleFormat::Csv => fields
+            .iter()
+            .map(|field| csv_field(field))
+            .collect::<Vec<_>>()
+            .join(","),
+        _ => fields
+            .iter()
+            .map(|field| tsv_field(field))
+            .collect::<Vec<_>>()
+            .join("\t"),
+    }
+}
+
+/// Formats a podping for stdout, `None` for the log format which goes through tracing instead
+pub(crate) fn format_podping(
+    format: ConsoleFormat,
+    block: &HiveBlockWithNum,
+    tx: &HiveTransactionWithTxId,
+    index: usize,
+    podping: &Podping,
+) -> Result<Option<String>, Error> {
+    let record = PodpingRecord::new(block, tx, index, podping);
+
+    let line = match format {
+        ConsoleFormat::Log => return Ok(None),
+        ConsoleFormat::Ndjson => serde_json::to_string(&record)?,
+        ConsoleFormat::Pretty => serde_json::to_string_pretty(&record)?,
+        ConsoleFormat::Csv | ConsoleFormat::Tsv => delimited_line(
+            &[
+                block.block_num.to_string(),
+                block.block_id.clone(),
+                block.timestamp.to_rfc3339(),
+                tx.tx_id.clone(),
+                index.to_string(),
+                podping_reason(podping).unwrap_or_default(),
+                podping_iris(podping).join(" "),
+            ],
+            format,
+        ),
+    };
+
+    Ok(Some(line))
+}
+
+pub(crate) fn console_output_block_transactions(
     block: HiveBlockWithNum,
+    format: ConsoleFormat,
+    payload_template: Option<&PayloadTemplate>,
 ) -> color_eyre::Result<(), Report> {
+    if let Some(replaced) = &block.replaces {
+        warn!(
+            "Block {} {} was replaced in a fork by {}",
+            block.block_num, replaced.block_id, block.block_id
+        );
+    }
+
     if block.transactions.is_empty() {
         info!("No Podpings for block {}", block.block_num);
     } else {
+        let mut stdout = std::io::stdout().lock();
+
         for tx in &block.transactions {
-            for podping in tx.podpings.iter() {
+            for (i, podping) in tx.indexed_podpings() {
+                // A closed pipe (podpingd | head) stops the writer rather than panicking
+                if let Some(line) = format_podping(format, &block, tx, i, podping)? {
+                    writeln!(stdout, "{}", line)?;
+                    continue;
+                }
+
+                if let Some(template) = payload_template {
+                    match template.render(&block, tx, i, podping) {
+                        Ok(rendered) => info!("{}", rendered),
+                        Err(e) => error!("Error rendering podping template: {:#}", e),
+                    }
+                    continue;
+                }
+
                 let json = serde_json::to_string(&podping);
 
                 match json {
@@ -45,71 +144,104 @@ pub fn console_output_block_transactions(
     Ok(())
 }
 
-pub(crate) struct ConsoleWriter {}
+pub(crate) struct ConsoleWriter {
+    format: ConsoleFormat,
+    payload_template: Option<PayloadTemplate>,
+    reorder_window: usize,
+}
 
 impl Writer for ConsoleWriter {
-    async fn new(_: &Settings) -> Self
+    async fn new(settings: &Settings) -> Result<Self, WriterInitError>
     where
         Self: Sized,
     {
-        ConsoleWriter {}
+        let format = settings.writer.console_format.unwrap_or(ConsoleFormat::Log);
+        let payload_template = PayloadTemplate::from_settings(
+            "console payload",
+            &settings.writer.console_payload_template,
+            &settings.writer.console_payload_template_file,
+        )
+        .map_err(|e| WriterInitError::Config(e.to_string()))?;
+
+        if payload_template.is_some() && format != ConsoleFormat::Log {
+            warn!("console_payload_template is only used with console_format \"log\", ignoring it");
+        }
+
+        if format == ConsoleFormat::Csv || format == ConsoleFormat::Tsv {
+            let header = CONSOLE_COLUMNS
+                .iter()
+                .map(|column| column.to_string())
+                .collect::<Vec<_>>();
+
+            println!("{}", delimited_line(&header, format));
+        }
+
+        Ok(ConsoleWriter {
+            format,
+            payload_template,
+            reorder_window: settings
+                .writer
+                .reorder_window
+                .unwrap_or(DEFAULT_REORDER_WINDOW),
+        })
     }
 
     async fn get_last_block(&self) -> Result<Option<u64>, Error> {
         Ok(None)
     }
 
-    async fn start(&self, mut rx: Receiver<HiveBlockWithNum>) -> color_eyre::Result<(), Report> {
-        loop {
-            let result = rx.recv().await;
+    async fn preflight(&self) -> Result<(), Error> {
+        Ok(())
+    }
 
-            let block = match result {
-                Ok(block) => Some(block),
-                Err(RecvError::Lagged(e)) => {
-                    warn!("Console writer is lagging: {}", e);
+    async fn start(
+        &self,
+        mut rx: Subscription<HiveBlockWithNum>,
+    ) -> color_eyre::Result<(), Report> {
+        let mut reorder_window = ReorderWindow::new(self.reorder_window);
 
-                    None
-                }
-                Err(RecvError::Closed) => {
-                    panic!("Console writer channel closed");
-                }
+        loop {
+            let event = match rx.recv().await {
+                Some(event) => event,
+                // The scanner stops after scanner.end_block
+                None => return Ok(()),
             };
-            match block {
-                Some(block) => {
-                    console_output_block_transactions(block)?;
-                }
-                None => {}
+
+            for block in reorder_window.push(event.payload)? {
+                console_output_block_transactions(
+                    block,
+                    self.format,
+                    self.payload_template.as_ref(),
+                )?;
             }
+            rx.ack(event.seq);
         }
     }
 
     async fn start_batch(
         &self,
-        mut rx: Receiver<Vec<HiveBlockWithNum>>,
+        mut rx: Subscription<BlockBatch>,
     ) -> color_eyre::Result<(), Report> {
-        loop {
-            let result = rx.recv().await;
-
-            let block = match result {
-                Ok(block) => Some(block),
-                Err(RecvError::Lagged(e)) => {
-                    warn!("Console writer is lagging: {}", e);
-
-                    None
-                }
-                Err(RecvError::Closed) => break,
-            };
-
-            match block {
-                Some(blocks) => {
-                    for block in blocks {
-                        console_output_block_transactions(block)?;
-                    }
-                }
-                None => {}
+        while let Some(event) = rx.recv().await {
+            for block in event.payload.blocks {
+                console_output_block_transactions(
+                    block,
+                    self.format,
+                    self.payload_template.as_ref(),
+                )?;
             }
+            rx.ack(event.seq);
         }
 
         Ok(())
     }
+
+    // Nothing is persisted, so there's nothing to verify
+    async fn missing_files(&self, _block: &HiveBlockWithNum) -> Result<Vec<PathBuf>, Error> {
+        Ok(vec![])
+    }
+
+    async fn repair_block(&self, _block: HiveBlockWithNum) -> Result<(), Error> {
+        Ok(())
+    }
 }
@@ -0,0 +1,626 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::secret_provider::{
+    AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY, AWS_SESSION_TOKEN, SECRET_STORE,
+};
+use crate::secrets::{secret_env_var, secret_env_var_is_set};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use object_store::aws::AwsCredential;
+use object_store::CredentialProvider;
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+const DEFAULT_IMDS_ENDPOINT: &str = "http://169.254.169.254";
+const ECS_CREDENTIALS_ENDPOINT: &str = "http://169.254.170.2";
+const IMDS_TOKEN_TTL_SECONDS: &str = "21600";
+const CREDENTIALS_HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+// Temporary credentials are replaced this long before they expire
+const REFRESH_BEFORE_EXPIRY: TimeDelta = TimeDelta::minutes(5);
+// The background refresher runs well before that, so writes never wait on a refresh
+const BACKGROUND_REFRESH_BEFORE_EXPIRY: TimeDelta = TimeDelta::minutes(15);
+const BACKGROUND_REFRESH_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+// How often the shared credentials file is re-read for rotated keys
+const PROFILE_RELOAD_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Where the object storage credentials come from
+#[derive(Debug)]
+enum CredentialSource {
+    /// Access key and secret from environment variables, or the files their `_FILE` variables name
+    Environment {
+        access_key_var: String,
+        secret_key_var: String,
+        session_token_var: Option<String>,
+    },
+    /// Keys fetched from Vault or a SOPS file, see `secrets.values`
+    SecretsProvider,
+    /// A profile in the shared credentials file
+    Profile { path: PathBuf, profile: String },
+    /// AssumeRoleWithWebIdentity with a token file, e.g. IRSA on EKS
+    WebIdentity {
+        role_arn: String,
+        token_file: PathBuf,
+        session_name: String,
+    },
+    /// The ECS task role, or EKS Pod Identity, from the container credentials endpoint
+    Container { uri: String },
+    /// The EC2 instance profile from instance metadata (IMDSv2)
+    InstanceMetadata { endpoint: String },
+}
+
+impl CredentialSource {
+    fn name(&self) -> &'static str {
+        match self {
+            CredentialSource::Environment { .. } => "environment",
+            CredentialSource::SecretsProvider => "secrets provider",
+            CredentialSource::Profile { .. } => "shared credentials file",
+            CredentialSource::WebIdentity { .. } => "web identity token",
+            CredentialSource::Container { .. } => "container credentials endpoint",
+            CredentialSource::InstanceMetadata { .. } => "instance metadata",
+        }
+    }
+}
+
+struct CachedCredentials {
+    credentials: Arc<AwsCredential>,
+    // None for long-lived keys
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl CachedCredentials {
+    fn expires_soon(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at - REFRESH_BEFORE_EXPIRY <= Utc::now())
+    }
+
+    fn expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+}
+
+/// The JSON both the container endpoint and instance metadata return
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct MetadataCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: Option<String>,
+    expiration: Option<DateTime<Utc>>,
+}
+
+impl From<MetadataCredentials> for CachedCredentials {
+    fn from(metadata: MetadataCredentials) -> Self {
+        CachedCredentials {
+            credentials: Arc::new(AwsCredential {
+                key_id: metadata.access_key_id,
+                secret_key: metadata.secret_access_key,
+                token: metadata.token,
+            }),
+            expires_at: metadata.expiration,
+        }
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn shared_credentials_path() -> Option<PathBuf> {
+    match env_var("AWS_SHARED_CREDENTIALS_FILE") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => env_var("HOME")
+            .or(env_var("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(".aws").join("credentials")),
+    }
+}
+
+/// Reads `profile`'s keys from an INI style shared credentials file
+fn read_profile(path: &Path, profile: &str) -> Result<CachedCredentials, Error> {
+    let text = std::fs::read_to_string(path)?;
+    let mut in_profile = false;
+    let mut values: HashMap<String, String> = HashMap::new();
+
+    for line in text.lines() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_profile = section.trim() == profile;
+            continue;
+        }
+
+        if in_profile {
+            if let Some((key, value)) = line.split_once('=') {
+                values.insert(key.trim().to_lowercase(), value.trim().to_string());
+            }
+        }
+    }
+
+    let (access_key, secret_key) = match (
+        values.remove("aws_access_key_id"),
+        values.remove("aws_secret_access_key"),
+    ) {
+        (Some(access_key), Some(secret_key)) => (access_key, secret_key),
+        _ => {
+            return Err(eyre!(
+                "profile {} in {} has no aws_access_key_id and aws_secret_access_key",
+                profile,
+                path.display()
+            ))
+        }
+    };
+
+    Ok(CachedCredentials {
+        credentials: Arc::new(AwsCredential {
+            key_id: access_key,
+            secret_key,
+            token: values.remove("aws_session_token"),
+        }),
+        expires_at: None,
+    })
+}
+
+/// The text of the first `<tag>` element, enough for the flat STS responses
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find(&close)? + start;
+
+    Some(xml[start..end].trim().to_string())
+}
+
+/// Object storage credentials, refreshed before temporary ones expire
+///
+/// Without explicit environment variable names the source is picked like the AWS SDKs do:
+/// AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, then the AWS_PROFILE (or default) profile in the
+/// shared credentials file, then a web identity token (IRSA), then the container credentials
+/// endpoint (ECS task roles), then EC2 instance metadata.
+pub(crate) struct CredentialsProvider {
+    source: CredentialSource,
+    http_client: Client,
+    cached: Mutex<Option<CachedCredentials>>,
+}
+
+impl CredentialsProvider {
+    fn new(source: CredentialSource) -> Arc<CredentialsProvider> {
+        info!("object storage: using credentials from {}", source.name());
+
+        let provider = Arc::new(CredentialsProvider {
+            source,
+            http_client: Client::builder()
+                .timeout(CREDENTIALS_HTTP_TIMEOUT)
+                .build()
+                .expect("Error building the credentials HTTP client"),
+            cached: Mutex::new(None),
+        });
+
+        if !matches!(provider.source, CredentialSource::Environment { .. }) {
+            tokio::spawn(refresh_in_background(Arc::downgrade(&provider)));
+        }
+
+        provider
+    }
+
+    /// Only reads the keys from the named environment variables
+    pub(crate) fn from_env_vars(
+        access_key_var: &str,
+        secret_key_var: &str,
+        session_token_var: Option<&str>,
+    ) -> Arc<CredentialsProvider> {
+        CredentialsProvider::new(CredentialSource::Environment {
+            access_key_var: access_key_var.to_string(),
+            secret_key_var: secret_key_var.to_string(),
+            session_token_var: session_token_var.map(|var| var.to_string()),
+        })
+    }
+
+    pub(crate) fn from_chain() -> Arc<CredentialsProvider> {
+        if SECRET_STORE.contains(AWS_ACCESS_KEY_ID) {
+            return CredentialsProvider::new(CredentialSource::SecretsProvider);
+        }
+
+        if secret_env_var_is_set("AWS_ACCESS_KEY_ID") {
+            return CredentialsProvider::from_env_vars(
+                "AWS_ACCESS_KEY_ID",
+                "AWS_SECRET_ACCESS_KEY",
+                Some("AWS_SESSION_TOKEN"),
+            );
+        }
+
+        if let Some(path) = shared_credentials_path() {
+            let profile = env_var("AWS_PROFILE").unwrap_or("default".to_string());
+
+            if path.is_file() {
+                match read_profile(&path, &profile) {
+                    Ok(_) => {
+                        return CredentialsProvider::new(CredentialSource::Profile {
+                            path,
+                            profile,
+                        })
+                    }
+                    Err(e) => debug!("Skipping shared credentials file: {:#}", e),
+                }
+            }
+        }
+
+        if let (Some(token_file), Some(role_arn)) = (
+            env_var("AWS_WEB_IDENTITY_TOKEN_FILE"),
+            env_var("AWS_ROLE_ARN"),
+        ) {
+            return CredentialsProvider::new(CredentialSource::WebIdentity {
+                role_arn,
+                token_file: PathBuf::from(token_file),
+                session_name: env_var("AWS_ROLE_SESSION_NAME").unwrap_or("podpingd".to_string()),
+            });
+        }
+
+        if let Some(relative_uri) = env_var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI") {
+            return CredentialsProvider::new(CredentialSource::Container {
+                uri: format!("{}{}", ECS_CREDENTIALS_ENDPOINT, relative_uri),
+            });
+        }
+
+        if let Some(uri) = env_var("AWS_CONTAINER_CREDENTIALS_FULL_URI") {
+            return CredentialsProvider::new(CredentialSource::Container { uri });
+        }
+
+        CredentialsProvider::new(CredentialSource::InstanceMetadata {
+            endpoint: env_var("AWS_EC2_METADATA_SERVICE_ENDPOINT")
+                .unwrap_or(DEFAULT_IMDS_ENDPOINT.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+        })
+    }
+
+    /// The current credentials, fetching new ones first when they're about to expire
+    ///
+    /// If the refresh fails the old credentials are used for as long as they're still valid.
+    pub(crate) async fn get(&self) -> Result<Arc<AwsCredential>, Error> {
+        let mut cached = self.cached.lock().await;
+
+        if let Some(current) = cached.as_ref() {
+            if !current.expires_soon() {
+                return Ok(current.credentials.clone());
+            }
+        }
+
+        self.refresh(&mut cached).await
+    }
+
+    async fn refresh(
+        &self,
+        cached: &mut Option<CachedCredentials>,
+    ) -> Result<Arc<AwsCredential>, Error> {
+        match self.fetch().await {
+            Ok(fresh) => {
+                if let Some(expires_at) = fresh.expires_at {
+                    debug!(
+                        "object storage: fetched credentials from {}, expiring at {}",
+                        self.source.name(),
+                        expires_at
+                    );
+                }
+
+                let credentials = fresh.credentials.clone();
+                *cached = Some(fresh);
+
+                Ok(credentials)
+            }
+            Err(e) => match cached.as_ref() {
+                Some(current) if !current.expired() => {
+                    warn!(
+                        "object storage: error refreshing credentials from {}, using the current ones until they expire: {:#}",
+                        self.source.name(),
+                        e
+                    );
+
+                    Ok(current.credentials.clone())
+                }
+                _ => Err(e.wrap_err(format!(
+                    "Error getting object storage credentials from {}",
+                    self.source.name()
+                ))),
+            },
+        }
+    }
+
+    async fn fetch(&self) -> Result<CachedCredentials, Error> {
+        match &self.source {
+            CredentialSource::Environment {
+                access_key_var,
+                secret_key_var,
+                session_token_var,
+            } => {
+                let access_key = secret_env_var(access_key_var)?
+                    .ok_or_else(|| eyre!("{} is not set", access_key_var))?;
+                let secret_key = secret_env_var(secret_key_var)?
+                    .ok_or_else(|| eyre!("{} is not set", secret_key_var))?;
+                let token = match session_token_var {
+                    Some(session_token_var) => secret_env_var(session_token_var)?,
+                    None => None,
+                };
+
+                let credentials = AwsCredential {
+                    key_id: access_key,
+                    secret_key,
+                    token,
+                };
+
+                // Set by `aws configure export-credentials`, the environment can't be refreshed
+                // so this only turns the 403s after expiry into a clear error
+                let expires_at = env_var("AWS_CREDENTIAL_EXPIRATION")
+                    .and_then(|expiration| DateTime::parse_from_rfc3339(&expiration).ok())
+                    .map(|expiration| expiration.with_timezone(&Utc));
+
+                if let Some(expires_at) = expires_at.filter(|expires_at| *expires_at <= Utc::now())
+                {
+                    return Err(eyre!(
+                        "The session credentials in {} expired at {}",
+                        access_key_var,
+                        expires_at
+                    ));
+                }
+
+                Ok(CachedCredentials {
+                    credentials: Arc::new(credentials),
+                    expires_at,
+                })
+            }
+            CredentialSource::SecretsProvider => {
+                let (access_key, expires_at) = SECRET_STORE
+                    .get(AWS_ACCESS_KEY_ID)
+                    .ok_or_else(|| eyre!("{} wasn't fetched", AWS_ACCESS_KEY_ID))?;
+                let (secret_key, _) = SECRET_STORE
+                    .get(AWS_SECRET_ACCESS_KEY)
+                    .ok_or_else(|| eyre!("{} wasn't fetched", AWS_SECRET_ACCESS_KEY))?;
+
+                Ok(CachedCredentials {
+                    credentials: Arc::new(AwsCredential {
+                        key_id: access_key,
+                        secret_key,
+                        token: SECRET_STORE.get(AWS_SESSION_TOKEN).map(|(token, _)| token),
+                    }),
+                    // Its lease, the store has new keys before then
+                    expires_at,
+                })
+            }
+            CredentialSource::Profile { path, profile } => read_profile(path, profile),
+            CredentialSource::WebIdentity {
+                role_arn,
+                token_file,
+                session_name,
+            } => {
+                self.assume_role_with_web_identity(role_arn, token_file, session_name)
+                    .await
+            }
+            CredentialSource::Container { uri } => {
+                let mut request = self.http_client.get(uri);
+
+                // EKS Pod Identity rotates the token file, so it's read on every fetch
+                if let Some(token_file) = env_var("AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE") {
+                    let token = tokio::fs::read_to_string(&token_file).await?;
+                    request = request.header("Authorization", token.trim());
+                } else if let Some(token) = env_var("AWS_CONTAINER_AUTHORIZATION_TOKEN") {
+                    request = request.header("Authorization", token);
+                }
+
+                let metadata: MetadataCredentials =
+                    request.send().await?.error_for_status()?.json().await?;
+
+                Ok(metadata.into())
+            }
+            CredentialSource::InstanceMetadata { endpoint } => {
+                let token = self
+                    .http_client
+                    .put(format!("{}/latest/api/token", endpoint))
+                    .header(
+                        "X-aws-ec2-metadata-token-ttl-seconds",
+                        IMDS_TOKEN_TTL_SECONDS,
+                    )
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .text()
+                    .await?;
+
+                let credentials_url =
+                    format!("{}/latest/meta-data/iam/security-credentials/", endpoint);
+
+                let role = self
+                    .http_client
+                    .get(&credentials_url)
+                    .header("X-aws-ec2-metadata-token", &token)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .text()
+                    .await?;
+                let role = match role.lines().next() {
+                    Some(role) if !role.trim().is_empty() => role.trim().to_string(),
+                    _ => return Err(eyre!("The instance has no IAM role attached")),
+                };
+
+                let metadata: MetadataCredentials = self
+                    .http_client
+                    .get(format!("{}{}", credentials_url, role))
+                    .header("X-aws-ec2-metadata-token", &token)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+
+                Ok(metadata.into())
+            }
+        }
+    }
+
+    /// How long the background refresher waits before its next refresh, None if it never needs to
+    async fn next_refresh_in(&self) -> Option<Duration> {
+        let cached = self.cached.lock().await;
+
+        let expires_at = match cached.as_ref() {
+            // Not fetched yet or the last refresh failed outright
+            None => return Some(BACKGROUND_REFRESH_RETRY_INTERVAL),
+            Some(current) => current.expires_at,
+        };
+
+        match (expires_at, &self.source) {
+            (Some(expires_at), _) => Some(
+                (expires_at - BACKGROUND_REFRESH_BEFORE_EXPIRY - Utc::now())
+                    .to_std()
+                    .unwrap_or_default()
+                    .max(BACKGROUND_REFRESH_RETRY_INTERVAL),
+            ),
+            (None, CredentialSource::Profile { .. } | CredentialSource::SecretsProvider) => {
+                Some(PROFILE_RELOAD_INTERVAL)
+            }
+            (None, _) => None,
+        }
+    }
+
+    async fn assume_role_with_web_identity(
+        &self,
+        role_arn: &str,
+        token_file: &Path,
+        session_name: &str,
+    ) -> Result<CachedCredentials, Error> {
+        // The token is rotated by the kubelet, so it's read on every fetch
+        let token = tokio::fs::read_to_string(token_file).await?;
+
+        let sts_url = match env_var("AWS_REGION").or(env_var("AWS_DEFAULT_REGION")) {
+            Some(region) => format!("https://sts.{}.amazonaws.com/", region),
+            None => "https://sts.amazonaws.com/".to_string(),
+        };
+
+        let response = self
+            .http_client
+            .get(sts_url)
+            .query(&[
+                ("Action", "AssumeRoleWithWebIdentity"),
+                ("Version", "2011-06-15"),
+                ("RoleArn", role_arn),
+                ("RoleSessionName", session_name),
+                ("WebIdentityToken", token.trim()),
+            ])
+            .send()
+            .await?;
+
+        let status = response.status();
+        let text = response.text().await?;
+
+        if !status.is_success() {
+            return Err(eyre!(
+                "AssumeRoleWithWebIdentity returned {}: {}",
+                status,
+                xml_value(&text, "Message").unwrap_or(text)
+            ));
+        }
+
+        match (
+            xml_value(&text, "AccessKeyId"),
+            xml_value(&text, "SecretAccessKey"),
+            xml_value(&text, "SessionToken"),
+        ) {
+            (Some(access_key), Some(secret_key), Some(token)) => Ok(CachedCredentials {
+                credentials: Arc::new(AwsCredential {
+                    key_id: access_key,
+                    secret_key,
+                    token: Some(token),
+                }),
+                expires_at: xml_value(&text, "Expiration")
+                    .and_then(|expiration| DateTime::parse_from_rfc3339(&expiration).ok())
+                    .map(|expiration| expiration.with_timezone(&Utc)),
+            }),
+            _ => Err(eyre!(
+                "AssumeRoleWithWebIdentity response is missing the credentials"
+            )),
+        }
+    }
+}
+
+// object_store wants Debug, leave the keys out of it
+impl fmt::Debug for CredentialsProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CredentialsProvider")
+            .field("source", &self.source.name())
+            .finish()
+    }
+}
+
+/// Signs every object_store S3 request with the current credentials
+#[async_trait]
+impl CredentialProvider for CredentialsProvider {
+    type Credential = AwsCredential;
+
+    async fn get_credential(&self) -> object_store::Result<Arc<AwsCredential>> {
+        self.get().await.map_err(|e| object_store::Error::Generic {
+            store: "S3",
+            source: e.into(),
+        })
+    }
+}
+
+/// Fetches new temporary credentials ahead of expiry, and re-reads the shared credentials file
+/// for rotated keys, until the provider is dropped
+async fn refresh_in_background(provider: Weak<CredentialsProvider>) {
+    loop {
+        let delay = match provider.upgrade() {
+            Some(provider) => match provider.next_refresh_in().await {
+                Some(delay) => delay,
+                None => return,
+            },
+            None => return,
+        };
+
+        sleep(delay).await;
+
+        let provider = match provider.upgrade() {
+            Some(provider) => provider,
+            None => return,
+        };
+
+        let mut cached = provider.cached.lock().await;
+        let previous_key = cached
+            .as_ref()
+            .map(|current| current.credentials.key_id.clone());
+
+        match provider.refresh(&mut cached).await {
+            Ok(credentials) => {
+                if previous_key.is_some_and(|previous_key| previous_key != credentials.key_id) {
+                    info!(
+                        "object storage: picked up new credentials from {}",
+                        provider.source.name()
+                    );
+                }
+            }
+            Err(e) => warn!("object storage: {:#}", e),
+        }
+    }
+}
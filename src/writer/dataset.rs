@@ -0,0 +1,325 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+pub const DEFAULT_DATASET_PREFIX: &str = "datasets";
+pub const DATASET_SUMS_FILE_NAME: &str = "SHA256SUMS";
+#[cfg(feature = "object-storage")]
+pub const CONTENT_TYPE_ZSTD: &str = "application/zstd";
+const DATASET_ZSTD_LEVEL: i32 = 19;
+
+pub fn dataset_file_name(date: NaiveDate) -> String {
+    format!("podping-{}.tar.zst", date.format("%Y-%m-%d"))
+}
+
+/// Relative directory a day's podpings are written under, i.e. `YYYY/M/D`
+pub fn day_path(date: NaiveDate) -> PathBuf {
+    PathBuf::new()
+        .join(date.year().to_string())
+        .join(date.month().to_string())
+        .join(date.day().to_string())
+}
+
+/// A file going into a dataset archive, read as it's added
+pub struct DatasetEntry {
+    /// Path relative to the writer root
+    pub path: String,
+    pub size: u64,
+    pub contents: Box<dyn Read + Send>,
+}
+
+/// Hashes everything written through it
+struct Sha256Writer<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for Sha256Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Streams the entries into `out` as a reproducible `.tar.zst`, returning `out` and the
+/// archive's SHA-256
+///
+/// Entries have to come in path order and get fixed metadata, so the same day always produces
+/// the same checksum. Only one entry is held at a time, but compressing a day takes a while, so
+/// run it with `spawn_blocking`.
+pub fn write_dataset_archive<W: Write>(
+    out: W,
+    entries: impl IntoIterator<Item = Result<DatasetEntry, Error>>,
+) -> Result<(W, String), Error> {
+    let encoder = zstd::Encoder::new(
+        Sha256Writer {
+            inner: out,
+            hasher: Sha256::new(),
+        },
+        DATASET_ZSTD_LEVEL,
+    )?;
+    let mut builder = tar::Builder::new(encoder);
+    let mut last_path: Option<String> = None;
+
+    for entry in entries {
+        let entry = entry?;
+
+        if last_path.as_ref().is_some_and(|last| *last >= entry.path) {
+            return Err(eyre!(
+                "dataset entry {} is out of order, the archive wouldn't be reproducible",
+                entry.path
+            ));
+        }
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(entry.size);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        header.set_cksum();
+
+        builder.append_data(&mut header, &entry.path, entry.contents)?;
+        last_path = Some(entry.path);
+    }
+
+    let writer = builder.into_inner()?.finish()?;
+
+    Ok((writer.inner, hex::encode(writer.hasher.finalize())))
+}
+
+/// Adds or replaces the archive's line in a `sha256sum` compatible checksum file
+pub fn update_sha256sums(existing: &str, name: &str, sha256: &str) -> String {
+    let mut lines = existing
+        .lines()
+        .filter(|line| !line.is_empty())
+        .filter(|line| line.split_whitespace().nth(1) != Some(name))
+        .map(|line| line.to_string())
+        .collect::<Vec<_>>();
+
+    lines.push(format!("{}  {}", sha256, name));
+    lines.sort_by(|a, b| {
+        a.split_whitespace()
+            .nth(1)
+            .cmp(&b.split_whitespace().nth(1))
+    });
+
+    lines.join("\n") + "\n"
+}
+
+/// Whether the checksum file has a line for the archive, it's written last so a listed archive
+/// was published in full
+pub fn sha256sums_lists(sums: &str, name: &str) -> bool {
+    sums.lines()
+        .any(|line| line.split_whitespace().nth(1) == Some(name))
+}
+
+/// Tracks the day of the last written block to spot when a day is complete, and publishes
+/// completed days one at a time
+///
+/// Publishing reads and rewrites `SHA256SUMS`, so two days published at once could lose a line.
+#[derive(Default)]
+pub struct DayRollover {
+    last_date: Mutex<Option<NaiveDate>>,
+    publishing: Arc<tokio::sync::Mutex<()>>,
+    pending: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl DayRollover {
+    /// Starts from the day of the writer's checkpoint, so a day that completes across a restart
+    /// is still published
+    pub fn starting_at(last_date: Option<NaiveDate>) -> DayRollover {
+        DayRollover {
+            last_date: Mutex::new(last_date),
+            publishing: Arc::default(),
+            pending: Mutex::default(),
+        }
+    }
+
+    /// Returns the previous day once a block from a later day is written
+    pub fn completed_day(&self, timestamp: &DateTime<Utc>) -> Option<NaiveDate> {
+        let date = timestamp.date_naive();
+        let mut last_date = self.last_date.lock().unwrap();
+
+        let completed = match *last_date {
+            Some(last) if date > last => Some(last),
+            _ => None,
+        };
+
+        if last_date.is_none_or(|last| date > last) {
+            *last_date = Some(date);
+        }
+
+        completed
+    }
+
+    /// Runs `publish` in the background once any day before it is done publishing
+    pub fn publish<F>(&self, publish: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let publishing = self.publishing.clone();
+        let mut pending = self.pending.lock().unwrap();
+
+        pending.retain(|handle| !handle.is_finished());
+        pending.push(tokio::spawn(async move {
+            let _publishing = publishing.lock().await;
+            publish.await;
+        }));
+    }
+
+    /// Waits for the days still being published, so a bounded scan doesn't exit part way through
+    /// one
+    pub async fn finish(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+
+        for handle in pending {
+            let _ = handle.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::io::Cursor;
+
+    fn entry(path: &str, contents: &str) -> Result<DatasetEntry, Error> {
+        Ok(DatasetEntry {
+            path: path.to_string(),
+            size: contents.len() as u64,
+            contents: Box::new(Cursor::new(contents.as_bytes().to_vec())),
+        })
+    }
+
+    fn day_entries() -> Vec<Result<DatasetEntry, Error>> {
+        vec![
+            entry("2025/1/7/0/0/3/95000000_abc_0.json", r#"{"version":"1.0"}"#),
+            entry("2025/1/7/0/0/3/95000000_abc_1.json", r#"{"version":"1.1"}"#),
+            entry("2025/1/7/23/59/57/95028799_def_0.json", r#"{"url":"x"}"#),
+        ]
+    }
+
+    #[test]
+    fn archive_is_reproducible() {
+        let (first, first_sha256) = write_dataset_archive(vec![], day_entries()).unwrap();
+        let (second, second_sha256) = write_dataset_archive(vec![], day_entries()).unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(first_sha256, second_sha256);
+        assert_eq!(first_sha256, hex::encode(Sha256::digest(&first)));
+    }
+
+    #[test]
+    fn archive_holds_the_entries_in_order() {
+        let (bytes, _) = write_dataset_archive(vec![], day_entries()).unwrap();
+        let tar = zstd::decode_all(bytes.as_slice()).unwrap();
+        let mut archive = tar::Archive::new(tar.as_slice());
+
+        let entries = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents).unwrap();
+
+                assert_eq!(entry.header().mtime().unwrap(), 0);
+                assert_eq!(entry.header().mode().unwrap(), 0o644);
+
+                (
+                    entry.path().unwrap().to_string_lossy().to_string(),
+                    contents,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            entries,
+            vec![
+                (
+                    "2025/1/7/0/0/3/95000000_abc_0.json".to_string(),
+                    r#"{"version":"1.0"}"#.to_string()
+                ),
+                (
+                    "2025/1/7/0/0/3/95000000_abc_1.json".to_string(),
+                    r#"{"version":"1.1"}"#.to_string()
+                ),
+                (
+                    "2025/1/7/23/59/57/95028799_def_0.json".to_string(),
+                    r#"{"url":"x"}"#.to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn archive_rejects_entries_out_of_order() {
+        let mut entries = day_entries();
+        entries.reverse();
+
+        assert!(write_dataset_archive(vec![], entries).is_err());
+    }
+
+    #[test]
+    fn sha256sums_adds_a_line_in_name_order() {
+        let sums = update_sha256sums("", "podping-2025-01-08.tar.zst", "bbb");
+        let sums = update_sha256sums(&sums, "podping-2025-01-07.tar.zst", "aaa");
+
+        assert_eq!(
+            sums,
+            "aaa  podping-2025-01-07.tar.zst\nbbb  podping-2025-01-08.tar.zst\n"
+        );
+    }
+
+    #[test]
+    fn sha256sums_replaces_a_republished_day() {
+        let existing = "aaa  podping-2025-01-07.tar.zst\n\nbbb  podping-2025-01-08.tar.zst\n";
+        let sums = update_sha256sums(existing, "podping-2025-01-07.tar.zst", "ccc");
+
+        assert_eq!(
+            sums,
+            "ccc  podping-2025-01-07.tar.zst\nbbb  podping-2025-01-08.tar.zst\n"
+        );
+        assert!(sha256sums_lists(&sums, "podping-2025-01-08.tar.zst"));
+        assert!(!sha256sums_lists(&sums, "podping-2025-01-09.tar.zst"));
+    }
+
+    #[test]
+    fn rollover_completes_the_checkpoint_day() {
+        let rollover = DayRollover::starting_at(NaiveDate::from_ymd_opt(2025, 1, 7));
+
+        let same_day = Utc.with_ymd_and_hms(2025, 1, 7, 23, 59, 57).unwrap();
+        let next_day = Utc.with_ymd_and_hms(2025, 1, 8, 0, 0, 0).unwrap();
+
+        assert_eq!(rollover.completed_day(&same_day), None);
+        assert_eq!(
+            rollover.completed_day(&next_day),
+            NaiveDate::from_ymd_opt(2025, 1, 7)
+        );
+        // A late block from the day before doesn't complete the new day
+        assert_eq!(rollover.completed_day(&same_day), None);
+    }
+}
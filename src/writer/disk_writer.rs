@@ -10,89 +10,362 @@
  *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
  */
 use crate::config::Settings;
-use crate::hive::scanner::HiveBlockWithNum;
-use crate::writer::writer::Writer;
-use crate::writer::writer::LAST_UPDATED_BLOCK_FILENAME;
+use crate::event_bus::Subscription;
+use crate::hive::scanner::{BlockBatch, HiveBlockWithNum};
+use crate::status::SYNC_STATUS;
+use crate::writer::block_metadata::{BlockMetadata, BlockSidecars};
+use crate::writer::body_encoding::decode_body;
+use crate::writer::dataset::{
+    dataset_file_name, day_path, sha256sums_lists, update_sha256sums, write_dataset_archive,
+    DatasetEntry, DayRollover, DATASET_SUMS_FILE_NAME, DEFAULT_DATASET_PREFIX,
+};
+use crate::writer::failure_policy::FailureHandler;
+use crate::writer::portable_path::{long_path_directory, portable_file_name, portable_path};
+use crate::writer::quarantine::quarantine_files;
+use crate::writer::reorder::{ReorderWindow, DEFAULT_REORDER_WINDOW};
+use crate::writer::tombstone::{orphaned_paths, tombstone_path, Tombstone};
+use crate::writer::writer::PREFLIGHT_FILENAME;
+use crate::writer::writer::{
+    block_file_paths, block_metadata_file_name, block_path, checkpoint_file_name,
+    checkpoint_timestamp, empty_block_marker_file_name, is_temp_file, parse_last_block,
+    podping_file_name, temp_path, BlockWriteError, Checkpoint, Writer, WriterInitError,
+};
 use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Timelike, Utc};
-use color_eyre::eyre::Error;
+use color_eyre::eyre::{eyre, Error};
 use color_eyre::Result;
-use podping_schemas::org::podcastindex::podping::podping_json::Podping;
 use regex::Regex;
 use std::fs::remove_dir_all;
-use std::path::PathBuf;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
-use tokio::sync::broadcast::error::RecvError;
-use tokio::sync::broadcast::Receiver;
+use tokio::io::AsyncWriteExt;
 use tokio::task::JoinSet;
 use tokio::time::Instant;
 use tracing::{debug, error, info, warn};
 use walkdir::WalkDir;
 
+/// Writes to a temp file next to `path` and renames it over, so a crash never leaves half a file
+///
+/// With `fsync` the file and its directory are flushed, so the rename survives a power cut too.
+pub(crate) async fn write_file_atomically(
+    path: &Path,
+    contents: impl AsRef<[u8]>,
+    fsync: bool,
+) -> Result<(), std::io::Error> {
+    let temp_path = temp_path(path);
+
+    let written = async {
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        file.write_all(contents.as_ref()).await?;
+        if fsync {
+            file.sync_all().await?;
+        }
+        drop(file);
+
+        tokio::fs::rename(&temp_path, path).await
+    }
+    .await;
+
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(e);
+    }
+
+    // Directories can't be opened for syncing on Windows
+    #[cfg(unix)]
+    if fsync {
+        if let Some(parent) = path.parent() {
+            tokio::fs::File::open(parent).await?.sync_all().await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn disk_tombstone_replaced_block(
+    data_dir_path: &PathBuf,
+    fsync: bool,
+    sidecars: BlockSidecars,
+    block: &HiveBlockWithNum,
+) -> Result<(), Error> {
+    let tombstone = match Tombstone::for_correction(block) {
+        Some(tombstone) => serde_json::to_string(&tombstone)?,
+        None => return Ok(()),
+    };
+
+    for path in orphaned_paths(block, sidecars) {
+        let file = data_dir_path.join(portable_path(&path));
+
+        match tokio::fs::remove_file(&file).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        }
+
+        info!(
+            "Tombstoning forked podping file: {}",
+            file.to_string_lossy()
+        );
+        write_file_atomically(&tombstone_path(&file), &tombstone, fsync).await?;
+    }
+
+    Ok(())
+}
+
+/// Writes the block's malformed podpings under `malformed/`, see `writer.quarantine_malformed`
+async fn disk_quarantine_malformed(
+    data_dir_path: &Path,
+    fsync: bool,
+    block: &HiveBlockWithNum,
+) -> Result<(), Error> {
+    for (path, json) in quarantine_files(block)? {
+        let quarantine_file = data_dir_path.join(portable_path(&path));
+
+        warn!(
+            "Quarantining malformed podping to file: {}",
+            quarantine_file.to_string_lossy()
+        );
+
+        if let Some(parent) = quarantine_file.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        write_file_atomically(&quarantine_file, json, fsync).await?;
+        SYNC_STATUS.increment_counter("podpings_quarantined", 1);
+    }
+
+    Ok(())
+}
+
+/// Podping files are written atomically so consumers never parse half a file, even after a crash
 async fn disk_write_block_transactions(
     data_dir_path: PathBuf,
+    sidecars: BlockSidecars,
+    fsync: bool,
     block: HiveBlockWithNum,
 ) -> Result<(), Error> {
+    disk_tombstone_replaced_block(&data_dir_path, fsync, sidecars, &block).await?;
+
+    if sidecars.quarantine(&block) {
+        disk_quarantine_malformed(&data_dir_path, fsync, &block).await?;
+    }
+
     if block.transactions.is_empty() {
         info!("No Podpings for block {}", block.block_num);
-    } else {
-        let current_block_dir = data_dir_path
-            .join(block.timestamp.year().to_string())
-            .join(block.timestamp.month().to_string())
-            .join(block.timestamp.day().to_string())
-            .join(block.timestamp.hour().to_string())
-            .join(block.timestamp.minute().to_string())
-            .join(block.timestamp.second().to_string());
-
-        let create_dir_future = tokio::fs::create_dir_all(&current_block_dir);
-
-        let mut write_join_set = JoinSet::new();
-
-        for tx in &block.transactions {
-            for (i, podping) in tx.podpings.iter().enumerate() {
-                let podping_file = match podping {
-                    Podping::V0(_) | Podping::V02(_) | Podping::V03(_) | Podping::V10(_) => {
-                        current_block_dir
-                            .join(format!("{}_{}_{}.json", block.block_num, tx.tx_id, i))
-                    }
-                    Podping::V11(pp) => current_block_dir.join(format!(
-                        "{}_{}_{}_{}.json",
-                        block.block_num,
-                        tx.tx_id,
-                        pp.session_id.to_string(),
-                        pp.timestamp_ns.to_string()
-                    )),
-                };
-
-                let json = serde_json::to_string(&podping);
-
-                match json {
-                    Ok(json) => {
-                        info!(
-                            "block: {}, tx: {}, podping: {}",
-                            block.block_num, tx.tx_id, json
-                        );
-
-                        info!(
-                            "Writing podping to file: {}",
-                            podping_file.to_string_lossy()
-                        );
-                        write_join_set.spawn(tokio::fs::write(podping_file, json));
-                    }
-                    Err(e) => {
-                        error!(
-                            "Error writing podping file {}: {}",
-                            podping_file.to_string_lossy(),
-                            e
-                        );
-                    }
+
+        if sidecars.empty_marker(&block) {
+            let current_block_dir = data_dir_path.join(block_path(&block.timestamp));
+            let marker_file = current_block_dir.join(empty_block_marker_file_name(block.block_num));
+
+            debug!(
+                "Writing empty block marker to file: {}",
+                marker_file.to_string_lossy()
+            );
+            tokio::fs::create_dir_all(&current_block_dir).await?;
+            write_file_atomically(&marker_file, "", fsync).await?;
+        }
+
+        if !sidecars.metadata(&block) {
+            return Ok(());
+        }
+    }
+
+    let current_block_dir = data_dir_path.join(block_path(&block.timestamp));
+
+    // Before any write is spawned, a spawned write can run before this future is polled
+    tokio::fs::create_dir_all(&current_block_dir).await?;
+
+    let mut write_join_set = JoinSet::new();
+    let mut failed = vec![];
+
+    for tx in &block.transactions {
+        for (i, podping) in tx.indexed_podpings() {
+            let podping_file = current_block_dir.join(portable_file_name(&podping_file_name(
+                block.block_num,
+                &tx.tx_id,
+                i,
+                podping,
+            )));
+
+            let json = serde_json::to_string(&podping);
+
+            match json {
+                Ok(json) => {
+                    info!(
+                        "block: {}, tx: {}, podping: {}",
+                        block.block_num, tx.tx_id, json
+                    );
+
+                    info!(
+                        "Writing podping to file: {}",
+                        podping_file.to_string_lossy()
+                    );
+                    write_join_set.spawn(async move {
+                        let result = write_file_atomically(&podping_file, json, fsync).await;
+
+                        (podping_file, result)
+                    });
+                }
+                Err(e) => {
+                    error!(
+                        "Error writing podping file {}: {}",
+                        podping_file.to_string_lossy(),
+                        e
+                    );
+                    failed.push((podping_file, e.to_string()));
                 }
             }
         }
+    }
+
+    let mut written = 0;
 
-        create_dir_future.await?;
+    for (podping_file, result) in write_join_set.join_all().await {
+        match result {
+            Ok(_) => written += 1,
+            Err(e) => failed.push((podping_file, e.to_string())),
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(BlockWriteError {
+            block_num: block.block_num,
+            written,
+            failed: failed
+                .into_iter()
+                .map(|(podping_file, e)| {
+                    let relative_path = podping_file
+                        .strip_prefix(&data_dir_path)
+                        .unwrap_or(&podping_file)
+                        .to_path_buf();
+
+                    (relative_path, e)
+                })
+                .collect(),
+        }
+        .into());
+    }
 
-        write_join_set.join_all().await;
+    if sidecars.metadata(&block) {
+        let metadata_file = current_block_dir.join(block_metadata_file_name(block.block_num));
+        let mut metadata = BlockMetadata::from_block(&block);
+        metadata.keys = metadata
+            .keys
+            .iter()
+            .map(|key| portable_path(Path::new(key)).to_string_lossy().to_string())
+            .collect();
+        let json = serde_json::to_string(&metadata)?;
+
+        debug!(
+            "Writing block metadata to file: {}",
+            metadata_file.to_string_lossy()
+        );
+        write_file_atomically(&metadata_file, json, fsync).await?;
     }
+
+    Ok(())
+}
+
+/// Streams a day's files from disk into its archive, returning the archive's size and SHA-256,
+/// or None for a day without podpings
+///
+/// Blocks while it compresses, see `write_dataset_archive`.
+fn disk_write_dataset_archive(
+    data_dir_path: &Path,
+    archive_file: &Path,
+    date: NaiveDate,
+) -> Result<Option<(u64, String)>, Error> {
+    let day_dir = data_dir_path.join(day_path(date));
+    let mut files = vec![];
+
+    if day_dir.is_dir() {
+        for entry in WalkDir::new(&day_dir) {
+            let entry = entry?;
+
+            // A temp file is a write still in progress, or one that died part way
+            if !entry.file_type().is_file() || is_temp_file(entry.path()) {
+                continue;
+            }
+
+            let relative_path = entry.path().strip_prefix(data_dir_path)?;
+            files.push((
+                relative_path.to_string_lossy().to_string(),
+                entry.path().to_path_buf(),
+                entry.metadata()?.len(),
+            ));
+        }
+    }
+
+    if files.is_empty() {
+        return Ok(None);
+    }
+
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    if let Some(parent) = archive_file.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let temp_path = temp_path(archive_file);
+
+    let entries = files.into_iter().map(|(path, file_path, size)| {
+        Ok(DatasetEntry {
+            path,
+            size,
+            contents: Box::new(std::fs::File::open(file_path)?),
+        })
+    });
+    let (out, sha256) =
+        write_dataset_archive(BufWriter::new(std::fs::File::create(&temp_path)?), entries)?;
+    let size = out.into_inner()?.metadata()?.len();
+
+    std::fs::rename(&temp_path, archive_file)?;
+
+    Ok(Some((size, sha256)))
+}
+
+async fn disk_publish_dataset(
+    data_dir_path: PathBuf,
+    dataset_dir_path: PathBuf,
+    date: NaiveDate,
+) -> Result<(), Error> {
+    let archive_file = dataset_dir_path.join(dataset_file_name(date));
+
+    let archive = {
+        let archive_file = archive_file.clone();
+
+        tokio::task::spawn_blocking(move || {
+            disk_write_dataset_archive(&data_dir_path, &archive_file, date)
+        })
+        .await??
+    };
+
+    let (size, sha256) = match archive {
+        Some(archive) => archive,
+        None => {
+            info!("dataset: no podpings for {}, skipping", date);
+            return Ok(());
+        }
+    };
+
+    let sums_file = dataset_dir_path.join(DATASET_SUMS_FILE_NAME);
+    let existing_sums = match tokio::fs::read_to_string(&sums_file).await {
+        Ok(sums) => sums,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    write_file_atomically(
+        &sums_file,
+        update_sha256sums(&existing_sums, &dataset_file_name(date), &sha256),
+        false,
+    )
+    .await?;
+
+    info!(
+        "dataset: published {} ({} bytes)",
+        archive_file.to_string_lossy(),
+        size
+    );
+
     Ok(())
 }
 
@@ -206,8 +479,9 @@ fn disk_trim_before(
             }
         };
 
+        // Skip the checkpoint file and dataset directory
         if !dir_entry.file_type().is_dir() {
-            break;
+            continue;
         }
 
         let entry_path = dir_entry.into_path();
@@ -250,7 +524,7 @@ fn disk_trim_before(
                             }
                         }
                     }
-                    None => break,
+                    None => continue,
                 }
             }
             _ => break,
@@ -279,54 +553,191 @@ pub(crate) struct DiskWriter {
     directory: PathBuf,
     last_block_file: PathBuf,
     keep_duration: Option<Duration>,
+    sidecars: BlockSidecars,
+    fsync: bool,
+    reorder_window: usize,
+    dataset_directory: Option<PathBuf>,
+    dataset_rollover: DayRollover,
+    failure_handler: FailureHandler,
+}
+
+impl DiskWriter {
+    async fn write_block(&self, block: HiveBlockWithNum) -> Result<(), Error> {
+        let checkpoint = Checkpoint::from_block(&block);
+        let is_correction = block.replaces.is_some();
+        self.failure_handler
+            .write_block(block, |block| {
+                disk_write_block_transactions(
+                    self.directory.clone(),
+                    self.sidecars,
+                    self.fsync,
+                    block,
+                )
+            })
+            .await?;
+
+        // Corrections are for blocks already behind the checkpoint
+        if is_correction {
+            return Ok(());
+        }
+
+        self.failure_handler
+            .write_checkpoint(checkpoint.block_num, || async {
+                Ok(
+                    write_file_atomically(&self.last_block_file, checkpoint.to_json(), true)
+                        .await?,
+                )
+            })
+            .await?;
+        self.publish_completed_day(&checkpoint.timestamp);
+
+        Ok(())
+    }
+
+    /// Packages the previous day in the background once a block from a new day is written
+    fn publish_completed_day(&self, block_timestamp: &DateTime<Utc>) {
+        let dataset_directory = match &self.dataset_directory {
+            Some(dataset_directory) => dataset_directory.clone(),
+            None => return,
+        };
+
+        if let Some(date) = self.dataset_rollover.completed_day(block_timestamp) {
+            self.publish_dataset(dataset_directory, date);
+        }
+    }
+
+    fn publish_dataset(&self, dataset_directory: PathBuf, date: NaiveDate) {
+        let directory = self.directory.clone();
+
+        self.dataset_rollover.publish(async move {
+            if let Err(e) = disk_publish_dataset(directory, dataset_directory, date).await {
+                error!("dataset: error publishing {}: {:#}", date, e);
+            }
+        });
+    }
 }
 
 impl Writer for DiskWriter {
-    async fn new(settings: &Settings) -> Self
+    async fn new(settings: &Settings) -> Result<Self, WriterInitError>
     where
         Self: Sized,
     {
         let dir_path = match settings.writer.disk_directory.clone() {
             Some(disk_directory) => match disk_directory.is_empty() {
-                true => panic!("Data directory is empty"),
+                true => {
+                    return Err(WriterInitError::Config(
+                        "Data directory is empty".to_string(),
+                    ))
+                }
                 false => PathBuf::from(disk_directory),
             },
-            None => panic!("Data directory is not set!"),
+            None => {
+                return Err(WriterInitError::Config(
+                    "Data directory is not set!".to_string(),
+                ))
+            }
         };
 
         if !dir_path.is_dir() {
-            panic!(
+            return Err(WriterInitError::Config(format!(
                 "Data directory {} is not a directory.  Please ensure it exists",
                 dir_path.display()
-            );
+            )));
         }
 
-        let last_block_file = dir_path.join(LAST_UPDATED_BLOCK_FILENAME);
+        let dir_path = long_path_directory(dir_path);
+
+        let last_block_file = dir_path.join(checkpoint_file_name(
+            settings.writer.checkpoint_name.as_deref(),
+        )?);
+
+        let sidecars = BlockSidecars::from_settings(&settings.writer);
+        let fsync = settings.writer.disk_fsync.unwrap_or(false);
+
+        let failure_handler = FailureHandler::new(
+            "disk",
+            settings.writer.failure_policy,
+            settings.writer.failure_dead_letter_directory.clone(),
+            settings.writer.failure_retry_interval,
+        )?
+        .with_destination(dir_path.display());
+
+        let reorder_window = settings
+            .writer
+            .reorder_window
+            .unwrap_or(DEFAULT_REORDER_WINDOW);
+
+        let dataset_directory = match settings.writer.dataset_publish.unwrap_or(false) {
+            true => Some(
+                dir_path.join(
+                    settings
+                        .writer
+                        .dataset_prefix
+                        .clone()
+                        .unwrap_or(DEFAULT_DATASET_PREFIX.to_string()),
+                ),
+            ),
+            false => None,
+        };
 
-        match settings.writer.disk_trim_old.unwrap_or(false) {
-            true => {
-                DiskWriter {
-                    directory: dir_path,
-                    last_block_file,
-                    keep_duration: Some(settings.writer.disk_trim_keep_duration.expect(
-                        "disk_trim_old is enabled but disk_trim_keep_duration is not set!",
-                    )),
+        let keep_duration = match settings.writer.disk_trim_old.unwrap_or(false) {
+            true => match settings.writer.disk_trim_keep_duration {
+                Some(keep_duration) => Some(keep_duration),
+                None => {
+                    return Err(WriterInitError::Config(
+                        "disk_trim_old is enabled but disk_trim_keep_duration is not set!"
+                            .to_string(),
+                    ))
                 }
-            }
-            false => DiskWriter {
-                directory: dir_path,
-                last_block_file,
-                keep_duration: None,
             },
+            false => None,
+        };
+
+        // Picks up from the checkpoint's day, so a day that completes across a restart is published
+        let checkpoint_date = match dataset_directory {
+            Some(_) => tokio::fs::read(&last_block_file)
+                .await
+                .ok()
+                .and_then(|bytes| decode_body(&bytes).ok())
+                .and_then(|text| checkpoint_timestamp(&text))
+                .map(|timestamp| timestamp.date_naive()),
+            None => None,
+        };
+
+        let disk_writer = DiskWriter {
+            directory: dir_path,
+            last_block_file,
+            keep_duration,
+            sidecars,
+            fsync,
+            reorder_window,
+            dataset_directory: dataset_directory.clone(),
+            dataset_rollover: DayRollover::starting_at(checkpoint_date),
+            failure_handler,
+        };
+
+        // The day before the checkpoint's is complete, stopping may have cut its publishing short
+        if let (Some(dataset_directory), Some(previous_date)) = (
+            dataset_directory,
+            checkpoint_date.and_then(|date| date.pred_opt()),
+        ) {
+            let sums = tokio::fs::read_to_string(dataset_directory.join(DATASET_SUMS_FILE_NAME))
+                .await
+                .unwrap_or_default();
+
+            if disk_writer.directory.join(day_path(previous_date)).is_dir()
+                && !sha256sums_lists(&sums, &dataset_file_name(previous_date))
+            {
+                disk_writer.publish_dataset(dataset_directory, previous_date);
+            }
         }
+
+        Ok(disk_writer)
     }
 
     async fn get_last_block(&self) -> Result<Option<u64>, Error> {
-        match tokio::fs::read_to_string(&self.last_block_file).await {
-            Ok(s) => match s.trim().parse::<u64>() {
-                Ok(block) => Ok(Some(block)),
-                _ => Ok(None),
-            },
+        match tokio::fs::read(&self.last_block_file).await {
+            Ok(bytes) => parse_last_block(&decode_body(&bytes)?),
             Err(e) => match e.kind() {
                 std::io::ErrorKind::NotFound => Ok(None),
                 _ => Err(e.into()),
@@ -334,21 +745,41 @@ impl Writer for DiskWriter {
         }
     }
 
-    async fn start(&self, mut rx: Receiver<HiveBlockWithNum>) -> Result<(), Error> {
-        let mut start_time = Instant::now();
+    async fn preflight(&self) -> Result<(), Error> {
+        let preflight_file = self.directory.join(PREFLIGHT_FILENAME);
 
-        loop {
-            let result = rx.recv().await;
+        if let Err(e) = tokio::fs::write(&preflight_file, "preflight").await {
+            return Err(eyre!(
+                "Can't write to data directory {}: {}",
+                self.directory.display(),
+                e
+            ));
+        }
 
-            let block = match result {
-                Ok(block) => Some(block),
-                Err(RecvError::Lagged(e)) => {
-                    warn!("Disk writer is lagging: {}", e);
+        if let Err(e) = tokio::fs::remove_file(&preflight_file).await {
+            return Err(eyre!(
+                "Can't delete from data directory {}: {}",
+                self.directory.display(),
+                e
+            ));
+        }
 
-                    None
-                }
-                Err(RecvError::Closed) => {
-                    panic!("Disk writer channel closed");
+        info!("disk preflight: data directory is writable");
+
+        Ok(())
+    }
+
+    async fn start(&self, mut rx: Subscription<HiveBlockWithNum>) -> Result<(), Error> {
+        let mut start_time = Instant::now();
+        let mut reorder_window = ReorderWindow::new(self.reorder_window);
+
+        loop {
+            let event = match rx.recv().await {
+                Some(event) => event,
+                // The scanner stops after scanner.end_block
+                None => {
+                    self.dataset_rollover.finish().await;
+                    return Ok(());
                 }
             };
 
@@ -368,49 +799,82 @@ impl Writer for DiskWriter {
                 None => (),
             };
 
-            match block {
-                Some(block) => {
-                    let block_num = block.block_num.to_owned();
-                    disk_write_block_transactions(self.directory.clone(), block).await?;
-                    tokio::fs::write(&self.last_block_file, block_num.to_string()).await?
-                }
-                None => {}
+            for block in reorder_window.push(event.payload)? {
+                let (block_num, timestamp) = (block.block_num, block.timestamp);
+
+                self.write_block(block).await?;
+                self.failure_handler
+                    .record_write_latency(block_num, timestamp);
             }
+            rx.ack(event.seq);
         }
     }
 
-    async fn start_batch(&self, mut rx: Receiver<Vec<HiveBlockWithNum>>) -> Result<(), Error> {
-        loop {
-            let result = rx.recv().await;
+    async fn start_batch(&self, mut rx: Subscription<BlockBatch>) -> Result<(), Error> {
+        while let Some(event) = rx.recv().await {
+            let blocks = event.payload.blocks;
+            let checkpoint = Checkpoint::from_block(blocks.last().unwrap());
+            let mut write_join_set = JoinSet::new();
+
+            for block in blocks {
+                let failure_handler = self.failure_handler.clone();
+                let directory = self.directory.clone();
+                let sidecars = self.sidecars;
+                let fsync = self.fsync;
+
+                write_join_set.spawn(async move {
+                    failure_handler
+                        .write_block(block, |block| {
+                            disk_write_block_transactions(directory.clone(), sidecars, fsync, block)
+                        })
+                        .await
+                });
+            }
 
-            let block = match result {
-                Ok(block) => Some(block),
-                Err(RecvError::Lagged(e)) => {
-                    warn!("Disk writer is lagging: {}", e);
+            for result in write_join_set.join_all().await {
+                result?;
+            }
 
-                    None
-                }
-                Err(RecvError::Closed) => break,
-            };
+            self.failure_handler
+                .write_checkpoint(checkpoint.block_num, || async {
+                    Ok(
+                        write_file_atomically(&self.last_block_file, checkpoint.to_json(), true)
+                            .await?,
+                    )
+                })
+                .await?;
+            self.publish_completed_day(&checkpoint.timestamp);
+            rx.ack(event.seq);
+        }
 
-            match block {
-                Some(blocks) => {
-                    let last_block_num = &blocks.last().unwrap().block_num.to_string();
-                    let mut write_join_set = JoinSet::new();
+        self.dataset_rollover.finish().await;
 
-                    for block in blocks {
-                        write_join_set
-                            .spawn(disk_write_block_transactions(self.directory.clone(), block));
-                    }
+        Ok(())
+    }
 
-                    write_join_set.join_all().await;
+    async fn missing_files(&self, block: &HiveBlockWithNum) -> Result<Vec<PathBuf>, Error> {
+        let mut missing = vec![];
 
-                    tokio::fs::write(&self.last_block_file, last_block_num.to_string()).await?;
-                }
-                None => {}
+        for path in block_file_paths(block, self.sidecars) {
+            let path = portable_path(&path);
+            if !tokio::fs::try_exists(self.directory.join(&path)).await? {
+                missing.push(path);
             }
         }
 
-        Ok(())
+        Ok(missing)
+    }
+
+    async fn repair_block(&self, block: HiveBlockWithNum) -> Result<(), Error> {
+        self.failure_handler
+            .write_block(block, |block| {
+                disk_write_block_transactions(
+                    self.directory.clone(),
+                    self.sidecars,
+                    self.fsync,
+                    block,
+                )
+            })
+            .await
     }
 }
@@ -0,0 +1,582 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::event_bus::Subscription;
+use crate::hive::scanner::{BlockBatch, HiveBlockWithNum, HiveTransactionWithTxId};
+use crate::podping::{event_id, podping_iris, podping_medium, podping_reason};
+use crate::writer::failure_policy::FailureHandler;
+use crate::writer::reorder::{ReorderWindow, DEFAULT_REORDER_WINDOW};
+use crate::writer::writer::{checkpoint_file_name, Checkpoint, Writer, WriterInitError};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{eyre, Error};
+use podping_schemas::org::podcastindex::podping::podping_json::Podping;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+const DEFAULT_INDEX_PATTERN: &str = "podpings-%Y.%m";
+const DEFAULT_CHECKPOINT_INDEX: &str = "podpingd-checkpoints";
+const DEFAULT_MAX_RETRIES: u32 = 5;
+/// Actions per bulk request, catch-up batches are split up to stay well under the request limit
+const BULK_MAX_ACTIONS: usize = 1000;
+const RETRY_MIN_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_MAX_INTERVAL: Duration = Duration::from_secs(30);
+const CONTENT_TYPE_NDJSON: &str = "application/x-ndjson";
+
+/// How a podping is indexed, the podping itself is kept but not indexed as its shape varies by version
+#[derive(Serialize)]
+struct PodpingDocument<'a> {
+    #[serde(rename = "@timestamp")]
+    timestamp: DateTime<Utc>,
+    event_id: String,
+    block_num: u64,
+    block_id: &'a str,
+    tx_id: &'a str,
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    medium: Option<String>,
+    iris: Vec<String>,
+    podping: &'a Podping,
+}
+
+#[derive(Deserialize)]
+struct BulkResponse {
+    errors: bool,
+    items: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+struct GetResponse<T> {
+    found: bool,
+    #[serde(rename = "_source")]
+    source: Option<T>,
+}
+
+fn document_id(block_num: u64, tx_id: &str, index: usize) -> String {
+    format!("{}_{}_{}", block_num, tx_id, index)
+}
+
+fn block_podpings(
+    block: &HiveBlockWithNum,
+) -> impl Iterator<Item = (&HiveTransactionWithTxId, usize, &Podping)> {
+    block.transactions.iter().flat_map(|tx| {
+        tx.indexed_podpings()
+            .map(move |(i, podping)| (tx, i, podping))
+    })
+}
+
+/// Bulk actions for a block, a correction also deletes the forked block's documents
+///
+/// Each action is its line and, for an index action, the document's line.
+fn block_actions(index_pattern: &str, block: &HiveBlockWithNum) -> Result<Vec<String>, Error> {
+    let index = block.timestamp.format(index_pattern).to_string();
+    let mut actions = vec![];
+    let mut ids = HashSet::new();
+
+    for (tx, i, podping) in block_podpings(block) {
+        let id = document_id(block.block_num, &tx.tx_id, i);
+        let document = serde_json::to_string(&PodpingDocument {
+            timestamp: block.timestamp,
+            event_id: event_id(block.block_num, &tx.tx_id, i),
+            block_num: block.block_num,
+            block_id: &block.block_id,
+            tx_id: &tx.tx_id,
+            index: i,
+            reason: podping_reason(podping),
+            medium: podping_medium(podping),
+            iris: podping_iris(podping),
+            podping,
+        })?;
+
+        actions.push(format!(
+            "{}\n{}",
+            json!({ "index": { "_index": index, "_id": id } }),
+            document
+        ));
+        ids.insert(id);
+    }
+
+    if let Some(replaced) = &block.replaces {
+        let replaced_index = replaced.timestamp.format(index_pattern).to_string();
+
+        for (tx, i, _) in block_podpings(replaced) {
+            let id = document_id(replaced.block_num, &tx.tx_id, i);
+
+            if !ids.contains(&id) {
+                actions
+                    .push(json!({ "delete": { "_index": replaced_index, "_id": id } }).to_string());
+            }
+        }
+    }
+
+    Ok(actions)
+}
+
+/// Index template so every monthly index gets the same mapping, keyword fields for the Kibana filters
+fn index_template(index_prefix: &str) -> Value {
+    json!({
+        "index_patterns": [format!("{}*", index_prefix)],
+        "template": {
+            "mappings": {
+                "dynamic": false,
+                "properties": {
+                    "@timestamp": { "type": "date" },
+                    "event_id": { "type": "keyword" },
+                    "block_num": { "type": "long" },
+                    "block_id": { "type": "keyword" },
+                    "tx_id": { "type": "keyword" },
+                    "index": { "type": "integer" },
+                    "reason": { "type": "keyword" },
+                    "medium": { "type": "keyword" },
+                    "iris": { "type": "keyword" },
+                    "podping": { "type": "object", "enabled": false }
+                }
+            }
+        }
+    })
+}
+
+#[derive(Clone)]
+struct ElasticsearchClient {
+    http_client: Client,
+    base_url: String,
+    username: Option<String>,
+    password: Option<String>,
+    api_key: Option<String>,
+    max_retries: u32,
+}
+
+impl ElasticsearchClient {
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        let request = self
+            .http_client
+            .request(method, format!("{}/{}", self.base_url, path));
+
+        match (&self.api_key, &self.username) {
+            (Some(api_key), _) => request.header("Authorization", format!("ApiKey {}", api_key)),
+            (None, Some(username)) => request.basic_auth(username, self.password.as_ref()),
+            (None, None) => request,
+        }
+    }
+
+    /// Sends the bulk request, resending the actions the cluster pushed back on with a 429
+    async fn bulk(&self, mut actions: Vec<String>) -> Result<(), Error> {
+        let mut attempt = 0;
+        let mut retry_interval = RETRY_MIN_INTERVAL;
+
+        loop {
+            let mut body = actions.join("\n");
+            body.push('\n');
+
+            let response = self
+                .request(reqwest::Method::POST, "_bulk")
+                .header(CONTENT_TYPE, CONTENT_TYPE_NDJSON)
+                .body(body)
+                .send()
+                .await?;
+
+            let rejected = match response.status() {
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => actions,
+                status if status.is_success() => {
+                    let response = response.json::<BulkResponse>().await?;
+
+                    if !response.errors {
+                        return Ok(());
+                    }
+
+                    let mut rejected = vec![];
+                    let mut failed = vec![];
+
+                    // One item per action, in the order they were sent
+                    for (item, action) in response.items.iter().zip(actions) {
+                        let (kind, result) = match item.as_object().and_then(|i| i.iter().next()) {
+                            Some(item) => item,
+                            None => return Err(eyre!("Unexpected bulk response item {}", item)),
+                        };
+
+                        match result["status"].as_u64().unwrap_or(0) {
+                            200..=299 => {}
+                            // Already gone
+                            404 if kind == "delete" => {}
+                            429 => rejected.push(action),
+                            _ => failed.push(format!(
+                                "{}/{} ({})",
+                                result["_index"].as_str().unwrap_or_default(),
+                                result["_id"].as_str().unwrap_or_default(),
+                                result["error"]
+                            )),
+                        }
+                    }
+
+                    if !failed.is_empty() {
+                        return Err(eyre!(
+                            "{} bulk actions failed: {}",
+                            failed.len(),
+                            failed.join(", ")
+                        ));
+                    }
+
+                    rejected
+                }
+                status => {
+                    return Err(eyre!(
+                        "Bulk request failed with {}: {}",
+                        status,
+                        response.text().await.unwrap_or_default()
+                    ))
+                }
+            };
+
+            attempt += 1;
+            if attempt > self.max_retries {
+                return Err(eyre!(
+                    "Cluster still rejecting {} bulk actions after {} retries",
+                    rejected.len(),
+                    self.max_retries
+                ));
+            }
+
+            warn!(
+                "elasticsearch writer: cluster is busy, retrying {} bulk actions in {:?}",
+                rejected.len(),
+                retry_interval
+            );
+            sleep(retry_interval).await;
+            retry_interval = (retry_interval * 2).min(RETRY_MAX_INTERVAL);
+            actions = rejected;
+        }
+    }
+
+    async fn put_json(&self, path: &str, body: &Value) -> Result<(), Error> {
+        let response = self
+            .request(reqwest::Method::PUT, path)
+            .json(body)
+            .send()
+            .await?;
+
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(eyre!(
+                "PUT {} failed with {}: {}",
+                path,
+                response.status(),
+                response.text().await.unwrap_or_default()
+            )),
+        }
+    }
+}
+
+/// Bulk indexes podpings into a time-based index per podping, e.g. `podpings-2025.01`
+///
+/// Documents are keyed by block, transaction and index, so writing a block again just replaces
+/// them. The checkpoint is a document in its own index.
+pub(crate) struct ElasticsearchWriter {
+    client: ElasticsearchClient,
+    index_pattern: String,
+    checkpoint_index: String,
+    checkpoint_id: String,
+    reorder_window: usize,
+    failure_handler: FailureHandler,
+}
+
+impl ElasticsearchWriter {
+    async fn write_blocks(&self, blocks: Vec<HiveBlockWithNum>) -> Result<(), Error> {
+        let mut actions = vec![];
+
+        for block in &blocks {
+            actions.extend(block_actions(&self.index_pattern, block)?);
+        }
+
+        for chunk in actions.chunks(BULK_MAX_ACTIONS) {
+            self.client.bulk(chunk.to_vec()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn write_checkpoint(&self, checkpoint: &Checkpoint) -> Result<(), Error> {
+        self.client
+            .put_json(
+                &format!("{}/_doc/{}", self.checkpoint_index, self.checkpoint_id),
+                &serde_json::to_value(checkpoint)?,
+            )
+            .await
+    }
+
+    async fn write_batch(&self, blocks: Vec<HiveBlockWithNum>) -> Result<(), Error> {
+        let (first, last) = match (blocks.first(), blocks.last()) {
+            (Some(first), Some(last)) => (first.block_num, last.block_num),
+            _ => return Ok(()),
+        };
+        let checkpoint = Checkpoint::from_block(blocks.last().unwrap());
+        let is_correction = blocks.len() == 1 && blocks[0].replaces.is_some();
+
+        if blocks
+            .iter()
+            .any(|block| !block.transactions.is_empty() || block.replaces.is_some())
+        {
+            self.failure_handler
+                .write(&format!("blocks {}-{}", first, last), blocks, |blocks| {
+                    self.write_blocks(blocks)
+                })
+                .await?;
+        } else {
+            debug!("No Podpings for blocks {}-{}", first, last);
+        }
+
+        // Corrections are for blocks already behind the checkpoint
+        if is_correction {
+            return Ok(());
+        }
+
+        self.failure_handler
+            .write_checkpoint(checkpoint.block_num, || self.write_checkpoint(&checkpoint))
+            .await
+    }
+}
+
+impl Writer for ElasticsearchWriter {
+    async fn new(settings: &Settings) -> Result<Self, WriterInitError>
+    where
+        Self: Sized,
+    {
+        let base_url = match settings.writer.elasticsearch_url.clone() {
+            Some(url) if !url.is_empty() => url.trim_end_matches('/').to_string(),
+            _ => {
+                return Err(WriterInitError::Config(
+                    "elasticsearch_url is not set!".to_string(),
+                ))
+            }
+        };
+
+        let index_pattern = settings
+            .writer
+            .elasticsearch_index_pattern
+            .clone()
+            .unwrap_or(DEFAULT_INDEX_PATTERN.to_string());
+
+        if StrftimeItems::new(&index_pattern).any(|item| item == Item::Error) {
+            return Err(WriterInitError::Config(format!(
+                "elasticsearch_index_pattern {} isn't a valid date format",
+                index_pattern
+            )));
+        }
+
+        // The template matches every index the pattern produces by the part before the date
+        let index_prefix = index_pattern.split('%').next().unwrap_or_default();
+        if index_prefix.is_empty() {
+            return Err(WriterInitError::Config(
+                "elasticsearch_index_pattern must start with a fixed prefix, e.g. podpings-%Y.%m"
+                    .to_string(),
+            ));
+        }
+
+        let client = ElasticsearchClient {
+            http_client: Client::new(),
+            base_url,
+            username: settings.writer.elasticsearch_username.clone(),
+            password: settings.writer.elasticsearch_password.clone(),
+            api_key: settings.writer.elasticsearch_api_key.clone(),
+            max_retries: settings
+                .writer
+                .elasticsearch_max_retries
+                .unwrap_or(DEFAULT_MAX_RETRIES),
+        };
+
+        if settings
+            .writer
+            .elasticsearch_install_template
+            .unwrap_or(true)
+        {
+            let template_name = index_prefix.trim_end_matches(['-', '_', '.']);
+
+            if let Err(e) = client
+                .put_json(
+                    &format!("_index_template/{}", template_name),
+                    &index_template(index_prefix),
+                )
+                .await
+            {
+                return Err(WriterInitError::Unavailable(format!(
+                    "Unable to install the index template {}: {:#}",
+                    template_name, e
+                )));
+            }
+
+            info!(
+                "elasticsearch writer: index template {} installed",
+                template_name
+            );
+        }
+
+        let failure_handler = FailureHandler::new(
+            "elasticsearch",
+            settings.writer.failure_policy,
+            settings.writer.failure_dead_letter_directory.clone(),
+            settings.writer.failure_retry_interval,
+        )?
+        .with_destination(&index_pattern);
+
+        Ok(ElasticsearchWriter {
+            client,
+            index_pattern,
+            checkpoint_index: settings
+                .writer
+                .elasticsearch_checkpoint_index
+                .clone()
+                .unwrap_or(DEFAULT_CHECKPOINT_INDEX.to_string()),
+            checkpoint_id: checkpoint_file_name(settings.writer.checkpoint_name.as_deref())?,
+            reorder_window: settings
+                .writer
+                .reorder_window
+                .unwrap_or(DEFAULT_REORDER_WINDOW),
+            failure_handler,
+        })
+    }
+
+    async fn get_last_block(&self) -> Result<Option<u64>, Error> {
+        let response = self
+            .client
+            .request(
+                reqwest::Method::GET,
+                &format!("{}/_doc/{}", self.checkpoint_index, self.checkpoint_id),
+            )
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                let response = response.json::<GetResponse<Checkpoint>>().await?;
+
+                Ok(match response.found {
+                    true => response.source.map(|checkpoint| checkpoint.block_num),
+                    false => None,
+                })
+            }
+            status => Err(eyre!(
+                "Error reading the checkpoint from {}: {}",
+                self.checkpoint_index,
+                status
+            )),
+        }
+    }
+
+    async fn preflight(&self) -> Result<(), Error> {
+        let response = self.client.request(reqwest::Method::GET, "").send().await?;
+
+        if !response.status().is_success() {
+            return Err(eyre!(
+                "Can't reach the cluster at {}: {}",
+                self.client.base_url,
+                response.status()
+            ));
+        }
+
+        info!("elasticsearch preflight: cluster is reachable");
+
+        Ok(())
+    }
+
+    async fn start(&self, mut rx: Subscription<HiveBlockWithNum>) -> Result<(), Error> {
+        let mut reorder_window = ReorderWindow::new(self.reorder_window);
+
+        loop {
+            let event = match rx.recv().await {
+                Some(event) => event,
+                // The scanner stops after scanner.end_block
+                None => return Ok(()),
+            };
+
+            for block in reorder_window.push(event.payload)? {
+                let (block_num, timestamp) = (block.block_num, block.timestamp);
+
+                self.write_batch(vec![block]).await?;
+                self.failure_handler
+                    .record_write_latency(block_num, timestamp);
+            }
+            rx.ack(event.seq);
+        }
+    }
+
+    async fn start_batch(&self, mut rx: Subscription<BlockBatch>) -> Result<(), Error> {
+        while let Some(event) = rx.recv().await {
+            self.write_batch(event.payload.blocks).await?;
+            rx.ack(event.seq);
+        }
+
+        Ok(())
+    }
+
+    async fn missing_files(&self, block: &HiveBlockWithNum) -> Result<Vec<PathBuf>, Error> {
+        let index = block.timestamp.format(&self.index_pattern).to_string();
+        let ids = block_podpings(block)
+            .map(|(tx, i, _)| document_id(block.block_num, &tx.tx_id, i))
+            .collect::<Vec<_>>();
+
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let response = self
+            .client
+            .request(
+                reqwest::Method::GET,
+                &format!("{}/_mget?_source=false", index),
+            )
+            .json(&json!({ "ids": ids }))
+            .send()
+            .await?;
+
+        // The month's index doesn't exist at all
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(ids
+                .iter()
+                .map(|id| PathBuf::from(&index).join(id))
+                .collect());
+        }
+
+        if !response.status().is_success() {
+            return Err(eyre!(
+                "Error checking documents in {}: {}",
+                index,
+                response.status()
+            ));
+        }
+
+        let docs = response.json::<Value>().await?;
+
+        Ok(docs["docs"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|doc| !doc["found"].as_bool().unwrap_or(false))
+            .filter_map(|doc| doc["_id"].as_str())
+            .map(|id| PathBuf::from(&index).join(id))
+            .collect())
+    }
+
+    async fn repair_block(&self, block: HiveBlockWithNum) -> Result<(), Error> {
+        self.failure_handler
+            .write_block(block, |block| self.write_blocks(vec![block]))
+            .await
+    }
+}
@@ -0,0 +1,275 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::WriterFailurePolicy;
+use crate::hive::scanner::HiveBlockWithNum;
+use crate::status::SYNC_STATUS;
+use crate::writer::writer::{BlockWriteError, WriterInitError};
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::Error;
+use color_eyre::Result;
+use serde::Serialize;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{error, instrument, warn};
+
+const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize)]
+struct DeadLetter<'a, T: Serialize> {
+    writer: &'static str,
+    // Where the payload was headed: the data directory, bucket, container or index
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<&'a str>,
+    error: String,
+    // The files that weren't written, when the rest of the block was
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed_paths: Vec<PathBuf>,
+    payload: &'a T,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct FailureHandler {
+    name: &'static str,
+    policy: WriterFailurePolicy,
+    dead_letter_directory: Option<PathBuf>,
+    retry_interval: Duration,
+    destination: Option<String>,
+}
+
+impl FailureHandler {
+    pub(crate) fn new(
+        name: &'static str,
+        policy: Option<WriterFailurePolicy>,
+        dead_letter_directory: Option<String>,
+        retry_interval: Option<Duration>,
+    ) -> Result<FailureHandler, WriterInitError> {
+        let policy = policy.unwrap_or(WriterFailurePolicy::Abort);
+
+        let dead_letter_directory = match policy {
+            WriterFailurePolicy::Skip => match dead_letter_directory {
+                Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+                _ => {
+                    return Err(WriterInitError::Config(format!(
+                        "{} failure_policy is skip but failure_dead_letter_directory is not set!",
+                        name
+                    )))
+                }
+            },
+            _ => None,
+        };
+
+        Ok(FailureHandler {
+            name,
+            policy,
+            dead_letter_directory,
+            retry_interval: retry_interval.unwrap_or(DEFAULT_RETRY_INTERVAL),
+            destination: None,
+        })
+    }
+
+    /// Names where payloads are written in their dead letter files
+    #[cfg_attr(
+        not(any(
+            feature = "disk",
+            feature = "object-storage",
+            feature = "elasticsearch",
+            feature = "parquet"
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) fn with_destination(mut self, destination: impl ToString) -> FailureHandler {
+        self.destination = Some(destination.to_string());
+        self
+    }
+
+    async fn dead_letter<T: Serialize>(
+        &self,
+        description: &str,
+        payload: &T,
+        err: &Error,
+    ) -> Result<(), Error> {
+        // Checked in new()
+        let dir = self.dead_letter_directory.as_ref().unwrap();
+
+        tokio::fs::create_dir_all(dir).await?;
+
+        let file_name = description.replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+        let dead_letter_file = dir.join(format!("{}_{}.json", self.name, file_name));
+        let json = serde_json::to_string(&DeadLetter {
+            writer: self.name,
+            destination: self.destination.as_deref(),
+            error: format!("{:#}", err),
+            failed_paths: match err.downcast_ref::<BlockWriteError>() {
+                Some(block_write_error) => block_write_error
+                    .failed
+                    .iter()
+                    .map(|(path, _)| path.clone())
+                    .collect(),
+                None => vec![],
+            },
+            payload,
+        })?;
+
+        tokio::fs::write(&dead_letter_file, json).await?;
+        SYNC_STATUS.increment_counter("dead_letters", 1);
+
+        warn!(
+            "{} writer: {} written to dead letter file {}",
+            self.name,
+            description,
+            dead_letter_file.to_string_lossy()
+        );
+
+        Ok(())
+    }
+
+    /// Records how long after it was produced a live block was written, see
+    /// `status.write_latency_budget`
+    pub(crate) fn record_write_latency(&self, block_num: u64, timestamp: DateTime<Utc>) {
+        SYNC_STATUS.record_write_latency(self.name, block_num, timestamp);
+    }
+
+    /// Runs `write` for the block, applying the configured policy when it fails.
+    #[instrument(level = "debug", skip_all, fields(writer = self.name, block_num = block.block_num))]
+    pub(crate) async fn write_block<F, Fut>(
+        &self,
+        block: HiveBlockWithNum,
+        write: F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(HiveBlockWithNum) -> Fut,
+        Fut: Future<Output = Result<(), Error>>,
+    {
+        let description = format!("block {}", block.block_num);
+
+        self.write(&description, block, write).await
+    }
+
+    /// Runs `write` for any payload, applying the configured policy when it fails.
+    ///
+    /// Skipped payloads are serialized to the dead letter directory.
+    pub(crate) async fn write<T, F, Fut>(
+        &self,
+        description: &str,
+        payload: T,
+        write: F,
+    ) -> Result<(), Error>
+    where
+        T: Serialize + Clone,
+        F: Fn(T) -> Fut,
+        Fut: Future<Output = Result<(), Error>>,
+    {
+        let mut attempt: u64 = 0;
+
+        loop {
+            let err = match write(payload.clone()).await {
+                Ok(_) => {
+                    if attempt > 0 {
+                        warn!(
+                            "{} writer: {} written after {} retries",
+                            self.name, description, attempt
+                        );
+                    }
+                    SYNC_STATUS.record_writer_ok(self.name);
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+
+            SYNC_STATUS.record_writer_error(self.name, &err);
+
+            if err
+                .downcast_ref::<BlockWriteError>()
+                .is_some_and(|block_write_error| block_write_error.written > 0)
+            {
+                SYNC_STATUS.increment_counter("partial_block_writes", 1);
+            }
+
+            match self.policy {
+                WriterFailurePolicy::Abort => return Err(err),
+                WriterFailurePolicy::Skip => {
+                    error!(
+                        "{} writer: failed to write {}, skipping: {}",
+                        self.name, description, err
+                    );
+                    return self.dead_letter(description, &payload, &err).await;
+                }
+                WriterFailurePolicy::Retry => {
+                    attempt += 1;
+                    error!(
+                        "ALERT: {} writer: failed to write {} (attempt {}), retrying in {:?}: {}",
+                        self.name, description, attempt, self.retry_interval, err
+                    );
+                    sleep(self.retry_interval).await;
+                }
+            }
+        }
+    }
+
+    /// Runs `write` for a checkpoint update, applying the configured policy when it fails.
+    ///
+    /// Skipped checkpoints are only logged since the next successful one supersedes them.
+    #[cfg_attr(
+        not(any(
+            feature = "disk",
+            feature = "object-storage",
+            feature = "elasticsearch",
+            feature = "parquet"
+        )),
+        allow(dead_code)
+    )]
+    #[instrument(level = "debug", skip_all, fields(writer = self.name, block_num))]
+    pub(crate) async fn write_checkpoint<F, Fut>(
+        &self,
+        block_num: u64,
+        write: F,
+    ) -> Result<(), Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<(), Error>>,
+    {
+        let mut attempt: u64 = 0;
+
+        loop {
+            let err = match write().await {
+                Ok(_) => {
+                    SYNC_STATUS.record_written_block(block_num);
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+
+            SYNC_STATUS.record_writer_error(self.name, &err);
+
+            match self.policy {
+                WriterFailurePolicy::Abort => return Err(err),
+                WriterFailurePolicy::Skip => {
+                    error!(
+                        "{} writer: failed to write checkpoint for block {}, skipping: {}",
+                        self.name, block_num, err
+                    );
+                    return Ok(());
+                }
+                WriterFailurePolicy::Retry => {
+                    attempt += 1;
+                    error!(
+                        "ALERT: {} writer: failed to write checkpoint for block {} (attempt {}), retrying in {:?}: {}",
+                        self.name, block_num, attempt, self.retry_interval, err
+                    );
+                    sleep(self.retry_interval).await;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::podping::{podping_medium, podping_reason};
+use crate::writer::dataset::day_path;
+use chrono::NaiveDate;
+use podping_schemas::org::podcastindex::podping::podping_json::Podping;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Where a day's manifest is written, i.e. `YYYY/M/D/manifest.json`
+pub fn manifest_path(date: NaiveDate) -> PathBuf {
+    day_path(date).join(MANIFEST_FILE_NAME)
+}
+
+/// Block number of a podping object from its key
+///
+/// `None` for block metadata, empty block markers, tombstones and anything else in the day.
+pub fn podping_key_block_num(key: &str) -> Option<u64> {
+    let stem = Path::new(key)
+        .file_name()?
+        .to_str()?
+        .strip_suffix(".json")?;
+    let (block_num, rest) = stem.split_once('_')?;
+
+    match rest {
+        "block" => None,
+        _ => block_num.parse().ok(),
+    }
+}
+
+/// Index of a completed day's podpings, so consumers can sync the day with one GET instead of
+/// listing it
+#[derive(Serialize, Debug)]
+pub struct DayManifest {
+    pub date: NaiveDate,
+    pub first_block: Option<u64>,
+    pub last_block: Option<u64>,
+    pub podping_count: u64,
+    pub reasons: BTreeMap<String, u64>,
+    pub mediums: BTreeMap<String, u64>,
+    pub keys: Vec<String>,
+}
+
+impl DayManifest {
+    pub fn new(date: NaiveDate) -> DayManifest {
+        DayManifest {
+            date,
+            first_block: None,
+            last_block: None,
+            podping_count: 0,
+            reasons: BTreeMap::new(),
+            mediums: BTreeMap::new(),
+            keys: vec![],
+        }
+    }
+
+    pub fn add(&mut self, key: String, block_num: u64, podping: &Podping) {
+        self.first_block = Some(
+            self.first_block
+                .map_or(block_num, |first| first.min(block_num)),
+        );
+        self.last_block = Some(
+            self.last_block
+                .map_or(block_num, |last| last.max(block_num)),
+        );
+        self.podping_count += 1;
+
+        // v0.x podpings have neither
+        if let Some(reason) = podping_reason(podping) {
+            *self.reasons.entry(reason).or_default() += 1;
+        }
+        if let Some(medium) = podping_medium(podping) {
+            *self.mediums.entry(medium).or_default() += 1;
+        }
+
+        self.keys.push(key);
+    }
+
+    /// Sorts the keys, so the same day always gives the same manifest
+    pub fn finish(mut self) -> DayManifest {
+        self.keys.sort();
+        self
+    }
+}
@@ -9,7 +9,45 @@
  *
  *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
  */
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+pub mod block_metadata;
+pub mod body_encoding;
+pub mod console_writer;
+#[cfg(feature = "object-storage")]
+pub mod credentials;
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+pub mod dataset;
+#[cfg(feature = "disk")]
 pub mod disk_writer;
+#[cfg(feature = "elasticsearch")]
+pub mod elasticsearch_writer;
+#[cfg(any(
+    feature = "disk",
+    feature = "object-storage",
+    feature = "elasticsearch",
+    feature = "parquet",
+    feature = "forwarder"
+))]
+pub mod failure_policy;
+#[cfg(feature = "object-storage")]
+pub mod manifest;
+#[cfg(feature = "object-storage")]
 pub mod object_storage_writer;
+#[cfg(feature = "object-storage")]
+pub mod object_tags;
+#[cfg(feature = "parquet")]
+pub mod parquet_writer;
+#[cfg(feature = "disk")]
+pub mod portable_path;
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+pub mod quarantine;
+pub mod reorder;
+#[cfg(feature = "object-storage")]
+pub mod s3_bucket;
+#[cfg(unix)]
+pub mod socket_writer;
+#[cfg(feature = "object-storage")]
+pub mod storage;
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+pub mod tombstone;
 pub mod writer;
-pub mod console_writer;
\ No newline at end of file
@@ -9,422 +9,1285 @@
  *
  *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
  */
-use crate::config::{Settings, WriterUrlStyle};
-use crate::hive::scanner::HiveBlockWithNum;
-use crate::writer::writer::{Writer, LAST_UPDATED_BLOCK_FILENAME};
-use chrono::{Datelike, Timelike};
-use color_eyre::eyre::Error;
+use crate::config::{ExistingObjects, ObjectStorageProvider, ObjectStorageReplica, Settings};
+use crate::event_bus::Subscription;
+use crate::hive::scanner::{BlockBatch, HiveBlockWithNum};
+use crate::status::SYNC_STATUS;
+use crate::writer::block_metadata::{BlockMetadata, BlockSidecars};
+use crate::writer::credentials::CredentialsProvider;
+use crate::writer::dataset::{
+    dataset_file_name, day_path, sha256sums_lists, update_sha256sums, write_dataset_archive,
+    DatasetEntry, DayRollover, CONTENT_TYPE_ZSTD, DATASET_SUMS_FILE_NAME, DEFAULT_DATASET_PREFIX,
+};
+use crate::writer::failure_policy::FailureHandler;
+use crate::writer::manifest::{manifest_path, podping_key_block_num, DayManifest};
+use crate::writer::object_tags::{ObjectTags, TaggedObject};
+use crate::writer::quarantine::quarantine_files;
+use crate::writer::reorder::{ReorderWindow, DEFAULT_REORDER_WINDOW};
+use crate::writer::s3_bucket::S3Bucket;
+use crate::writer::storage::{
+    client_options, resolve_url_style, upload_headers, S3Endpoint, Storage,
+};
+use crate::writer::tombstone::{orphaned_paths, tombstone_path, Tombstone};
+use crate::writer::writer::{
+    block_file_paths, block_metadata_file_name, block_path, checkpoint_file_name,
+    checkpoint_timestamp, empty_block_marker_file_name, parse_last_block, podping_file_name,
+    BlockWriteError, Checkpoint, Writer, WriterInitError, PREFLIGHT_FILENAME,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use color_eyre::eyre::{eyre, Error};
 use color_eyre::Result;
+use futures::StreamExt;
+use object_store::{Attributes, ClientOptions, TagSet};
 use podping_schemas::org::podcastindex::podping::podping_json::Podping;
-use reqwest::{Client, Response, StatusCode};
-use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
-use std::env;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
 use std::time::Duration;
-use thiserror::Error;
-use tokio::sync::broadcast::error::RecvError;
-use tokio::sync::broadcast::Receiver;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::Semaphore;
 use tokio::task::JoinSet;
+use tokio::time::timeout;
 use tracing::{debug, error, info, warn};
-use url::Url;
 
 const CONTENT_TYPE_APPLICATION_JSON: &'static str = "application/json";
 const CONTENT_TYPE_TEXT_PLAIN: &'static str = "text/plain";
-const ONE_MINUTE: Duration = Duration::from_secs(60);
-
-#[derive(Error, Debug)]
-pub enum HeadBucketError {
-    #[error("Bucket not found")]
-    NotFound,
-    #[error("Permission denied accessing bucket")]
-    AccessDenied,
-    #[error("Bad request accessing bucket")]
-    BadRequest,
-    #[error("Unknown error accessing bucket")]
-    UnknownError,
+// Other tools (and compaction jobs) don't always store the checkpoint as text/plain
+const CHECKPOINT_CONTENT_TYPES: &[&str] = &[
+    CONTENT_TYPE_TEXT_PLAIN,
+    CONTENT_TYPE_APPLICATION_JSON,
+    "application/octet-stream",
+    "binary/octet-stream",
+    "application/gzip",
+    "application/x-gzip",
+];
+const DEFAULT_REPLICA_MAX_BACKLOG: usize = 10_000;
+const DEFAULT_UPLOAD_CONCURRENCY: usize = 64;
+const REPLICA_WRITE_CONCURRENCY: usize = 100;
+const REPLICA_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+const MANIFEST_READ_CONCURRENCY: usize = 100;
+// Objects read ahead of the one being archived, they're archived in key order
+const DATASET_READ_CONCURRENCY: usize = 32;
+
+// Endpoint and bucket name
+type BucketLocation = (String, String);
+
+/// Upload slots for each bucket, by endpoint and bucket name
+///
+/// A writer opened again after a failed start, or a replica on another writer's bucket, takes
+/// from the same slots rather than adding its own, so the bucket never has more than
+/// `object_storage_upload_concurrency` uploads from podpingd in flight.
+static BUCKET_UPLOADS: LazyLock<Mutex<HashMap<BucketLocation, Arc<Semaphore>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn bucket_uploads(storage: &Storage, upload_concurrency: usize) -> Arc<Semaphore> {
+    let (endpoint, bucket) = storage.location();
+
+    BUCKET_UPLOADS
+        .lock()
+        .unwrap()
+        .entry((endpoint.to_string(), bucket.to_string()))
+        .or_insert_with(|| Arc::new(Semaphore::new(upload_concurrency)))
+        .clone()
 }
 
-async fn head_bucket(osw: &ObjectStorageWriter) -> Result<Response, HeadBucketError> {
-    let action = osw.bucket.head_bucket(Some(&osw.credentials));
-    let url = action.sign(ONE_MINUTE);
+/// How block objects are written, the same for the primary and any replicas
+#[derive(Clone)]
+struct PodpingWriteOptions {
+    sidecars: BlockSidecars,
+    existing_objects: ExistingObjects,
+    tags: Option<Arc<ObjectTags>>,
+    headers: Arc<Attributes>,
+    // Uploads in flight to the bucket across every block, see `object_storage_upload_concurrency`
+    uploads: Arc<Semaphore>,
+}
+
+impl PodpingWriteOptions {
+    fn tags(&self, block: &HiveBlockWithNum, object: TaggedObject) -> Result<TagSet, Error> {
+        match &self.tags {
+            Some(tags) => tags.render(block, object),
+            None => Ok(TagSet::default()),
+        }
+    }
 
-    debug!("head_bucket_url: {:?}", url.clone().to_string());
+    /// The same options with the upload slots of another bucket
+    fn for_bucket(&self, storage: &Storage, upload_concurrency: usize) -> PodpingWriteOptions {
+        PodpingWriteOptions {
+            uploads: bucket_uploads(storage, upload_concurrency),
+            ..self.clone()
+        }
+    }
+}
 
-    // TODO: Add retry logic
-    let response = match osw.http_client.head(url).send().await {
-        Ok(exists) => exists,
-        Err(_) => return Err(HeadBucketError::UnknownError),
+/// Writes a podping or block metadata object, leaving it alone if it's already there and
+/// `existing_objects` says to
+///
+/// Waits for one of the bucket's upload slots first. Returns whether the object was written.
+async fn put_podping_object(
+    storage: &Storage,
+    path: &Path,
+    body: String,
+    write_options: &PodpingWriteOptions,
+    tags: TagSet,
+) -> Result<bool, Error> {
+    let _upload = match write_options.uploads.try_acquire() {
+        Ok(upload) => upload,
+        Err(_) => {
+            SYNC_STATUS.increment_counter("object_storage_upload_waits", 1);
+            write_options.uploads.acquire().await?
+        }
     };
+    let headers = &write_options.headers;
+
+    match write_options.existing_objects {
+        ExistingObjects::Overwrite => {
+            storage
+                .put_with(path, body, CONTENT_TYPE_APPLICATION_JSON, tags, headers)
+                .await?;
+
+            Ok(true)
+        }
+        ExistingObjects::Conditional => {
+            storage
+                .put_if_absent(path, body, CONTENT_TYPE_APPLICATION_JSON, tags, headers)
+                .await
+        }
+        ExistingObjects::Head => {
+            if storage.exists(path).await? {
+                return Ok(false);
+            }
 
-    let status = response.status();
+            storage
+                .put_with(path, body, CONTENT_TYPE_APPLICATION_JSON, tags, headers)
+                .await?;
 
-    match status {
-        StatusCode::OK => {
-            debug!("Successfully connected to bucket.");
-            Ok(response)
+            Ok(true)
         }
-        StatusCode::NOT_FOUND => Err(HeadBucketError::NotFound),
-        StatusCode::FORBIDDEN => Err(HeadBucketError::AccessDenied),
-        StatusCode::BAD_REQUEST => Err(HeadBucketError::BadRequest),
-        _ => Err(HeadBucketError::UnknownError),
     }
 }
 
-#[derive(Error, Debug)]
-pub enum GetObjectError {
-    #[error("Object not found")]
-    NotFound,
-    #[error("Permission denied accessing object")]
-    AccessDenied,
-    #[error("Bad request accessing object")]
-    BadRequest,
-    #[error("Unknown error accessing object")]
-    UnknownError,
+async fn object_storage_tombstone_replaced_block(
+    storage: &Storage,
+    sidecars: BlockSidecars,
+    block: &HiveBlockWithNum,
+) -> Result<(), Error> {
+    let tombstone = match Tombstone::for_correction(block) {
+        Some(tombstone) => serde_json::to_string(&tombstone)?,
+        None => return Ok(()),
+    };
+
+    for path in orphaned_paths(block, sidecars) {
+        info!(
+            "Tombstoning forked podping object: {}",
+            path.to_string_lossy()
+        );
+
+        storage
+            .put(
+                &tombstone_path(&path),
+                tombstone.clone(),
+                CONTENT_TYPE_APPLICATION_JSON,
+            )
+            .await?;
+        storage.delete(&path).await?;
+    }
+
+    Ok(())
 }
 
-async fn get_object(osw: &ObjectStorageWriter, path: PathBuf) -> Result<Response, GetObjectError> {
-    let path_str = path.to_string_lossy();
-    let mut action = osw.bucket.get_object(Some(&osw.credentials), &path_str);
-    action
-        .query_mut()
-        .insert("response-cache-control", "no-cache, no-store");
-    let url = action.sign(ONE_MINUTE);
+async fn object_storage_publish_manifest(storage: &Storage, date: NaiveDate) -> Result<(), Error> {
+    // Trailing slash so 2025/1/1 doesn't also match 2025/1/10
+    let day_prefix = format!("{}/", day_path(date).to_string_lossy());
+    let mut keys = storage
+        .list(&day_prefix)
+        .await?
+        .into_iter()
+        .filter_map(|key| podping_key_block_num(&key).map(|block_num| (key, block_num)))
+        .collect::<Vec<_>>();
+    let mut manifest = DayManifest::new(date);
 
-    debug!("get_object_url: {:?}", url.clone().to_string());
+    while !keys.is_empty() {
+        let mut read_join_set = JoinSet::new();
 
-    // TODO: Add retry logic
-    let response = match osw.http_client.get(url).send().await {
-        Ok(response) => response,
-        Err(_) => return Err(GetObjectError::UnknownError),
-    };
+        for (key, block_num) in keys.drain(..keys.len().min(MANIFEST_READ_CONCURRENCY)) {
+            let storage = storage.clone();
 
-    let status = response.status();
+            read_join_set.spawn(async move {
+                let result = storage.get(Path::new(&key)).await;
 
-    debug!(
-        "bucket: {}, path: {}, get_object_status: {:?}",
-        &osw.bucket.name(),
-        path_str,
-        status
-    );
+                (key, block_num, result)
+            });
+        }
 
-    match status {
-        StatusCode::OK => Ok(response),
-        StatusCode::NOT_FOUND => Err(GetObjectError::NotFound),
-        StatusCode::FORBIDDEN => Err(GetObjectError::AccessDenied),
-        StatusCode::BAD_REQUEST => Err(GetObjectError::BadRequest),
-        _ => Err(GetObjectError::UnknownError),
+        for (key, block_num, result) in read_join_set.join_all().await {
+            let bytes = match result? {
+                Some(bytes) => bytes,
+                None => return Err(eyre!("{} disappeared while indexing {}", key, date)),
+            };
+
+            match serde_json::from_slice::<Podping>(&bytes) {
+                Ok(podping) => manifest.add(key, block_num, &podping),
+                Err(e) => warn!("manifest: skipping {}: {}", key, e),
+            }
+        }
     }
-}
 
-#[derive(Error, Debug)]
-pub enum PutObjectError {
-    #[error("Permission denied writing object")]
-    AccessDenied,
-    #[error("Bad request writing object")]
-    BadRequest,
-    #[error("Unknown error writing object")]
-    UnknownError,
+    let manifest = manifest.finish();
+
+    storage
+        .put(
+            &manifest_path(date),
+            serde_json::to_string(&manifest)?,
+            CONTENT_TYPE_APPLICATION_JSON,
+        )
+        .await?;
+
+    info!(
+        "manifest: published {} ({} podpings)",
+        manifest_path(date).to_string_lossy(),
+        manifest.podping_count
+    );
+
+    Ok(())
 }
 
-async fn put_object(
-    bucket: Arc<Bucket>,
-    credentials: Arc<Credentials>,
-    http_client: Arc<Client>,
-    path: PathBuf,
-    body: String,
-    content_type: Option<String>,
-) -> Result<Response, PutObjectError> {
-    let path_str = path.to_string_lossy();
-    let action = bucket.put_object(Some(&credentials), &path_str);
-    let url = action.sign(ONE_MINUTE);
+async fn object_storage_publish_dataset(
+    storage: Storage,
+    dataset_prefix: PathBuf,
+    date: NaiveDate,
+) -> Result<(), Error> {
+    // Trailing slash so 2025/1/1 doesn't also match 2025/1/10
+    let day_prefix = format!("{}/", day_path(date).to_string_lossy());
+    let mut keys = storage.list(&day_prefix).await?;
 
-    debug!("put_object_url: {:?}", url.clone().to_string());
+    if keys.is_empty() {
+        info!("dataset: no podpings for {}, skipping", date);
+        return Ok(());
+    }
 
-    let content_type_str = content_type.unwrap_or_else(|| CONTENT_TYPE_TEXT_PLAIN.to_string());
+    keys.sort();
 
-    // TODO: Add retry logic
-    let response = match http_client
-        .clone()
-        .put(url)
-        .header("Content-Type", content_type_str)
-        .body(body)
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(_) => return Err(PutObjectError::UnknownError),
+    // Objects are handed to the compressor as they're read, so only the compressed archive and
+    // the objects read ahead are held in memory
+    let (entries_tx, mut entries_rx) = mpsc::channel(DATASET_READ_CONCURRENCY);
+    let archive = tokio::task::spawn_blocking(move || {
+        write_dataset_archive(vec![], std::iter::from_fn(|| entries_rx.blocking_recv()))
+    });
+
+    let mut objects = futures::stream::iter(keys)
+        .map(|key| {
+            let storage = storage.clone();
+
+            async move {
+                match storage.get(Path::new(&key)).await? {
+                    Some(bytes) => Ok(DatasetEntry {
+                        path: key,
+                        size: bytes.len() as u64,
+                        contents: Box::new(Cursor::new(bytes)),
+                    }),
+                    None => Err(eyre!("{} disappeared while packaging {}", key, date)),
+                }
+            }
+        })
+        .buffered(DATASET_READ_CONCURRENCY);
+
+    while let Some(entry) = objects.next().await {
+        let failed = entry.is_err();
+
+        // The archive stops at the first error, and gives it back below
+        if entries_tx.send(entry).await.is_err() || failed {
+            break;
+        }
+    }
+
+    drop(entries_tx);
+    let (bytes, sha256) = archive.await??;
+
+    let archive_path = dataset_prefix.join(dataset_file_name(date));
+    let size = bytes.len();
+
+    storage.put(&archive_path, bytes, CONTENT_TYPE_ZSTD).await?;
+
+    let sums_path = dataset_prefix.join(DATASET_SUMS_FILE_NAME);
+    let existing_sums = match storage.get(&sums_path).await? {
+        Some(bytes) => String::from_utf8(bytes)?,
+        None => String::new(),
     };
 
-    let status = response.status();
+    storage
+        .put(
+            &sums_path,
+            update_sha256sums(&existing_sums, &dataset_file_name(date), &sha256),
+            CONTENT_TYPE_TEXT_PLAIN,
+        )
+        .await?;
 
-    debug!(
-        "bucket: {}, path: {}, put_object_status: {:?}",
-        bucket.name(),
-        path_str,
-        status
+    info!(
+        "dataset: published {} ({} bytes)",
+        archive_path.to_string_lossy(),
+        size
     );
 
-    match status {
-        StatusCode::OK => Ok(response),
-        StatusCode::FORBIDDEN => Err(PutObjectError::AccessDenied),
-        StatusCode::BAD_REQUEST => Err(PutObjectError::BadRequest),
-        _ => Err(PutObjectError::UnknownError),
+    Ok(())
+}
+
+/// Writes the block's malformed podpings under `malformed/`, see `writer.quarantine_malformed`
+async fn object_storage_quarantine_malformed(
+    storage: &Storage,
+    write_options: &PodpingWriteOptions,
+    block: &HiveBlockWithNum,
+) -> Result<(), Error> {
+    for (path, json) in quarantine_files(block)? {
+        warn!(
+            "Quarantining malformed podping to object storage: {}",
+            path.to_string_lossy()
+        );
+
+        put_podping_object(
+            storage,
+            &path,
+            json,
+            write_options,
+            write_options.tags(block, TaggedObject::Malformed)?,
+        )
+        .await?;
+        SYNC_STATUS.increment_counter("podpings_quarantined", 1);
     }
+
+    Ok(())
 }
 
 async fn object_storage_write_block_transactions(
-    bucket: Arc<Bucket>,
-    credentials: Arc<Credentials>,
-    http_client: Arc<Client>,
+    storage: Storage,
+    write_options: PodpingWriteOptions,
     block: HiveBlockWithNum,
 ) -> Result<(), Error> {
+    object_storage_tombstone_replaced_block(&storage, write_options.sidecars, &block).await?;
+
+    if write_options.sidecars.quarantine(&block) {
+        object_storage_quarantine_malformed(&storage, &write_options, &block).await?;
+    }
+
     if block.transactions.is_empty() {
         info!("No Podpings for block {}", block.block_num);
-    } else {
-        let current_block_path = PathBuf::new()
-            .join(block.timestamp.year().to_string())
-            .join(block.timestamp.month().to_string())
-            .join(block.timestamp.day().to_string())
-            .join(block.timestamp.hour().to_string())
-            .join(block.timestamp.minute().to_string())
-            .join(block.timestamp.second().to_string());
 
-        let mut write_join_set = JoinSet::new();
+        if write_options.sidecars.empty_marker(&block) {
+            let marker_file =
+                block_path(&block.timestamp).join(empty_block_marker_file_name(block.block_num));
 
-        for tx in &block.transactions {
-            for (i, podping) in tx.podpings.iter().enumerate() {
-                let podping_file = match podping {
-                    Podping::V0(_) | Podping::V02(_) | Podping::V03(_) | Podping::V10(_) => {
-                        current_block_path
-                            .join(format!("{}_{}_{}.json", block.block_num, tx.tx_id, i))
-                    }
-                    Podping::V11(pp) => current_block_path.join(format!(
-                        "{}_{}_{}_{}.json",
-                        block.block_num,
-                        tx.tx_id,
-                        pp.session_id.to_string(),
-                        pp.timestamp_ns.to_string()
-                    )),
-                };
-
-                let json = serde_json::to_string(&podping);
-
-                match json {
-                    Ok(json) => {
-                        info!(
-                            "block: {}, tx: {}, podping: {}",
-                            block.block_num, tx.tx_id, json
-                        );
+            debug!(
+                "Writing empty block marker to object storage: {}",
+                marker_file.to_string_lossy()
+            );
+            put_podping_object(
+                &storage,
+                &marker_file,
+                String::new(),
+                &write_options,
+                write_options.tags(&block, TaggedObject::EmptyMarker)?,
+            )
+            .await?;
+        }
 
-                        info!(
-                            "Writing podping to object storage: {}",
-                            podping_file.to_string_lossy()
-                        );
+        if !write_options.sidecars.metadata(&block) {
+            return Ok(());
+        }
+    }
 
-                        write_join_set.spawn(put_object(
-                            bucket.clone(),
-                            credentials.clone(),
-                            http_client.clone(),
-                            podping_file,
-                            json,
-                            Some(CONTENT_TYPE_APPLICATION_JSON.to_string()),
-                        ));
-                    }
-                    Err(e) => {
-                        error!(
-                            "Error writing podping file {}: {}",
-                            podping_file.to_string_lossy(),
-                            e
-                        );
-                    }
+    let current_block_path = block_path(&block.timestamp);
+
+    let mut write_join_set = JoinSet::new();
+    let mut failed = vec![];
+
+    for tx in &block.transactions {
+        for (i, podping) in tx.indexed_podpings() {
+            let podping_file =
+                current_block_path.join(podping_file_name(block.block_num, &tx.tx_id, i, podping));
+
+            let object = serde_json::to_string(&podping)
+                .map_err(Error::from)
+                .and_then(|json| {
+                    Ok((
+                        json,
+                        write_options.tags(&block, TaggedObject::Podping(podping))?,
+                    ))
+                });
+
+            match object {
+                Ok((json, tags)) => {
+                    info!(
+                        "block: {}, tx: {}, podping: {}",
+                        block.block_num, tx.tx_id, json
+                    );
+
+                    info!(
+                        "Writing podping to object storage: {}",
+                        podping_file.to_string_lossy()
+                    );
+
+                    let storage = storage.clone();
+                    let write_options = write_options.clone();
+
+                    write_join_set.spawn(async move {
+                        let result =
+                            put_podping_object(&storage, &podping_file, json, &write_options, tags)
+                                .await;
+
+                        (podping_file, result)
+                    });
                 }
+                Err(e) => {
+                    error!(
+                        "Error writing podping file {}: {:#}",
+                        podping_file.to_string_lossy(),
+                        e
+                    );
+                    failed.push((podping_file, format!("{:#}", e)));
+                }
+            }
+        }
+    }
+
+    let mut written = 0;
+    let mut skipped: u64 = 0;
+
+    for (podping_file, result) in write_join_set.join_all().await {
+        match result {
+            Ok(true) => written += 1,
+            Ok(false) => {
+                written += 1;
+                skipped += 1;
             }
+            Err(e) => failed.push((podping_file, format!("{:#}", e))),
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(BlockWriteError {
+            block_num: block.block_num,
+            written,
+            failed,
+        }
+        .into());
+    }
+
+    if write_options.sidecars.metadata(&block) {
+        let metadata_file = current_block_path.join(block_metadata_file_name(block.block_num));
+        let json = serde_json::to_string(&BlockMetadata::from_block(&block))?;
+
+        debug!(
+            "Writing block metadata to object storage: {}",
+            metadata_file.to_string_lossy()
+        );
+
+        if !put_podping_object(
+            &storage,
+            &metadata_file,
+            json,
+            &write_options,
+            write_options.tags(&block, TaggedObject::BlockMetadata)?,
+        )
+        .await?
+        {
+            skipped += 1;
         }
+    }
 
-        write_join_set.join_all().await;
+    if skipped > 0 {
+        debug!(
+            "Skipped {} objects already in object storage for block {}",
+            skipped, block.block_num
+        );
+        SYNC_STATUS.increment_counter("objects_skipped_existing", skipped);
     }
+
     Ok(())
 }
 
-async fn object_storage_write_last_block(
-    osw: &ObjectStorageWriter,
-    block_num: u64,
+/// A PUT replaces the object in one go, readers get the old checkpoint or the new one
+pub(crate) async fn object_storage_write_last_block(
+    storage: &Storage,
+    path: &Path,
+    checkpoint: &Checkpoint,
 ) -> Result<(), Error> {
-    let path = PathBuf::from(LAST_UPDATED_BLOCK_FILENAME);
-    let block_num_str = block_num.to_string();
-    let response = put_object(
-        osw.bucket.clone(),
-        osw.credentials.clone(),
-        osw.http_client.clone(),
-        path,
-        block_num_str,
-        Some(CONTENT_TYPE_TEXT_PLAIN.to_string()),
+    storage
+        .put(path, checkpoint.to_json(), CONTENT_TYPE_APPLICATION_JSON)
+        .await
+}
+
+pub(crate) async fn object_storage_read_last_block(
+    storage: &Storage,
+    path: &Path,
+) -> Result<Option<u64>, Error> {
+    match storage.read_text(path, CHECKPOINT_CONTENT_TYPES).await? {
+        Some(s) => parse_last_block(&s),
+        None => Ok(None),
+    }
+}
+
+/// Creates the writer's S3 bucket if it's missing, see `object_storage_create_bucket`
+async fn object_storage_create_bucket(
+    settings: &Settings,
+    bucket_name: &str,
+) -> Result<(), WriterInitError> {
+    let endpoint = S3Endpoint::from_settings(settings)?;
+
+    let bucket = S3Bucket::new(
+        &endpoint.base_url,
+        endpoint.url_style,
+        bucket_name,
+        &endpoint.region,
+        CredentialsProvider::from_chain(),
+        &settings.http,
+        settings.writer.object_storage_tls_ca_cert.as_deref(),
     )
-    .await;
+    .map_err(|e| WriterInitError::Config(format!("Error setting up bucket creation: {:#}", e)))?;
+
+    bucket.create_if_missing().await.map_err(|e| {
+        WriterInitError::Unavailable(format!("Error creating bucket {}: {:#}", bucket_name, e))
+    })
+}
 
-    match response {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e.into()),
+/// Block number a podping, block metadata or empty block marker key starts with
+fn object_block_num(key: &str) -> Option<u64> {
+    let file_name = key.rsplit('/').next()?;
+
+    // Tombstones are for blocks that were replaced
+    if file_name.ends_with(".tombstone") {
+        return None;
     }
+
+    file_name[..file_name.find(['_', '.'])?].parse().ok()
 }
 
-pub(crate) struct ObjectStorageWriter {
-    bucket: Arc<Bucket>,
-    credentials: Arc<Credentials>,
-    http_client: Arc<Client>,
+/// Numeric "directories" directly under `prefix` in descending order, i.e. the years, months or
+/// days
+async fn newest_numeric_prefixes(storage: &Storage, prefix: &str) -> Result<Vec<u32>, Error> {
+    let mut numbers = storage
+        .list_common_prefixes(prefix)
+        .await?
+        .iter()
+        .filter_map(|common_prefix| {
+            common_prefix
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()?
+                .parse::<u32>()
+                .ok()
+        })
+        .collect::<Vec<_>>();
+    numbers.sort_by(|a, b| b.cmp(a));
+
+    Ok(numbers)
 }
 
-impl Writer for ObjectStorageWriter {
-    async fn new(settings: &Settings) -> Self
-    where
-        Self: Sized,
-    {
-        let base_url_result = match settings.writer.object_storage_base_url.clone() {
-            Some(base_url) => base_url,
-            None => panic!("object_storage_base_url is not set"),
+/// Finds the newest block in the bucket from its object keys, for when the checkpoint is gone
+///
+/// Walks down the newest year, month and day and takes the highest block number of the newest
+/// day that has any, so only one day's objects are listed.
+async fn object_storage_find_newest_block(storage: &Storage) -> Result<Option<u64>, Error> {
+    for year in newest_numeric_prefixes(storage, "").await? {
+        for month in newest_numeric_prefixes(storage, &format!("{}/", year)).await? {
+            for day in newest_numeric_prefixes(storage, &format!("{}/{}/", year, month)).await? {
+                let newest_block = storage
+                    .list(&format!("{}/{}/{}/", year, month, day))
+                    .await?
+                    .iter()
+                    .filter_map(|key| object_block_num(key))
+                    .max();
+
+                if newest_block.is_some() {
+                    return Ok(newest_block);
+                }
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// A bucket every block is copied to after the primary, see `run_replica`
+struct Replica {
+    name: &'static str,
+    storage: Storage,
+    queue: UnboundedSender<Vec<HiveBlockWithNum>>,
+}
+
+impl Replica {
+    fn new(
+        settings: &ObjectStorageReplica,
+        write_options: &PodpingWriteOptions,
+        upload_concurrency: usize,
+        checkpoint_path: PathBuf,
+        max_backlog: usize,
+        client_options: ClientOptions,
+    ) -> Result<Replica, WriterInitError> {
+        let credentials = match (&settings.access_key_id_env, &settings.secret_access_key_env) {
+            (Some(access_key_var), Some(secret_key_var)) => CredentialsProvider::from_env_vars(
+                access_key_var,
+                secret_key_var,
+                settings.session_token_env.as_deref(),
+            ),
+            (None, None) => CredentialsProvider::from_chain(),
+            _ => return Err(WriterInitError::Config(format!(
+                "object storage replica {}: set both access_key_id_env and secret_access_key_env, or neither",
+                settings.bucket_name
+            ))),
+        };
+        let preset = ObjectStorageProvider::S3.s3_preset();
+        let endpoint = S3Endpoint {
+            base_url: settings.base_url.clone(),
+            url_style: resolve_url_style(&settings.base_url, settings.url_style, preset),
+            region: settings.region.clone(),
+            preset,
+            encryption: None,
+        };
+        let storage = Storage::s3(
+            &endpoint,
+            &settings.bucket_name,
+            credentials,
+            client_options,
+        )?;
+
+        // Leaked once per replica at startup, the sync status keys writers by &'static str
+        let name: &'static str = Box::leak(
+            format!(
+                "object storage replica {}",
+                settings.name.as_deref().unwrap_or(&settings.bucket_name)
+            )
+            .into_boxed_str(),
+        );
+
+        let (queue, rx) = mpsc::unbounded_channel();
+
+        // Each bucket has its own connections, so its own limit
+        tokio::spawn(run_replica(
+            name,
+            storage.clone(),
+            write_options.for_bucket(&storage, upload_concurrency),
+            checkpoint_path,
+            max_backlog,
+            rx,
+        ));
+
+        Ok(Replica {
+            name,
+            storage,
+            queue,
+        })
+    }
+}
+
+/// Writes the next run of blocks from the front of `backlog` concurrently
+///
+/// Corrections are written on their own so they land after the block they replace. Anything that
+/// fails goes back on the front of the backlog, returns the last block written when nothing did.
+async fn write_replica_backlog(
+    name: &'static str,
+    storage: &Storage,
+    write_options: &PodpingWriteOptions,
+    backlog: &mut VecDeque<HiveBlockWithNum>,
+) -> Result<Option<Checkpoint>, ()> {
+    let mut last_written: Option<Checkpoint> = None;
+
+    while !backlog.is_empty() {
+        let chunk_len = match backlog
+            .iter()
+            .take(REPLICA_WRITE_CONCURRENCY)
+            .position(|block| block.replaces.is_some())
+        {
+            Some(position) => position.max(1),
+            None => backlog.len().min(REPLICA_WRITE_CONCURRENCY),
+        };
+
+        let mut write_join_set = JoinSet::new();
+
+        for block in backlog.drain(..chunk_len) {
+            let storage = storage.clone();
+            let write_options = write_options.clone();
+
+            write_join_set.spawn(async move {
+                let result =
+                    object_storage_write_block_transactions(storage, write_options, block.clone())
+                        .await;
+
+                (block, result)
+            });
+        }
+
+        let mut failed = vec![];
+
+        for (block, result) in write_join_set.join_all().await {
+            match result {
+                Ok(_) => {
+                    if block.replaces.is_none()
+                        && last_written
+                            .as_ref()
+                            .is_none_or(|last_written| block.block_num > last_written.block_num)
+                    {
+                        last_written = Some(Checkpoint::from_block(&block));
+                    }
+                }
+                Err(e) => {
+                    error!("{}: error writing block {}: {:#}", name, block.block_num, e);
+                    SYNC_STATUS.record_writer_error(name, &e);
+                    failed.push(block);
+                }
+            }
         }
-        .parse::<Url>();
 
-        let access_key = match env::var("AWS_ACCESS_KEY_ID") {
-            Ok(access_key) => access_key,
-            Err(e) => panic!("AWS_ACCESS_KEY_ID is not set: {}", e),
+        if !failed.is_empty() {
+            failed.sort_by_key(|block| block.block_num);
+
+            for block in failed.into_iter().rev() {
+                backlog.push_front(block);
+            }
+
+            return Err(());
+        }
+    }
+
+    Ok(last_written)
+}
+
+/// Copies every block queued by the writer to the replica, in the background
+///
+/// A replica that's down doesn't hold up the writer, its blocks build up in a backlog that's
+/// retried with each new block. Its checkpoint only moves once the backlog is written, and not at
+/// all after the backlog overflowed, so a restart rescans whatever it missed.
+#[allow(clippy::too_many_arguments)]
+async fn run_replica(
+    name: &'static str,
+    storage: Storage,
+    write_options: PodpingWriteOptions,
+    checkpoint_path: PathBuf,
+    max_backlog: usize,
+    mut rx: UnboundedReceiver<Vec<HiveBlockWithNum>>,
+) {
+    let mut backlog: VecDeque<HiveBlockWithNum> = VecDeque::new();
+    let mut checkpoint: Option<u64> = None;
+    let mut overflowed = false;
+
+    loop {
+        let blocks = match backlog.is_empty() {
+            true => rx.recv().await,
+            // Retry the backlog even if the writer goes quiet
+            false => timeout(REPLICA_RETRY_INTERVAL, rx.recv())
+                .await
+                .unwrap_or(Some(vec![])),
         };
 
-        let access_secret = match env::var("AWS_SECRET_ACCESS_KEY") {
-            Ok(access_secret) => access_secret,
-            Err(e) => panic!("AWS_SECRET_ACCESS_KEY is not set: {}", e),
+        match blocks {
+            Some(blocks) => backlog.extend(blocks),
+            None => return,
+        }
+        while let Ok(blocks) = rx.try_recv() {
+            backlog.extend(blocks);
+        }
+
+        let last_written =
+            write_replica_backlog(name, &storage, &write_options, &mut backlog).await;
+
+        if backlog.len() > max_backlog {
+            if !overflowed {
+                error!(
+                    "{}: more than {} blocks behind, dropping the oldest. Restart podpingd once it's back to fill the gap",
+                    name, max_backlog
+                );
+            }
+
+            overflowed = true;
+            backlog.drain(..backlog.len() - max_backlog);
+        }
+
+        let last_written = match last_written {
+            Ok(Some(last_written)) => last_written,
+            _ => continue,
         };
 
-        let credentials = Arc::new(Credentials::new(access_key, access_secret));
+        if overflowed || checkpoint.is_some_and(|checkpoint| last_written.block_num <= checkpoint) {
+            continue;
+        }
+
+        match object_storage_write_last_block(&storage, &checkpoint_path, &last_written).await {
+            Ok(_) => {
+                checkpoint = Some(last_written.block_num);
+                SYNC_STATUS.record_writer_ok(name);
+            }
+            Err(e) => {
+                error!("{}: error writing last_updated_block: {:#}", name, e);
+                SYNC_STATUS.record_writer_error(name, &e);
+            }
+        }
+    }
+}
 
-        let base_url = match base_url_result {
-            Ok(base_url) => base_url,
-            Err(e) => panic!("Error parsing object storage base URL: {}", e),
+pub(crate) struct ObjectStorageWriter {
+    storage: Storage,
+    write_options: PodpingWriteOptions,
+    checkpoint_path: PathBuf,
+    reorder_window: usize,
+    dataset_prefix: Option<PathBuf>,
+    day_manifest: bool,
+    day_rollover: DayRollover,
+    checkpoint_recovery: bool,
+    failure_handler: FailureHandler,
+    replicas: Vec<Replica>,
+}
+
+impl ObjectStorageWriter {
+    /// Hands the blocks to each replica's background task, after the primary has them
+    fn queue_for_replicas(&self, blocks: &[HiveBlockWithNum]) {
+        for replica in &self.replicas {
+            if replica.queue.send(blocks.to_vec()).is_err() {
+                error!("{}: replica task has stopped", replica.name);
+            }
+        }
+    }
+
+    async fn write_block(&self, block: HiveBlockWithNum) -> Result<(), Error> {
+        let checkpoint = Checkpoint::from_block(&block);
+        let is_correction = block.replaces.is_some();
+
+        if !self.replicas.is_empty() {
+            self.queue_for_replicas(std::slice::from_ref(&block));
+        }
+
+        self.failure_handler
+            .write_block(block, |block| {
+                object_storage_write_block_transactions(
+                    self.storage.clone(),
+                    self.write_options.clone(),
+                    block,
+                )
+            })
+            .await?;
+
+        // Corrections are for blocks already behind the checkpoint
+        if is_correction {
+            return Ok(());
+        }
+
+        self.failure_handler
+            .write_checkpoint(checkpoint.block_num, || {
+                object_storage_write_last_block(&self.storage, &self.checkpoint_path, &checkpoint)
+            })
+            .await?;
+        self.publish_completed_day(&checkpoint.timestamp);
+
+        Ok(())
+    }
+
+    /// Indexes and packages the previous day in the background once a block from a new day is
+    /// written
+    fn publish_completed_day(&self, block_timestamp: &DateTime<Utc>) {
+        if !self.day_manifest && self.dataset_prefix.is_none() {
+            return;
+        }
+
+        if let Some(date) = self.day_rollover.completed_day(block_timestamp) {
+            self.publish_day(date);
+        }
+    }
+
+    /// Whether the day's manifest and dataset, whichever are enabled, are in the bucket, or the
+    /// day has no podpings
+    ///
+    /// A dataset counts once it's in `SHA256SUMS`, which is written last. None if the bucket
+    /// can't say.
+    async fn day_published(&self, date: NaiveDate) -> Option<bool> {
+        // A day without podpings has nothing to publish
+        let day_prefix = format!("{}/", day_path(date).to_string_lossy());
+        if self.storage.list(&day_prefix).await.ok()?.is_empty() {
+            return Some(true);
+        }
+
+        let manifest = match self.day_manifest {
+            true => self.storage.exists(&manifest_path(date)).await.ok()?,
+            false => true,
+        };
+        let dataset = match &self.dataset_prefix {
+            Some(dataset_prefix) => {
+                let sums = self
+                    .storage
+                    .get(&dataset_prefix.join(DATASET_SUMS_FILE_NAME))
+                    .await
+                    .ok()?
+                    .unwrap_or_default();
+
+                sha256sums_lists(&String::from_utf8_lossy(&sums), &dataset_file_name(date))
+            }
+            None => true,
         };
 
+        Some(manifest && dataset)
+    }
+
+    fn publish_day(&self, date: NaiveDate) {
+        let storage = self.storage.clone();
+        let day_manifest = self.day_manifest;
+        let dataset_prefix = self.dataset_prefix.clone();
+
+        // One after the other, so the dataset always includes the manifest
+        self.day_rollover.publish(async move {
+            if day_manifest {
+                if let Err(e) = object_storage_publish_manifest(&storage, date).await {
+                    error!("manifest: error publishing {}: {:#}", date, e);
+                }
+            }
+
+            if let Some(dataset_prefix) = dataset_prefix {
+                if let Err(e) = object_storage_publish_dataset(storage, dataset_prefix, date).await
+                {
+                    error!("dataset: error publishing {}: {:#}", date, e);
+                }
+            }
+        });
+    }
+}
+
+impl Writer for ObjectStorageWriter {
+    async fn new(settings: &Settings) -> Result<Self, WriterInitError>
+    where
+        Self: Sized,
+    {
         let bucket_name = match settings.writer.object_storage_bucket_name.clone() {
             Some(bucket_name) => bucket_name,
-            None => panic!("object_storage_bucket_name is not set"),
+            None => {
+                return Err(WriterInitError::Config(
+                    "object_storage_bucket_name is not set".to_string(),
+                ))
+            }
         };
 
-        let region = match settings.writer.object_storage_region.clone() {
-            Some(region) => region,
-            None => panic!("object_storage_region is not set"),
+        let storage = Storage::from_settings(settings, &bucket_name)?;
+
+        if settings
+            .writer
+            .object_storage_create_bucket
+            .unwrap_or(false)
+            && settings
+                .writer
+                .object_storage_provider
+                .unwrap_or(ObjectStorageProvider::S3)
+                .is_s3()
+        {
+            object_storage_create_bucket(settings, &bucket_name).await?;
+        }
+
+        // Checked before the replicas are started, so a retry doesn't start them twice
+        if let Err(e) = storage.check().await {
+            return Err(WriterInitError::Unavailable(format!(
+                "Error accessing bucket {}: {:#}",
+                bucket_name, e
+            )));
+        }
+
+        let tags = match settings.writer.object_storage_tags.as_deref() {
+            Some(tags) if !tags.is_empty() => match ObjectTags::new(tags) {
+                Ok(tags) => Some(Arc::new(tags)),
+                Err(e) => return Err(WriterInitError::Config(format!("{:#}", e))),
+            },
+            _ => None,
         };
 
-        let url_style = match settings.writer.object_storage_url_style {
-            Some(WriterUrlStyle::Path) => UrlStyle::Path,
-            Some(WriterUrlStyle::VirtualHost) => UrlStyle::VirtualHost,
-            None => panic!("object_storage_url_style is not set"),
+        let upload_concurrency = settings
+            .writer
+            .object_storage_upload_concurrency
+            .unwrap_or(DEFAULT_UPLOAD_CONCURRENCY);
+        let write_options = PodpingWriteOptions {
+            sidecars: BlockSidecars::from_settings(&settings.writer),
+            existing_objects: settings
+                .writer
+                .object_storage_existing_objects
+                .unwrap_or(ExistingObjects::Overwrite),
+            tags,
+            headers: Arc::new(upload_headers(&settings.writer)),
+            uploads: bucket_uploads(&storage, upload_concurrency),
         };
 
-        let bucket = match Bucket::new(base_url, url_style, bucket_name.clone(), region) {
-            Ok(client) => Arc::new(client),
-            Err(e) => panic!("Error creating S3 client: {}", e),
+        let failure_handler = FailureHandler::new(
+            "object storage",
+            settings.writer.failure_policy,
+            settings.writer.failure_dead_letter_directory.clone(),
+            settings.writer.failure_retry_interval,
+        )?
+        .with_destination(storage.name());
+
+        let checkpoint_path = PathBuf::from(checkpoint_file_name(
+            settings.writer.checkpoint_name.as_deref(),
+        )?);
+
+        let replica_max_backlog = settings
+            .writer
+            .object_storage_replica_max_backlog
+            .unwrap_or(DEFAULT_REPLICA_MAX_BACKLOG);
+        // Replicas trust the same CA, and keep as many connections open, as the primary
+        let replica_client_options = client_options(&settings.http, &settings.writer)?;
+        let replicas = settings
+            .writer
+            .object_storage_replicas
+            .iter()
+            .flatten()
+            .map(|replica| {
+                Replica::new(
+                    replica,
+                    &write_options,
+                    upload_concurrency,
+                    checkpoint_path.clone(),
+                    replica_max_backlog,
+                    replica_client_options.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let dataset_prefix = match settings.writer.dataset_publish.unwrap_or(false) {
+            true => Some(PathBuf::from(
+                settings
+                    .writer
+                    .dataset_prefix
+                    .clone()
+                    .unwrap_or(DEFAULT_DATASET_PREFIX.to_string()),
+            )),
+            false => None,
         };
+        let day_manifest = settings.writer.day_manifest.unwrap_or(false);
 
-        let http_client = Arc::new(Client::new());
+        // Picks up from the checkpoint's day, so a day that completes across a restart is published
+        let checkpoint_date = match day_manifest || dataset_prefix.is_some() {
+            true => storage
+                .read_text(&checkpoint_path, CHECKPOINT_CONTENT_TYPES)
+                .await
+                .ok()
+                .flatten()
+                .and_then(|text| checkpoint_timestamp(&text))
+                .map(|timestamp| timestamp.date_naive()),
+            false => None,
+        };
 
         let osw = ObjectStorageWriter {
-            bucket,
-            credentials,
-            http_client,
+            storage,
+            write_options,
+            checkpoint_path,
+            reorder_window: settings
+                .writer
+                .reorder_window
+                .unwrap_or(DEFAULT_REORDER_WINDOW),
+            dataset_prefix,
+            day_manifest,
+            day_rollover: DayRollover::starting_at(checkpoint_date),
+            checkpoint_recovery: settings.writer.checkpoint_recovery.unwrap_or(false),
+            failure_handler,
+            replicas,
         };
 
-        match head_bucket(&osw).await {
-            Ok(_) => osw,
-            Err(e) => panic!("Error accessing bucket {}: {}", bucket_name, e),
+        // The day before the checkpoint's is complete, stopping may have cut its publishing short
+        if let Some(previous_date) = checkpoint_date.and_then(|date| date.pred_opt()) {
+            if osw.day_published(previous_date).await == Some(false) {
+                osw.publish_day(previous_date);
+            }
+        }
+
+        // A replica that's down now is caught up once it's back
+        for replica in &osw.replicas {
+            if let Err(e) = replica.storage.check().await {
+                error!("{}: error accessing bucket: {:#}", replica.name, e);
+            }
         }
+
+        Ok(osw)
     }
 
+    /// The primary's checkpoint, or the furthest behind replica's so a restart fills its gaps
+    ///
+    /// Replicas without a checkpoint yet, or that can't be reached, are left out.
     async fn get_last_block(&self) -> Result<Option<u64>, Error> {
-        let path = PathBuf::from(LAST_UPDATED_BLOCK_FILENAME);
-        let response = get_object(self, path).await;
-
-        match response {
-            Ok(r) => match r.text().await {
-                Ok(s) => match s.trim().parse::<u64>() {
-                    Ok(block) => Ok(Some(block)),
-                    _ => Ok(None),
-                },
-                _ => Ok(None),
-            },
-            Err(GetObjectError::NotFound) => Ok(None),
-            Err(e) => Err(e.into()),
+        let mut last_block =
+            object_storage_read_last_block(&self.storage, &self.checkpoint_path).await?;
+
+        if last_block.is_none() && self.checkpoint_recovery {
+            last_block = object_storage_find_newest_block(&self.storage).await?;
+
+            match last_block {
+                Some(newest_block) => warn!(
+                    "{} is missing, resuming from block {}, the newest in the bucket. Run podpingd verify to check for blocks missing before it",
+                    self.checkpoint_path.to_string_lossy(),
+                    newest_block
+                ),
+                None => info!(
+                    "{} is missing and the bucket has no blocks to recover it from",
+                    self.checkpoint_path.to_string_lossy()
+                ),
+            }
         }
+
+        for replica in &self.replicas {
+            match object_storage_read_last_block(&replica.storage, &self.checkpoint_path).await {
+                Ok(Some(replica_last_block)) => {
+                    if last_block.is_some_and(|last_block| replica_last_block < last_block) {
+                        info!(
+                            "{} is behind at block {}, resuming from there",
+                            replica.name, replica_last_block
+                        );
+                        last_block = Some(replica_last_block);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => error!(
+                    "{}: error reading last_updated_block: {:#}",
+                    replica.name, e
+                ),
+            }
+        }
+
+        Ok(last_block)
     }
 
-    async fn start(&self, mut rx: Receiver<HiveBlockWithNum>) -> Result<(), Error> {
-        loop {
-            let result = rx.recv().await;
+    async fn preflight(&self) -> Result<(), Error> {
+        let path = PathBuf::from(PREFLIGHT_FILENAME);
 
-            let block = match result {
-                Ok(block) => Some(block),
-                Err(RecvError::Lagged(e)) => {
-                    warn!("Object Storage writer is lagging: {}", e);
+        if let Err(e) = self
+            .storage
+            .put(&path, "preflight", CONTENT_TYPE_TEXT_PLAIN)
+            .await
+        {
+            return Err(eyre!(
+                "Can't write to bucket {}: {:#}",
+                self.storage.name(),
+                e
+            ));
+        }
 
-                    None
-                }
-                Err(RecvError::Closed) => {
-                    panic!("Object Storage writer channel closed");
-                }
-            };
+        if let Err(e) = self.storage.delete(&path).await {
+            return Err(eyre!(
+                "Can't delete from bucket {}: {:#}",
+                self.storage.name(),
+                e
+            ));
+        }
 
-            match block {
-                Some(block) => {
-                    let block_num = block.block_num.to_owned();
+        info!("object storage preflight: bucket is writable");
 
-                    object_storage_write_block_transactions(
-                        self.bucket.clone(),
-                        self.credentials.clone(),
-                        self.http_client.clone(),
-                        block,
-                    )
-                    .await?;
-                    object_storage_write_last_block(self, block_num).await?
-                }
-                None => {}
+        for replica in &self.replicas {
+            match replica
+                .storage
+                .put(&path, "preflight", CONTENT_TYPE_TEXT_PLAIN)
+                .await
+            {
+                Ok(_) => info!("{} preflight: bucket is writable", replica.name),
+                Err(e) => error!(
+                    "{} preflight: can't write to bucket {}: {:#}",
+                    replica.name,
+                    replica.storage.name(),
+                    e
+                ),
             }
         }
-    }
 
-    async fn start_batch(&self, mut rx: Receiver<Vec<HiveBlockWithNum>>) -> Result<(), Error> {
-        loop {
-            let result = rx.recv().await;
+        Ok(())
+    }
 
-            let block = match result {
-                Ok(block) => Some(block),
-                Err(RecvError::Lagged(e)) => {
-                    warn!("Object Storage writer is lagging: {}", e);
+    async fn start(&self, mut rx: Subscription<HiveBlockWithNum>) -> Result<(), Error> {
+        let mut reorder_window = ReorderWindow::new(self.reorder_window);
 
-                    None
+        loop {
+            let event = match rx.recv().await {
+                Some(event) => event,
+                // The scanner stops after scanner.end_block
+                None => {
+                    self.day_rollover.finish().await;
+                    return Ok(());
                 }
-                Err(RecvError::Closed) => break,
             };
 
-            match block {
-                Some(blocks) => {
-                    let last_block_num = blocks.last().unwrap().block_num;
-                    let mut write_join_set = JoinSet::new();
-
-                    for block in blocks {
-                        write_join_set.spawn(object_storage_write_block_transactions(
-                            self.bucket.clone(),
-                            self.credentials.clone(),
-                            self.http_client.clone(),
-                            block,
-                        ));
-                    }
+            for block in reorder_window.push(event.payload)? {
+                let (block_num, timestamp) = (block.block_num, block.timestamp);
 
-                    write_join_set.join_all().await;
+                self.write_block(block).await?;
+                self.failure_handler
+                    .record_write_latency(block_num, timestamp);
+            }
+            rx.ack(event.seq);
+        }
+    }
 
-                    object_storage_write_last_block(self, last_block_num).await?;
-                }
-                None => {}
+    async fn start_batch(&self, mut rx: Subscription<BlockBatch>) -> Result<(), Error> {
+        while let Some(event) = rx.recv().await {
+            let blocks = event.payload.blocks;
+            let checkpoint = Checkpoint::from_block(blocks.last().unwrap());
+            let mut write_join_set = JoinSet::new();
+
+            if !self.replicas.is_empty() {
+                self.queue_for_replicas(&blocks);
             }
+
+            for block in blocks {
+                let failure_handler = self.failure_handler.clone();
+                let storage = self.storage.clone();
+                let write_options = self.write_options.clone();
+
+                write_join_set.spawn(async move {
+                    failure_handler
+                        .write_block(block, |block| {
+                            object_storage_write_block_transactions(
+                                storage.clone(),
+                                write_options.clone(),
+                                block,
+                            )
+                        })
+                        .await
+                });
+            }
+
+            for result in write_join_set.join_all().await {
+                result?;
+            }
+
+            self.failure_handler
+                .write_checkpoint(checkpoint.block_num, || {
+                    object_storage_write_last_block(
+                        &self.storage,
+                        &self.checkpoint_path,
+                        &checkpoint,
+                    )
+                })
+                .await?;
+            self.publish_completed_day(&checkpoint.timestamp);
+            rx.ack(event.seq);
         }
 
+        self.day_rollover.finish().await;
+
         Ok(())
     }
+
+    async fn missing_files(&self, block: &HiveBlockWithNum) -> Result<Vec<PathBuf>, Error> {
+        let paths = block_file_paths(block, self.write_options.sidecars);
+
+        if paths.is_empty() {
+            return Ok(paths);
+        }
+
+        let prefix = format!("{}/", block_path(&block.timestamp).to_string_lossy());
+        let keys = self.storage.list(&prefix).await?;
+
+        Ok(paths
+            .into_iter()
+            .filter(|path| !keys.contains(&path.to_string_lossy().to_string()))
+            .collect())
+    }
+
+    async fn repair_block(&self, block: HiveBlockWithNum) -> Result<(), Error> {
+        self.failure_handler
+            .write_block(block, |block| {
+                object_storage_write_block_transactions(
+                    self.storage.clone(),
+                    self.write_options.clone(),
+                    block,
+                )
+            })
+            .await
+    }
 }
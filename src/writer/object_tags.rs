@@ -0,0 +1,100 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::ObjectStorageTag;
+use crate::hive::scanner::HiveBlockWithNum;
+use crate::podping::{podping_medium, podping_reason};
+use chrono::Datelike;
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use minijinja::{context, Environment, Value};
+use object_store::TagSet;
+use podping_schemas::org::podcastindex::podping::podping_json::Podping;
+
+/// What an object is, for the `kind` tag templates see
+#[derive(Clone, Copy)]
+pub(crate) enum TaggedObject<'a> {
+    Podping(&'a Podping),
+    BlockMetadata,
+    EmptyMarker,
+    Malformed,
+}
+
+/// Tags for every block object, see `writer.object_storage_tags`
+///
+/// Values are minijinja templates seeing `kind` ("podping", "block_metadata", "empty_marker" or
+/// "malformed"), `block` (block_num, timestamp, year, month, day) and for podpings `podping`
+/// (medium, reason).
+pub(crate) struct ObjectTags {
+    env: Environment<'static>,
+    keys: Vec<String>,
+}
+
+impl ObjectTags {
+    pub(crate) fn new(tags: &[ObjectStorageTag]) -> Result<ObjectTags, Error> {
+        let mut env = Environment::new();
+
+        for tag in tags {
+            if tag.key.is_empty() {
+                return Err(eyre!("Object storage tag with an empty key"));
+            }
+
+            env.add_template_owned(tag.key.clone(), tag.value.clone())
+                .map_err(|e| eyre!("Error parsing object storage tag {}: {:#}", tag.key, e))?;
+        }
+
+        Ok(ObjectTags {
+            env,
+            keys: tags.iter().map(|tag| tag.key.clone()).collect(),
+        })
+    }
+
+    pub(crate) fn render(
+        &self,
+        block: &HiveBlockWithNum,
+        object: TaggedObject,
+    ) -> Result<TagSet, Error> {
+        let (kind, podping) = match object {
+            TaggedObject::Podping(podping) => (
+                "podping",
+                context! {
+                    medium => podping_medium(podping).unwrap_or_default(),
+                    reason => podping_reason(podping).unwrap_or_default(),
+                },
+            ),
+            TaggedObject::BlockMetadata => ("block_metadata", Value::UNDEFINED),
+            TaggedObject::EmptyMarker => ("empty_marker", Value::UNDEFINED),
+            TaggedObject::Malformed => ("malformed", Value::UNDEFINED),
+        };
+        let context = context! {
+            kind => kind,
+            block => context! {
+                block_num => block.block_num,
+                timestamp => block.timestamp.to_rfc3339(),
+                year => block.timestamp.year(),
+                month => block.timestamp.month(),
+                day => block.timestamp.day(),
+            },
+            podping => podping,
+        };
+
+        let mut tags = TagSet::default();
+        for key in &self.keys {
+            let value = self.env.get_template(key)?.render(&context)?;
+            // An empty value leaves the tag off, so a template can tag some objects only
+            if !value.is_empty() {
+                tags.push(key, &value);
+            }
+        }
+
+        Ok(tags)
+    }
+}
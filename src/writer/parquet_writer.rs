@@ -0,0 +1,500 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::Settings;
+use crate::event_bus::Subscription;
+use crate::hive::scanner::{BlockBatch, HiveBlockWithNum};
+use crate::podping::{event_id, podping_iris, podping_medium, podping_reason};
+use crate::writer::failure_policy::FailureHandler;
+#[cfg(feature = "object-storage")]
+use crate::writer::object_storage_writer::{
+    object_storage_read_last_block, object_storage_write_last_block,
+};
+#[cfg(feature = "object-storage")]
+use crate::writer::storage::Storage;
+use crate::writer::writer::PREFLIGHT_FILENAME;
+use crate::writer::writer::{
+    checkpoint_file_name, parse_last_block, temp_path, Checkpoint, Writer, WriterInitError,
+};
+use arrow_array::builder::{ListBuilder, StringBuilder};
+use arrow_array::{
+    ArrayRef, RecordBatch, StringArray, TimestampMillisecondArray, UInt32Array, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::{DateTime, NaiveDate, Utc};
+use color_eyre::eyre::{eyre, Error};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+const DEFAULT_PARQUET_PREFIX: &str = "parquet";
+const DEFAULT_MAX_ROWS: usize = 100_000;
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const CONTENT_TYPE_PARQUET: &str = "application/vnd.apache.parquet";
+
+/// One podping, as a row of the Parquet files
+#[derive(Debug, Clone, Serialize)]
+struct ParquetRow {
+    event_id: String,
+    block_num: u64,
+    block_id: String,
+    timestamp: DateTime<Utc>,
+    tx_id: String,
+    index: u32,
+    reason: Option<String>,
+    medium: Option<String>,
+    iris: Vec<String>,
+    replaces_block_id: Option<String>,
+    /// The whole podping as JSON, its shape varies by schema version
+    podping: String,
+}
+
+fn block_rows(block: &HiveBlockWithNum) -> Result<Vec<ParquetRow>, Error> {
+    let mut rows = vec![];
+
+    for tx in &block.transactions {
+        for (i, podping) in tx.indexed_podpings() {
+            rows.push(ParquetRow {
+                event_id: event_id(block.block_num, &tx.tx_id, i),
+                block_num: block.block_num,
+                block_id: block.block_id.clone(),
+                timestamp: block.timestamp,
+                tx_id: tx.tx_id.clone(),
+                index: i as u32,
+                reason: podping_reason(podping),
+                medium: podping_medium(podping),
+                iris: podping_iris(podping),
+                replaces_block_id: block
+                    .replaces
+                    .as_ref()
+                    .map(|replaced| replaced.block_id.clone()),
+                podping: serde_json::to_string(podping)?,
+            });
+        }
+    }
+
+    Ok(rows)
+}
+
+fn parquet_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("event_id", DataType::Utf8, false),
+        Field::new("block_num", DataType::UInt64, false),
+        Field::new("block_id", DataType::Utf8, false),
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("tx_id", DataType::Utf8, false),
+        Field::new("index", DataType::UInt32, false),
+        Field::new("reason", DataType::Utf8, true),
+        Field::new("medium", DataType::Utf8, true),
+        Field::new(
+            "iris",
+            DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))),
+            false,
+        ),
+        Field::new("replaces_block_id", DataType::Utf8, true),
+        Field::new("podping", DataType::Utf8, false),
+    ])
+}
+
+/// Encodes the rows as a zstd compressed Parquet file
+fn parquet_file(rows: &[ParquetRow]) -> Result<Vec<u8>, Error> {
+    let schema = Arc::new(parquet_schema());
+
+    let mut iris = ListBuilder::new(StringBuilder::new());
+    for row in rows {
+        for iri in &row.iris {
+            iris.values().append_value(iri);
+        }
+        iris.append(true);
+    }
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|row| &row.event_id),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            rows.iter().map(|row| row.block_num),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|row| &row.block_id),
+        )),
+        Arc::new(
+            TimestampMillisecondArray::from_iter_values(
+                rows.iter().map(|row| row.timestamp.timestamp_millis()),
+            )
+            .with_timezone("UTC"),
+        ),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|row| &row.tx_id),
+        )),
+        Arc::new(UInt32Array::from_iter_values(
+            rows.iter().map(|row| row.index),
+        )),
+        Arc::new(StringArray::from_iter(
+            rows.iter().map(|row| row.reason.as_deref()),
+        )),
+        Arc::new(StringArray::from_iter(
+            rows.iter().map(|row| row.medium.as_deref()),
+        )),
+        Arc::new(iris.finish()),
+        Arc::new(StringArray::from_iter(
+            rows.iter().map(|row| row.replaces_block_id.as_deref()),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|row| &row.podping),
+        )),
+    ];
+
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .build();
+
+    let mut writer = ArrowWriter::try_new(vec![], schema, Some(properties))?;
+    writer.write(&batch)?;
+
+    Ok(writer.into_inner()?)
+}
+
+/// Hive style partition, so DuckDB and Athena pick up `date` as a column
+fn parquet_file_path(prefix: &str, date: NaiveDate, first_block: u64, last_block: u64) -> PathBuf {
+    PathBuf::from(prefix)
+        .join(format!("date={}", date.format("%Y-%m-%d")))
+        .join(format!("podpings-{}-{}.parquet", first_block, last_block))
+}
+
+enum ParquetOutput {
+    Disk(PathBuf),
+    #[cfg(feature = "object-storage")]
+    ObjectStorage(Storage),
+}
+
+impl ParquetOutput {
+    #[cfg_attr(not(feature = "object-storage"), allow(unused_variables))]
+    async fn write(&self, path: PathBuf, bytes: Vec<u8>, content_type: &str) -> Result<(), Error> {
+        match self {
+            ParquetOutput::Disk(directory) => {
+                let path = directory.join(path);
+
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+
+                // Renamed into place so a reader never opens half a file
+                let temp_path = temp_path(&path);
+                tokio::fs::write(&temp_path, bytes).await?;
+                tokio::fs::rename(&temp_path, &path).await?;
+
+                Ok(())
+            }
+            #[cfg(feature = "object-storage")]
+            ParquetOutput::ObjectStorage(storage) => storage.put(&path, bytes, content_type).await,
+        }
+    }
+
+    async fn delete(&self, path: PathBuf) -> Result<(), Error> {
+        match self {
+            ParquetOutput::Disk(directory) => {
+                Ok(tokio::fs::remove_file(directory.join(path)).await?)
+            }
+            #[cfg(feature = "object-storage")]
+            ParquetOutput::ObjectStorage(storage) => storage.delete(&path).await,
+        }
+    }
+
+    async fn write_checkpoint(&self, path: PathBuf, checkpoint: &Checkpoint) -> Result<(), Error> {
+        match self {
+            ParquetOutput::Disk(_) => {
+                self.write(path, checkpoint.to_json().into_bytes(), "")
+                    .await
+            }
+            #[cfg(feature = "object-storage")]
+            ParquetOutput::ObjectStorage(storage) => {
+                object_storage_write_last_block(storage, &path, checkpoint).await
+            }
+        }
+    }
+
+    async fn read_checkpoint(&self, path: PathBuf) -> Result<Option<u64>, Error> {
+        match self {
+            ParquetOutput::Disk(directory) => {
+                match tokio::fs::read_to_string(directory.join(path)).await {
+                    Ok(text) => parse_last_block(&text),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            #[cfg(feature = "object-storage")]
+            ParquetOutput::ObjectStorage(storage) => {
+                object_storage_read_last_block(storage, &path).await
+            }
+        }
+    }
+}
+
+/// Podpings waiting for the next file, and the last block they cover
+#[derive(Default)]
+struct ParquetBuffer {
+    rows: Vec<ParquetRow>,
+    date: Option<NaiveDate>,
+    first_block: Option<u64>,
+    last_block: Option<Checkpoint>,
+}
+
+/// Collects podpings and writes them as a Parquet file per day, split every `parquet_max_rows`
+/// rows or `parquet_flush_interval`
+///
+/// The checkpoint only moves when a file is written, so podpings still in the buffer when
+/// podpingd stops are scanned again on the next start. Corrections after a fork are appended
+/// with `replaces_block_id` set, files already written aren't touched.
+pub(crate) struct ParquetWriter {
+    output: ParquetOutput,
+    prefix: String,
+    checkpoint_path: PathBuf,
+    max_rows: usize,
+    flush_interval: Duration,
+    buffer: Mutex<ParquetBuffer>,
+    last_flush: Mutex<Instant>,
+    failure_handler: FailureHandler,
+}
+
+impl ParquetWriter {
+    async fn flush(&self, buffer: &mut ParquetBuffer) -> Result<(), Error> {
+        let buffered = std::mem::take(buffer);
+        *self.last_flush.lock().await = Instant::now();
+
+        if let (Some(date), Some(first_block), Some(last_block)) = (
+            buffered.date,
+            buffered.first_block,
+            buffered.last_block.as_ref(),
+        ) {
+            let path = parquet_file_path(&self.prefix, date, first_block, last_block.block_num);
+            let description = path.to_string_lossy().to_string();
+
+            self.failure_handler
+                .write(&description, buffered.rows, |rows| {
+                    let path = path.clone();
+
+                    async move {
+                        let bytes = parquet_file(&rows)?;
+                        let size = bytes.len();
+
+                        self.output
+                            .write(path.clone(), bytes, CONTENT_TYPE_PARQUET)
+                            .await?;
+                        info!(
+                            "parquet: wrote {} podpings to {} ({} bytes)",
+                            rows.len(),
+                            path.to_string_lossy(),
+                            size
+                        );
+
+                        Ok(())
+                    }
+                })
+                .await?;
+        }
+
+        let checkpoint = match buffered.last_block {
+            Some(checkpoint) => checkpoint,
+            None => return Ok(()),
+        };
+
+        self.failure_handler
+            .write_checkpoint(checkpoint.block_num, || {
+                self.output
+                    .write_checkpoint(self.checkpoint_path.clone(), &checkpoint)
+            })
+            .await
+    }
+
+    async fn push_block(&self, block: HiveBlockWithNum) -> Result<(), Error> {
+        let mut buffer = self.buffer.lock().await;
+        let date = block.timestamp.date_naive();
+
+        // A file never spans two days, corrections go with whatever day is buffered
+        if block.replaces.is_none() && buffer.date.is_some_and(|buffered| buffered != date) {
+            self.flush(&mut buffer).await?;
+        }
+
+        let rows = block_rows(&block)?;
+
+        if rows.is_empty() {
+            debug!("No Podpings for block {}", block.block_num);
+        } else {
+            buffer.date.get_or_insert(date);
+            buffer.first_block.get_or_insert(block.block_num);
+            buffer.rows.extend(rows);
+        }
+
+        // Corrections are for blocks already behind the checkpoint
+        if block.replaces.is_none() {
+            buffer.last_block = Some(Checkpoint::from_block(&block));
+        }
+
+        if buffer.rows.len() >= self.max_rows
+            || self.last_flush.lock().await.elapsed() >= self.flush_interval
+        {
+            self.flush(&mut buffer).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn flush_buffer(&self) -> Result<(), Error> {
+        let mut buffer = self.buffer.lock().await;
+
+        self.flush(&mut buffer).await
+    }
+}
+
+impl Writer for ParquetWriter {
+    async fn new(settings: &Settings) -> Result<Self, WriterInitError>
+    where
+        Self: Sized,
+    {
+        let prefix = settings
+            .writer
+            .parquet_prefix
+            .clone()
+            .unwrap_or(DEFAULT_PARQUET_PREFIX.to_string());
+
+        let output = match settings.writer.parquet_object_storage.unwrap_or(false) {
+            #[cfg(feature = "object-storage")]
+            true => {
+                let bucket_name = match settings.writer.object_storage_bucket_name.clone() {
+                    Some(bucket_name) => bucket_name,
+                    None => {
+                        return Err(WriterInitError::Config(
+                            "object_storage_bucket_name is not set".to_string(),
+                        ))
+                    }
+                };
+                info!("parquet: writing to bucket {}", bucket_name);
+
+                ParquetOutput::ObjectStorage(Storage::from_settings(settings, &bucket_name)?)
+            }
+            #[cfg(not(feature = "object-storage"))]
+            true => {
+                return Err(WriterInitError::Config(
+                    "parquet_object_storage is set but podpingd was built without the object-storage feature"
+                        .to_string(),
+                ))
+            }
+            false => {
+                let directory = match settings.writer.parquet_directory.clone() {
+                    Some(directory) if !directory.is_empty() => PathBuf::from(directory),
+                    _ => {
+                        return Err(WriterInitError::Config(
+                            "parquet_directory is not set!".to_string(),
+                        ))
+                    }
+                };
+
+                info!("parquet: writing to {}", directory.to_string_lossy());
+
+                ParquetOutput::Disk(directory)
+            }
+        };
+
+        Ok(ParquetWriter {
+            output,
+            checkpoint_path: PathBuf::from(&prefix).join(checkpoint_file_name(
+                settings.writer.checkpoint_name.as_deref(),
+            )?),
+            prefix,
+            max_rows: settings
+                .writer
+                .parquet_max_rows
+                .unwrap_or(DEFAULT_MAX_ROWS)
+                .max(1),
+            flush_interval: settings
+                .writer
+                .parquet_flush_interval
+                .unwrap_or(DEFAULT_FLUSH_INTERVAL),
+            buffer: Mutex::new(ParquetBuffer::default()),
+            last_flush: Mutex::new(Instant::now()),
+            failure_handler: FailureHandler::new(
+                "parquet",
+                settings.writer.failure_policy,
+                settings.writer.failure_dead_letter_directory.clone(),
+                settings.writer.failure_retry_interval,
+            )?,
+        })
+    }
+
+    async fn get_last_block(&self) -> Result<Option<u64>, Error> {
+        self.output
+            .read_checkpoint(self.checkpoint_path.clone())
+            .await
+    }
+
+    async fn preflight(&self) -> Result<(), Error> {
+        let path = PathBuf::from(&self.prefix).join(PREFLIGHT_FILENAME);
+
+        if let Err(e) = self
+            .output
+            .write(path.clone(), b"preflight".to_vec(), "text/plain")
+            .await
+        {
+            return Err(eyre!("Can't write Parquet files: {:#}", e));
+        }
+
+        if let Err(e) = self.output.delete(path).await {
+            return Err(eyre!("Can't delete Parquet files: {:#}", e));
+        }
+
+        info!("parquet preflight: output is writable");
+
+        Ok(())
+    }
+
+    async fn start(&self, mut rx: Subscription<HiveBlockWithNum>) -> Result<(), Error> {
+        while let Some(event) = rx.recv().await {
+            self.push_block(event.payload).await?;
+            rx.ack(event.seq);
+        }
+
+        // The scanner stops after scanner.end_block
+        self.flush_buffer().await
+    }
+
+    async fn start_batch(&self, mut rx: Subscription<BlockBatch>) -> Result<(), Error> {
+        while let Some(event) = rx.recv().await {
+            for block in event.payload.blocks {
+                self.push_block(block).await?;
+            }
+            rx.ack(event.seq);
+        }
+
+        self.flush_buffer().await
+    }
+
+    // Files cover many blocks, there's nothing per block to check
+    async fn missing_files(&self, _block: &HiveBlockWithNum) -> Result<Vec<PathBuf>, Error> {
+        Ok(vec![])
+    }
+
+    async fn repair_block(&self, _block: HiveBlockWithNum) -> Result<(), Error> {
+        Ok(())
+    }
+}
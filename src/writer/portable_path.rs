@@ -0,0 +1,180 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+// NTFS and APFS both cap a single name at 255 (UTF-16 units or bytes), stay under both
+const MAX_FILE_NAME_BYTES: usize = 255;
+
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// Makes a generated file name safe on NTFS and APFS as well as Linux
+///
+/// Only the disk writer uses it, object keys and block metadata elsewhere keep the generated names.
+///
+/// Names are lowercased so case-insensitive filesystems can't merge two of them,
+/// characters Windows rejects become `_`, reserved device names get a `_` prefix,
+/// trailing dots and spaces are dropped and overlong names are truncated before the extension.
+pub fn portable_file_name(name: &str) -> String {
+    let mut portable = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c.to_ascii_lowercase(),
+        })
+        .collect::<String>()
+        .trim_end_matches(['.', ' '])
+        .to_string();
+
+    let stem = portable.split('.').next().unwrap_or_default();
+    if WINDOWS_RESERVED_NAMES.contains(&stem) {
+        portable.insert(0, '_');
+    }
+
+    if portable.len() > MAX_FILE_NAME_BYTES {
+        let extension = match portable.rfind('.') {
+            Some(i) if portable.len() - i < 16 => portable[i..].to_string(),
+            _ => String::new(),
+        };
+
+        let mut end = MAX_FILE_NAME_BYTES - extension.len();
+        while !portable.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        portable = format!("{}{}", &portable[..end], extension);
+    }
+
+    portable
+}
+
+/// Relative path with its file name made portable, the date directories are already safe
+pub fn portable_path(path: &Path) -> PathBuf {
+    match path.file_name() {
+        Some(file_name) => path.with_file_name(portable_file_name(&file_name.to_string_lossy())),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Data directory in a form that can hold deep paths
+///
+/// On Windows this is the `\\?\` verbatim form of the absolute path, which lifts the
+/// 260 character MAX_PATH limit. Elsewhere the directory is returned unchanged.
+pub fn long_path_directory(directory: PathBuf) -> PathBuf {
+    if !cfg!(windows) {
+        return directory;
+    }
+
+    match std::path::absolute(&directory) {
+        Ok(absolute) => verbatim_path(&absolute),
+        Err(e) => {
+            warn!(
+                "Couldn't resolve data directory {}, paths past 260 characters will fail: {}",
+                directory.display(),
+                e
+            );
+            directory
+        }
+    }
+}
+
+// Verbatim paths skip normalization, so this must already be absolute with `\` separators
+fn verbatim_path(absolute: &Path) -> PathBuf {
+    let path = absolute.as_os_str();
+
+    match path.to_str() {
+        Some(path) if path.starts_with(r"\\?\") => absolute.to_path_buf(),
+        Some(path) if path.starts_with(r"\\") => PathBuf::from(format!(r"\\?\UNC\{}", &path[2..])),
+        _ => {
+            let mut verbatim = OsString::from(r"\\?\");
+            verbatim.push(path);
+            PathBuf::from(verbatim)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_names_get_a_prefix() {
+        assert_eq!(portable_file_name("con.json"), "_con.json");
+        assert_eq!(portable_file_name("lpt1"), "_lpt1");
+        assert_eq!(portable_file_name("LPT1.txt"), "_lpt1.txt");
+        assert_eq!(portable_file_name("console.json"), "console.json");
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces_are_dropped() {
+        assert_eq!(portable_file_name("name. . "), "name");
+        assert_eq!(portable_file_name("name.json."), "name.json");
+    }
+
+    #[test]
+    fn rejected_characters_become_underscores() {
+        assert_eq!(portable_file_name("a:b.json"), "a_b.json");
+        assert_eq!(portable_file_name("a<b>|c?*.json"), "a_b__c__.json");
+    }
+
+    #[test]
+    fn names_are_lowercased() {
+        assert_eq!(
+            portable_file_name("90000000_ABCdef_0.JSON"),
+            "90000000_abcdef_0.json"
+        );
+    }
+
+    #[test]
+    fn long_multibyte_names_are_truncated_before_the_extension() {
+        // Byte 250 falls inside an é, so the cut moves back to 249
+        let name = format!("a{}.json", "é".repeat(200));
+        let portable = portable_file_name(&name);
+
+        assert_eq!(portable.len(), 254);
+        assert!(portable.starts_with("aé"));
+        assert!(portable.ends_with("é.json"));
+    }
+
+    #[test]
+    fn only_the_file_name_is_changed() {
+        assert_eq!(
+            portable_path(Path::new("2024/10/1/0/0/0/1_AUX.json")),
+            PathBuf::from("2024/10/1/0/0/0/1_aux.json")
+        );
+        assert_eq!(
+            portable_path(Path::new("malformed/2024/10/1/0/0/0/aux.json")),
+            PathBuf::from("malformed/2024/10/1/0/0/0/_aux.json")
+        );
+    }
+
+    #[test]
+    fn verbatim_paths() {
+        assert_eq!(
+            verbatim_path(Path::new(r"C:\data")),
+            PathBuf::from(r"\\?\C:\data")
+        );
+        assert_eq!(
+            verbatim_path(Path::new(r"\\server\share\data")),
+            PathBuf::from(r"\\?\UNC\server\share\data")
+        );
+        assert_eq!(
+            verbatim_path(Path::new(r"\\?\C:\data")),
+            PathBuf::from(r"\\?\C:\data")
+        );
+    }
+}
@@ -0,0 +1,59 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::hive::scanner::HiveBlockWithNum;
+use crate::writer::writer::block_path;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::Error;
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// Prefix malformed podpings are quarantined under, outside the day directories so replays,
+/// datasets and manifests never see them
+pub(crate) const QUARANTINE_PREFIX: &str = "malformed";
+
+/// A podping custom_json that didn't parse, as it was on the chain and where
+#[derive(Serialize, Debug)]
+struct QuarantinedPodping<'a> {
+    block_num: u64,
+    block_id: &'a str,
+    timestamp: DateTime<Utc>,
+    tx_id: &'a str,
+    id: &'a str,
+    json: &'a str,
+    error: &'a str,
+}
+
+/// Relative path and contents for each of the block's malformed podpings, i.e.
+/// `malformed/YYYY/M/D/h/m/s/<block_num>_<tx_id>_<n>.json`
+pub(crate) fn quarantine_files(block: &HiveBlockWithNum) -> Result<Vec<(PathBuf, String)>, Error> {
+    let quarantine_path = PathBuf::from(QUARANTINE_PREFIX).join(block_path(&block.timestamp));
+
+    block
+        .malformed
+        .iter()
+        .enumerate()
+        .map(|(i, malformed)| {
+            let json = serde_json::to_string(&QuarantinedPodping {
+                block_num: block.block_num,
+                block_id: &block.block_id,
+                timestamp: block.timestamp,
+                tx_id: &malformed.tx_id,
+                id: &malformed.id,
+                json: &malformed.json,
+                error: &malformed.error,
+            })?;
+            let file_name = format!("{}_{}_{}.json", block.block_num, malformed.tx_id, i);
+
+            Ok((quarantine_path.join(file_name), json))
+        })
+        .collect()
+}
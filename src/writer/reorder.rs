@@ -0,0 +1,221 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::hive::scanner::HiveBlockWithNum;
+use crate::podping::event_id;
+use crate::status::SYNC_STATUS;
+use color_eyre::eyre::{eyre, Error};
+use std::collections::{BTreeMap, HashSet};
+use tracing::{error, warn};
+
+pub const DEFAULT_REORDER_WINDOW: usize = 10;
+
+fn block_event_ids(block: &HiveBlockWithNum) -> HashSet<String> {
+    block
+        .transactions
+        .iter()
+        .flat_map(|tx| {
+            tx.indexed_podpings()
+                .map(|(index, _)| event_id(block.block_num, &tx.tx_id, index))
+        })
+        .collect()
+}
+
+/// Puts live blocks back in order and drops re-delivered ones before they're written
+///
+/// Node failover can hand the same block over twice or deliver one a little late.
+/// Blocks ahead of the next expected one are held until the gap fills. Once more than `window`
+/// are waiting, the missing blocks are treated as lost in transit and `push` errors, so the
+/// writer stops with its checkpoint before them and they're scanned again, rather than written
+/// around.
+///
+/// A re-delivered block is a duplicate when its podpings' event ids were all delivered before.
+pub(crate) struct ReorderWindow {
+    window: usize,
+    next_block: Option<u64>,
+    pending: BTreeMap<u64, HiveBlockWithNum>,
+    // Event ids of the last `window` blocks handed out
+    delivered: BTreeMap<u64, HashSet<String>>,
+}
+
+impl ReorderWindow {
+    pub(crate) fn new(window: usize) -> ReorderWindow {
+        ReorderWindow {
+            window,
+            next_block: None,
+            pending: BTreeMap::new(),
+            delivered: BTreeMap::new(),
+        }
+    }
+
+    /// Accepts a block and returns every block now ready to write, in order
+    ///
+    /// Errors once blocks have been missing for longer than the window.
+    pub(crate) fn push(&mut self, block: HiveBlockWithNum) -> Result<Vec<HiveBlockWithNum>, Error> {
+        // Fork corrections re-deliver an earlier block on purpose
+        if block.replaces.is_some() {
+            return Ok(vec![block]);
+        }
+
+        let next_block = *self.next_block.get_or_insert(block.block_num);
+
+        if block.block_num < next_block || self.pending.contains_key(&block.block_num) {
+            self.drop_redelivery(&block);
+            return Ok(vec![]);
+        }
+
+        self.pending.insert(block.block_num, block);
+
+        let mut ready = vec![];
+
+        while let Some(block) = self.pending.remove(&self.next_block.unwrap()) {
+            self.next_block = Some(block.block_num + 1);
+            self.delivered
+                .insert(block.block_num, block_event_ids(&block));
+            ready.push(block);
+        }
+
+        while self.delivered.len() > self.window {
+            self.delivered.pop_first();
+        }
+
+        if self.pending.len() > self.window {
+            let (&oldest, _) = self.pending.first_key_value().unwrap();
+
+            return Err(eyre!(
+                "Blocks {} to {} never arrived, {} later blocks are waiting on them. Stopping \
+                 before them so they're scanned again",
+                self.next_block.unwrap(),
+                oldest - 1,
+                self.pending.len()
+            ));
+        }
+
+        Ok(ready)
+    }
+
+    fn drop_redelivery(&self, block: &HiveBlockWithNum) {
+        let first_delivery = match self.pending.get(&block.block_num) {
+            Some(pending) => Some(block_event_ids(pending)),
+            None => self.delivered.get(&block.block_num).cloned(),
+        };
+
+        match first_delivery {
+            // Older than the window, its event ids are no longer kept
+            None => warn!("Dropping late re-delivery of block {}", block.block_num),
+            Some(event_ids) if block_event_ids(block).is_subset(&event_ids) => {
+                warn!("Dropping duplicate delivery of block {}", block.block_num)
+            }
+            // Fork detection would have sent a correction for a block that really changed
+            Some(_) => {
+                error!(
+                    "Dropping re-delivery of block {} with podpings its first delivery didn't have, \
+                     keeping the first",
+                    block.block_num
+                );
+                SYNC_STATUS.increment_counter("conflicting_block_deliveries", 1);
+                return;
+            }
+        }
+
+        SYNC_STATUS.increment_counter("duplicate_block_deliveries", 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hive::scanner::HiveTransactionWithTxId;
+    use chrono::Utc;
+
+    fn block(block_num: u64, tx_ids: &[&str]) -> HiveBlockWithNum {
+        HiveBlockWithNum {
+            block_num,
+            block_id: format!("{:08x}", block_num),
+            previous: format!("{:08x}", block_num - 1),
+            witness: "w".to_string(),
+            timestamp: Utc::now(),
+            transactions: tx_ids
+                .iter()
+                .map(|tx_id| {
+                    HiveTransactionWithTxId::new(
+                        tx_id.to_string(),
+                        0,
+                        serde_json::from_str(
+                            r#"{"version":"1.0","medium":"podcast","reason":"update","iris":["https://example.com/feed.xml"]}"#,
+                        )
+                        .unwrap(),
+                    )
+                })
+                .collect(),
+            malformed: vec![],
+            replaces: None,
+        }
+    }
+
+    fn block_nums(blocks: Vec<HiveBlockWithNum>) -> Vec<u64> {
+        blocks.iter().map(|block| block.block_num).collect()
+    }
+
+    #[test]
+    fn late_blocks_are_put_back_in_order() {
+        let mut window = ReorderWindow::new(3);
+
+        assert_eq!(block_nums(window.push(block(10, &["a"])).unwrap()), [10]);
+        assert!(window.push(block(12, &["c"])).unwrap().is_empty());
+        assert!(window.push(block(13, &["d"])).unwrap().is_empty());
+        assert_eq!(
+            block_nums(window.push(block(11, &["b"])).unwrap()),
+            [11, 12, 13]
+        );
+    }
+
+    #[test]
+    fn redeliveries_are_dropped() {
+        let mut window = ReorderWindow::new(3);
+
+        window.push(block(10, &["a"])).unwrap();
+        window.push(block(12, &["c"])).unwrap();
+
+        // Already written, and still waiting
+        assert!(window.push(block(10, &["a"])).unwrap().is_empty());
+        assert!(window.push(block(12, &["c"])).unwrap().is_empty());
+        // A block that changed keeps its first delivery
+        assert!(window.push(block(10, &["a", "x"])).unwrap().is_empty());
+
+        assert_eq!(
+            block_nums(window.push(block(11, &["b"])).unwrap()),
+            [11, 12]
+        );
+    }
+
+    #[test]
+    fn a_gap_longer_than_the_window_errors() {
+        let mut window = ReorderWindow::new(2);
+
+        window.push(block(10, &["a"])).unwrap();
+        window.push(block(12, &[])).unwrap();
+        window.push(block(13, &[])).unwrap();
+
+        assert!(window.push(block(14, &[])).is_err());
+    }
+
+    #[test]
+    fn corrections_pass_straight_through() {
+        let mut window = ReorderWindow::new(2);
+        window.push(block(10, &["a"])).unwrap();
+
+        let mut correction = block(10, &["b"]);
+        correction.replaces = Some(Box::new(block(10, &["a"])));
+
+        assert_eq!(block_nums(window.push(correction).unwrap()), [10]);
+    }
+}
@@ -0,0 +1,234 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::{Http, WriterUrlStyle};
+use crate::http_client::client_builder;
+use crate::writer::credentials::CredentialsProvider;
+use chrono::Utc;
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use hmac::{Hmac, Mac};
+use object_store::aws::AwsCredential;
+use reqwest::{Certificate, Client, Method, StatusCode};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::info;
+use url::Url;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// What object_store signs with when the region is left empty
+const DEFAULT_REGION: &str = "us-east-1";
+// R2's only region, it takes location hints rather than regions as a location constraint
+const R2_REGION: &str = "auto";
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take a key of any size");
+    mac.update(data.as_bytes());
+
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// HeadBucket and CreateBucket for an S3 compatible store
+///
+/// object_store only works with objects, so these two requests are signed (SigV4) here.
+pub(crate) struct S3Bucket {
+    http_client: Client,
+    url: Url,
+    bucket_name: String,
+    region: String,
+    credentials: Arc<CredentialsProvider>,
+}
+
+impl S3Bucket {
+    pub(crate) fn new(
+        base_url: &str,
+        url_style: WriterUrlStyle,
+        bucket_name: &str,
+        region: &str,
+        credentials: Arc<CredentialsProvider>,
+        http: &Http,
+        ca_file: Option<&str>,
+    ) -> Result<S3Bucket, Error> {
+        let mut url = base_url.parse::<Url>()?;
+
+        match url_style {
+            WriterUrlStyle::Path => {
+                url.path_segments_mut()
+                    .map_err(|_| eyre!("{} can't be a base URL", base_url))?
+                    .pop_if_empty()
+                    .push(bucket_name);
+            }
+            WriterUrlStyle::VirtualHost => {
+                let host = match url.host_str() {
+                    Some(host) => format!("{}.{}", bucket_name, host),
+                    None => return Err(eyre!("{} has no host", base_url)),
+                };
+                url.set_host(Some(&host))?;
+            }
+        }
+
+        let mut builder = client_builder(http)?;
+        if let Some(ca_file) = ca_file.filter(|ca_file| !ca_file.is_empty()) {
+            for certificate in Certificate::from_pem_bundle(&std::fs::read(ca_file)?)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        Ok(S3Bucket {
+            http_client: builder.build()?,
+            url,
+            bucket_name: bucket_name.to_string(),
+            region: match region.is_empty() {
+                true => DEFAULT_REGION.to_string(),
+                false => region.to_string(),
+            },
+            credentials,
+        })
+    }
+
+    /// Signs the request with AWS Signature Version 4
+    fn signed_request(
+        &self,
+        method: Method,
+        body: &str,
+        credential: &AwsCredential,
+    ) -> Result<reqwest::RequestBuilder, Error> {
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(body.as_bytes()));
+        let host = match (self.url.host_str(), self.url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(eyre!("{} has no host", self.url)),
+        };
+
+        // Sorted by name, as the canonical request needs them
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &credential.token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{}\n{}\n\n{}\n{}\n{}",
+            method,
+            self.url.path(),
+            headers
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+                .collect::<String>(),
+            signed_headers,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signing_key = ["aws4_request", "s3", self.region.as_str(), date.as_str()]
+            .iter()
+            .rev()
+            .fold(
+                format!("AWS4{}", credential.secret_key).into_bytes(),
+                |key, data| hmac_sha256(&key, data),
+            );
+        let signature = hex::encode(hmac_sha256(&signing_key, &string_to_sign));
+
+        let mut request = self.http_client.request(method, self.url.clone()).header(
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                credential.key_id, scope, signed_headers, signature
+            ),
+        );
+        // reqwest sets Host itself
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+
+        Ok(request.body(body.to_string()))
+    }
+
+    async fn exists(&self) -> Result<bool, Error> {
+        let credential = self.credentials.get().await?;
+        let response = self
+            .signed_request(Method::HEAD, "", &credential)?
+            .send()
+            .await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            status => Err(eyre!(
+                "HeadBucket for {} answered {}",
+                self.bucket_name,
+                status
+            )),
+        }
+    }
+
+    async fn create(&self) -> Result<(), Error> {
+        // us-east-1 is the one region that refuses a location constraint, R2 picks a location
+        // itself without one
+        let body = match self.region.as_str() {
+            DEFAULT_REGION | R2_REGION => String::new(),
+            region => format!(
+                "<CreateBucketConfiguration xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\"><LocationConstraint>{}</LocationConstraint></CreateBucketConfiguration>",
+                region
+            ),
+        };
+
+        let credential = self.credentials.get().await?;
+        let response = self
+            .signed_request(Method::PUT, &body, &credential)?
+            .send()
+            .await?;
+        let status = response.status();
+
+        match status {
+            status if status.is_success() => Ok(()),
+            // Created by another instance in the meantime
+            StatusCode::CONFLICT => Ok(()),
+            _ => Err(eyre!(
+                "CreateBucket for {} answered {}: {}",
+                self.bucket_name,
+                status,
+                response.text().await.unwrap_or_default()
+            )),
+        }
+    }
+
+    /// Creates the bucket if HeadBucket says it isn't there
+    pub(crate) async fn create_if_missing(&self) -> Result<(), Error> {
+        if self.exists().await? {
+            return Ok(());
+        }
+
+        info!(
+            "Bucket {} doesn't exist, creating it in {}",
+            self.bucket_name, self.region
+        );
+        self.create().await
+    }
+}
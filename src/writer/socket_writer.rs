@@ -0,0 +1,279 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::{ConsoleFormat, Settings};
+use crate::event_bus::Subscription;
+use crate::hive::scanner::{BlockBatch, HiveBlockWithNum};
+use crate::status::SYNC_STATUS;
+use crate::writer::console_writer::format_podping;
+use crate::writer::reorder::{ReorderWindow, DEFAULT_REORDER_WINDOW};
+use crate::writer::writer::{Writer, WriterInitError};
+use color_eyre::eyre::Error;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::unix::pipe;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, error, info, warn};
+
+const FIFO_READER_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// A client that can't take a block within this long is disconnected
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+enum SocketOutput {
+    /// Blocks wait for a reader to open the FIFO
+    Fifo(Mutex<Option<pipe::Sender>>),
+    /// Every connected client gets each podping, nothing is kept for clients that aren't connected
+    Socket(Arc<Mutex<Vec<UnixStream>>>),
+}
+
+/// Streams podpings as NDJSON to a FIFO or to the clients of a Unix socket
+///
+/// Nothing is persisted, so like the console writer the scan starts from the configured start
+/// every time.
+pub(crate) struct SocketWriter {
+    path: PathBuf,
+    output: SocketOutput,
+    reorder_window: usize,
+}
+
+fn block_lines(block: &HiveBlockWithNum) -> Result<String, Error> {
+    let mut lines = String::new();
+
+    for tx in &block.transactions {
+        for (i, podping) in tx.indexed_podpings() {
+            if let Some(line) = format_podping(ConsoleFormat::Ndjson, block, tx, i, podping)? {
+                lines.push_str(&line);
+                lines.push('\n');
+            }
+        }
+    }
+
+    Ok(lines)
+}
+
+async fn accept_clients(listener: UnixListener, clients: Arc<Mutex<Vec<UnixStream>>>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                info!("socket writer: client connected");
+                clients.lock().await.push(stream);
+            }
+            Err(e) => {
+                error!("socket writer: error accepting a client: {}", e);
+                sleep(FIFO_READER_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+impl SocketWriter {
+    async fn write_fifo(&self, sender: &Mutex<Option<pipe::Sender>>, lines: &str) {
+        let mut sender = sender.lock().await;
+        let mut waiting = false;
+
+        loop {
+            if sender.is_none() {
+                match pipe::OpenOptions::new().open_sender(&self.path) {
+                    Ok(opened) => {
+                        info!("socket writer: reader opened {}", self.path.display());
+                        *sender = Some(opened);
+                    }
+                    // ENXIO, nobody has the FIFO open for reading yet
+                    Err(e) if e.raw_os_error() == Some(6) => {
+                        if !waiting {
+                            warn!(
+                                "socket writer: waiting for a reader on {}",
+                                self.path.display()
+                            );
+                            waiting = true;
+                        }
+                        sleep(FIFO_READER_RETRY_INTERVAL).await;
+                        continue;
+                    }
+                    Err(e) => {
+                        error!(
+                            "socket writer: error opening {}: {}",
+                            self.path.display(),
+                            e
+                        );
+                        sleep(FIFO_READER_RETRY_INTERVAL).await;
+                        continue;
+                    }
+                }
+            }
+
+            // Checked above
+            match sender.as_mut().unwrap().write_all(lines.as_bytes()).await {
+                Ok(_) => return,
+                Err(e) => {
+                    // The reader went away, the block is written again for the next one
+                    warn!(
+                        "socket writer: reader closed {}: {}",
+                        self.path.display(),
+                        e
+                    );
+                    *sender = None;
+                }
+            }
+        }
+    }
+
+    async fn write_clients(&self, clients: &Mutex<Vec<UnixStream>>, lines: &str) {
+        let mut clients = clients.lock().await;
+        let mut connected = Vec::with_capacity(clients.len());
+
+        for mut client in clients.drain(..) {
+            match timeout(CLIENT_WRITE_TIMEOUT, client.write_all(lines.as_bytes())).await {
+                Ok(Ok(_)) => connected.push(client),
+                Ok(Err(e)) => info!("socket writer: client disconnected: {}", e),
+                Err(_) => warn!("socket writer: client is too slow, disconnecting it"),
+            }
+        }
+
+        *clients = connected;
+    }
+
+    async fn write_block(&self, block: &HiveBlockWithNum) -> Result<(), Error> {
+        if block.transactions.is_empty() {
+            debug!("No Podpings for block {}", block.block_num);
+            return Ok(());
+        }
+
+        let lines = block_lines(block)?;
+
+        match &self.output {
+            SocketOutput::Fifo(sender) => self.write_fifo(sender, &lines).await,
+            SocketOutput::Socket(clients) => self.write_clients(clients, &lines).await,
+        }
+
+        Ok(())
+    }
+}
+
+impl Writer for SocketWriter {
+    async fn new(settings: &Settings) -> Result<Self, WriterInitError>
+    where
+        Self: Sized,
+    {
+        let path = match settings.writer.socket_path.clone() {
+            Some(socket_path) if !socket_path.is_empty() => PathBuf::from(socket_path),
+            _ => {
+                return Err(WriterInitError::Config(
+                    "socket_path is not set!".to_string(),
+                ))
+            }
+        };
+
+        let existing = std::fs::symlink_metadata(&path).ok();
+
+        let output = match existing.map(|metadata| metadata.file_type()) {
+            Some(file_type) if file_type.is_fifo() => {
+                info!("socket writer: writing to FIFO {}", path.display());
+                SocketOutput::Fifo(Mutex::new(None))
+            }
+            Some(file_type) if !file_type.is_socket() => {
+                return Err(WriterInitError::Config(format!(
+                    "socket_path {} exists and isn't a FIFO or socket",
+                    path.display()
+                )))
+            }
+            file_type => {
+                // Left behind by the last run
+                if file_type.is_some() {
+                    if let Err(e) = std::fs::remove_file(&path) {
+                        return Err(WriterInitError::Config(format!(
+                            "Unable to remove old socket {}: {}",
+                            path.display(),
+                            e
+                        )));
+                    }
+                }
+
+                let listener = match UnixListener::bind(&path) {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        return Err(WriterInitError::Config(format!(
+                            "Unable to listen on socket {}: {}",
+                            path.display(),
+                            e
+                        )))
+                    }
+                };
+                info!("socket writer: listening on {}", path.display());
+
+                let clients = Arc::new(Mutex::new(vec![]));
+                tokio::spawn(accept_clients(listener, clients.clone()));
+
+                SocketOutput::Socket(clients)
+            }
+        };
+
+        Ok(SocketWriter {
+            path,
+            output,
+            reorder_window: settings
+                .writer
+                .reorder_window
+                .unwrap_or(DEFAULT_REORDER_WINDOW),
+        })
+    }
+
+    async fn get_last_block(&self) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    async fn preflight(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn start(&self, mut rx: Subscription<HiveBlockWithNum>) -> Result<(), Error> {
+        let mut reorder_window = ReorderWindow::new(self.reorder_window);
+
+        loop {
+            let event = match rx.recv().await {
+                Some(event) => event,
+                // The scanner stops after scanner.end_block
+                None => return Ok(()),
+            };
+
+            for block in reorder_window.push(event.payload)? {
+                self.write_block(&block).await?;
+                SYNC_STATUS.record_write_latency("socket", block.block_num, block.timestamp);
+            }
+            rx.ack(event.seq);
+        }
+    }
+
+    async fn start_batch(&self, mut rx: Subscription<BlockBatch>) -> Result<(), Error> {
+        while let Some(event) = rx.recv().await {
+            for block in &event.payload.blocks {
+                self.write_block(block).await?;
+            }
+            rx.ack(event.seq);
+        }
+
+        Ok(())
+    }
+
+    // Nothing is persisted, so there's nothing to verify
+    async fn missing_files(&self, _block: &HiveBlockWithNum) -> Result<Vec<PathBuf>, Error> {
+        Ok(vec![])
+    }
+
+    async fn repair_block(&self, _block: HiveBlockWithNum) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,897 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::{
+    Http, HttpVersion, ObjectStorageProvider, ObjectStorageSse, R2Jurisdiction, S3Preset, Settings,
+    Writer, WriterUrlStyle,
+};
+use crate::writer::body_encoding::{content_type_accepted, decode_body};
+use crate::writer::credentials::CredentialsProvider;
+use crate::writer::writer::WriterInitError;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use color_eyre::eyre::{eyre, Error};
+use color_eyre::Result;
+use futures::TryStreamExt;
+use md5::{Digest, Md5};
+use object_store::aws::{AmazonS3Builder, AmazonS3ConfigKey};
+use object_store::azure::{AzureConfigKey, MicrosoftAzureBuilder};
+use object_store::gcp::GoogleCloudStorageBuilder;
+use object_store::local::LocalFileSystem;
+use object_store::path::Path as ObjectPath;
+use object_store::{
+    Attribute, Attributes, Certificate, ClientOptions, ObjectStore, PutMode, PutMultipartOpts,
+    PutOptions, PutPayload, PutPayloadMut, RetryConfig, TagSet,
+};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+use url::Host;
+
+const HTTP2_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_MULTIPART_THRESHOLD_MB: usize = 16;
+const DEFAULT_MULTIPART_PART_SIZE_MB: usize = 8;
+const MULTIPART_CONCURRENCY: usize = 4;
+
+#[derive(Error, Debug)]
+pub enum ReadObjectError {
+    #[error("Unexpected content type {0} reading object")]
+    UnexpectedContentType(String),
+    #[error("Error decoding object body: {0}")]
+    Decode(#[from] std::io::Error),
+    #[error("Object body doesn't match its ETag {0}, it was corrupted in transit or storage")]
+    ChecksumMismatch(String),
+}
+
+/// The ETag if it's the object's MD5
+///
+/// Multipart and SSE-KMS uploads have ETags that aren't a plain MD5 of the body.
+fn etag_md5(e_tag: Option<&str>) -> Option<String> {
+    let etag = e_tag?.trim_matches('"');
+
+    match etag.len() == 32 && etag.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => Some(etag.to_ascii_lowercase()),
+        false => None,
+    }
+}
+
+/// HTTP options for every provider from `[http]`, trusting `object_storage_tls_ca_cert` on top
+/// of the usual roots
+///
+/// Every request to a bucket goes through the one client its `Storage` is built with, so
+/// connections are kept alive and reused across blocks. object_store has no way to present a
+/// client certificate, so only a private CA can be set.
+pub(crate) fn client_options(
+    http: &Http,
+    writer: &Writer,
+) -> Result<ClientOptions, WriterInitError> {
+    let mut options = ClientOptions::new()
+        .with_connect_timeout(http.connect_timeout())
+        .with_timeout(http.timeout())
+        // Keeps idle HTTP/2 connections open between blocks
+        .with_http2_keep_alive_interval(HTTP2_KEEP_ALIVE_INTERVAL)
+        .with_http2_keep_alive_while_idle();
+
+    if let Some(max_idle) = writer
+        .object_storage_max_connections_per_host
+        .or(http.pool_max_idle_per_host)
+    {
+        options = options.with_pool_max_idle_per_host(max_idle);
+    }
+    if let Some(idle_timeout) = http.pool_idle_timeout {
+        options = options.with_pool_idle_timeout(idle_timeout);
+    }
+
+    // object_store's own default is HTTP/1.1 only, auto negotiates HTTP/2 over TLS instead so
+    // small uploads share a connection
+    options = match http.version.unwrap_or(HttpVersion::Auto) {
+        HttpVersion::Auto => options.with_allow_http2(),
+        HttpVersion::Http1 => options.with_http1_only(),
+        HttpVersion::Http2 => options.with_http2_only(),
+    };
+
+    if let Some(proxy) = http.proxy() {
+        options = options.with_proxy_url(proxy);
+
+        if let Some(no_proxy) = http
+            .no_proxy
+            .as_deref()
+            .filter(|no_proxy| !no_proxy.is_empty())
+        {
+            options = options.with_proxy_excludes(no_proxy);
+        }
+    }
+
+    let ca_file = match writer
+        .object_storage_tls_ca_cert
+        .as_deref()
+        .filter(|ca_file| !ca_file.is_empty())
+    {
+        Some(ca_file) => ca_file,
+        None => return Ok(options),
+    };
+
+    let certificates = std::fs::read(ca_file)
+        .map_err(|e| e.to_string())
+        .and_then(|pem| Certificate::from_pem_bundle(&pem).map_err(|e| e.to_string()))
+        .map_err(|e| {
+            WriterInitError::Config(format!(
+                "Unable to read object storage CA certificate {}: {}",
+                ca_file, e
+            ))
+        })?;
+
+    Ok(certificates
+        .into_iter()
+        .fold(options, |options, certificate| {
+            options.with_root_certificate(certificate)
+        }))
+}
+
+/// The configured URL style, or the preset's
+///
+/// Falls back to path style for an IP address or localhost, which a bucket can't be put in front
+/// of as a subdomain.
+pub(crate) fn resolve_url_style(
+    base_url: &str,
+    configured: Option<WriterUrlStyle>,
+    preset: S3Preset,
+) -> WriterUrlStyle {
+    let url_style = configured.unwrap_or(preset.url_style);
+    let host = base_url
+        .parse::<url::Url>()
+        .ok()
+        .and_then(|url| url.host().map(|host| host.to_owned()));
+
+    match (url_style, host) {
+        (WriterUrlStyle::VirtualHost, Some(Host::Ipv4(_) | Host::Ipv6(_))) => {}
+        (WriterUrlStyle::VirtualHost, Some(Host::Domain(domain))) if domain == "localhost" => {}
+        _ => return url_style,
+    }
+
+    info!(
+        "Using path style requests for {}, it can't take the bucket as a subdomain",
+        base_url
+    );
+    WriterUrlStyle::Path
+}
+
+/// The S3 API endpoint for an R2 account, a bucket in a jurisdiction is only reachable through
+/// the jurisdiction's own endpoint
+fn r2_base_url(account_id: &str, jurisdiction: R2Jurisdiction) -> String {
+    match jurisdiction {
+        R2Jurisdiction::Default => format!("https://{}.r2.cloudflarestorage.com", account_id),
+        R2Jurisdiction::Eu => format!("https://{}.eu.r2.cloudflarestorage.com", account_id),
+        R2Jurisdiction::Fedramp => {
+            format!("https://{}.fedramp.r2.cloudflarestorage.com", account_id)
+        }
+    }
+}
+
+/// Server side encryption for every upload, see `object_storage_sse`
+#[derive(Clone)]
+pub(crate) enum S3Encryption {
+    Aes256,
+    // None for the bucket's default KMS key
+    Kms(Option<String>),
+    // The base64 encoded 256-bit key, sent with reads as well
+    Customer(String),
+}
+
+impl S3Encryption {
+    fn from_settings(settings: &Settings) -> Result<Option<S3Encryption>, WriterInitError> {
+        let writer = &settings.writer;
+
+        match writer.object_storage_sse {
+            None => Ok(None),
+            Some(ObjectStorageSse::Aes256) => Ok(Some(S3Encryption::Aes256)),
+            Some(ObjectStorageSse::Kms) => Ok(Some(S3Encryption::Kms(
+                writer
+                    .object_storage_sse_kms_key_id
+                    .clone()
+                    .filter(|key_id| !key_id.is_empty()),
+            ))),
+            Some(ObjectStorageSse::Customer) => {
+                let key = writer
+                    .object_storage_sse_customer_key
+                    .clone()
+                    .unwrap_or_default();
+
+                match BASE64_STANDARD.decode(&key) {
+                    Ok(decoded) if decoded.len() == 32 => Ok(Some(S3Encryption::Customer(key))),
+                    _ => Err(WriterInitError::Config(
+                        "object_storage_sse_customer_key isn't a base64 encoded 256-bit key"
+                            .to_string(),
+                    )),
+                }
+            }
+        }
+    }
+
+    fn apply(&self, builder: AmazonS3Builder) -> AmazonS3Builder {
+        match self {
+            S3Encryption::Aes256 => builder.with_config(
+                AmazonS3ConfigKey::from_str("aws_server_side_encryption")
+                    .expect("object_store knows its own config keys"),
+                "AES256",
+            ),
+            S3Encryption::Kms(key_id) => {
+                builder.with_sse_kms_encryption(key_id.clone().unwrap_or_default())
+            }
+            S3Encryption::Customer(key) => builder.with_ssec_encryption(key),
+        }
+    }
+
+    /// Whether the store still gives single part uploads an MD5 ETag
+    fn keeps_md5_etags(&self) -> bool {
+        matches!(self, S3Encryption::Aes256)
+    }
+}
+
+/// Where the writer's S3 compatible bucket is, with the provider's defaults filled in
+pub(crate) struct S3Endpoint {
+    pub(crate) base_url: String,
+    pub(crate) url_style: WriterUrlStyle,
+    pub(crate) region: String,
+    pub(crate) preset: S3Preset,
+    pub(crate) encryption: Option<S3Encryption>,
+}
+
+impl S3Endpoint {
+    pub(crate) fn from_settings(settings: &Settings) -> Result<S3Endpoint, WriterInitError> {
+        let provider = settings
+            .writer
+            .object_storage_provider
+            .unwrap_or(ObjectStorageProvider::S3);
+        let preset = provider.s3_preset();
+
+        let r2_account_id = settings
+            .writer
+            .object_storage_r2_account_id
+            .as_deref()
+            .filter(|account_id| !account_id.is_empty());
+        let base_url = match (
+            settings.writer.object_storage_base_url.as_deref(),
+            r2_account_id,
+        ) {
+            (Some(base_url), _) if !base_url.is_empty() => base_url.to_string(),
+            (_, Some(account_id)) if provider == ObjectStorageProvider::R2 => r2_base_url(
+                account_id,
+                settings
+                    .writer
+                    .object_storage_r2_jurisdiction
+                    .unwrap_or(R2Jurisdiction::Default),
+            ),
+            _ => String::new(),
+        };
+        if base_url.is_empty() {
+            return Err(WriterInitError::Config(
+                "object_storage_base_url is not set".to_string(),
+            ));
+        }
+
+        let region = match (settings.writer.object_storage_region.clone(), preset.region) {
+            (Some(region), _) if !region.is_empty() => region,
+            (_, Some(region)) => region.to_string(),
+            (Some(region), None) => region,
+            (None, None) => {
+                return Err(WriterInitError::Config(
+                    "object_storage_region is not set".to_string(),
+                ))
+            }
+        };
+
+        Ok(S3Endpoint {
+            url_style: resolve_url_style(
+                &base_url,
+                settings.writer.object_storage_url_style,
+                preset,
+            ),
+            base_url,
+            region,
+            preset,
+            encryption: S3Encryption::from_settings(settings)?,
+        })
+    }
+}
+
+/// Headers for every block object, see `object_storage_cache_control`,
+/// `object_storage_content_disposition` and `object_storage_metadata`
+pub(crate) fn upload_headers(writer: &Writer) -> Attributes {
+    let mut headers = Attributes::new();
+
+    if let Some(cache_control) = writer
+        .object_storage_cache_control
+        .clone()
+        .filter(|cache_control| !cache_control.is_empty())
+    {
+        headers.insert(Attribute::CacheControl, cache_control.into());
+    }
+    if let Some(content_disposition) = writer
+        .object_storage_content_disposition
+        .clone()
+        .filter(|content_disposition| !content_disposition.is_empty())
+    {
+        headers.insert(Attribute::ContentDisposition, content_disposition.into());
+    }
+    // Sent as x-amz-meta-<key> to S3, x-ms-meta-<key> to Azure
+    for (key, value) in writer.object_storage_metadata.iter().flatten() {
+        headers.insert(
+            Attribute::Metadata(key.clone().into()),
+            value.clone().into(),
+        );
+    }
+
+    headers
+}
+
+fn object_path(path: &Path) -> ObjectPath {
+    ObjectPath::from(path.to_string_lossy().replace('\\', "/"))
+}
+
+fn prefix_path(prefix: &str) -> Option<ObjectPath> {
+    match prefix.trim_matches('/').is_empty() {
+        true => None,
+        false => Some(ObjectPath::from(prefix)),
+    }
+}
+
+/// A bucket (or container, or directory) behind the `object_store` crate
+///
+/// Every provider gets the same retries with backoff from `object_store`, and the same paths
+/// the disk writer uses.
+#[derive(Clone)]
+pub(crate) struct Storage {
+    store: Arc<dyn ObjectStore>,
+    name: String,
+    // The same bucket name can be on more than one endpoint
+    endpoint: String,
+    bucket: String,
+    // The local filesystem has nowhere to keep a Content-Type, or any other header
+    content_types: bool,
+    // Whether an ETag that looks like an MD5 is one, see `read_text`
+    md5_etags: bool,
+    multipart: Multipart,
+}
+
+/// When `put_with` switches to a multipart upload, and the size of its parts
+#[derive(Debug, Clone, Copy)]
+struct Multipart {
+    threshold: usize,
+    part_size: usize,
+}
+
+impl Default for Multipart {
+    fn default() -> Self {
+        Multipart {
+            threshold: DEFAULT_MULTIPART_THRESHOLD_MB * 1024 * 1024,
+            part_size: DEFAULT_MULTIPART_PART_SIZE_MB * 1024 * 1024,
+        }
+    }
+}
+
+impl Multipart {
+    fn from_settings(writer: &Writer) -> Multipart {
+        let default = Multipart::default();
+
+        Multipart {
+            threshold: writer
+                .object_storage_multipart_threshold_mb
+                .map_or(default.threshold, |threshold| threshold * 1024 * 1024),
+            part_size: writer
+                .object_storage_multipart_part_size_mb
+                .map_or(default.part_size, |part_size| part_size * 1024 * 1024),
+        }
+    }
+
+    /// `body` cut into `part_size` parts, the last one shorter
+    fn split(&self, body: PutPayload) -> Vec<PutPayload> {
+        let new_part = || PutPayloadMut::new().with_block_size(self.part_size);
+        let mut parts = vec![];
+        let mut part = new_part();
+
+        for bytes in &body {
+            let mut bytes: &[u8] = bytes;
+
+            while !bytes.is_empty() {
+                let len = bytes.len().min(self.part_size - part.content_length());
+                part.extend_from_slice(&bytes[..len]);
+                bytes = &bytes[len..];
+
+                if part.content_length() == self.part_size {
+                    parts.push(std::mem::replace(&mut part, new_part()).freeze());
+                }
+            }
+        }
+
+        if !part.is_empty() {
+            parts.push(part.freeze());
+        }
+
+        parts
+    }
+}
+
+impl Storage {
+    /// An S3 compatible bucket, `put_if_absent` sends If-None-Match: *
+    pub(crate) fn s3(
+        endpoint: &S3Endpoint,
+        bucket_name: &str,
+        credentials: Arc<CredentialsProvider>,
+        client_options: ClientOptions,
+    ) -> Result<Storage, WriterInitError> {
+        let base_url = endpoint.base_url.as_str();
+        let virtual_host = endpoint.url_style == WriterUrlStyle::VirtualHost;
+
+        if let Err(e) = base_url.parse::<url::Url>() {
+            return Err(WriterInitError::Config(format!(
+                "Error parsing object storage base URL: {}",
+                e
+            )));
+        }
+
+        // object_store expects a virtual host endpoint to already name the bucket
+        let endpoint_url = match virtual_host {
+            true => match base_url.split_once("://") {
+                Some((scheme, host)) => format!("{}://{}.{}", scheme, bucket_name, host),
+                None => base_url.to_string(),
+            },
+            false => base_url.to_string(),
+        };
+
+        let mut builder = AmazonS3Builder::new()
+            .with_endpoint(endpoint_url)
+            .with_region(&endpoint.region)
+            .with_bucket_name(bucket_name)
+            .with_virtual_hosted_style_request(virtual_host)
+            .with_conditional_put(object_store::aws::S3ConditionalPut::ETagMatch)
+            .with_credentials(credentials)
+            .with_client_options(client_options)
+            // After the client options, which would reset it
+            .with_allow_http(base_url.starts_with("http://"))
+            .with_retry(RetryConfig::default());
+        if let Some(encryption) = &endpoint.encryption {
+            builder = encryption.apply(builder);
+        }
+
+        match builder.build() {
+            Ok(store) => Ok(Storage {
+                store: Arc::new(store),
+                name: format!("s3://{}", bucket_name),
+                endpoint: base_url.to_string(),
+                bucket: bucket_name.to_string(),
+                content_types: true,
+                md5_etags: endpoint.preset.md5_etags
+                    && endpoint
+                        .encryption
+                        .as_ref()
+                        .is_none_or(S3Encryption::keeps_md5_etags),
+                multipart: Multipart::default(),
+            }),
+            Err(e) => Err(WriterInitError::Config(format!(
+                "Error creating S3 client: {}",
+                e
+            ))),
+        }
+    }
+
+    /// The writer's bucket on the configured `object_storage_provider`
+    pub(crate) fn from_settings(
+        settings: &Settings,
+        bucket_name: &str,
+    ) -> Result<Storage, WriterInitError> {
+        let base_url = settings
+            .writer
+            .object_storage_base_url
+            .clone()
+            .unwrap_or_default();
+        let client_options = client_options(&settings.http, &settings.writer)?;
+
+        let storage = match settings
+            .writer
+            .object_storage_provider
+            .unwrap_or(ObjectStorageProvider::S3)
+        {
+            ObjectStorageProvider::S3
+            | ObjectStorageProvider::Minio
+            | ObjectStorageProvider::Ceph
+            | ObjectStorageProvider::B2
+            | ObjectStorageProvider::R2
+            | ObjectStorageProvider::Wasabi => Storage::s3(
+                &S3Endpoint::from_settings(settings)?,
+                bucket_name,
+                CredentialsProvider::from_chain(),
+                client_options,
+            ),
+            ObjectStorageProvider::Gcs => {
+                let store = GoogleCloudStorageBuilder::from_env()
+                    .with_bucket_name(bucket_name)
+                    .with_client_options(client_options)
+                    .with_retry(RetryConfig::default())
+                    .build();
+
+                match store {
+                    Ok(store) => Ok(Storage {
+                        store: Arc::new(store),
+                        name: format!("gs://{}", bucket_name),
+                        endpoint: "gs://".to_string(),
+                        bucket: bucket_name.to_string(),
+                        content_types: true,
+                        md5_etags: true,
+                        multipart: Multipart::default(),
+                    }),
+                    Err(e) => Err(WriterInitError::Config(format!(
+                        "Error creating GCS client: {}",
+                        e
+                    ))),
+                }
+            }
+            ObjectStorageProvider::Azure => {
+                let mut builder = MicrosoftAzureBuilder::from_env()
+                    .with_container_name(bucket_name)
+                    .with_client_options(client_options)
+                    .with_retry(RetryConfig::default());
+
+                if let Some(account_name) = &settings.writer.azure_account_name {
+                    builder = builder.with_account(account_name);
+                }
+                // Without a SAS token or account key the builder falls back to the managed
+                // identity, and the client id picks a user-assigned one
+                if let Some(sas_token) = &settings.writer.azure_sas_token {
+                    builder = builder.with_config(AzureConfigKey::SasKey, sas_token);
+                }
+                if let Some(client_id) = &settings.writer.azure_managed_identity_client_id {
+                    builder = builder.with_client_id(client_id);
+                }
+
+                if !base_url.is_empty() {
+                    builder = builder
+                        .with_allow_http(base_url.starts_with("http://"))
+                        .with_endpoint(base_url.clone());
+                }
+
+                let endpoint = match base_url.is_empty() {
+                    true => "az://".to_string(),
+                    false => base_url.clone(),
+                };
+
+                match builder.build() {
+                    Ok(store) => Ok(Storage {
+                        store: Arc::new(store),
+                        name: format!("az://{}", bucket_name),
+                        endpoint,
+                        bucket: bucket_name.to_string(),
+                        content_types: true,
+                        md5_etags: true,
+                        multipart: Multipart::default(),
+                    }),
+                    Err(e) => Err(WriterInitError::Config(format!(
+                        "Error creating Azure client: {}",
+                        e
+                    ))),
+                }
+            }
+            ObjectStorageProvider::Local => {
+                if base_url.is_empty() {
+                    return Err(WriterInitError::Config(
+                        "object_storage_base_url is not set".to_string(),
+                    ));
+                }
+
+                let directory = PathBuf::from(&base_url).join(bucket_name);
+                if let Err(e) = std::fs::create_dir_all(&directory) {
+                    return Err(WriterInitError::Unavailable(format!(
+                        "Error creating {}: {}",
+                        directory.display(),
+                        e
+                    )));
+                }
+
+                match LocalFileSystem::new_with_prefix(&directory) {
+                    Ok(store) => Ok(Storage {
+                        store: Arc::new(store),
+                        name: directory.display().to_string(),
+                        endpoint: base_url,
+                        bucket: bucket_name.to_string(),
+                        content_types: false,
+                        md5_etags: true,
+                        multipart: Multipart::default(),
+                    }),
+                    Err(e) => Err(WriterInitError::Unavailable(format!(
+                        "Error opening {}: {}",
+                        directory.display(),
+                        e
+                    ))),
+                }
+            }
+        }?;
+
+        Ok(Storage {
+            multipart: Multipart::from_settings(&settings.writer),
+            ..storage
+        })
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The endpoint and bucket name, which together tell buckets apart
+    pub(crate) fn location(&self) -> (&str, &str) {
+        (&self.endpoint, &self.bucket)
+    }
+
+    /// Lists the top of the bucket, which fails if it doesn't exist or can't be read
+    pub(crate) async fn check(&self) -> Result<(), Error> {
+        self.store.list_with_delimiter(None).await?;
+
+        debug!("Successfully connected to {}.", self.name);
+
+        Ok(())
+    }
+
+    fn put_options(
+        &self,
+        mode: PutMode,
+        content_type: &str,
+        tags: TagSet,
+        headers: &Attributes,
+    ) -> PutOptions {
+        let mut attributes = Attributes::new();
+        if self.content_types {
+            attributes = headers.clone();
+            attributes.insert(Attribute::ContentType, content_type.to_string().into());
+        }
+
+        PutOptions {
+            mode,
+            tags,
+            attributes,
+        }
+    }
+
+    pub(crate) async fn put(
+        &self,
+        path: &Path,
+        body: impl Into<PutPayload>,
+        content_type: &str,
+    ) -> Result<(), Error> {
+        self.put_with(
+            path,
+            body,
+            content_type,
+            TagSet::default(),
+            &Attributes::new(),
+        )
+        .await
+    }
+
+    /// A put with tags and headers from `upload_headers`, the local filesystem drops both
+    ///
+    /// Bodies over `object_storage_multipart_threshold_mb` are uploaded in parts.
+    pub(crate) async fn put_with(
+        &self,
+        path: &Path,
+        body: impl Into<PutPayload>,
+        content_type: &str,
+        tags: TagSet,
+        headers: &Attributes,
+    ) -> Result<(), Error> {
+        let location = object_path(path);
+        let body = body.into();
+        let options = self.put_options(PutMode::Overwrite, content_type, tags, headers);
+
+        if body.content_length() > self.multipart.threshold {
+            return self.put_multipart(&location, body, options).await;
+        }
+
+        debug!("{}: put {}", self.name, location);
+
+        self.store.put_opts(&location, body, options).await?;
+
+        Ok(())
+    }
+
+    /// Uploads `body` in parts, a few at once
+    ///
+    /// object_store retries each part on its own, so a dropped connection only sends that part
+    /// again and no request has to fit the whole object in `http.timeout`. A part that still
+    /// fails aborts the upload, so its other parts aren't left behind in the bucket.
+    async fn put_multipart(
+        &self,
+        location: &ObjectPath,
+        body: PutPayload,
+        options: PutOptions,
+    ) -> Result<(), Error> {
+        let size = body.content_length();
+        let parts = self.multipart.split(body);
+
+        debug!(
+            "{}: multipart put {}, {} bytes in {} parts",
+            self.name,
+            location,
+            size,
+            parts.len()
+        );
+
+        let mut upload = self
+            .store
+            .put_multipart_opts(
+                location,
+                PutMultipartOpts {
+                    tags: options.tags,
+                    attributes: options.attributes,
+                },
+            )
+            .await?;
+        let mut uploading = JoinSet::new();
+
+        let result = async {
+            for part in parts {
+                while uploading.len() >= MULTIPART_CONCURRENCY {
+                    uploading.join_next().await.unwrap()??;
+                }
+
+                uploading.spawn(upload.put_part(part));
+            }
+
+            while let Some(part_result) = uploading.join_next().await {
+                part_result??;
+            }
+
+            upload.complete().await?;
+
+            Ok::<_, Error>(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            uploading.shutdown().await;
+
+            if let Err(abort_error) = upload.abort().await {
+                warn!(
+                    "{}: error aborting the multipart upload of {}: {}",
+                    self.name, location, abort_error
+                );
+            }
+
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Writes the object unless it's already there, returns whether it was written
+    pub(crate) async fn put_if_absent(
+        &self,
+        path: &Path,
+        body: impl Into<PutPayload>,
+        content_type: &str,
+        tags: TagSet,
+        headers: &Attributes,
+    ) -> Result<bool, Error> {
+        let location = object_path(path);
+
+        debug!("{}: conditional put {}", self.name, location);
+
+        match self
+            .store
+            .put_opts(
+                &location,
+                body.into(),
+                self.put_options(PutMode::Create, content_type, tags, headers),
+            )
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::AlreadyExists { .. }) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub(crate) async fn exists(&self, path: &Path) -> Result<bool, Error> {
+        match self.store.head(&object_path(path)).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The object's body, None if it doesn't exist
+    pub(crate) async fn get(&self, path: &Path) -> Result<Option<Vec<u8>>, Error> {
+        match self.store.get(&object_path(path)).await {
+            Ok(result) => Ok(Some(result.bytes().await?.to_vec())),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// A text object like the checkpoint, checked against its ETag and decoded
+    ///
+    /// None if it doesn't exist.
+    pub(crate) async fn read_text(
+        &self,
+        path: &Path,
+        accepted_content_types: &[&str],
+    ) -> Result<Option<String>, Error> {
+        let result = match self.store.get(&object_path(path)).await {
+            Ok(result) => result,
+            Err(object_store::Error::NotFound { .. }) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let content_type = result
+            .attributes
+            .get(&Attribute::ContentType)
+            .map(|content_type| content_type.to_string());
+
+        if !content_type_accepted(content_type.as_deref(), accepted_content_types) {
+            return Err(
+                ReadObjectError::UnexpectedContentType(content_type.unwrap_or_default()).into(),
+            );
+        }
+
+        let expected_md5 = match self.md5_etags {
+            true => etag_md5(result.meta.e_tag.as_deref()),
+            false => None,
+        };
+        let bytes = result.bytes().await?;
+
+        if let Some(expected_md5) = expected_md5 {
+            if hex::encode(Md5::digest(&bytes)) != expected_md5 {
+                return Err(ReadObjectError::ChecksumMismatch(expected_md5).into());
+            }
+        }
+
+        // Bodies stored pre-compressed, object_store never undoes a Content-Encoding
+        Ok(Some(decode_body(&bytes).map_err(ReadObjectError::from)?))
+    }
+
+    /// Deletes the object, one that's already gone isn't an error
+    pub(crate) async fn delete(&self, path: &Path) -> Result<(), Error> {
+        let location = object_path(path);
+
+        debug!("{}: delete {}", self.name, location);
+
+        match self.store.delete(&location).await {
+            Ok(_) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Every key under `prefix`, read back as `/` separated paths
+    pub(crate) async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        self.store
+            .list(prefix_path(prefix).as_ref())
+            .map_ok(|meta| meta.location.to_string())
+            .try_collect()
+            .await
+            .map_err(|e| eyre!("Error listing {} in {}: {}", prefix, self.name, e))
+    }
+
+    /// Lists the "directories" directly under `prefix`, each ending in `/`
+    pub(crate) async fn list_common_prefixes(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let listing = self
+            .store
+            .list_with_delimiter(prefix_path(prefix).as_ref())
+            .await
+            .map_err(|e| eyre!("Error listing {} in {}: {}", prefix, self.name, e))?;
+
+        Ok(listing
+            .common_prefixes
+            .into_iter()
+            .map(|common_prefix| format!("{}/", common_prefix))
+            .collect())
+    }
+}
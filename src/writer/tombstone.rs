@@ -0,0 +1,61 @@
+/*
+ * Copyright (c) 2024 Gates Solutions LLC.
+ *
+ *      This file is part of podpingd.
+ *
+ *     podpingd is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+ *
+ *     podpingd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::hive::scanner::HiveBlockWithNum;
+use crate::writer::block_metadata::BlockSidecars;
+use crate::writer::writer::block_file_paths;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Left in place of a podping file whose block was replaced in a fork
+///
+/// Uses its own extension so tools picking up `.json` files don't treat it as a podping.
+#[derive(Serialize)]
+pub struct Tombstone<'a> {
+    pub block_num: u64,
+    pub replaced_block_id: &'a str,
+    pub replacement_block_id: &'a str,
+}
+
+impl Tombstone<'_> {
+    pub fn for_correction(replacement: &HiveBlockWithNum) -> Option<Tombstone<'_>> {
+        replacement.replaces.as_ref().map(|replaced| Tombstone {
+            block_num: replacement.block_num,
+            replaced_block_id: &replaced.block_id,
+            replacement_block_id: &replacement.block_id,
+        })
+    }
+}
+
+pub fn tombstone_path(path: &Path) -> PathBuf {
+    let mut tombstone = path.to_path_buf().into_os_string();
+    tombstone.push(".tombstone");
+
+    PathBuf::from(tombstone)
+}
+
+/// Files written for the replaced block that the replacement won't overwrite
+pub fn orphaned_paths(replacement: &HiveBlockWithNum, sidecars: BlockSidecars) -> Vec<PathBuf> {
+    let replaced = match &replacement.replaces {
+        Some(replaced) => replaced,
+        None => return vec![],
+    };
+
+    let replacement_paths = block_file_paths(replacement, sidecars)
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    block_file_paths(replaced, sidecars)
+        .into_iter()
+        .filter(|path| !replacement_paths.contains(path))
+        .collect()
+}
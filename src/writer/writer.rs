@@ -10,24 +10,350 @@
  *     You should have received a copy of the GNU Lesser General Public License along with podpingd. If not, see <https://www.gnu.org/licenses/>.
  */
 use crate::config::Settings;
-use crate::hive::scanner::HiveBlockWithNum;
+use crate::event_bus::Subscription;
+use crate::hive::scanner::{BlockBatch, HiveBlockWithNum};
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+use crate::writer::block_metadata::BlockSidecars;
+#[cfg(any(
+    feature = "disk",
+    feature = "object-storage",
+    feature = "elasticsearch",
+    feature = "parquet"
+))]
+use chrono::{DateTime, Utc};
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+use chrono::{Datelike, Timelike};
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+use color_eyre::eyre::eyre;
 use color_eyre::eyre::Error;
 use color_eyre::Result;
-use tokio::sync::broadcast::Receiver;
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+use podping_schemas::org::podcastindex::podping::podping_json::Podping;
+#[cfg(any(
+    feature = "disk",
+    feature = "object-storage",
+    feature = "elasticsearch",
+    feature = "parquet"
+))]
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+#[cfg(any(feature = "disk", feature = "parquet"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::sleep;
+use tracing::warn;
 
 pub(crate) trait Writer {
-    async fn new(settings: &Settings) -> Self
+    async fn new(settings: &Settings) -> Result<Self, WriterInitError>
     where
         Self: Sized;
-    async fn get_last_block(&self) -> Result<Option<u64>, Error>;
+    fn get_last_block(
+        &self,
+    ) -> impl std::future::Future<Output = Result<Option<u64>, Error>> + Send;
+    /// Checks the output can actually be written to before any block is scanned
+    fn preflight(&self) -> impl std::future::Future<Output = Result<(), Error>> + Send;
     fn start(
         &self,
-        rx: Receiver<HiveBlockWithNum>,
+        rx: Subscription<HiveBlockWithNum>,
     ) -> impl std::future::Future<Output = Result<(), Error>> + Send;
     fn start_batch(
         &self,
-        rx: Receiver<Vec<HiveBlockWithNum>>,
+        rx: Subscription<BlockBatch>,
+    ) -> impl std::future::Future<Output = Result<(), Error>> + Send;
+    /// Files the block should have produced that aren't in the output
+    fn missing_files(
+        &self,
+        block: &HiveBlockWithNum,
+    ) -> impl std::future::Future<Output = Result<Vec<PathBuf>, Error>> + Send;
+    /// Writes a block's files again without moving the checkpoint
+    fn repair_block(
+        &self,
+        block: HiveBlockWithNum,
     ) -> impl std::future::Future<Output = Result<(), Error>> + Send;
 }
 
+#[cfg(any(
+    feature = "disk",
+    feature = "object-storage",
+    feature = "elasticsearch",
+    feature = "parquet"
+))]
 pub const LAST_UPDATED_BLOCK_FILENAME: &str = "last_updated_block";
+/// Written and removed again by the preflight check
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+pub const PREFLIGHT_FILENAME: &str = ".podpingd_preflight";
+/// Files are written under a name ending in this and renamed into place, readers skip them
+pub const TEMP_FILE_EXTENSION: &str = "tmp";
+
+#[cfg(any(feature = "disk", feature = "parquet"))]
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// A temp file name next to `path`, unique so concurrent writes of the same file don't share one
+#[cfg(any(feature = "disk", feature = "parquet"))]
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(
+        ".{}-{}.{}",
+        std::process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed),
+        TEMP_FILE_EXTENSION
+    ));
+
+    PathBuf::from(temp_path)
+}
+
+pub(crate) fn is_temp_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == TEMP_FILE_EXTENSION)
+}
+
+/// Why a writer couldn't be created
+#[derive(Debug, Error)]
+pub enum WriterInitError {
+    /// A setting is missing or wrong, nothing will change until the config does
+    #[error("{0}")]
+    Config(String),
+    /// The output couldn't be reached, it may be there on another try
+    #[cfg_attr(
+        not(any(
+            feature = "object-storage",
+            feature = "elasticsearch",
+            feature = "forwarder"
+        )),
+        allow(dead_code)
+    )]
+    #[error("{0}")]
+    Unavailable(String),
+}
+
+impl WriterInitError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, WriterInitError::Unavailable(_))
+    }
+}
+
+const WRITER_INIT_ATTEMPTS: u32 = 5;
+const WRITER_INIT_MIN_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const WRITER_INIT_MAX_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Creates the writer, trying again with backoff while its output is unreachable
+pub(crate) async fn new_writer<W: Writer>(settings: &Settings) -> Result<W, WriterInitError> {
+    let mut retry_interval = WRITER_INIT_MIN_RETRY_INTERVAL;
+    let mut attempt = 1;
+
+    loop {
+        match W::new(settings).await {
+            Err(e) if e.is_transient() && attempt < WRITER_INIT_ATTEMPTS => {
+                warn!(
+                    "Unable to start the writer ({}), trying again in {:?}",
+                    e, retry_interval
+                );
+
+                sleep(retry_interval).await;
+                retry_interval = (retry_interval * 2).min(WRITER_INIT_MAX_RETRY_INTERVAL);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Some of a block's files weren't written, so the block isn't done and the checkpoint can't move
+///
+/// The rest of its files were still written, one podping that can't be doesn't hold them up.
+#[cfg(any(
+    feature = "disk",
+    feature = "object-storage",
+    feature = "elasticsearch",
+    feature = "parquet",
+    feature = "forwarder"
+))]
+#[derive(Debug, Error)]
+#[error("block {} was partly written, {} of its {} files failed: {}", .block_num, .failed.len(), .written + .failed.len(), failed_files(.failed))]
+pub struct BlockWriteError {
+    pub block_num: u64,
+    pub written: usize,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+#[cfg(any(
+    feature = "disk",
+    feature = "object-storage",
+    feature = "elasticsearch",
+    feature = "parquet",
+    feature = "forwarder"
+))]
+fn failed_files(failed: &[(PathBuf, String)]) -> String {
+    failed
+        .iter()
+        .map(|(path, err)| format!("{} ({})", path.to_string_lossy(), err))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Where a writer got to, stored as JSON once every file of the block is written
+#[cfg(any(
+    feature = "disk",
+    feature = "object-storage",
+    feature = "elasticsearch",
+    feature = "parquet"
+))]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub block_num: u64,
+    pub block_id: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[cfg(any(
+    feature = "disk",
+    feature = "object-storage",
+    feature = "elasticsearch",
+    feature = "parquet"
+))]
+impl Checkpoint {
+    pub(crate) fn from_block(block: &HiveBlockWithNum) -> Checkpoint {
+        Checkpoint {
+            block_num: block.block_num,
+            block_id: block.block_id.clone(),
+            timestamp: block.timestamp,
+        }
+    }
+
+    #[cfg(any(feature = "disk", feature = "object-storage"))]
+    pub(crate) fn to_json(&self) -> String {
+        // Only plain fields, serializing can't fail
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// The checkpoint's file or object name, `last_updated_block.<checkpoint_name>` when one is set
+///
+/// Fails on a name that isn't safe as a file name and object key.
+#[cfg(any(
+    feature = "disk",
+    feature = "object-storage",
+    feature = "elasticsearch",
+    feature = "parquet"
+))]
+pub fn checkpoint_file_name(checkpoint_name: Option<&str>) -> Result<String, WriterInitError> {
+    match checkpoint_name {
+        None => Ok(LAST_UPDATED_BLOCK_FILENAME.to_string()),
+        Some(name) => {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+            {
+                return Err(WriterInitError::Config(format!(
+                    "checkpoint_name {:?} must only contain letters, digits, '-', '_' and '.'",
+                    name
+                )));
+            }
+
+            Ok(format!("{}.{}", LAST_UPDATED_BLOCK_FILENAME, name))
+        }
+    }
+}
+
+/// Parses a stored checkpoint, either the JSON state or the bare block number older versions wrote
+///
+/// An empty checkpoint means there isn't one yet. Anything else that isn't a checkpoint is an
+/// error rather than None, starting over from the configured start would silently skip blocks.
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+pub fn parse_last_block(text: &str) -> Result<Option<u64>, Error> {
+    let text = text.trim();
+
+    if text.is_empty() {
+        return Ok(None);
+    }
+
+    if text.starts_with('{') {
+        return match serde_json::from_str::<Checkpoint>(text) {
+            Ok(checkpoint) => Ok(Some(checkpoint.block_num)),
+            Err(e) => Err(eyre!("Invalid checkpoint {:?}: {}", text, e)),
+        };
+    }
+
+    match text.parse::<u64>() {
+        Ok(block) => Ok(Some(block)),
+        Err(e) => Err(eyre!("Invalid checkpoint {:?}: {}", text, e)),
+    }
+}
+
+/// The block timestamp of a stored checkpoint, bare block numbers from older versions don't have
+/// one
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+pub fn checkpoint_timestamp(text: &str) -> Option<DateTime<Utc>> {
+    serde_json::from_str::<Checkpoint>(text.trim())
+        .ok()
+        .map(|checkpoint| checkpoint.timestamp)
+}
+
+/// Relative directory a block's podpings are written to, i.e. `YYYY/M/D/h/m/s`
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+pub fn block_path(timestamp: &DateTime<Utc>) -> PathBuf {
+    PathBuf::new()
+        .join(timestamp.year().to_string())
+        .join(timestamp.month().to_string())
+        .join(timestamp.day().to_string())
+        .join(timestamp.hour().to_string())
+        .join(timestamp.minute().to_string())
+        .join(timestamp.second().to_string())
+}
+
+/// File name for a podping, also its object key; the disk writer makes it portable
+///
+/// `index` is the podping's operation index in its transaction.
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+pub fn podping_file_name(block_num: u64, tx_id: &str, index: usize, podping: &Podping) -> String {
+    match podping {
+        Podping::V0(_) | Podping::V02(_) | Podping::V03(_) | Podping::V10(_) => {
+            format!("{}_{}_{}.json", block_num, tx_id, index)
+        }
+        Podping::V11(pp) => format!(
+            "{}_{}_{}_{}.json",
+            block_num,
+            tx_id,
+            pp.session_id.to_string(),
+            pp.timestamp_ns.to_string()
+        ),
+    }
+}
+
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+pub fn block_metadata_file_name(block_num: u64) -> String {
+    format!("{}_block.json", block_num)
+}
+
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+pub fn empty_block_marker_file_name(block_num: u64) -> String {
+    format!("{}.empty", block_num)
+}
+
+/// Relative paths of every file written for a block
+#[cfg(any(feature = "disk", feature = "object-storage"))]
+pub fn block_file_paths(block: &HiveBlockWithNum, sidecars: BlockSidecars) -> Vec<PathBuf> {
+    let current_block_path = block_path(&block.timestamp);
+
+    let mut paths = block
+        .transactions
+        .iter()
+        .flat_map(|tx| {
+            tx.indexed_podpings().map(|(i, podping)| {
+                current_block_path.join(podping_file_name(block.block_num, &tx.tx_id, i, podping))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if sidecars.metadata(block) {
+        paths.push(current_block_path.join(block_metadata_file_name(block.block_num)));
+    }
+
+    if sidecars.empty_marker(block) {
+        paths.push(current_block_path.join(empty_block_marker_file_name(block.block_num)));
+    }
+
+    paths
+}